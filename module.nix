@@ -12,13 +12,14 @@ let
   # Generate the JSON config that refindgen expects
   refindInstallConfig = pkgs.writeText "refind-install.json" (
     builtins.toJSON {
+      schemaVersion = 2;
       nixPath = "${config.nix.package}";
       refindPath = "${cfg.package}";
       efiMountPoint = efi.efiSysMountPoint;
       efiBootMgrPath = "${pkgs.efibootmgr}";
       canTouchEfiVariables = efi.canTouchEfiVariables;
       efiRemovable = cfg.efiInstallAsRemovable;
-      timeout = if config.boot.loader.timeout != null then config.boot.loader.timeout else 10;
+      timeout = config.boot.loader.timeout;
       maxGenerations = if cfg.maxGenerations == null then 0 else cfg.maxGenerations;
       extraConfig = cfg.extraConfig;
       hostArchitecture = pkgs.stdenv.hostPlatform.system;
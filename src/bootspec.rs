@@ -1,9 +1,11 @@
 use anyhow::{Context, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
-#[derive(Debug, Deserialize, Clone)]
+use crate::fs::Filesystem;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct BootSpec {
     pub system: String,
@@ -18,14 +20,24 @@ pub struct BootSpec {
     pub initrd_secrets: Option<PathBuf>,
     #[serde(default)]
     pub specialisations: HashMap<String, Box<BootSpec>>,
+    /// Top-level boot.json keys besides the bootspec and specialisations, e.g. vendor
+    /// `org.*`/`com.*` extensions, in whatever shape they came
+    #[serde(default)]
+    pub extensions: HashMap<String, serde_json::Value>,
 }
 
+const BOOTSPEC_V1: &str = "org.nixos.bootspec.v1";
+const SPECIALISATION_V1: &str = "org.nixos.specialisation.v1";
+
+/// A boot.json document as written by NixOS, before it is flattened into a [`BootSpec`]
 #[derive(Debug, Deserialize)]
-struct BootJson {
+pub struct BootJson {
     #[serde(rename = "org.nixos.bootspec.v1")]
     bootspec: BootSpecV1,
     #[serde(rename = "org.nixos.specialisation.v1", default)]
     specialisation: HashMap<String, BootJson>,
+    #[serde(flatten)]
+    extensions: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -43,19 +55,282 @@ struct BootSpecV1 {
     initrd_secrets: Option<PathBuf>,
 }
 
+impl BootJson {
+    /// Parse boot.json content. Errors name the offending field, e.g.
+    /// `org.nixos.bootspec.v1.kernelParams`.
+    ///
+    /// Documents that only carry a newer `org.nixos.bootspec.vN` are read through the
+    /// fields v1 shares with it, with a warning; v1 wins when both are present.
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut value: serde_json::Value =
+            serde_json::from_str(content).context("boot.json is not valid JSON")?;
+        downgrade_newer_bootspec(&mut value);
+        check_boot_json(&value, "")?;
+
+        serde_json::from_value(value).context("Failed to parse boot.json")
+    }
+}
+
+/// Fields every bootspec version so far has had
+const COMMON_FIELDS: &[&str] = &[
+    "kernel",
+    "init",
+    "initrd",
+    "kernelParams",
+    "label",
+    "system",
+    "toplevel",
+];
+
+/// Stand-in for a common field a newer bootspec lacks; kernel and init have none
+fn common_field_default(field: &str) -> Option<serde_json::Value> {
+    match field {
+        "initrd" => Some(serde_json::Value::Null),
+        "kernelParams" => Some(serde_json::json!([])),
+        "label" | "system" | "toplevel" => Some(serde_json::json!("")),
+        _ => None,
+    }
+}
+
+/// If a document has no v1 bootspec but a newer one, synthesize v1 from the newest
+/// version's common fields. Applies to specialisations as well.
+fn downgrade_newer_bootspec(value: &mut serde_json::Value) {
+    let Some(document) = value.as_object_mut() else {
+        return;
+    };
+
+    if let Some(specialisations) = document
+        .get_mut(SPECIALISATION_V1)
+        .and_then(|s| s.as_object_mut())
+    {
+        for spec in specialisations.values_mut() {
+            downgrade_newer_bootspec(spec);
+        }
+    }
+
+    if document.contains_key(BOOTSPEC_V1) {
+        return;
+    }
+    let Some((newest, _)) = document
+        .keys()
+        .filter_map(|key| {
+            let version: u32 = key.strip_prefix("org.nixos.bootspec.v")?.parse().ok()?;
+            (version > 1).then(|| (key.clone(), version))
+        })
+        .max_by_key(|(_, version)| *version)
+    else {
+        return;
+    };
+    let Some(newer) = document[&newest].as_object() else {
+        return;
+    };
+
+    let mut v1 = serde_json::Map::new();
+    for &field in COMMON_FIELDS {
+        if let Some(value) = newer
+            .get(field)
+            .cloned()
+            .or_else(|| common_field_default(field))
+        {
+            v1.insert(field.to_string(), value);
+        }
+    }
+
+    crate::warn!(
+        "boot.json only has {}, which refindgen doesn't fully support yet; using the fields it shares with v1",
+        newest
+    );
+    document.insert(BOOTSPEC_V1.to_string(), serde_json::Value::Object(v1));
+}
+
+#[derive(Clone, Copy)]
+enum FieldKind {
+    String,
+    OptionalString,
+    StringArray,
+}
+
+const BOOTSPEC_V1_FIELDS: &[(&str, FieldKind)] = &[
+    ("system", FieldKind::String),
+    ("init", FieldKind::String),
+    ("kernel", FieldKind::String),
+    ("kernelParams", FieldKind::StringArray),
+    ("label", FieldKind::String),
+    ("toplevel", FieldKind::String),
+    ("initrd", FieldKind::OptionalString),
+    ("initrdSecrets", FieldKind::OptionalString),
+];
+
+/// Check the shape serde will expect, so errors point at a field instead of a line
+fn check_boot_json(value: &serde_json::Value, prefix: &str) -> Result<()> {
+    let document = value.as_object().with_context(|| match prefix {
+        "" => "boot.json: expected an object".to_string(),
+        _ => format!("{}: expected an object", prefix.trim_end_matches('.')),
+    })?;
+    let v1 = document
+        .get(BOOTSPEC_V1)
+        .with_context(|| format!("missing field {}{}", prefix, BOOTSPEC_V1))?
+        .as_object()
+        .with_context(|| format!("{}{}: expected an object", prefix, BOOTSPEC_V1))?;
+
+    for &(field, kind) in BOOTSPEC_V1_FIELDS {
+        let name = format!("{}{}.{}", prefix, BOOTSPEC_V1, field);
+        let valid = match (kind, v1.get(field)) {
+            (FieldKind::OptionalString, None | Some(serde_json::Value::Null)) => true,
+            (_, None) => anyhow::bail!("missing field {}", name),
+            (FieldKind::String | FieldKind::OptionalString, Some(value)) => value.is_string(),
+            (FieldKind::StringArray, Some(value)) => value
+                .as_array()
+                .is_some_and(|items| items.iter().all(|item| item.is_string())),
+        };
+        if !valid {
+            let expected = match kind {
+                FieldKind::StringArray => "an array of strings",
+                _ => "a string",
+            };
+            anyhow::bail!("{}: expected {}", name, expected);
+        }
+    }
+
+    if let Some(specialisations) = document.get(SPECIALISATION_V1) {
+        let specialisations = specialisations
+            .as_object()
+            .with_context(|| format!("{}{}: expected an object", prefix, SPECIALISATION_V1))?;
+        for (name, spec) in specialisations {
+            check_boot_json(spec, &format!("{}{}.{}.", prefix, SPECIALISATION_V1, name))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// boot.json key listing initrds to load before the main one, as an array of paths
+pub const EARLY_INITRDS_EXTENSION: &str = "org.refindgen.earlyInitrds";
+
 impl BootSpec {
-    pub fn load(system_path: &Path) -> Result<Self> {
+    /// Load a generation's boot.json, or [`BootSpec::synthesize`] it when the generation
+    /// predates bootspec or was built with `boot.bootspec.enable = false`
+    pub fn load(filesystem: &dyn Filesystem, system_path: &Path) -> Result<Self> {
         let boot_json_path = system_path.join("boot.json");
-        let content = std::fs::read_to_string(&boot_json_path)
+        if !filesystem.exists(&boot_json_path) {
+            return Self::synthesize(filesystem, system_path);
+        }
+        let content = filesystem
+            .read_to_string(&boot_json_path)
             .with_context(|| format!("Failed to read boot.json at {:?}", boot_json_path))?;
 
-        let boot_json: BootJson =
-            serde_json::from_str(&content).context("Failed to parse boot.json")?;
+        content
+            .parse()
+            .with_context(|| format!("Invalid boot.json at {:?}", boot_json_path))
+    }
+
+    /// Parse boot.json content from a reader
+    pub fn from_reader(mut reader: impl std::io::Read) -> Result<Self> {
+        let mut content = String::new();
+        reader
+            .read_to_string(&mut content)
+            .context("Failed to read boot.json")?;
+        content.parse()
+    }
+
+    /// Kernel release, e.g. `6.6.30`, read from the `lib/modules/` directory next to the
+    /// kernel image in its store path
+    pub fn kernel_version(&self, filesystem: &dyn Filesystem) -> Result<String> {
+        let kernel = filesystem
+            .canonicalize(&self.kernel)
+            .with_context(|| format!("Failed to resolve kernel {}", self.kernel.display()))?;
+        let modules_dir = kernel
+            .parent()
+            .context("Kernel path has no parent directory")?
+            .join("lib/modules");
+
+        let entry = filesystem
+            .read_dir(&modules_dir)
+            .with_context(|| format!("Failed to read {}", modules_dir.display()))?
+            .into_iter()
+            .next()
+            .with_context(|| format!("No kernel modules in {}", modules_dir.display()))?;
+
+        entry
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(str::to_string)
+            .with_context(|| format!("Invalid kernel version: {:?}", entry))
+    }
+
+    /// Initrds listed in the [`EARLY_INITRDS_EXTENSION`] extension, in order. Entries
+    /// that aren't strings are ignored.
+    pub fn early_initrds(&self) -> Vec<PathBuf> {
+        self.extensions
+            .get(EARLY_INITRDS_EXTENSION)
+            .and_then(|value| value.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|path| path.as_str().map(PathBuf::from))
+            .collect()
+    }
+
+    /// Reconstruct boot parameters from the legacy `kernel`, `initrd`, `kernel-params`
+    /// and `init` files of a generation, plus its `specialisation/*` directories
+    pub fn synthesize(filesystem: &dyn Filesystem, gen_dir: &Path) -> Result<Self> {
+        let mut bootspec = Self::synthesize_one(filesystem, gen_dir)?;
+
+        let specialisation_dir = bootspec.toplevel.join("specialisation");
+        if filesystem.is_dir(&specialisation_dir) {
+            for path in filesystem
+                .read_dir(&specialisation_dir)
+                .with_context(|| format!("Failed to read {}", specialisation_dir.display()))?
+            {
+                let name = path
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string();
+                let spec = Self::synthesize_one(filesystem, &path)
+                    .with_context(|| format!("Failed to read specialisation '{}'", name))?;
+                bootspec.specialisations.insert(name, Box::new(spec));
+            }
+        }
+
+        Ok(bootspec)
+    }
+
+    fn synthesize_one(filesystem: &dyn Filesystem, gen_dir: &Path) -> Result<Self> {
+        let toplevel = filesystem
+            .canonicalize(gen_dir)
+            .with_context(|| format!("readlink {} failed", gen_dir.display()))?;
+
+        let kernel = toplevel.join("kernel");
+        if !filesystem.exists(&kernel) {
+            anyhow::bail!("No boot.json and no kernel in {}", toplevel.display());
+        }
+
+        let read = |name: &str| {
+            filesystem
+                .read_to_string(&toplevel.join(name))
+                .unwrap_or_default()
+        };
+        let initrd = toplevel.join("initrd");
 
-        Ok(Self::from_boot_json(boot_json))
+        Ok(Self {
+            system: read("system").trim().to_string(),
+            init: toplevel.join("init"),
+            kernel,
+            kernel_params: read("kernel-params")
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            // NixOS only writes labels into boot.json; empty marks a pre-bootspec generation
+            label: String::new(),
+            initrd: filesystem.exists(&initrd).then_some(initrd),
+            initrd_secrets: None,
+            toplevel,
+            specialisations: HashMap::new(),
+            extensions: HashMap::new(),
+        })
     }
 
-    fn from_boot_json(boot_json: BootJson) -> Self {
+    pub fn from_boot_json(boot_json: BootJson) -> Self {
         let specialisations = boot_json
             .specialisation
             .into_iter()
@@ -72,6 +347,358 @@ impl BootSpec {
             initrd: boot_json.bootspec.initrd,
             initrd_secrets: boot_json.bootspec.initrd_secrets,
             specialisations,
+            extensions: boot_json.extensions,
         }
     }
 }
+
+impl std::str::FromStr for BootSpec {
+    type Err = anyhow::Error;
+
+    /// Parse boot.json content
+    fn from_str(content: &str) -> Result<Self> {
+        BootJson::parse(content).map(Self::from_boot_json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::tests::{ScratchDir, put};
+
+    /// A generation link at `/profiles/system-1-link` to a pre-bootspec toplevel with a
+    /// `rescue` specialisation
+    fn legacy_generation(scratch: &ScratchDir) -> PathBuf {
+        let filesystem = scratch.rooted();
+        let files = [
+            ("/store/legacy-system/kernel", "kernel"),
+            ("/store/legacy-system/initrd", "initrd"),
+            ("/store/legacy-system/init", "#!/bin/sh"),
+            ("/store/legacy-system/system", "x86_64-linux\n"),
+            (
+                "/store/legacy-system/kernel-params",
+                "root=/dev/sda1  quiet\nloglevel=4\n",
+            ),
+            ("/store/rescue-system/kernel", "kernel"),
+            ("/store/rescue-system/init", "#!/bin/sh"),
+            (
+                "/store/rescue-system/kernel-params",
+                "systemd.unit=rescue.target",
+            ),
+        ];
+        for (path, content) in files {
+            put(filesystem.as_ref(), path, content);
+        }
+        std::fs::create_dir_all(scratch.path().join("store/legacy-system/specialisation")).unwrap();
+        std::os::unix::fs::symlink(
+            "../../rescue-system",
+            scratch
+                .path()
+                .join("store/legacy-system/specialisation/rescue"),
+        )
+        .unwrap();
+        std::fs::create_dir_all(scratch.path().join("profiles")).unwrap();
+        std::os::unix::fs::symlink(
+            "../store/legacy-system",
+            scratch.path().join("profiles/system-1-link"),
+        )
+        .unwrap();
+        PathBuf::from("/profiles/system-1-link")
+    }
+
+    /// A valid v1 document
+    fn boot_json() -> serde_json::Value {
+        serde_json::json!({
+            "org.nixos.bootspec.v1": {
+                "system": "x86_64-linux",
+                "init": "/store/system/init",
+                "kernel": "/store/linux/bzImage",
+                "kernelParams": ["quiet"],
+                "label": "NixOS 24.05 (Linux 6.6.30)",
+                "toplevel": "/store/system",
+                "initrd": "/store/initrd/initrd",
+            }
+        })
+    }
+
+    /// The error parsing `content` gives, which must not panic
+    fn parse_error(content: &str) -> String {
+        format!("{:#}", content.parse::<BootSpec>().unwrap_err())
+    }
+
+    #[test]
+    fn malformed_boot_json_is_an_error() {
+        let valid = boot_json().to_string();
+        for truncated in [
+            &valid[..valid.len() / 2],
+            &valid[..valid.len() - 1],
+            "",
+            "{",
+        ] {
+            assert!(
+                parse_error(truncated).starts_with("boot.json is not valid JSON"),
+                "{}",
+                truncated
+            );
+        }
+        for not_an_object in ["[]", "null", "42", "\"boot\"", "[{}]"] {
+            assert_eq!(
+                parse_error(not_an_object),
+                "boot.json: expected an object",
+                "{}",
+                not_an_object
+            );
+        }
+        assert_eq!(parse_error("{}"), "missing field org.nixos.bootspec.v1");
+        assert_eq!(
+            parse_error(r#"{"org.nixos.bootspec.v1": []}"#),
+            "org.nixos.bootspec.v1: expected an object"
+        );
+    }
+
+    #[test]
+    fn fields_of_the_wrong_type_are_named() {
+        let field = "org.nixos.bootspec.v1";
+        for (key, value, expected) in [
+            ("kernel", serde_json::json!(5), "kernel: expected a string"),
+            ("init", serde_json::json!(null), "init: expected a string"),
+            (
+                "label",
+                serde_json::json!(["a"]),
+                "label: expected a string",
+            ),
+            (
+                "initrd",
+                serde_json::json!(false),
+                "initrd: expected a string",
+            ),
+            (
+                "kernelParams",
+                serde_json::json!("quiet"),
+                "kernelParams: expected an array of strings",
+            ),
+            (
+                "kernelParams",
+                serde_json::json!(["quiet", 1]),
+                "kernelParams: expected an array of strings",
+            ),
+            (
+                "initrdSecrets",
+                serde_json::json!({}),
+                "initrdSecrets: expected a string",
+            ),
+        ] {
+            let mut document = boot_json();
+            document[field][key] = value;
+            assert_eq!(
+                parse_error(&document.to_string()),
+                format!("{}.{}", field, expected)
+            );
+        }
+
+        let mut document = boot_json();
+        document[field].as_object_mut().unwrap().remove("toplevel");
+        assert_eq!(
+            parse_error(&document.to_string()),
+            "missing field org.nixos.bootspec.v1.toplevel"
+        );
+    }
+
+    #[test]
+    fn malformed_specialisations_are_named() {
+        let mut document = boot_json();
+        document["org.nixos.specialisation.v1"] = serde_json::json!([]);
+        assert_eq!(
+            parse_error(&document.to_string()),
+            "org.nixos.specialisation.v1: expected an object"
+        );
+
+        let mut rescue = boot_json();
+        rescue["org.nixos.bootspec.v1"]["kernelParams"] = serde_json::json!(7);
+        document["org.nixos.specialisation.v1"] = serde_json::json!({ "rescue": rescue });
+        assert_eq!(
+            parse_error(&document.to_string()),
+            "org.nixos.specialisation.v1.rescue.org.nixos.bootspec.v1.kernelParams: \
+             expected an array of strings"
+        );
+
+        document["org.nixos.specialisation.v1"] = serde_json::json!({ "rescue": "x" });
+        assert_eq!(
+            parse_error(&document.to_string()),
+            "org.nixos.specialisation.v1.rescue: expected an object"
+        );
+    }
+
+    #[test]
+    fn unreadable_boot_json_is_an_error() {
+        let error = BootSpec::from_reader(&b"{\"org.nixos\xff"[..]).unwrap_err();
+        assert_eq!(error.to_string(), "Failed to read boot.json");
+
+        let scratch = ScratchDir::new();
+        put(
+            scratch.rooted().as_ref(),
+            "/system/boot.json",
+            "{\"org.nixos.bootspec.v1\": ",
+        );
+        let error = BootSpec::load(scratch.rooted().as_ref(), Path::new("/system")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid boot.json at \"/system/boot.json\""
+        );
+    }
+
+    /// [`boot_json`] with its bootspec under `version` instead
+    fn boot_json_as(version: &str) -> serde_json::Value {
+        let mut document = boot_json();
+        let bootspec = document
+            .as_object_mut()
+            .unwrap()
+            .remove("org.nixos.bootspec.v1")
+            .unwrap();
+        document[version] = bootspec;
+        document
+    }
+
+    #[test]
+    fn v2_documents_are_read_through_the_common_fields() {
+        let mut document = boot_json_as("org.nixos.bootspec.v2");
+        document["org.nixos.bootspec.v2"]["kernelModules"] = serde_json::json!(["kvm"]);
+        document["org.nixos.bootspec.v2"]
+            .as_object_mut()
+            .unwrap()
+            .remove("label");
+
+        let (bootspec, warned) =
+            crate::log::tests::warnings(|| document.to_string().parse::<BootSpec>());
+        let bootspec = bootspec.unwrap();
+        assert_eq!(
+            warned,
+            [
+                "boot.json only has org.nixos.bootspec.v2, which refindgen doesn't fully support yet; using the fields it shares with v1"
+            ]
+        );
+        assert_eq!(bootspec.kernel, Path::new("/store/linux/bzImage"));
+        assert_eq!(
+            bootspec.initrd.as_deref(),
+            Some(Path::new("/store/initrd/initrd"))
+        );
+        assert_eq!(bootspec.kernel_params, ["quiet"]);
+        assert_eq!(bootspec.label, "");
+        assert_eq!(bootspec.initrd_secrets, None);
+        assert_eq!(
+            bootspec.extensions["org.nixos.bootspec.v2"]["kernelModules"],
+            serde_json::json!(["kvm"])
+        );
+    }
+
+    #[test]
+    fn the_newest_version_is_downgraded() {
+        let mut document = boot_json_as("org.nixos.bootspec.v3");
+        document["org.nixos.bootspec.v2"] = document["org.nixos.bootspec.v3"].clone();
+        document["org.nixos.bootspec.v2"]["kernel"] = serde_json::json!("/store/old/bzImage");
+
+        let (bootspec, _) =
+            crate::log::tests::warnings(|| document.to_string().parse::<BootSpec>());
+        assert_eq!(bootspec.unwrap().kernel, Path::new("/store/linux/bzImage"));
+    }
+
+    #[test]
+    fn v2_documents_still_need_a_kernel() {
+        let mut document = boot_json_as("org.nixos.bootspec.v2");
+        document["org.nixos.bootspec.v2"]
+            .as_object_mut()
+            .unwrap()
+            .remove("kernel");
+
+        let (error, _) = crate::log::tests::warnings(|| parse_error(&document.to_string()));
+        assert_eq!(error, "missing field org.nixos.bootspec.v1.kernel");
+    }
+
+    #[test]
+    fn v1_wins_over_v2() {
+        let mut document = boot_json();
+        document["org.nixos.bootspec.v2"] = boot_json()["org.nixos.bootspec.v1"].clone();
+        document["org.nixos.bootspec.v2"]["kernel"] = serde_json::json!("/store/v2/bzImage");
+
+        let (bootspec, warned) =
+            crate::log::tests::warnings(|| document.to_string().parse::<BootSpec>());
+        let bootspec = bootspec.unwrap();
+        assert!(warned.is_empty(), "{:?}", warned);
+        assert_eq!(bootspec.kernel, Path::new("/store/linux/bzImage"));
+        assert_eq!(
+            bootspec.extensions["org.nixos.bootspec.v2"]["kernel"],
+            "/store/v2/bzImage"
+        );
+    }
+
+    #[test]
+    fn v2_specialisations_are_downgraded() {
+        let mut rescue = boot_json_as("org.nixos.bootspec.v2");
+        rescue["org.nixos.bootspec.v2"]["kernelParams"] =
+            serde_json::json!(["systemd.unit=rescue.target"]);
+        let mut document = boot_json();
+        document["org.nixos.specialisation.v1"] = serde_json::json!({ "rescue": rescue });
+
+        let (bootspec, warned) =
+            crate::log::tests::warnings(|| document.to_string().parse::<BootSpec>());
+        let bootspec = bootspec.unwrap();
+        assert_eq!(warned.len(), 1, "{:?}", warned);
+        assert_eq!(
+            bootspec.specialisations["rescue"].kernel_params,
+            ["systemd.unit=rescue.target"]
+        );
+    }
+
+    #[test]
+    fn legacy_generations_are_synthesized() {
+        let scratch = ScratchDir::new();
+        let link = legacy_generation(&scratch);
+        let bootspec = BootSpec::load(scratch.rooted().as_ref(), &link).unwrap();
+
+        let toplevel = Path::new("/store/legacy-system");
+        assert_eq!(bootspec.toplevel, toplevel);
+        assert_eq!(bootspec.system, "x86_64-linux");
+        assert_eq!(bootspec.kernel, toplevel.join("kernel"));
+        assert_eq!(bootspec.init, toplevel.join("init"));
+        assert_eq!(bootspec.initrd, Some(toplevel.join("initrd")));
+        assert_eq!(
+            bootspec.kernel_params,
+            ["root=/dev/sda1", "quiet", "loglevel=4"]
+        );
+        assert_eq!(bootspec.label, "");
+        assert_eq!(bootspec.initrd_secrets, None);
+
+        assert_eq!(bootspec.specialisations.len(), 1);
+        let rescue = &bootspec.specialisations["rescue"];
+        let toplevel = Path::new("/store/rescue-system");
+        assert_eq!(rescue.toplevel, toplevel);
+        assert_eq!(rescue.kernel, toplevel.join("kernel"));
+        assert_eq!(rescue.initrd, None);
+        assert_eq!(rescue.kernel_params, ["systemd.unit=rescue.target"]);
+        assert_eq!(rescue.system, "");
+        assert!(rescue.specialisations.is_empty());
+    }
+
+    #[test]
+    fn legacy_generations_need_a_kernel() {
+        let scratch = ScratchDir::new();
+        let link = legacy_generation(&scratch);
+        std::fs::remove_file(scratch.path().join("store/legacy-system/kernel")).unwrap();
+
+        let error = BootSpec::load(scratch.rooted().as_ref(), &link).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "No boot.json and no kernel in /store/legacy-system"
+        );
+    }
+
+    #[test]
+    fn broken_specialisations_are_named() {
+        let scratch = ScratchDir::new();
+        let link = legacy_generation(&scratch);
+        std::fs::remove_file(scratch.path().join("store/rescue-system/kernel")).unwrap();
+
+        let error = BootSpec::load(scratch.rooted().as_ref(), &link).unwrap_err();
+        assert_eq!(error.to_string(), "Failed to read specialisation 'rescue'");
+    }
+}
@@ -1,29 +1,1719 @@
 use anyhow::{Context, Result};
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
+mod layers;
+
+pub use layers::{Kind, ResolvedConfig, SETTINGS, Setting, Source};
+
+/// What refindgen installs and where, read from the JSON or TOML file in `CONFIG_PATH`.
+/// Keys are camelCase, snake_case works too.
+///
+/// Only `nixPath` and `refindPath` are required, plus `efiBootMgrPath` when
+/// `canTouchEfiVariables` is set with the efibootmgr backend; everything else has a
+/// default. Unknown keys are an error, so a typo doesn't silently fall back to one.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct InstallConfig {
+    /// Schema the config is written against. Defaults to 1, the schema from before
+    /// versioning. Older schemas are migrated to [`SCHEMA_VERSION`] when loaded.
+    #[serde(default = "default_schema_version", alias = "schema_version")]
+    pub schema_version: u64,
+    /// Nix package providing `bin/nix-env`. Required.
+    #[serde(alias = "nix_path")]
     pub nix_path: PathBuf,
+    /// rEFInd package providing `share/refind`. Required.
+    #[serde(alias = "refind_path")]
     pub refind_path: PathBuf,
+    /// Where the ESP is mounted. Defaults to `/boot`.
+    #[serde(default = "default_efi_mount_point", alias = "efi_mount_point")]
     pub efi_mount_point: PathBuf,
+    /// efibootmgr package providing `bin/efibootmgr`. Required when
+    /// `canTouchEfiVariables` is set and `nvramBackend` is `efibootmgr`, unused otherwise.
+    #[serde(default, alias = "efi_boot_mgr_path")]
     pub efi_boot_mgr_path: PathBuf,
+    /// Whether NVRAM boot entries may be created. Defaults to `false`.
+    #[serde(default, alias = "can_touch_efi_variables")]
     pub can_touch_efi_variables: bool,
+    /// Also install to the removable-media fallback path `EFI/BOOT/BOOT<arch>.EFI`, with a
+    /// copy of refind.conf beside it, and never touch NVRAM. Defaults to `false`.
+    #[serde(default, alias = "efi_removable")]
     pub efi_removable: bool,
-    pub timeout: u32,
+    /// Seconds rEFInd shows the menu before booting the default, as NixOS'
+    /// `boot.loader.timeout` means it: 0 boots the default right away, `null` waits for
+    /// a choice. Defaults to 20.
+    #[serde(default = "default_timeout")]
+    pub timeout: Option<u32>,
+    /// Show rEFInd's menu as text instead of graphics (`textonly`), e.g. when the
+    /// firmware hands it a broken framebuffer. Defaults to `false`.
+    #[serde(default, alias = "text_only")]
+    pub text_only: bool,
+    /// Firmware text mode rEFInd switches to (`textmode`): 0 is 80x25, 1 usually 80x50,
+    /// higher numbers are firmware-specific and 1024 keeps the mode rEFInd started in.
+    /// Defaults to none, leaving it to rEFInd.
+    #[serde(default, alias = "text_mode")]
+    pub text_mode: Option<u32>,
+    /// Generations to keep per profile, 0 for all of them. Defaults to 0.
+    #[serde(default, alias = "max_generations")]
     pub max_generations: usize,
-    pub extra_config: String,
+    /// rEFInd config prepended verbatim: a string, `{ "inline": "..." }`, or
+    /// `{ "file": "/path" }`, read when the config is loaded, relative paths from the
+    /// config's directory. Defaults to empty.
+    #[serde(default, alias = "extra_config")]
+    pub extra_config: ExtraConfig,
+    /// Nix system double, e.g. `x86_64-linux`. Defaults to the architecture refindgen was built for.
+    #[serde(default = "default_host_architecture", alias = "host_architecture")]
     pub host_architecture: String,
+    /// Extra files to copy, keyed by destination relative to `efi/refind`. Defaults to none.
+    #[serde(default, alias = "additional_files")]
     pub additional_files: HashMap<String, PathBuf>,
+    /// LUKS devices as (name, device) pairs, unlocked through kernel parameters added to
+    /// every entry. Defaults to none.
+    #[serde(default, alias = "luks_devices")]
     pub luks_devices: Vec<(String, String)>,
+    /// How `luksDevices` are passed to the initrd. Defaults to `rdLuksName`.
+    #[serde(default, alias = "luks_param_style")]
+    pub luks_param_style: LuksParamStyle,
+    /// Kernel staging layout. Defaults to `flat`.
+    #[serde(default, alias = "kernel_layout")]
+    pub kernel_layout: KernelLayout,
+    /// Handling of files shared between generation directories. Defaults to `duplicate`.
+    #[serde(default, alias = "shared_files")]
+    pub shared_files: SharedFiles,
+    /// Names shown in menu titles instead of the raw profile name. Defaults to none.
+    #[serde(default, alias = "profile_labels")]
+    pub profile_labels: HashMap<String, String>,
+    /// Emit `enable_and_lock_vmx true` in every boot entry. Defaults to `false`.
+    ///
+    /// rEFInd then enables Intel VMX and sets the lock bit in IA32_FEATURE_CONTROL before
+    /// starting the kernel. Once locked, the MSR can't be rewritten until the next reset, so
+    /// a compromised OS can neither turn virtualization off to disrupt a hypervisor nor
+    /// reconfigure it, and the setting doesn't depend on whatever the firmware left behind.
+    #[serde(default, alias = "enable_and_lock_vmx")]
+    pub enable_and_lock_vmx: bool,
+    /// Directory holding the `system` profile and `system-profiles/`. Defaults to
+    /// `$NIX_STATE_DIR/profiles`, or `/nix/var/nix/profiles` when that isn't set.
+    #[serde(
+        default = "crate::generation::default_profiles_root",
+        alias = "profiles_root"
+    )]
+    pub profiles_root: PathBuf,
+    /// memtest86plus EFI binary to offer in the menu. Defaults to looking for it in the
+    /// default generation's system closure.
+    #[serde(default, alias = "memtest86_path")]
+    pub memtest86_path: Option<PathBuf>,
+    /// NVRAM entry (4 hex digits, e.g. `0000`) of Windows Boot Manager. When set, adds a
+    /// "Windows (via firmware)" entry that chain-boots it with `firmware_bootnum`
+    /// (rEFInd 0.13.3+). Defaults to none.
+    #[serde(default, alias = "windows_firmware_bootnum")]
+    pub windows_firmware_bootnum: Option<String>,
+    /// Initrds loaded before each generation's own, in order, e.g. CPU microcode
+    /// (`intel-ucode.img`/`amd-ucode.img`). Defaults to none.
+    #[serde(default, alias = "early_initrds")]
+    pub early_initrds: Vec<PathBuf>,
+    /// rEFInd `ostype` for every entry, choosing the theme icon. Defaults to one derived
+    /// from each generation's system double.
+    #[serde(default)]
+    pub ostype: Option<String>,
+    /// Where `menuentry` blocks in `extraConfig` go relative to the NixOS entries; other
+    /// settings always come first. Defaults to `after`.
+    #[serde(default, alias = "extra_config_placement")]
+    pub extra_config_placement: ExtraConfigPlacement,
+    /// Title entries with the label NixOS wrote into boot.json, prefixed by the generation
+    /// number. Generations without a label keep the synthesized title. Defaults to `false`.
+    #[serde(default, alias = "use_bootspec_label")]
+    pub use_bootspec_label: bool,
+    /// Separate partition (XBOOTLDR) that kernels and initrds are staged to instead of the
+    /// ESP. Entries then name it with a `volume` line; refind.conf and the NVRAM entry stay
+    /// on the ESP. Defaults to none, staging on the ESP.
+    #[serde(default, alias = "boot_mount_point")]
+    pub boot_mount_point: Option<PathBuf>,
+    /// Read every file copied to the ESP back and compare its hash with the source, so
+    /// flash that silently corrupts writes fails the install instead of the boot. Slow
+    /// media can turn this off. Defaults to `true`.
+    #[serde(default = "default_verify_copies", alias = "verify_copies")]
+    pub verify_copies: bool,
+    /// Days an unused staged file is kept after it was first staged, so rolling a profile
+    /// back doesn't have to copy it again. Ages come from the manifest. Defaults to 0,
+    /// removing unused files right away.
+    #[serde(default, alias = "stale_file_retention_days")]
+    pub stale_file_retention_days: u64,
+    /// Timestamped copies of the previous refind.conf kept beside it for
+    /// `refindgen rollback`, 0 for none. Defaults to 3.
+    #[serde(default = "default_config_backups", alias = "config_backups")]
+    pub config_backups: usize,
+    /// Find the ESP's disk and partition number for its NVRAM entry from its kernel
+    /// device name, as before PARTUUIDs were used, for firmware that misbehaves with the
+    /// result. Defaults to `false`.
+    #[serde(default, alias = "legacy_efi_device_lookup")]
+    pub legacy_efi_device_lookup: bool,
+    /// Where rEFInd's NVRAM entries go in the firmware BootOrder. Defaults to `keep`.
+    #[serde(default, alias = "boot_order_position")]
+    pub boot_order_position: BootOrderPosition,
+    /// Move rEFInd's NVRAM entries to the front of the BootOrder on every install, e.g.
+    /// when Windows updates keep putting their boot manager first. Overrides
+    /// `bootOrderPosition`. Defaults to `false`.
+    #[serde(default, alias = "make_default_boot")]
+    pub make_default_boot: bool,
+    /// Label of rEFInd's NVRAM entry, e.g. to tell apart the entries of two installs. RAID1
+    /// ESPs get one entry per member, labelled `<label> (diskN)`. Defaults to `rEFInd`.
+    #[serde(default = "default_efi_entry_label", alias = "efi_entry_label")]
+    pub efi_entry_label: String,
+    /// More ESPs, e.g. one per disk, that get the same kernels and refind.conf as
+    /// `efiMountPoint` so either disk boots alone. Each gets its own NVRAM entry, labelled
+    /// `<efiEntryLabel> (ESP n)`. Kernels go onto each ESP itself, whatever
+    /// `bootMountPoint` says. Defaults to none.
+    #[serde(default, alias = "additional_esp_mounts")]
+    pub additional_esp_mounts: Vec<PathBuf>,
+    /// How NVRAM entries are read and written. Defaults to `efivarfs`.
+    #[serde(default, alias = "nvram_backend")]
+    pub nvram_backend: NvramBackend,
+    /// Tries for each NVRAM write before giving up, when it fails with a busy or I/O
+    /// error. Defaults to 3.
+    #[serde(
+        default = "default_nvram_write_attempts",
+        alias = "nvram_write_attempts"
+    )]
+    pub nvram_write_attempts: u32,
+    /// Milliseconds before retrying a failed NVRAM write, doubled for each further try.
+    /// Defaults to 500.
+    #[serde(
+        default = "default_nvram_retry_delay_ms",
+        alias = "nvram_retry_delay_ms"
+    )]
+    pub nvram_retry_delay_ms: u64,
+    /// rEFInd icon for the NixOS entries, a path on the ESP such as
+    /// `/EFI/refind/icons/os_nixos.png`. Defaults to none, leaving it to `ostype`.
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// Kernel parameters added to every entry after the generation's own. Defaults to none.
+    #[serde(default, alias = "extra_kernel_params")]
+    pub extra_kernel_params: Vec<String>,
+    /// Offer each generation's specialisations in a submenu. Defaults to `true`.
+    #[serde(
+        default = "default_include_specialisations",
+        alias = "include_specialisations"
+    )]
+    pub include_specialisations: bool,
+    /// Settings for single profiles, keyed by profile name (`system` for the default
+    /// one), shadowing the top-level values they set. See [`ProfileOverrides`]. Defaults
+    /// to none.
+    #[serde(default)]
+    pub profiles: HashMap<String, ProfileOverrides>,
+    /// rEFInd theme copied to `efi/refind/themes/<name>` and included at the top of
+    /// refind.conf. See [`Theme`]. Defaults to none.
+    #[serde(default)]
+    pub theme: Option<Theme>,
+    /// Also install rEFInd's filesystem drivers for `hostArchitecture` (`drivers_x64/`
+    /// and the like) from `refindPath`, so it can read kernels off ext4 or btrfs.
+    /// Defaults to `false`.
+    #[serde(default, alias = "install_drivers")]
+    pub install_drivers: bool,
+    /// Expand `${VAR}` and `${VAR:-default}` in every string value from the environment
+    /// when the config is loaded, e.g. `"${ESP_MOUNT:-/boot}"`. `$${` is a literal `${`.
+    /// An unset variable without a default is an error. Defaults to `false`.
+    #[serde(default, alias = "interpolate_env")]
+    pub interpolate_env: bool,
+    /// Sign the kernels, rEFInd and its drivers for Secure Boot as they're staged. See
+    /// [`SecureBoot`]. Defaults to none, staging them unsigned.
+    #[serde(default, alias = "secure_boot")]
+    pub secure_boot: Option<SecureBoot>,
+    /// Generation to boot by default instead of the newest system generation. It's staged
+    /// even when `maxGenerations` would leave it out. See [`DefaultGeneration`]. Defaults
+    /// to none.
+    #[serde(default, alias = "default_generation")]
+    pub default_generation: Option<DefaultGeneration>,
+}
+
+fn default_efi_mount_point() -> PathBuf {
+    PathBuf::from("/boot")
+}
+
+fn default_profile() -> String {
+    "system".to_string()
+}
+
+fn default_schema_version() -> u64 {
+    1
+}
+
+fn default_timeout() -> Option<u32> {
+    Some(20)
+}
+
+fn default_verify_copies() -> bool {
+    true
+}
+
+fn default_efi_entry_label() -> String {
+    "rEFInd".to_string()
+}
+
+fn default_config_backups() -> usize {
+    3
+}
+
+fn default_nvram_write_attempts() -> u32 {
+    3
+}
+
+fn default_nvram_retry_delay_ms() -> u64 {
+    500
+}
+
+fn default_include_specialisations() -> bool {
+    true
+}
+
+fn default_host_architecture() -> String {
+    format!("{}-linux", std::env::consts::ARCH)
+}
+
+/// How staged kernels and initrds are arranged under `efi/refind/kernels`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "camelCase")]
+pub enum KernelLayout {
+    /// All files directly in kernels/
+    #[default]
+    Flat,
+    /// One kernels/<profile>-<generation>/ directory per generation, specialisations alongside
+    PerGeneration,
+}
+
+/// Hand-written rEFInd config, given as text or as a file to read it from
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "ExtraConfigValue", rename_all = "camelCase")]
+pub enum ExtraConfig {
+    Inline(String),
+    File(PathBuf),
+}
+
+impl Default for ExtraConfig {
+    fn default() -> Self {
+        ExtraConfig::Inline(String::new())
+    }
+}
+
+impl ExtraConfig {
+    /// The config text, reading it if it's a file
+    pub fn read(&self) -> Result<String> {
+        match self {
+            ExtraConfig::Inline(text) => Ok(text.clone()),
+            ExtraConfig::File(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read extra config {}", path.display())),
+        }
+    }
+}
+
+/// How `extraConfig` is spelled: a bare string is inline text
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ExtraConfigValue {
+    Text(String),
+    Tagged(TaggedExtraConfig),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+enum TaggedExtraConfig {
+    Inline(String),
+    File(PathBuf),
+}
+
+impl From<ExtraConfigValue> for ExtraConfig {
+    fn from(value: ExtraConfigValue) -> Self {
+        match value {
+            ExtraConfigValue::Text(text)
+            | ExtraConfigValue::Tagged(TaggedExtraConfig::Inline(text)) => {
+                ExtraConfig::Inline(text)
+            }
+            ExtraConfigValue::Tagged(TaggedExtraConfig::File(path)) => ExtraConfig::File(path),
+        }
+    }
+}
+
+/// Where hand-written `menuentry` blocks from the extra config are placed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "camelCase")]
+pub enum ExtraConfigPlacement {
+    /// Before the NixOS entries
+    Before,
+    /// After the NixOS entries, so NixOS stays first in the menu
+    #[default]
+    After,
+}
+
+/// Where rEFInd's NVRAM entries are placed in the firmware BootOrder
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BootOrderPosition {
+    /// Where they were, or first when they're new
+    #[default]
+    Keep,
+    /// Before every other entry
+    First,
+    /// After every other entry
+    Last,
+}
+
+/// How refindgen reads and writes NVRAM boot entries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NvramBackend {
+    /// The Boot#### variables in /sys/firmware/efi/efivars, directly
+    #[default]
+    Efivarfs,
+    /// efibootmgr from `efiBootMgrPath`, parsing what it prints
+    Efibootmgr,
+}
+
+/// Kernel parameters `luksDevices` become
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LuksParamStyle {
+    /// `rd.luks.name=<uuid>=<name>`, for systemd's initrd. Devices must be given by UUID.
+    #[default]
+    RdLuksName,
+    /// `luks.<name>.device=<device>`
+    LuksDevice,
+    /// None, the initrd already knows its devices
+    Off,
+}
+
+/// What to do with a store path staged by several generations in the per-generation layout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SharedFiles {
+    /// Copy the file into every generation directory that uses it
+    #[default]
+    Duplicate,
+    /// Hard-link to an existing copy, falling back to copying where the filesystem has no
+    /// hard links (FAT ESPs)
+    Hardlink,
+}
+
+/// A rEFInd theme directory, e.g. rEFInd-dreary, installed to the ESP. refindgen owns
+/// `themes/<name>` while a theme is configured: files there that aren't part of the theme
+/// are removed, and so is the whole directory once the theme is dropped from the config.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Theme {
+    /// Directory holding the theme
+    pub source: PathBuf,
+    /// The theme's config file, relative to `source`, e.g. `theme.conf`
+    #[serde(alias = "conf_file")]
+    pub conf_file: String,
+    /// Directory name under `themes/`. Defaults to the last component of `source`,
+    /// without the hash of a Nix store path.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl Theme {
+    /// Directory name under `themes/`
+    pub fn name(&self) -> String {
+        if let Some(ref name) = self.name {
+            return name.clone();
+        }
+        let base = self
+            .source
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        // /nix/store/<32 character hash>-<name>
+        match base.split_once('-') {
+            Some((hash, name)) if hash.len() == 32 && self.source.starts_with("/nix/store") => {
+                name.to_string()
+            }
+            _ => base,
+        }
+    }
+
+    /// The `include` line for refind.conf, relative to `efi/refind`
+    pub fn include_line(&self) -> String {
+        format!("include themes/{}/{}\n", self.name(), self.conf_file)
+    }
+}
+
+/// Keys that EFI binaries staged on the ESP are signed with, for firmware enforcing
+/// Secure Boot with the user's own keys. Each signature is checked with sbverify before
+/// the file is used.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SecureBoot {
+    /// Private key sbsign signs with. Either this or `signCommand` is required.
+    #[serde(default, alias = "key_path")]
+    pub key_path: Option<PathBuf>,
+    /// Certificate of the signing key, which sbsign embeds and sbverify checks every
+    /// signature against. Required.
+    #[serde(alias = "cert_path")]
+    pub cert_path: PathBuf,
+    /// Command to sign with instead of sbsign, e.g. a wrapper around a hardware token.
+    /// Run as `<signCommand> <unsigned file> <signed file to write>`.
+    #[serde(default, alias = "sign_command")]
+    pub sign_command: Option<PathBuf>,
+    /// sbsigntools package providing `bin/sbsign` and `bin/sbverify`. Defaults to
+    /// finding them on `PATH`.
+    #[serde(default, alias = "sbsigntool_path")]
+    pub sbsigntool_path: Option<PathBuf>,
+}
+
+/// A generation pinned as the default boot target, e.g. a known-good image on a kiosk
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DefaultGeneration {
+    /// Profile the generation belongs to. Defaults to `system`.
+    #[serde(default = "default_profile")]
+    pub profile: String,
+    /// Generation number
+    pub number: u64,
+    /// Boot the newest system generation by default when the pinned one no longer
+    /// exists, rather than failing the install. Defaults to `false`.
+    #[serde(default, alias = "fallback_to_newest")]
+    pub fallback_to_newest: bool,
+}
+
+/// Top-level settings one profile sets differently. Unset fields keep the top-level
+/// value; see [`InstallConfig::for_profile`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ProfileOverrides {
+    /// Shadows `maxGenerations`
+    #[serde(default, alias = "max_generations")]
+    pub max_generations: Option<usize>,
+    /// Shadows `icon`
+    #[serde(default)]
+    pub icon: Option<String>,
+    /// Shadows the profile's `profileLabels` entry
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Shadows `extraKernelParams`, replacing the list rather than adding to it
+    #[serde(default, alias = "extra_kernel_params")]
+    pub extra_kernel_params: Option<Vec<String>>,
+    /// Shadows `includeSpecialisations`
+    #[serde(default, alias = "include_specialisations")]
+    pub include_specialisations: Option<bool>,
 }
 
 impl InstallConfig {
+    /// Read the config at `path`: TOML if it ends in `.toml`, JSON with `//` comments if
+    /// it ends in `.json`, otherwise whichever the content looks like. Errors name the
+    /// file, line and key.
     pub fn load(path: &str) -> Result<Self> {
-        let content = std::fs::read_to_string(path).context("Failed to read config file")?;
+        Ok(Self::load_layered(path, &[], &|_| None)?.config)
+    }
+
+    /// Like [`InstallConfig::load`], with [`SETTINGS`] overridden by the variables set in
+    /// `env`, then by the flags in `cli`, each with the values typed for it. Reports where
+    /// each value came from.
+    pub fn load_layered(
+        path: &str,
+        cli: &[(&'static Setting, Vec<String>)],
+        env: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<ResolvedConfig> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path))?;
+        let overrides = layers::overrides(cli, env)?;
+
+        let is_toml = match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => true,
+            Some("json") => false,
+            _ => !content.trim_start().starts_with(['{', '/']),
+        };
+        let (mut config, file_keys) = if is_toml {
+            Self::from_toml(&content, &overrides)
+                .with_context(|| format!("Failed to parse TOML {}", path))?
+        } else {
+            Self::from_json(&content, &overrides)
+                .with_context(|| format!("Failed to parse JSON {}", path))?
+        };
+
+        // Read extraConfig now, so a missing file fails before anything is written
+        if let ExtraConfig::File(ref file) = config.extra_config {
+            let file = Path::new(path).parent().unwrap_or(Path::new("")).join(file);
+            let text = ExtraConfig::File(file).read().context("extraConfig")?;
+            config.extra_config = ExtraConfig::Inline(text);
+        }
+
+        let mut sources: BTreeMap<String, Source> = file_keys
+            .iter()
+            .map(|key| (layers::camel_case(key), Source::File))
+            .collect();
+        for (key, _, source) in overrides {
+            sources.insert(key.to_string(), source);
+        }
+        Ok(ResolvedConfig { config, sources })
+    }
+
+    /// Parse JSON, replacing keys with `overrides` once it's migrated. Also returns the
+    /// top-level keys the file sets.
+    fn from_json(
+        content: &str,
+        overrides: &[(&'static str, Value, Source)],
+    ) -> Result<(Self, Vec<String>)> {
+        let content = strip_comments(content);
+        let key_regex = Regex::new(r#""([^"\\]+)"\s*:"#)?;
+        let located = |error: serde_json::Error| {
+            let key = content
+                .lines()
+                .nth(error.line().saturating_sub(1))
+                .and_then(|line| key_regex.captures(line))
+                .map(|caps| caps[1].to_string());
+            located_error(&error, Some(error.line()), key.as_deref())
+        };
+
+        let Value::Object(mut table) = serde_json::from_str(&content).map_err(located)? else {
+            anyhow::bail!("expected an object of settings at the top level");
+        };
+        let file_keys = table.keys().cloned().collect();
+
+        // A migrated, interpolated or overridden config no longer matches the text, so it's
+        // read like TOML, by key
+        if !prepare(&mut table)? && overrides.is_empty() {
+            return Ok((serde_json::from_str(&content).map_err(located)?, file_keys));
+        }
+        layers::apply(&mut table, overrides);
+        let mut key_lines: Vec<(String, usize)> = Vec::new();
+        for (index, line) in content.lines().enumerate() {
+            let Some(caps) = key_regex.captures(line) else {
+                continue;
+            };
+            let key = &caps[1];
+            if table.contains_key(key) && !key_lines.iter().any(|(name, _)| name == key) {
+                key_lines.push((key.to_string(), index + 1));
+            }
+        }
+        Ok((Self::from_table(&table, &key_lines)?, file_keys))
+    }
+
+    /// Parse TOML, replacing keys with `overrides` once it's migrated. Also returns the
+    /// top-level keys the file sets.
+    fn from_toml(
+        content: &str,
+        overrides: &[(&'static str, Value, Source)],
+    ) -> Result<(Self, Vec<String>)> {
+        let line_at = |offset: usize| content[..offset].matches('\n').count() + 1;
+        let mut key_lines: Vec<(String, usize)> = Vec::new();
+        // An error belongs to the last top-level key at or before its line, which also
+        // covers the lines of a `[table]`
+        let located = |error: toml::de::Error, key_lines: &[(String, usize)]| {
+            let line = error.span().map(|span| line_at(span.start));
+            let key = line.and_then(|line| {
+                key_lines
+                    .iter()
+                    .filter(|(_, at)| *at <= line)
+                    .max_by_key(|(_, at)| *at)
+                    .map(|(key, _)| key.as_str())
+            });
+            located_error(&error.message(), line, key)
+        };
+
+        let document = toml::de::DeTable::parse(content).map_err(|error| located(error, &[]))?;
+        for key in document.get_ref().keys() {
+            key_lines.push((key.get_ref().to_string(), line_at(key.span().start)));
+        }
+        let mut table: Map<String, Value> =
+            toml::from_str(content).map_err(|error| located(error, &key_lines))?;
+        let file_keys = table.keys().cloned().collect();
+
+        if !prepare(&mut table)? && overrides.is_empty() {
+            let config = toml::from_str(content).map_err(|error| located(error, &key_lines))?;
+            return Ok((config, file_keys));
+        }
+        layers::apply(&mut table, overrides);
+        Ok((Self::from_table(&table, &key_lines)?, file_keys))
+    }
+
+    /// Deserialize a top-level table, `key_lines` giving the line each key is on
+    fn from_table(table: &Map<String, Value>, key_lines: &[(String, usize)]) -> Result<Self> {
+        // One top-level key per line, so the line of a serde error says which key failed
+        let mut json = String::from("{\n");
+        for (index, (key, value)) in table.iter().enumerate() {
+            let separator = if index + 1 == table.len() { "" } else { "," };
+            json.push_str(&format!(
+                "{}:{}{}\n",
+                Value::from(key.as_str()),
+                value,
+                separator
+            ));
+        }
+        json.push('}');
+
+        serde_json::from_str(&json).map_err(|error| {
+            let key = error
+                .line()
+                .checked_sub(2)
+                .and_then(|index| table.keys().nth(index));
+            let line = key.and_then(|key| {
+                key_lines
+                    .iter()
+                    .find(|(name, _)| name == key)
+                    .map(|(_, line)| *line)
+            });
+            located_error(&error, line, key.map(String::as_str))
+        })
+    }
+
+    /// A config with every field set to an example value and a `//` comment explaining it.
+    ///
+    /// [`InstallConfig::load`] strips the comments, so the output loads as is.
+    pub fn example() -> String {
+        EXAMPLE_CONFIG.to_string()
+    }
+
+    /// Check the config as a whole before anything is written: paths absolute and
+    /// present, `refindPath` a rEFInd package for `hostArchitecture`, efibootmgr there
+    /// when it will be run, numbers in range and `additionalFiles` inside the ESP. Every
+    /// problem found is reported in one error.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        let mut check_path = |key: &str, path: &Path, must_exist: bool| {
+            if !path.is_absolute() {
+                problems.push(format!(
+                    "{} {} is not an absolute path",
+                    key,
+                    path.display()
+                ));
+            } else if must_exist && !path.exists() {
+                problems.push(format!("{} {} does not exist", key, path.display()));
+            }
+        };
+
+        check_path("nixPath", &self.nix_path, true);
+        check_path("refindPath", &self.refind_path, true);
+        check_path("efiMountPoint", &self.efi_mount_point, true);
+        check_path("profilesRoot", &self.profiles_root, false);
+        if let Some(ref boot) = self.boot_mount_point {
+            check_path("bootMountPoint", boot, true);
+        }
+        if let Some(ref memtest) = self.memtest86_path {
+            check_path("memtest86Path", memtest, true);
+        }
+        for mount in &self.additional_esp_mounts {
+            check_path("additionalEspMounts entry", mount, true);
+        }
+        for initrd in &self.early_initrds {
+            check_path("earlyInitrds entry", initrd, true);
+        }
+        let uses_efibootmgr = self.can_touch_efi_variables
+            && !self.efi_removable
+            && self.nvram_backend == NvramBackend::Efibootmgr;
+        if uses_efibootmgr {
+            check_path("efiBootMgrPath", &self.efi_boot_mgr_path, true);
+        }
+        let mut sources: Vec<(&String, &PathBuf)> = self.additional_files.iter().collect();
+        sources.sort();
+        for (dest, source) in &sources {
+            check_path(
+                &format!("additionalFiles source for {}", dest),
+                source,
+                true,
+            );
+        }
+        if let Some(ref theme) = self.theme {
+            check_path("theme source", &theme.source, true);
+        }
+        if let Some(ref secure_boot) = self.secure_boot {
+            check_path("secureBoot certPath", &secure_boot.cert_path, true);
+            if let Some(ref key) = secure_boot.key_path {
+                check_path("secureBoot keyPath", key, true);
+            }
+            if let Some(ref command) = secure_boot.sign_command {
+                check_path("secureBoot signCommand", command, true);
+            }
+            if let Some(ref tools) = secure_boot.sbsigntool_path {
+                check_path("secureBoot sbsigntoolPath", tools, true);
+            }
+        }
+
+        match crate::efi::efi_arch(&self.host_architecture) {
+            Ok(arch) => {
+                let share = self.refind_path.join("share/refind");
+                let binary = share.join(arch.refind_file);
+                if self.refind_path.is_dir() && !binary.is_file() {
+                    problems.push(format!(
+                        "refindPath {} is not a rEFInd package, {} is missing",
+                        self.refind_path.display(),
+                        binary.display()
+                    ));
+                } else if self.install_drivers && !share.join(arch.drivers_dir).is_dir() {
+                    problems.push(format!(
+                        "installDrivers is set, but refindPath {} has no {}",
+                        self.refind_path.display(),
+                        arch.drivers_dir
+                    ));
+                }
+            }
+            Err(error) => problems.push(format!("hostArchitecture: {:#}", error)),
+        }
+        let efibootmgr = self.efi_boot_mgr_path.join("bin/efibootmgr");
+        if uses_efibootmgr && self.efi_boot_mgr_path.is_dir() && !efibootmgr.is_file() {
+            problems.push(format!(
+                "efiBootMgrPath {} has no bin/efibootmgr",
+                self.efi_boot_mgr_path.display()
+            ));
+        }
+
+        // rEFInd counts the timeout in seconds; anything past an hour is a typo
+        if let Some(timeout) = self.timeout.filter(|timeout| *timeout > 3600) {
+            problems.push(format!("timeout {} is more than an hour", timeout));
+        }
+        // Firmware lists a handful of text modes; 1024 is rEFInd's "don't change it"
+        if let Some(mode) = self.text_mode.filter(|mode| *mode >= 100 && *mode != 1024) {
+            problems.push(format!(
+                "textMode {} is not a text mode, use a mode number below 100 or 1024 to keep \
+                 the current one",
+                mode
+            ));
+        }
+        if self.max_generations > 1000 {
+            problems.push(format!(
+                "maxGenerations {} is implausibly large, use 0 to keep every generation",
+                self.max_generations
+            ));
+        }
+        let mut overridden: Vec<(&String, &ProfileOverrides)> = self.profiles.iter().collect();
+        overridden.sort_by(|a, b| a.0.cmp(b.0));
+        for (profile, overrides) in overridden {
+            if overrides.max_generations.is_some_and(|max| max > 1000) {
+                problems.push(format!(
+                    "profiles.{}.maxGenerations is implausibly large, use 0 to keep every \
+                     generation",
+                    profile
+                ));
+            }
+        }
+        if self.efi_entry_label.trim().is_empty() {
+            problems.push("efiEntryLabel is empty".to_string());
+        }
+
+        for (dest, _) in &sources {
+            if !is_plain_relative(Path::new(dest)) {
+                problems.push(format!(
+                    "additionalFiles destination {} must be a relative path without `..`",
+                    dest
+                ));
+            }
+        }
+
+        if let Some(ref theme) = self.theme {
+            if !is_plain_relative(Path::new(&theme.conf_file)) {
+                problems.push(format!(
+                    "theme confFile {} must be a relative path without `..`",
+                    theme.conf_file
+                ));
+            } else if theme.source.is_dir() && !theme.source.join(&theme.conf_file).is_file() {
+                problems.push(format!(
+                    "theme confFile {} is not in {}",
+                    theme.conf_file,
+                    theme.source.display()
+                ));
+            }
+            let name = theme.name();
+            if name.is_empty() || Path::new(&name).components().count() != 1 || name == ".." {
+                problems.push(format!(
+                    "theme name {:?} must be a single directory name",
+                    name
+                ));
+            }
+        }
+
+        if let Some(ref secure_boot) = self.secure_boot {
+            match (&secure_boot.key_path, &secure_boot.sign_command) {
+                (None, None) => {
+                    problems.push("secureBoot needs keyPath or signCommand".to_string())
+                }
+                (Some(_), Some(_)) => {
+                    problems.push("secureBoot takes keyPath or signCommand, not both".to_string())
+                }
+                _ => {}
+            }
+            if let Some(ref tools) = secure_boot.sbsigntool_path {
+                let needed: &[&str] = match secure_boot.sign_command {
+                    Some(_) => &["sbverify"],
+                    None => &["sbsign", "sbverify"],
+                };
+                for tool in needed {
+                    if tools.is_dir() && !tools.join("bin").join(tool).is_file() {
+                        problems.push(format!(
+                            "secureBoot sbsigntoolPath {} has no bin/{}",
+                            tools.display(),
+                            tool
+                        ));
+                    }
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+        anyhow::bail!(
+            "Invalid install configuration:\n  {}",
+            problems.join("\n  ")
+        )
+    }
+
+    /// `additionalFiles` as (source, destination on the ESP) pairs, in destination order.
+    /// Fails on a destination that would leave `efi/refind`.
+    pub fn additional_file_copies(&self) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let refind_dir = self.efi_mount_point.join("efi/refind");
+        let mut copies = Vec::new();
+        for (dest, source) in &self.additional_files {
+            if !is_plain_relative(Path::new(dest)) {
+                anyhow::bail!(
+                    "additionalFiles destination {} must be a relative path without `..`",
+                    dest
+                );
+            }
+            copies.push((source.clone(), refind_dir.join(dest)));
+        }
+        copies.sort_by(|a, b| a.1.cmp(&b.1));
+        Ok(copies)
+    }
+
+    /// Files of `theme` as (source, destination on the ESP) pairs, in destination order.
+    /// Nothing without a theme.
+    pub fn theme_copies(
+        &self,
+        filesystem: &dyn crate::fs::Filesystem,
+    ) -> Result<Vec<(PathBuf, PathBuf)>> {
+        match (&self.theme, self.theme_dir()) {
+            (Some(theme), Some(theme_dir)) => {
+                crate::fs::tree_copies(filesystem, &theme.source, &theme_dir)
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// rEFInd from `refindPath` as (source, destination on the ESP) pairs, in destination
+    /// order: its binary where NVRAM entries point, its icons, and its drivers if
+    /// `installDrivers` is set. Directories the package lacks are left out.
+    pub fn refind_copies(
+        &self,
+        filesystem: &dyn crate::fs::Filesystem,
+    ) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let arch = crate::efi::efi_arch(&self.host_architecture)?;
+        let share = self.refind_path.join("share/refind");
+        let refind_dir = self.efi_mount_point.join("efi/refind");
+
+        let mut copies = vec![(
+            share.join(arch.refind_file),
+            self.efi_mount_point.join(arch.install_path()),
+        )];
+        let mut dirs = vec!["icons"];
+        if self.install_drivers {
+            dirs.push(arch.drivers_dir);
+        }
+        for dir in dirs {
+            if filesystem.is_dir(&share.join(dir)) {
+                copies.extend(crate::fs::tree_copies(
+                    filesystem,
+                    &share.join(dir),
+                    &refind_dir.join(dir),
+                )?);
+            } else {
+                crate::warn!("{} has no {}, not installing it", share.display(), dir);
+            }
+        }
+        copies.sort_by(|a, b| a.1.cmp(&b.1));
+        Ok(copies)
+    }
+
+    /// Where `theme` goes on the ESP, if there is one
+    pub fn theme_dir(&self) -> Option<PathBuf> {
+        self.theme.as_ref().map(|theme| {
+            self.efi_mount_point
+                .join("efi/refind/themes")
+                .join(theme.name())
+        })
+    }
+
+    /// This config retargeted at the `index`th of `additionalEspMounts`, `mount`
+    pub fn for_additional_esp(&self, mount: &Path, index: usize) -> Self {
+        Self {
+            efi_mount_point: mount.to_path_buf(),
+            boot_mount_point: None,
+            additional_esp_mounts: Vec::new(),
+            efi_entry_label: format!("{} (ESP {})", self.efi_entry_label, index + 2),
+            ..self.clone()
+        }
+    }
+
+    /// This config as `profile` sees it: the top-level values with those its `profiles`
+    /// entry sets replaced
+    pub fn for_profile(&self, profile: &str) -> Self {
+        let mut config = self.clone();
+        let Some(overrides) = self.profiles.get(profile) else {
+            return config;
+        };
+        if let Some(max_generations) = overrides.max_generations {
+            config.max_generations = max_generations;
+        }
+        if let Some(ref icon) = overrides.icon {
+            config.icon = Some(icon.clone());
+        }
+        if let Some(ref label) = overrides.label {
+            config
+                .profile_labels
+                .insert(profile.to_string(), label.clone());
+        }
+        if let Some(ref params) = overrides.extra_kernel_params {
+            config.extra_kernel_params = params.clone();
+        }
+        if let Some(include) = overrides.include_specialisations {
+            config.include_specialisations = include;
+        }
+        config
+    }
+
+    /// Where kernels and initrds are staged: `bootMountPoint` if set, else the ESP
+    pub fn kernel_mount_point(&self) -> &Path {
+        self.boot_mount_point
+            .as_deref()
+            .unwrap_or(&self.efi_mount_point)
+    }
+}
+
+const EXAMPLE_CONFIG: &str = r#"{
+  // Schema the config is written against, 1 if left out
+  "schemaVersion": 2,
+  // Nix package providing bin/nix-env (required)
+  "nixPath": "/nix/store/...-nix-2.18.1",
+  // rEFInd package providing share/refind (required)
+  "refindPath": "/nix/store/...-refind-0.14.2",
+  // efibootmgr package providing bin/efibootmgr (required with nvramBackend "efibootmgr")
+  "efiBootMgrPath": "/nix/store/...-efibootmgr-18",
+  // Where the ESP is mounted
+  "efiMountPoint": "/boot",
+  // Create or update the NVRAM boot entry for rEFInd
+  "canTouchEfiVariables": true,
+  // Also install to the removable-media fallback path (EFI/BOOT/BOOTX64.EFI), no NVRAM entry
+  "efiRemovable": false,
+  // Seconds rEFInd shows the menu before booting the default, 0 to boot it right away,
+  // null to wait for a choice
+  "timeout": 10,
+  // Show the menu as text instead of graphics
+  "textOnly": false,
+  // Firmware text mode: 0 is 80x25, 1 usually 80x50, 1024 keeps the current one; null
+  // leaves it to rEFInd
+  "textMode": null,
+  // Generations to keep per profile, 0 for all of them
+  "maxGenerations": 10,
+  // rEFInd config prepended verbatim, or {"file": "/etc/refind-extra.conf"} to read it
+  "extraConfig": "resolution max",
+  // Nix system double of the machine being installed
+  "hostArchitecture": "x86_64-linux",
+  // Extra files to copy, keyed by destination relative to efi/refind
+  "additionalFiles": {
+    "drivers_x64/btrfs_x64.efi": "/nix/store/...-refind-0.14.2/share/refind/drivers_x64/btrfs_x64.efi"
+  },
+  // LUKS devices as [name, device] pairs
+  "luksDevices": [["cryptroot", "/dev/disk/by-uuid/..."]],
+  // "rdLuksName" (rd.luks.name=<uuid>=<name>), "luksDevice" (luks.<name>.device=...) or "off"
+  "luksParamStyle": "rdLuksName",
+  // "flat" (all kernels in kernels/) or "perGeneration" (kernels/<profile>-<generation>/)
+  "kernelLayout": "flat",
+  // "duplicate" or "hardlink" files shared between generation directories
+  "sharedFiles": "duplicate",
+  // Names shown in menu titles instead of raw profile names
+  "profileLabels": {
+    "work": "Work laptop"
+  },
+  // Enable Intel VMX and lock IA32_FEATURE_CONTROL before starting the kernel
+  "enableAndLockVmx": false,
+  // Directory holding the system profile and system-profiles/
+  "profilesRoot": "/nix/var/nix/profiles",
+  // memtest86plus EFI binary to offer in the menu, null to look in the system closure
+  "memtest86Path": null,
+  // NVRAM entry of Windows Boot Manager to chain-boot from the menu, null for none
+  "windowsFirmwareBootnum": "0000",
+  // Initrds loaded before each generation's own, microcode first
+  "earlyInitrds": ["/nix/store/...-intel-ucode/intel-ucode.img"],
+  // rEFInd ostype (theme icon) for every entry, null to derive it from the system
+  "ostype": "Linux",
+  // "after" or "before": where menuentry blocks in extraConfig go relative to NixOS
+  "extraConfigPlacement": "after",
+  // Title entries with the label from boot.json instead of a synthesized one
+  "useBootspecLabel": false,
+  // XBOOTLDR partition to stage kernels on instead of the ESP, null for the ESP
+  "bootMountPoint": null,
+  // Read copies back and compare hashes, false to skip on slow media
+  "verifyCopies": true,
+  // Days to keep unused staged kernels after they were first staged, 0 to remove them
+  "staleFileRetentionDays": 0,
+  // Backups of the previous refind.conf kept for `refindgen rollback`, 0 for none
+  "configBackups": 3,
+  // Resolve the ESP for its NVRAM entry by device name instead of PARTUUID
+  "legacyEfiDeviceLookup": false,
+  // "keep", "first" or "last": where rEFInd's entries go in the firmware BootOrder
+  "bootOrderPosition": "keep",
+  // Put rEFInd first in the BootOrder on every install, whatever bootOrderPosition says
+  "makeDefaultBoot": false,
+  // Label of rEFInd's NVRAM entry
+  "efiEntryLabel": "rEFInd (workstation)",
+  // More ESPs to keep in sync with efiMountPoint, each with its own NVRAM entry
+  "additionalEspMounts": ["/boot2"],
+  // "efivarfs" (write EFI variables directly) or "efibootmgr" (run efiBootMgrPath)
+  "nvramBackend": "efivarfs",
+  // Tries for each NVRAM write that fails with a busy or I/O error
+  "nvramWriteAttempts": 3,
+  // Milliseconds before the first retry, doubled for each one after
+  "nvramRetryDelayMs": 500,
+  // rEFInd icon for the NixOS entries, null to leave it to ostype
+  "icon": "/EFI/refind/icons/os_nixos.png",
+  // Kernel parameters added to every entry
+  "extraKernelParams": ["quiet"],
+  // Offer specialisations in a submenu of each generation
+  "includeSpecialisations": true,
+  // Per-profile maxGenerations, icon, label, extraKernelParams and includeSpecialisations
+  "profiles": {
+    "test": {
+      "maxGenerations": 2,
+      "label": "Testing",
+      "includeSpecialisations": false
+    }
+  },
+  // rEFInd theme copied to efi/refind/themes/<name> and included from refind.conf
+  "theme": {
+    "source": "/nix/store/...-refind-theme-regular",
+    "confFile": "theme.conf"
+  },
+  // Also install rEFInd's filesystem drivers from refindPath
+  "installDrivers": false,
+  // Expand ${VAR} and ${VAR:-default} in string values from the environment
+  "interpolateEnv": false,
+  // Sign kernels, rEFInd and its drivers with sbsign (keyPath) or signCommand, checking
+  // each signature with sbverify against certPath; null to stage them unsigned
+  "secureBoot": {
+    "keyPath": "/etc/secureboot/db.key",
+    "certPath": "/etc/secureboot/db.crt",
+    "sbsigntoolPath": "/nix/store/...-sbsigntool-0.9.5"
+  },
+  // Boot this generation by default instead of the newest; null for the newest
+  "defaultGeneration": {
+    "profile": "system",
+    "number": 42,
+    "fallbackToNewest": true
+  }
+}
+"#;
+
+/// Install config schema this build reads. Bump it when a field changes meaning or shape
+/// and add a migration from the previous schema to [`MIGRATIONS`].
+pub const SCHEMA_VERSION: u64 = 2;
+
+/// Upgrades a top-level table by one schema, returning the fields it changed
+type Migration = fn(&mut Map<String, Value>) -> Result<Vec<&'static str>>;
+
+/// Upgrades between schemas: `MIGRATIONS[i]` turns schema `i + 1` into schema `i + 2`
+const MIGRATIONS: &[Migration] = &[v1_to_v2];
+const _: () = assert!(MIGRATIONS.len() as u64 + 1 == SCHEMA_VERSION);
+
+/// Schema 1 passed `timeout` to rEFInd as is, where 0 waits for a choice. Schema 2 takes
+/// NixOS' meaning, 0 booting right away, and waits on `null`.
+fn v1_to_v2(table: &mut Map<String, Value>) -> Result<Vec<&'static str>> {
+    if table.get("timeout") == Some(&Value::from(0)) {
+        table.insert("timeout".to_string(), Value::Null);
+        return Ok(vec!["timeout"]);
+    }
+    Ok(Vec::new())
+}
+
+/// Migrate a config's top-level table, then interpolate the environment into it if it
+/// asks for that. Returns whether the table changed.
+fn prepare(table: &mut Map<String, Value>) -> Result<bool> {
+    let migrated = migrate(table)?;
+    let wants_env = [table.get("interpolateEnv"), table.get("interpolate_env")]
+        .contains(&Some(&Value::Bool(true)));
+    if !wants_env {
+        return Ok(migrated);
+    }
+    for (key, value) in table.iter_mut() {
+        interpolate_value(value, &|name| std::env::var(name).ok())
+            .with_context(|| format!("key `{}`", key))?;
+    }
+    Ok(true)
+}
+
+/// [`interpolate_env`] every string in `value`, however deeply nested
+fn interpolate_value(value: &mut Value, lookup: &dyn Fn(&str) -> Option<String>) -> Result<()> {
+    match value {
+        Value::String(text) => *text = interpolate_env(text, lookup)?,
+        Value::Array(items) => {
+            for item in items {
+                interpolate_value(item, lookup)?;
+            }
+        }
+        Value::Object(map) => {
+            for item in map.values_mut() {
+                interpolate_value(item, lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replace `${VAR}` in `text` by `lookup(VAR)`, and `${VAR:-default}` by the default when
+/// VAR is unset or empty. Defaults are taken literally up to the first `}`, so they can't
+/// nest. `$${` is a literal `${`, and a `$` not followed by `{` is left alone.
+fn interpolate_env(text: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+            continue;
+        }
+        let Some(after) = rest.strip_prefix("${") else {
+            out.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let end = after
+            .find('}')
+            .with_context(|| format!("`${{` without a closing `}}` in {:?}", text))?;
+        let (name, default) = match after[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&after[..end], None),
+        };
+        let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            anyhow::bail!(
+                "`${{{}}}` doesn't name an environment variable",
+                &after[..end]
+            );
+        }
+        match (
+            lookup(name).filter(|value| !value.is_empty() || default.is_none()),
+            default,
+        ) {
+            (Some(value), _) => out.push_str(&value),
+            (None, Some(default)) => out.push_str(default),
+            (None, None) => anyhow::bail!(
+                "environment variable {} is not set and `${{{}}}` has no default",
+                name,
+                name
+            ),
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Upgrade a config's top-level table from its `schemaVersion` to [`SCHEMA_VERSION`], one
+/// schema at a time, warning about the fields that changed. Returns whether it was older.
+fn migrate(table: &mut Map<String, Value>) -> Result<bool> {
+    let version = match table
+        .remove("schemaVersion")
+        .or_else(|| table.remove("schema_version"))
+    {
+        None => 1,
+        Some(value) => value
+            .as_u64()
+            .filter(|version| *version > 0)
+            .with_context(|| format!("schemaVersion {} is not a positive integer", value))?,
+    };
+    if version > SCHEMA_VERSION {
+        anyhow::bail!(
+            "schemaVersion {} is newer than this refindgen understands (up to {}), upgrade \
+             refindgen to use it",
+            version,
+            SCHEMA_VERSION
+        );
+    }
+    table.insert("schemaVersion".to_string(), Value::from(SCHEMA_VERSION));
+    if version == SCHEMA_VERSION {
+        return Ok(false);
+    }
+
+    let mut migrated = BTreeSet::new();
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        migrated.extend(migration(table)?);
+    }
+    let changed = if migrated.is_empty() {
+        "no fields changed".to_string()
+    } else {
+        format!(
+            "changed {}",
+            migrated.into_iter().collect::<Vec<_>>().join(", ")
+        )
+    };
+    crate::warn!(
+        "install config schema {} is deprecated, read it as schema {} ({}).\n  \
+         Set schemaVersion to {} once the config is updated.",
+        version,
+        SCHEMA_VERSION,
+        changed,
+        SCHEMA_VERSION
+    );
+    Ok(true)
+}
+
+/// Whether `dest`, an `additionalFiles` destination, stays under `efi/refind`: relative
+/// and without `..`
+fn is_plain_relative(dest: &Path) -> bool {
+    use std::path::Component;
+
+    dest.components().next().is_some()
+        && dest
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// A serde error with its position replaced by the line and key of the config file
+fn located_error(
+    error: &dyn std::fmt::Display,
+    line: Option<usize>,
+    key: Option<&str>,
+) -> anyhow::Error {
+    let message = error.to_string();
+    let message = message
+        .rsplit_once(" at line ")
+        .map_or(message.as_str(), |(message, _)| message);
+    match (line, key) {
+        (Some(line), Some(key)) => anyhow::anyhow!("line {}, key `{}`: {}", line, key, message),
+        (Some(line), None) => anyhow::anyhow!("line {}: {}", line, message),
+        (None, Some(key)) => anyhow::anyhow!("key `{}`: {}", key, message),
+        (None, None) => anyhow::anyhow!("{}", message),
+    }
+}
+
+/// Drop `//` comments outside of strings, leaving plain JSON
+fn strip_comments(content: &str) -> String {
+    let mut out = String::with_capacity(content.len());
+    for line in content.lines() {
+        let mut in_string = false;
+        let mut escaped = false;
+        let mut end = line.len();
+        for (i, c) in line.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if in_string => escaped = true,
+                '"' => in_string = !in_string,
+                '/' if !in_string && line[i..].starts_with("//") => {
+                    end = i;
+                    break;
+                }
+                _ => {}
+            }
+        }
+        out.push_str(&line[..end]);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(content: &str) -> Result<InstallConfig> {
+        InstallConfig::from_json(content, &[]).map(|(config, _)| config)
+    }
+
+    fn error(result: Result<InstallConfig>) -> String {
+        format!("{:#}", result.expect_err("should be refused"))
+    }
+
+    #[test]
+    fn minimal_config_takes_the_defaults() {
+        let config = json(
+            r#"{
+  "schemaVersion": 2,
+  "nixPath": "/nix",
+  "refindPath": "/refind"
+}"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.nix_path, Path::new("/nix"));
+        assert_eq!(config.efi_mount_point, Path::new("/boot"));
+        assert_eq!(config.timeout, Some(20));
+        assert_eq!(config.max_generations, 0);
+        assert!(config.luks_devices.is_empty());
+        assert!(config.additional_files.is_empty());
+        assert!(matches!(config.extra_config, ExtraConfig::Inline(ref text) if text.is_empty()));
+        assert!(!config.can_touch_efi_variables);
+        assert_eq!(config.efi_entry_label, "rEFInd");
+    }
+
+    #[test]
+    fn snake_case_keys_are_accepted() {
+        let config =
+            json(r#"{"nix_path": "/nix", "refind_path": "/refind", "max_generations": 3}"#)
+                .unwrap();
+        assert_eq!(config.max_generations, 3);
+    }
+
+    #[test]
+    fn unknown_keys_name_their_line() {
+        let message = error(json(
+            r#"{
+  "nixPath": "/nix",
+  "refindPath": "/refind",
+  "maxGeneration": 3
+}"#,
+        ));
+        assert!(
+            message.starts_with("line 4, key `maxGeneration`: unknown field `maxGeneration`"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn required_keys_are_required() {
+        let message = error(json(r#"{"refindPath": "/refind"}"#));
+        assert!(message.contains("missing field `nixPath`"), "{}", message);
+    }
+
+    #[test]
+    fn toml_and_json_load_the_same_config() {
+        let scratch = crate::fs::tests::ScratchDir::new();
+        let load = |name: &str, content: &str| {
+            let path = scratch.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            InstallConfig::load(path.to_str().unwrap())
+        };
+
+        let from_json = load(
+            "install.json",
+            r#"{
+  // comments are allowed
+  "schemaVersion": 2,
+  "nixPath": "/nix",
+  "refind_path": "/refind",
+  "timeout": 5,
+  "luksDevices": [["root", "/dev/disk/by-uuid/1234"]],
+  "extraConfig": "showtools shell\nscanfor manual\n",
+  "profiles": { "work": { "maxGenerations": 2, "icon": "os_work.png" } }
+}"#,
+        )
+        .unwrap();
+        let from_toml = load(
+            "install.toml",
+            r#"# comments are allowed
+schemaVersion = 2
+nix_path = "/nix"
+refindPath = "/refind"
+timeout = 5
+luksDevices = [["root", "/dev/disk/by-uuid/1234"]]
+extraConfig = """
+showtools shell
+scanfor manual
+"""
+
+[profiles.work]
+maxGenerations = 2
+icon = "os_work.png"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(format!("{:?}", from_json), format!("{:?}", from_toml));
+        assert_eq!(from_toml.profiles["work"].max_generations, Some(2));
+    }
+
+    #[test]
+    fn load_errors_name_the_file_line_and_key() {
+        let scratch = crate::fs::tests::ScratchDir::new();
+        let load = |name: &str, content: &str| {
+            let path = scratch.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            format!(
+                "{:#}",
+                InstallConfig::load(path.to_str().unwrap()).unwrap_err()
+            )
+        };
+
+        let message = load(
+            "install.json",
+            "{\n  \"nixPath\": \"/nix\",\n  \"refindPath\": \"/refind\",\n  \"timeout\": \"5\"\n}",
+        );
+        assert!(message.contains("install.json"), "{}", message);
+        assert!(message.contains("line 4, key `timeout`"), "{}", message);
+
+        let message = load(
+            "install.toml",
+            "nixPath = \"/nix\"\nrefindPath = \"/refind\"\ntimeout = \"5\"\n",
+        );
+        assert!(message.contains("install.toml"), "{}", message);
+        assert!(message.contains("line 3, key `timeout`"), "{}", message);
+
+        let message = load(
+            "table.toml",
+            "nixPath = \"/nix\"\nrefindPath = \"/refind\"\n\n[profiles.work]\nmaxGenerations = \"2\"\n",
+        );
+        assert!(message.contains("line 4, key `profiles`"), "{}", message);
+
+        let message = load("syntax.toml", "nixPath = \"/nix\"\ntimeout = 5 6\n");
+        assert!(message.contains("line 2: "), "{}", message);
+    }
+
+    fn with_profiles() -> InstallConfig {
+        json(
+            r#"{
+  "schemaVersion": 2,
+  "nixPath": "/nix",
+  "refindPath": "/refind",
+  "maxGenerations": 10,
+  "icon": "os_nixos.png",
+  "extraKernelParams": ["quiet"],
+  "profileLabels": { "system": "NixOS" },
+  "profiles": {
+    "test": {
+      "maxGenerations": 2,
+      "icon": "os_test.png",
+      "label": "Testing",
+      "extraKernelParams": [],
+      "includeSpecialisations": false
+    },
+    "work": { "icon": "os_work.png" }
+  }
+}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn profile_overrides_shadow_the_top_level() {
+        let test = with_profiles().for_profile("test");
+        assert_eq!(test.max_generations, 2);
+        assert_eq!(test.icon.as_deref(), Some("os_test.png"));
+        assert_eq!(test.profile_labels["test"], "Testing");
+        assert!(test.extra_kernel_params.is_empty(), "the list is replaced");
+        assert!(!test.include_specialisations);
+    }
+
+    #[test]
+    fn unset_overrides_keep_the_top_level_values() {
+        let config = with_profiles();
+        let work = config.for_profile("work");
+        assert_eq!(work.icon.as_deref(), Some("os_work.png"));
+        assert_eq!(work.max_generations, 10);
+        assert_eq!(work.extra_kernel_params, ["quiet"]);
+        assert!(work.include_specialisations);
+        assert!(!work.profile_labels.contains_key("work"));
+
+        let system = config.for_profile("system");
+        assert_eq!(system.max_generations, 10);
+        assert_eq!(system.icon.as_deref(), Some("os_nixos.png"));
+        assert_eq!(system.profile_labels["system"], "NixOS");
+    }
+
+    #[test]
+    fn unknown_override_keys_are_refused() {
+        let message = error(json(
+            r#"{"nixPath": "/nix", "refindPath": "/refind",
+  "profiles": { "test": { "maxGens": 2 } }}"#,
+        ));
+        assert!(message.contains("unknown field `maxGens`"), "{}", message);
+    }
+
+    #[test]
+    fn overrides_for_unknown_profiles_warn() {
+        let config = with_profiles();
+        let profiles = ["test".to_string()];
+        let ((), warnings) = crate::log::tests::warnings(|| {
+            crate::generation::warn_unknown_profiles(
+                "profile overrides",
+                config.profiles.keys(),
+                &profiles,
+            )
+        });
+        assert_eq!(
+            warnings,
+            ["profile overrides given for unknown profile 'work'"]
+        );
+    }
+
+    #[test]
+    fn schema_1_is_migrated_with_a_warning() {
+        let v1 = r#"{
+  // Written by a NixOS module from before schemaVersion
+  "nixPath": "/nix",
+  "refindPath": "/refind",
+  "timeout": 0
+}"#;
+        let (config, warnings) = crate::log::tests::warnings(|| json(v1).unwrap());
+        assert_eq!(config.schema_version, SCHEMA_VERSION);
+        assert_eq!(config.timeout, None, "schema 1 waited on 0");
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(
+            warnings[0].starts_with(
+                "install config schema 1 is deprecated, read it as schema 2 (changed timeout)"
+            ),
+            "{}",
+            warnings[0]
+        );
+
+        let (config, warnings) = crate::log::tests::warnings(|| {
+            json(
+                r#"{"schemaVersion": 1, "nixPath": "/nix", "refindPath": "/refind", "timeout": 5}"#,
+            )
+            .unwrap()
+        });
+        assert_eq!(config.timeout, Some(5));
+        assert!(
+            warnings[0].contains("(no fields changed)"),
+            "{:?}",
+            warnings
+        );
+    }
+
+    #[test]
+    fn current_schema_is_read_as_is() {
+        let (config, warnings) = crate::log::tests::warnings(|| {
+            json(
+                r#"{"schemaVersion": 2, "nixPath": "/nix", "refindPath": "/refind", "timeout": 0}"#,
+            )
+            .unwrap()
+        });
+        assert_eq!(config.timeout, Some(0));
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn unknown_schemas_are_refused() {
+        let message = error(json(
+            r#"{"schemaVersion": 3, "nixPath": "/nix", "refindPath": "/refind"}"#,
+        ));
+        assert!(
+            message.contains("schemaVersion 3 is newer than this refindgen understands (up to 2), upgrade refindgen"),
+            "{}",
+            message
+        );
+        for version in ["0", "-1", "\"2\""] {
+            let content = format!(
+                r#"{{"schemaVersion": {}, "nixPath": "/nix", "refindPath": "/refind"}}"#,
+                version
+            );
+            let message = error(json(&content));
+            assert!(message.contains("is not a positive integer"), "{}", message);
+        }
+    }
+
+    fn env(name: &str) -> Option<String> {
+        match name {
+            "ESP_MOUNT" => Some("/efi".into()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    fn interpolated(text: &str) -> String {
+        interpolate_env(text, &env).unwrap()
+    }
+
+    #[test]
+    fn variables_and_defaults() {
+        assert_eq!(interpolated("${ESP_MOUNT}"), "/efi");
+        assert_eq!(interpolated("${ESP_MOUNT}/EFI"), "/efi/EFI");
+        assert_eq!(interpolated("${ESP_MOUNT:-/boot}"), "/efi");
+        assert_eq!(interpolated("${UNSET:-/boot}"), "/boot");
+        assert_eq!(
+            interpolated("${EMPTY:-/boot}"),
+            "/boot",
+            "empty takes the default"
+        );
+        assert_eq!(interpolated("${EMPTY}"), "");
+        assert_eq!(interpolated("${UNSET:-}"), "");
+        assert_eq!(interpolated("a${ESP_MOUNT}b${UNSET:-c}d"), "a/efibcd");
+    }
+
+    #[test]
+    fn escapes_and_lone_dollars_are_left_alone() {
+        assert_eq!(interpolated("$${ESP_MOUNT}"), "${ESP_MOUNT}");
+        // A lone `$`, then the escape
+        assert_eq!(interpolated("$$${ESP_MOUNT}"), "$${ESP_MOUNT}");
+        assert_eq!(interpolated("cost: $5, $HOME"), "cost: $5, $HOME");
+        assert_eq!(interpolated("trailing $"), "trailing $");
+    }
+
+    #[test]
+    fn nested_looking_input_is_not_expanded_twice() {
+        // Defaults end at the first `}`, and substituted values aren't scanned again
+        assert_eq!(interpolated("${UNSET:-${ESP_MOUNT}}"), "${ESP_MOUNT}");
+        assert_eq!(
+            interpolate_env("${SELF}", &|_| Some("${ESP_MOUNT}".into())).unwrap(),
+            "${ESP_MOUNT}"
+        );
+        assert_eq!(interpolated("$${UNSET:-x}"), "${UNSET:-x}");
+    }
+
+    #[test]
+    fn interpolation_errors_name_the_variable() {
+        let message = interpolate_env("${UNSET}/EFI", &env)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            message,
+            "environment variable UNSET is not set and `${UNSET}` has no default"
+        );
+        for text in ["${}", "${1ABC}", "${ESP-MOUNT}", "${ ESP_MOUNT}"] {
+            assert!(interpolate_env(text, &env).is_err(), "{}", text);
+        }
+        let message = interpolate_env("${ESP_MOUNT", &env)
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("without a closing"), "{}", message);
+    }
+
+    #[test]
+    fn interpolation_is_opt_in() {
+        let config = json(
+            r#"{"schemaVersion": 2, "nixPath": "/nix", "refindPath": "/refind",
+  "efiEntryLabel": "${UNSET_REFINDGEN_TEST_VARIABLE}"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.efi_entry_label, "${UNSET_REFINDGEN_TEST_VARIABLE}");
+
+        let message = error(json(
+            r#"{"schemaVersion": 2, "nixPath": "/nix", "refindPath": "/refind",
+  "interpolateEnv": true,
+  "efiEntryLabel": "${UNSET_REFINDGEN_TEST_VARIABLE}"}"#,
+        ));
+        assert!(
+            message.contains(
+                "key `efiEntryLabel`: environment variable UNSET_REFINDGEN_TEST_VARIABLE"
+            ),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn text_mode_must_be_a_text_mode() {
+        let problems = |mode: u32| {
+            let mut config = json(r#"{"nixPath": "/nix", "refindPath": "/refind"}"#).unwrap();
+            config.text_mode = Some(mode);
+            config
+                .validate()
+                .map_or_else(|error| format!("{:#}", error), |()| String::new())
+        };
 
-        serde_json::from_str(&content).context("Failed to parse config JSON")
+        for mode in [0, 99, 1024] {
+            assert!(!problems(mode).contains("textMode"), "{}", problems(mode));
+        }
+        for mode in [100, 1023, 1025] {
+            assert!(
+                problems(mode).contains(&format!(
+                    "textMode {} is not a text mode, use a mode number below 100 or 1024",
+                    mode
+                )),
+                "{}",
+                problems(mode)
+            );
+        }
     }
 }
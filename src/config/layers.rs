@@ -0,0 +1,498 @@
+//! Install config settings the environment and the command line override. The file is
+//! read first, then each `REFINDGEN_*` variable replaces the key it names, then each flag
+//! given on the command line does. Settings are listed once, in [`SETTINGS`]; their
+//! variables, flags and parsing all follow from the table.
+
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::InstallConfig;
+
+/// How a setting's value is spelled on the command line and in the environment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// `true` or `false`, `1` or `0`
+    Bool,
+    /// A non-negative integer
+    Integer,
+    /// A non-negative integer or `null`
+    OptionalInteger,
+    /// Any text, paths included
+    String,
+    /// A variant, kebab-case as clap spells it or camelCase as the config does
+    Enum,
+    /// Repeated flags, or a JSON array of strings in the environment
+    List,
+    /// Repeated `NAME=VALUE` flags, or a JSON object of strings in the environment
+    Map,
+}
+
+/// An install config key the environment and the command line may override
+#[derive(Debug)]
+pub struct Setting {
+    /// Top-level key in the install config
+    pub key: &'static str,
+    /// Id of the clap argument overriding it, the field name in the CLI, if there is one
+    pub flag: Option<&'static str>,
+    pub kind: Kind,
+}
+
+/// Every setting layered over the install config. Adding a row is all a new setting needs.
+pub const SETTINGS: &[Setting] = &[
+    Setting {
+        key: "efiMountPoint",
+        flag: Some("efi_mount"),
+        kind: Kind::String,
+    },
+    Setting {
+        key: "bootMountPoint",
+        flag: None,
+        kind: Kind::String,
+    },
+    Setting {
+        key: "profilesRoot",
+        flag: Some("profiles_root"),
+        kind: Kind::String,
+    },
+    Setting {
+        key: "timeout",
+        flag: Some("timeout"),
+        kind: Kind::OptionalInteger,
+    },
+    Setting {
+        key: "textOnly",
+        flag: None,
+        kind: Kind::Bool,
+    },
+    Setting {
+        key: "textMode",
+        flag: None,
+        kind: Kind::OptionalInteger,
+    },
+    Setting {
+        key: "maxGenerations",
+        flag: None,
+        kind: Kind::Integer,
+    },
+    Setting {
+        key: "kernelLayout",
+        flag: Some("kernel_layout"),
+        kind: Kind::Enum,
+    },
+    Setting {
+        key: "earlyInitrds",
+        flag: Some("early_initrd"),
+        kind: Kind::List,
+    },
+    Setting {
+        key: "profileLabels",
+        flag: Some("profile_label"),
+        kind: Kind::Map,
+    },
+    Setting {
+        key: "useBootspecLabel",
+        flag: Some("use_bootspec_label"),
+        kind: Kind::Bool,
+    },
+    Setting {
+        key: "extraConfigPlacement",
+        flag: Some("extra_config_placement"),
+        kind: Kind::Enum,
+    },
+    Setting {
+        key: "hostArchitecture",
+        flag: None,
+        kind: Kind::String,
+    },
+    Setting {
+        key: "canTouchEfiVariables",
+        flag: None,
+        kind: Kind::Bool,
+    },
+    Setting {
+        key: "efiRemovable",
+        flag: None,
+        kind: Kind::Bool,
+    },
+    Setting {
+        key: "nvramBackend",
+        flag: None,
+        kind: Kind::Enum,
+    },
+    Setting {
+        key: "efiEntryLabel",
+        flag: Some("efi_entry_label"),
+        kind: Kind::String,
+    },
+];
+
+impl Setting {
+    /// The variable overriding it: `REFINDGEN_` and the key in upper snake case, e.g.
+    /// `REFINDGEN_EFI_MOUNT_POINT`
+    pub fn env_var(&self) -> String {
+        format!("REFINDGEN_{}", snake_case(self.key).to_uppercase())
+    }
+
+    /// The flag overriding it as typed, e.g. `--efi-mount`
+    pub fn flag_name(&self) -> Option<String> {
+        self.flag.map(|id| format!("--{}", id.replace('_', "-")))
+    }
+
+    /// The value of an environment variable
+    fn parse_env(&self, text: &str) -> Result<Value> {
+        let integer = |text: &str| {
+            text.parse::<u64>()
+                .map(Value::from)
+                .with_context(|| format!("expected a number, got `{}`", text))
+        };
+        match self.kind {
+            Kind::Bool => match text {
+                "true" | "1" => Ok(Value::Bool(true)),
+                "false" | "0" => Ok(Value::Bool(false)),
+                _ => anyhow::bail!("expected true or false, got `{}`", text),
+            },
+            Kind::Integer => integer(text),
+            Kind::OptionalInteger if text == "null" => Ok(Value::Null),
+            Kind::OptionalInteger => integer(text),
+            Kind::String => Ok(Value::from(text)),
+            Kind::Enum => Ok(Value::from(camel_case(text))),
+            Kind::List => {
+                let items: Vec<String> =
+                    serde_json::from_str(text).context("expected a JSON array of strings")?;
+                Ok(Value::from(items))
+            }
+            Kind::Map => {
+                let entries: BTreeMap<String, String> =
+                    serde_json::from_str(text).context("expected a JSON object of strings")?;
+                Ok(Value::Object(
+                    entries
+                        .into_iter()
+                        .map(|(name, value)| (name, Value::from(value)))
+                        .collect(),
+                ))
+            }
+        }
+    }
+
+    /// The values given to the flag, one per occurrence
+    fn parse_cli(&self, values: &[String]) -> Result<Value> {
+        match self.kind {
+            Kind::List => Ok(Value::from(values.to_vec())),
+            Kind::Map => values
+                .iter()
+                .map(|value| {
+                    value
+                        .split_once('=')
+                        .map(|(name, value)| (name.to_string(), Value::from(value)))
+                        .with_context(|| format!("expected NAME=VALUE, got `{}`", value))
+                })
+                .collect::<Result<Map<String, Value>>>()
+                .map(Value::Object),
+            _ => self.parse_env(values.last().map_or("", String::as_str)),
+        }
+    }
+}
+
+/// Where an effective setting came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// Nothing set it
+    Default,
+    /// The install config file
+    File,
+    /// An environment variable, by name
+    Env(String),
+    /// A command-line flag, as typed
+    Cli(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File => write!(f, "config file"),
+            Source::Env(name) => write!(f, "${}", name),
+            Source::Cli(flag) => write!(f, "{}", flag),
+        }
+    }
+}
+
+/// An install config and where each of its top-level values came from
+#[derive(Debug, Clone)]
+pub struct ResolvedConfig {
+    pub config: InstallConfig,
+    /// Source of every top-level key, camelCase
+    pub sources: BTreeMap<String, Source>,
+}
+
+impl ResolvedConfig {
+    /// One `key = value  # source` line per top-level key, values as JSON
+    pub fn show(&self) -> Result<String> {
+        let Value::Object(table) = serde_json::to_value(&self.config)? else {
+            anyhow::bail!("install config did not serialize to a table");
+        };
+        let mut out = String::new();
+        for (key, value) in &table {
+            let source = self.sources.get(key).unwrap_or(&Source::Default);
+            out.push_str(&format!("{} = {}  # {}\n", key, value, source));
+        }
+        Ok(out)
+    }
+}
+
+/// Values replacing keys of the file, lowest precedence first: variables set in `env`,
+/// then the flags in `cli`, each with the values typed for it
+pub(super) fn overrides(
+    cli: &[(&'static Setting, Vec<String>)],
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<Vec<(&'static str, Value, Source)>> {
+    let mut overrides = Vec::new();
+    for setting in SETTINGS {
+        let name = setting.env_var();
+        if let Some(text) = env(&name) {
+            let value = setting
+                .parse_env(&text)
+                .with_context(|| format!("${}", name))?;
+            overrides.push((setting.key, value, Source::Env(name)));
+        }
+    }
+    for (setting, values) in cli {
+        let flag = setting
+            .flag_name()
+            .unwrap_or_else(|| setting.key.to_string());
+        let value = setting.parse_cli(values).with_context(|| flag.clone())?;
+        overrides.push((setting.key, value, Source::Cli(flag)));
+    }
+    Ok(overrides)
+}
+
+/// Replace keys of `table` with `overrides`, in order, dropping snake_case spellings of
+/// them the file used
+pub(super) fn apply(table: &mut Map<String, Value>, overrides: &[(&'static str, Value, Source)]) {
+    for (key, value, _) in overrides {
+        table.remove(&snake_case(key));
+        table.insert(key.to_string(), value.clone());
+    }
+}
+
+/// `efiMountPoint` -> `efi_mount_point`
+pub(super) fn snake_case(key: &str) -> String {
+    let mut out = String::with_capacity(key.len() + 4);
+    for c in key.chars() {
+        if c.is_ascii_uppercase() {
+            out.push('_');
+            out.push(c.to_ascii_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// `efi_mount_point` or `per-generation` -> `efiMountPoint` or `perGeneration`
+pub(super) fn camel_case(key: &str) -> String {
+    let mut out = String::with_capacity(key.len());
+    let mut upper = false;
+    for c in key.chars() {
+        match c {
+            '_' | '-' => upper = true,
+            c if upper => {
+                out.push(c.to_ascii_uppercase());
+                upper = false;
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::tests::ScratchDir;
+
+    fn setting(key: &str) -> &'static Setting {
+        SETTINGS.iter().find(|setting| setting.key == key).unwrap()
+    }
+
+    fn env<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn names_follow_the_key() {
+        assert_eq!(
+            setting("efiMountPoint").env_var(),
+            "REFINDGEN_EFI_MOUNT_POINT"
+        );
+        assert_eq!(
+            setting("efiMountPoint").flag_name().as_deref(),
+            Some("--efi-mount")
+        );
+        assert_eq!(setting("bootMountPoint").flag_name(), None);
+        assert_eq!(snake_case("useBootspecLabel"), "use_bootspec_label");
+        assert_eq!(camel_case("per-generation"), "perGeneration");
+        assert_eq!(camel_case("use_bootspec_label"), "useBootspecLabel");
+    }
+
+    #[test]
+    fn env_values_parse_by_kind() {
+        let parse = |key, text| setting(key).parse_env(text).unwrap();
+        assert_eq!(parse("textOnly", "1"), Value::Bool(true));
+        assert_eq!(parse("textOnly", "false"), Value::Bool(false));
+        assert_eq!(parse("timeout", "null"), Value::Null);
+        assert_eq!(parse("timeout", "5"), Value::from(5));
+        assert_eq!(
+            parse("kernelLayout", "per-generation"),
+            Value::from("perGeneration")
+        );
+        assert_eq!(
+            parse("earlyInitrds", r#"["/a", "/b"]"#),
+            serde_json::json!(["/a", "/b"])
+        );
+        assert_eq!(
+            parse("profileLabels", r#"{"gaming": "Games"}"#),
+            serde_json::json!({"gaming": "Games"})
+        );
+
+        assert!(setting("textOnly").parse_env("yes").is_err());
+        assert!(setting("maxGenerations").parse_env("-1").is_err());
+        assert!(setting("maxGenerations").parse_env("null").is_err());
+        assert!(setting("earlyInitrds").parse_env("/a").is_err());
+    }
+
+    #[test]
+    fn cli_values_parse_by_kind() {
+        let values = |values: &[&str]| values.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            setting("profileLabels")
+                .parse_cli(&values(&["a=A", "b=B=C"]))
+                .unwrap(),
+            serde_json::json!({"a": "A", "b": "B=C"})
+        );
+        assert_eq!(
+            setting("earlyInitrds")
+                .parse_cli(&values(&["/a", "/b"]))
+                .unwrap(),
+            serde_json::json!(["/a", "/b"])
+        );
+        assert_eq!(
+            setting("timeout").parse_cli(&values(&["3", "4"])).unwrap(),
+            Value::from(4)
+        );
+        assert!(setting("profileLabels").parse_cli(&values(&["a"])).is_err());
+    }
+
+    #[test]
+    fn flags_come_after_variables() {
+        let overrides = overrides(
+            &[(setting("timeout"), vec!["9".to_string()])],
+            &env(&[("REFINDGEN_TIMEOUT", "7"), ("REFINDGEN_TEXT_ONLY", "1")]),
+        )
+        .unwrap();
+        let order: Vec<(&str, &Source)> = overrides
+            .iter()
+            .map(|(key, _, source)| (*key, source))
+            .collect();
+        assert_eq!(
+            order,
+            [
+                ("timeout", &Source::Env("REFINDGEN_TIMEOUT".to_string())),
+                ("textOnly", &Source::Env("REFINDGEN_TEXT_ONLY".to_string())),
+                ("timeout", &Source::Cli("--timeout".to_string())),
+            ]
+        );
+
+        let mut table = Map::new();
+        table.insert("timeout".to_string(), Value::from(5));
+        apply(&mut table, &overrides);
+        assert_eq!(table["timeout"], Value::from(9));
+        assert_eq!(table["textOnly"], Value::Bool(true));
+    }
+
+    #[test]
+    fn bad_variables_are_named() {
+        let error = overrides(&[], &env(&[("REFINDGEN_TEXT_ONLY", "yes")])).unwrap_err();
+        assert_eq!(error.to_string(), "$REFINDGEN_TEXT_ONLY");
+    }
+
+    #[test]
+    fn overrides_replace_snake_case_spellings() {
+        let mut table = Map::new();
+        table.insert("efi_mount_point".to_string(), Value::from("/boot"));
+        apply(
+            &mut table,
+            &[("efiMountPoint", Value::from("/efi"), Source::Default)],
+        );
+        assert_eq!(table.len(), 1);
+        assert_eq!(table["efiMountPoint"], Value::from("/efi"));
+    }
+
+    /// Load `content` as the install config file with `cli` and `env` layered over it
+    fn load(
+        content: &str,
+        cli: &[(&'static Setting, Vec<String>)],
+        vars: &[(&str, &str)],
+    ) -> ResolvedConfig {
+        let scratch = ScratchDir::new();
+        let path = scratch.path().join("config.json");
+        std::fs::write(&path, content).unwrap();
+        InstallConfig::load_layered(path.to_str().unwrap(), cli, &env(vars)).unwrap()
+    }
+
+    const FILE: &str = r#"{
+        "nixPath": "/nix",
+        "refindPath": "/refind",
+        "timeout": 5,
+        "kernel_layout": "perGeneration"
+    }"#;
+
+    #[test]
+    fn file_then_environment_then_flags() {
+        let resolved = load(FILE, &[], &[]);
+        assert_eq!(resolved.config.timeout, Some(5));
+        assert_eq!(resolved.sources["timeout"], Source::File);
+        assert_eq!(resolved.sources["kernelLayout"], Source::File);
+
+        let resolved = load(FILE, &[], &[("REFINDGEN_TIMEOUT", "7")]);
+        assert_eq!(resolved.config.timeout, Some(7));
+        assert_eq!(
+            resolved.sources["timeout"],
+            Source::Env("REFINDGEN_TIMEOUT".to_string())
+        );
+
+        let resolved = load(
+            FILE,
+            &[
+                (setting("timeout"), vec!["9".to_string()]),
+                (setting("kernelLayout"), vec!["flat".to_string()]),
+            ],
+            &[("REFINDGEN_TIMEOUT", "7")],
+        );
+        assert_eq!(resolved.config.timeout, Some(9));
+        assert_eq!(
+            resolved.config.kernel_layout,
+            crate::config::KernelLayout::Flat
+        );
+        assert_eq!(
+            resolved.sources["timeout"],
+            Source::Cli("--timeout".to_string())
+        );
+        assert!(!resolved.sources.contains_key("textOnly"));
+    }
+
+    #[test]
+    fn show_names_each_source() {
+        let resolved = load(FILE, &[], &[("REFINDGEN_TEXT_ONLY", "true")]);
+        let shown = resolved.show().unwrap();
+        assert!(shown.contains("timeout = 5  # config file\n"), "{}", shown);
+        assert!(shown.contains("textOnly = true  # $REFINDGEN_TEXT_ONLY\n"));
+        assert!(shown.contains("maxGenerations = 0  # default\n"));
+    }
+}
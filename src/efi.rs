@@ -1,113 +1,963 @@
 use anyhow::{Context, Result};
 use regex::Regex;
+use std::fmt;
+use std::io::Read;
 use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::Duration;
 
-use crate::config::InstallConfig;
+use crate::config::{BootOrderPosition, InstallConfig, NvramBackend};
 
-pub fn setup_efi_boot_entry(config: &InstallConfig) -> Result<()> {
-    let efibootmgr = config.efi_boot_mgr_path.join("bin/efibootmgr");
+mod efivarfs;
 
-    // Get current EFI boot entries
-    let output = Command::new(&efibootmgr)
-        .output()
-        .context("Failed to run efibootmgr")?;
-
-    if !output.status.success() {
+/// Create or update rEFInd's NVRAM entries and put them in the BootOrder, through the
+/// config's `nvramBackend`. With `print_only`, everything is still discovered, but the
+/// changes to NVRAM are printed instead of made.
+///
+/// Of several entries with our label, the one that is right (or can be updated) stays
+/// and the rest are deleted, except entries booting another ESP that still exists, which
+/// are another install's unless `prune_foreign` is set.
+pub fn setup_efi_boot_entry(
+    config: &InstallConfig,
+    print_only: bool,
+    prune_foreign: bool,
+) -> Result<()> {
+    if config.nvram_backend == NvramBackend::Efibootmgr
+        && config.efi_boot_mgr_path.as_os_str().is_empty()
+    {
         anyhow::bail!(
-            "efibootmgr failed: {}",
-            String::from_utf8_lossy(&output.stderr)
+            "efiBootMgrPath is required when canTouchEfiVariables is true and nvramBackend \
+             is efibootmgr"
         );
     }
+    if config.efi_entry_label.trim().is_empty() {
+        anyhow::bail!("efiEntryLabel must not be empty");
+    }
 
-    let efibootmgr_output = String::from_utf8(output.stdout)?;
+    match detect_boot_mode() {
+        BootMode::Uefi => {}
+        BootMode::UefiCsm => crate::warn!(
+            "EFI variables are missing or read-only (CSM or restricted firmware?).\n  NVRAM changes may be ignored by the firmware."
+        ),
+        BootMode::LegacyBios => {
+            crate::warn!(
+                "system was not booted via UEFI, skipping NVRAM boot entry setup.\n  Boot from the ESP once through UEFI or use efiInstallAsRemovable."
+            );
+            return Ok(());
+        }
+    }
 
-    // Find existing rEFInd entry
-    let entry_regex = Regex::new(r"Boot([0-9a-fA-F]{4})\*? rEFInd")?;
-    let existing_entry = entry_regex
-        .captures(&efibootmgr_output)
-        .and_then(|c| c.get(1))
-        .map(|m| m.as_str().to_string());
+    let backend: Box<dyn Nvram> = match config.nvram_backend {
+        NvramBackend::Efivarfs => Box::new(efivarfs::Efivarfs {
+            dir: PathBuf::from(efivarfs::EFIVARS),
+            print_only,
+        }),
+        NvramBackend::Efibootmgr => Box::new(Efibootmgr {
+            path: config.efi_boot_mgr_path.join("bin/efibootmgr"),
+            print_only,
+        }),
+    };
+    let nvram = Retrying {
+        backend,
+        attempts: config.nvram_write_attempts.max(1),
+        delay: Duration::from_millis(config.nvram_retry_delay_ms),
+    };
 
-    // Find EFI partition
+    // Find EFI partition, or the partitions mirroring it
     let efi_partition = find_mounted_device(&config.efi_mount_point)?;
-    let efi_disk = find_disk_device(&efi_partition)?;
-
-    // Determine boot file based on architecture
-    let boot_file = match config.host_architecture.as_str() {
-        arch if arch.starts_with("x86_64") => "BOOTX64.EFI",
-        arch if arch.starts_with("i686") => "BOOTIA32.EFI",
-        arch if arch.starts_with("aarch64") => "BOOTAA64.EFI",
-        arch => anyhow::bail!("Unsupported architecture: {}", arch),
-    };
+    let targets = boot_entry_targets(
+        &efi_partition,
+        config.legacy_efi_device_lookup,
+        &config.efi_entry_label,
+    )?;
 
-    let efi_path = format!("\\efi\\refind\\{}", boot_file);
-    let partition_num = extract_partition_number(&efi_partition, &efi_disk)?;
-
-    if let Some(entry_id) = existing_entry {
-        // Update existing entry
-        let boot_order_regex = Regex::new(r"BootOrder: ((?:[0-9a-fA-F]{4},?)*)")?;
-        let boot_order = boot_order_regex
-            .captures(&efibootmgr_output)
-            .and_then(|c| c.get(1))
-            .map(|m| m.as_str())
-            .unwrap_or("");
-
-        // Delete old entry
-        Command::new(&efibootmgr)
-            .args(["-b", &entry_id, "-B"])
-            .output()
-            .context("Failed to delete old EFI entry")?;
-
-        // Create new entry with same ID and preserve boot order
-        let status = Command::new(&efibootmgr)
-            .args([
-                "-c",
-                "-b",
-                &entry_id,
-                "-d",
-                &efi_disk,
-                "-p",
-                &partition_num,
-                "-l",
-                &efi_path,
-                "-L",
-                "rEFInd",
-                "-o",
-                boot_order,
-            ])
-            .status()
-            .context("Failed to create EFI entry")?;
-
-        if !status.success() {
-            anyhow::bail!("efibootmgr failed to create boot entry");
+    // The rEFInd binary the install step copied for this architecture
+    let efi_path = efi_arch(&config.host_architecture)?.loader();
+
+    // Entries we made for a layout that's gone, e.g. a RAID member that was removed, and
+    // ones made under an earlier efiEntryLabel, which the new entries replace
+    let mut previous_order = nvram.boot_order()?;
+    let mut relabeled = Vec::new();
+    for entry in nvram.entries()? {
+        if targets.iter().any(|target| target.label == entry.label) {
+            continue;
+        }
+        if is_managed_label(&entry.label, &config.efi_entry_label) {
+            if !prune_foreign && leave_foreign_entry(&entry, &targets) {
+                continue;
+            }
+            crate::info!(
+                "removing stale NVRAM entry Boot{} ({}){}",
+                entry.id,
+                entry.label,
+                entry.partition_state(&targets).reason()
+            );
+        } else if let Some(target) = targets
+            .iter()
+            .find(|target| entry.points_at(target, &efi_path))
+        {
+            crate::info!(
+                "relabeling NVRAM entry Boot{} from {} to {}",
+                entry.id,
+                entry.label,
+                target.label
+            );
+            relabeled.push((entry.id.to_uppercase(), target.label.clone()));
+        } else {
+            continue;
+        }
+        nvram.delete(&entry.id)?;
+    }
+
+    let entries = nvram.entries()?;
+
+    for target in &targets {
+        // Prefer an entry that's already right, then one on the right partition
+        let mut candidates: Vec<&BootEntry> = entries
+            .iter()
+            .filter(|entry| entry.label == target.label)
+            .filter(|entry| prune_foreign || !leave_foreign_entry(entry, &targets))
+            .collect();
+        candidates.sort_by_key(|entry| {
+            (
+                !entry.differences(target, &efi_path).is_empty(),
+                entry.partition_state(&targets) != PartitionState::Ours,
+            )
+        });
+        let existing_entry = candidates.first().copied();
+        for duplicate in candidates.iter().skip(1) {
+            crate::info!(
+                "removing duplicate NVRAM entry Boot{} ({}){}",
+                duplicate.id,
+                duplicate.label,
+                duplicate.partition_state(&targets).reason()
+            );
+            nvram.delete(&duplicate.id)?;
+        }
+
+        // Firmware NVRAM is write-limited, leave entries that are already right alone
+        if let Some(entry) = existing_entry {
+            let changes = entry.differences(target, &efi_path);
+            if changes.is_empty() {
+                crate::info!(
+                    "NVRAM entry Boot{} ({}) is up to date",
+                    entry.id,
+                    entry.label
+                );
+                continue;
+            }
+            crate::info!(
+                "updating NVRAM entry Boot{} ({}) under the same ID: {}",
+                entry.id,
+                entry.label,
+                changes.join(", ")
+            );
+        }
+        nvram.create(
+            existing_entry.map(|entry| entry.id.as_str()),
+            target,
+            &efi_path,
+        )?;
+    }
+
+    // Write the whole order back, explicitly, so creating entries can't reshuffle it.
+    // When only printing, entries that would be created have no ID to place yet.
+    let entries = nvram.entries()?;
+    let current_order = nvram.boot_order()?;
+    // Relabeled entries take the place of the ones they replace
+    for (old_id, label) in relabeled {
+        if let Some(entry) = entries.iter().find(|entry| entry.label == label) {
+            for id in &mut previous_order {
+                if *id == old_id {
+                    *id = entry.id.to_uppercase();
+                }
+            }
         }
+    }
+    let ours: Vec<String> = targets
+        .iter()
+        .filter_map(|target| entries.iter().find(|entry| entry.label == target.label))
+        .map(|entry| entry.id.to_uppercase())
+        .collect();
+    let order = arrange_boot_order(
+        &previous_order,
+        &current_order,
+        &entries,
+        &ours,
+        boot_order_position(config),
+    );
+    if order != current_order {
+        crate::info!("setting BootOrder to {}", order.join(","));
+        nvram.set_boot_order(&order)?;
+    }
+
+    Ok(())
+}
+
+/// Where our entries go in the BootOrder: first with `makeDefaultBoot`, otherwise as
+/// `bootOrderPosition` says
+fn boot_order_position(config: &InstallConfig) -> BootOrderPosition {
+    if config.make_default_boot {
+        BootOrderPosition::First
     } else {
-        // Create new entry
-        let status = Command::new(&efibootmgr)
-            .args([
-                "-c",
-                "-d",
-                &efi_disk,
-                "-p",
-                &partition_num,
-                "-l",
-                &efi_path,
-                "-L",
-                "rEFInd",
-            ])
-            .status()
-            .context("Failed to create EFI entry")?;
-
-        if !status.success() {
-            anyhow::bail!("efibootmgr failed to create boot entry");
+        config.boot_order_position
+    }
+}
+
+/// Where Boot#### entries and the BootOrder are read and written: efibootmgr or
+/// efivarfs. Entry IDs are 4-digit uppercase hex.
+trait Nvram {
+    fn entries(&self) -> Result<Vec<BootEntry>>;
+
+    /// Empty when firmware has no BootOrder variable
+    fn boot_order(&self) -> Result<Vec<String>>;
+
+    fn delete(&self, entry_id: &str) -> Result<()>;
+
+    /// Write an entry for `target` booting `loader`, replacing `existing_id` or under a
+    /// new ID. The BootOrder is left to the caller.
+    fn create(
+        &self,
+        existing_id: Option<&str>,
+        target: &BootEntryTarget,
+        loader: &str,
+    ) -> Result<()>;
+
+    /// Replace the BootOrder, refusing duplicates and IDs with no Boot#### entry
+    fn set_boot_order(&self, order: &[String]) -> Result<()>;
+}
+
+/// An [`Nvram`] whose writes are retried with exponential backoff when they fail in a way
+/// that can pass, e.g. fwupd holding efivarfs busy at the same moment
+struct Retrying {
+    backend: Box<dyn Nvram>,
+    attempts: u32,
+    /// Before the first retry, doubled for each one after
+    delay: Duration,
+}
+
+impl Retrying {
+    fn retry(&self, mut write: impl FnMut() -> Result<()>) -> Result<()> {
+        let mut delay = self.delay;
+        let mut attempt = 1;
+        loop {
+            let error = match write() {
+                Ok(()) => return Ok(()),
+                Err(error) => error,
+            };
+            if attempt >= self.attempts || !is_transient_nvram_error(&error) {
+                return Err(error.context(format!(
+                    "NVRAM write failed after {} attempt{}",
+                    attempt,
+                    if attempt == 1 { "" } else { "s" }
+                )));
+            }
+            crate::warn!(
+                "NVRAM write failed (attempt {} of {}), retrying in {}ms: {:#}",
+                attempt,
+                self.attempts,
+                delay.as_millis(),
+                error
+            );
+            std::thread::sleep(delay);
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+impl Nvram for Retrying {
+    fn entries(&self) -> Result<Vec<BootEntry>> {
+        self.backend.entries()
+    }
+
+    fn boot_order(&self) -> Result<Vec<String>> {
+        self.backend.boot_order()
+    }
+
+    fn delete(&self, entry_id: &str) -> Result<()> {
+        self.retry(|| self.backend.delete(entry_id))
+    }
+
+    fn create(
+        &self,
+        existing_id: Option<&str>,
+        target: &BootEntryTarget,
+        loader: &str,
+    ) -> Result<()> {
+        self.retry(|| self.backend.create(existing_id, target, loader))
+    }
+
+    fn set_boot_order(&self, order: &[String]) -> Result<()> {
+        self.retry(|| self.backend.set_boot_order(order))
+    }
+}
+
+/// Whether an NVRAM write failed in a way that can pass on its own: the variable store
+/// busy or a flaky I/O error. Read-only efivarfs, missing support and refused
+/// permissions are permanent.
+fn is_transient_nvram_error(error: &anyhow::Error) -> bool {
+    const TRANSIENT: [i32; 4] = [libc::EBUSY, libc::EIO, libc::EAGAIN, libc::EINTR];
+    // efibootmgr only reports strerror() text, in the C locale it runs under
+    const TRANSIENT_MESSAGES: [&str; 4] = [
+        "Device or resource busy",
+        "Input/output error",
+        "Resource temporarily unavailable",
+        "Interrupted system call",
+    ];
+    error
+        .chain()
+        .any(|cause| match cause.downcast_ref::<std::io::Error>() {
+            Some(io) => io
+                .raw_os_error()
+                .is_some_and(|code| TRANSIENT.contains(&code)),
+            None => {
+                let message = cause.to_string();
+                TRANSIENT_MESSAGES
+                    .iter()
+                    .any(|transient| message.contains(transient))
+            }
+        })
+}
+
+/// Refuse `order` if it repeats an ID or names one not in `entries`, since firmware
+/// behaviour on a dangling BootOrder is anyone's guess
+fn validate_boot_order(order: &[String], entries: &[BootEntry]) -> Result<()> {
+    let mut seen = std::collections::HashSet::new();
+    for id in order {
+        if !seen.insert(id.to_uppercase()) {
+            anyhow::bail!("Boot entry {} appears more than once in the boot order", id);
+        }
+    }
+
+    if let Some(missing) = order.iter().find(|id| {
+        !entries
+            .iter()
+            .any(|entry| entry.id.eq_ignore_ascii_case(id))
+    }) {
+        anyhow::bail!("Boot entry {} does not exist in NVRAM", missing);
+    }
+    Ok(())
+}
+
+/// IDs in the BootOrder line of efibootmgr's output, uppercase. Empty when firmware has
+/// no BootOrder variable.
+fn boot_order(efibootmgr_output: &str) -> Result<Vec<String>> {
+    let boot_order_regex = Regex::new(r"(?m)^BootOrder: ?(.*)$")?;
+    let Some(caps) = boot_order_regex.captures(efibootmgr_output) else {
+        return Ok(Vec::new());
+    };
+    Ok(caps[1]
+        .split(',')
+        .map(|id| id.trim().to_uppercase())
+        .filter(|id| !id.is_empty())
+        .collect())
+}
+
+/// The BootOrder to write: `previous` (from before this run touched NVRAM) minus entries
+/// that are gone, then entries that appeared in `current` meanwhile, with `ours` placed as
+/// `position` says. With `keep`, ours go back to their previous indices, or first if
+/// they weren't in the order.
+fn arrange_boot_order(
+    previous: &[String],
+    current: &[String],
+    entries: &[BootEntry],
+    ours: &[String],
+    position: BootOrderPosition,
+) -> Vec<String> {
+    let exists = |id: &String| {
+        entries
+            .iter()
+            .any(|entry| entry.id.eq_ignore_ascii_case(id))
+    };
+
+    let mut order: Vec<String> = Vec::new();
+    for id in previous.iter().chain(current) {
+        if exists(id) && !ours.contains(id) && !order.contains(id) {
+            order.push(id.clone());
+        }
+    }
+
+    match position {
+        BootOrderPosition::First => {
+            order.splice(0..0, ours.iter().cloned());
+        }
+        BootOrderPosition::Last => order.extend(ours.iter().cloned()),
+        BootOrderPosition::Keep => {
+            let mut placed: Vec<(usize, &String)> = ours
+                .iter()
+                .map(|id| (previous.iter().position(|p| p == id).unwrap_or(0), id))
+                .collect();
+            placed.sort_by_key(|(index, _)| *index);
+            for (index, id) in placed {
+                order.insert(index.min(order.len()), id.clone());
+            }
+        }
+    }
+    order
+}
+
+/// EFI file names for one CPU architecture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EfiArch {
+    /// CPU part of the Nix system double, e.g. `x86_64` in `x86_64-linux`
+    pub cpu: &'static str,
+    /// Name firmware looks for in `EFI/BOOT` on removable media, which refindgen also
+    /// gives rEFInd in `efi/refind`
+    pub boot_file: &'static str,
+    /// rEFInd's binary in `share/refind` of its package
+    pub refind_file: &'static str,
+    /// Directory of rEFInd's filesystem drivers in `share/refind`, which rEFInd also
+    /// loads from beside itself
+    pub drivers_dir: &'static str,
+}
+
+impl EfiArch {
+    /// Where refindgen installs rEFInd's binary, relative to the ESP
+    pub fn install_path(&self) -> PathBuf {
+        Path::new("efi/refind").join(self.boot_file)
+    }
+
+    /// [`EfiArch::install_path`] as NVRAM load options spell it
+    pub fn loader(&self) -> String {
+        format!("\\efi\\refind\\{}", self.boot_file)
+    }
+}
+
+/// Every architecture refindgen can install rEFInd for
+pub const EFI_ARCHES: &[EfiArch] = &[
+    EfiArch {
+        cpu: "x86_64",
+        boot_file: "BOOTX64.EFI",
+        refind_file: "refind_x64.efi",
+        drivers_dir: "drivers_x64",
+    },
+    EfiArch {
+        cpu: "i686",
+        boot_file: "BOOTIA32.EFI",
+        refind_file: "refind_ia32.efi",
+        drivers_dir: "drivers_ia32",
+    },
+    EfiArch {
+        cpu: "aarch64",
+        boot_file: "BOOTAA64.EFI",
+        refind_file: "refind_aa64.efi",
+        drivers_dir: "drivers_aa64",
+    },
+    EfiArch {
+        cpu: "riscv64",
+        boot_file: "BOOTRISCV64.EFI",
+        refind_file: "refind_riscv64.efi",
+        drivers_dir: "drivers_riscv64",
+    },
+];
+
+/// File names for `host_architecture`, a Nix system double like `aarch64-linux`
+pub fn efi_arch(host_architecture: &str) -> Result<&'static EfiArch> {
+    let cpu = host_architecture.split('-').next().unwrap_or_default();
+    EFI_ARCHES
+        .iter()
+        .find(|arch| arch.cpu == cpu)
+        .with_context(|| {
+            let supported: Vec<&str> = EFI_ARCHES.iter().map(|arch| arch.cpu).collect();
+            format!(
+                "Unsupported architecture: {} (supported: {})",
+                host_architecture,
+                supported.join(", ")
+            )
+        })
+}
+
+/// A disk partition that gets its own NVRAM entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BootEntryTarget {
+    label: String,
+    /// The partition's canonical /dev path
+    device: PathBuf,
+    disk: String,
+    partition: String,
+    partuuid: Option<String>,
+}
+
+/// A Boot#### entry as `efibootmgr -v` lists it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BootEntry {
+    id: String,
+    label: String,
+    /// Everything after the label: the device path and any optional data
+    device_path: String,
+}
+
+impl BootEntry {
+    /// Partition number and GPT GUID of the `HD(...)` node, if there is one
+    fn hard_drive(&self) -> Option<(String, String)> {
+        parse_device_path(&self.device_path)
+            .into_iter()
+            .find_map(|node| match node {
+                DevicePathNode::Node { name, args }
+                    if name == "HD" && args.len() >= 3 && args[1] == "GPT" =>
+                {
+                    Some((args[0].clone(), args[2].to_lowercase()))
+                }
+                _ => None,
+            })
+    }
+
+    /// The loader path, from a `File(...)` node or the bare path newer efibootmgr prints
+    fn loader(&self) -> Option<String> {
+        parse_device_path(&self.device_path)
+            .into_iter()
+            .find_map(|node| match node {
+                DevicePathNode::Node { name, args } if name == "File" => Some(args.join(",")),
+                DevicePathNode::Path(path) => Some(path),
+                _ => None,
+            })
+    }
+
+    /// Whether this entry boots `loader` from `target`'s partition, identified by its
+    /// PARTUUID; without one, partition numbers alone could match another disk's ESP
+    fn points_at(&self, target: &BootEntryTarget, loader: &str) -> bool {
+        let Some(ref partuuid) = target.partuuid else {
+            return false;
+        };
+        self.hard_drive()
+            .is_some_and(|(_, guid)| guid.eq_ignore_ascii_case(partuuid))
+            && self
+                .loader()
+                .is_some_and(|current| current.eq_ignore_ascii_case(loader))
+    }
+
+    /// Whether the partition this entry boots is one of `targets`, gone, or another one
+    fn partition_state(&self, targets: &[BootEntryTarget]) -> PartitionState {
+        let Some((_, guid)) = self.hard_drive() else {
+            return PartitionState::Unknown;
+        };
+        if targets.iter().any(|target| {
+            target
+                .partuuid
+                .as_ref()
+                .is_some_and(|partuuid| partuuid.eq_ignore_ascii_case(&guid))
+        }) {
+            return PartitionState::Ours;
+        }
+        // Without all our PARTUUIDs (legacy lookup), another GUID could still be ours
+        if targets.iter().any(|target| target.partuuid.is_none()) {
+            return PartitionState::Unknown;
+        }
+        if Path::new("/dev/disk/by-partuuid").join(&guid).exists() {
+            PartitionState::Foreign(guid)
+        } else {
+            PartitionState::Missing(guid)
+        }
+    }
+
+    /// What differs between this entry and one created for `target` booting `loader`, as
+    /// `field old -> new` descriptions. Empty when the entry can stay.
+    fn differences(&self, target: &BootEntryTarget, loader: &str) -> Vec<String> {
+        let mut changes = Vec::new();
+        let (partition, guid) = self.hard_drive().unzip();
+        if partition.as_deref() != Some(target.partition.as_str()) {
+            changes.push(format!(
+                "partition {} -> {}",
+                partition.as_deref().unwrap_or("none"),
+                target.partition
+            ));
+        }
+        if let Some(ref expected) = target.partuuid
+            && guid.as_deref() != Some(expected.to_lowercase().as_str())
+        {
+            changes.push(format!(
+                "PARTUUID {} -> {}",
+                guid.as_deref().unwrap_or("none"),
+                expected
+            ));
+        }
+        // FAT paths are case-insensitive
+        let current = self.loader();
+        if !current
+            .as_deref()
+            .is_some_and(|current| current.eq_ignore_ascii_case(loader))
+        {
+            changes.push(format!(
+                "loader {} -> {}",
+                current.as_deref().unwrap_or("none"),
+                loader
+            ));
+        }
+        changes
+    }
+}
+
+/// Where an NVRAM entry with one of our labels points, as far as this machine can tell
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PartitionState {
+    /// One of the partitions we're creating entries for
+    Ours,
+    /// A partition that isn't attached, e.g. from a replaced disk
+    Missing(String),
+    /// Another partition that exists, e.g. a second install's ESP
+    Foreign(String),
+    /// No GPT partition in the device path, or ours aren't all known
+    Unknown,
+}
+
+impl PartitionState {
+    /// Why an entry in this state is being removed, for the log
+    fn reason(&self) -> String {
+        match self {
+            PartitionState::Missing(guid) => {
+                format!(", its partition {} no longer exists", guid)
+            }
+            PartitionState::Foreign(guid) => format!(", it boots another ESP ({})", guid),
+            PartitionState::Ours | PartitionState::Unknown => String::new(),
         }
     }
+}
+
+/// Whether `entry` boots another ESP that exists and should be left alone. Logs why.
+fn leave_foreign_entry(entry: &BootEntry, targets: &[BootEntryTarget]) -> bool {
+    let PartitionState::Foreign(guid) = entry.partition_state(targets) else {
+        return false;
+    };
+    crate::info!(
+        "leaving NVRAM entry Boot{} ({}) alone, it boots another ESP ({}); pass \
+         --prune-foreign to remove it",
+        entry.id,
+        entry.label,
+        guid
+    );
+    true
+}
+
+/// Where NVRAM entries should point: the ESP's partition, or when the ESP is an md RAID1
+/// array, each member partition, so the firmware can boot off whichever disk survives.
+///
+/// Partitions are resolved through their PARTUUID unless `legacy` is set. efibootmgr only
+/// takes a disk and partition number, but it puts the partition's GPT GUID into the
+/// entry's device path, which is what firmware matches on.
+fn boot_entry_targets(
+    efi_partition: &str,
+    legacy: bool,
+    label: &str,
+) -> Result<Vec<BootEntryTarget>> {
+    let device = std::fs::canonicalize(efi_partition)
+        .with_context(|| format!("Failed to resolve {}", efi_partition))?;
+    let name = device
+        .file_name()
+        .context("Invalid partition path")?
+        .to_string_lossy()
+        .to_string();
+
+    let sysfs = Path::new(SYSFS_BLOCK).join(&name);
+    let is_raid = sysfs.join("md").is_dir();
+    let is_mapped = sysfs.join("dm").is_dir();
+    let single = |device: &Path| -> Result<Vec<BootEntryTarget>> {
+        let (path, partuuid) = stable_partition_path(device, legacy);
+        let (disk, partition) = disk_and_partition(&path)?;
+        Ok(vec![BootEntryTarget {
+            label: label.to_string(),
+            device: device.to_path_buf(),
+            disk,
+            partition,
+            partuuid,
+        }])
+    };
+    if !is_raid && !is_mapped {
+        return single(&device);
+    }
+
+    // Stacked devices (LVM, dm-linear, RAID) bottom out in the partitions firmware sees
+    let mut members = Vec::new();
+    underlying_partitions(&name, &mut members)?;
+    members.sort();
+    members.dedup();
+    if members.is_empty() {
+        anyhow::bail!("{} holding the ESP has no underlying partitions", name);
+    }
+    if is_mapped {
+        crate::warn!(
+            "ESP is device-mapper device {} on {}; firmware can only read it if the mapping \
+             is a plain linear one onto the whole partition",
+            name,
+            members.join(", ")
+        );
+        if members.len() == 1 {
+            return single(&Path::new("/dev").join(&members[0]));
+        }
+    }
+
+    let mut targets = Vec::new();
+    for (index, member) in members.iter().enumerate() {
+        let member = Path::new("/dev").join(member);
+        let (path, partuuid) = stable_partition_path(&member, legacy);
+        let (disk, partition) = disk_and_partition(&path)?;
+        targets.push(BootEntryTarget {
+            label: format!("{} (disk{})", label, index + 1),
+            device: member,
+            disk,
+            partition,
+            partuuid,
+        });
+    }
+    crate::info!(
+        "ESP {} spans several partitions, adding an NVRAM entry for each of {}",
+        name,
+        members.join(", ")
+    );
+    Ok(targets)
+}
 
+/// Collect the partitions at the bottom of the stack of block devices `name` is built on
+/// (its `slaves/` in sysfs, recursively). A device with no slaves is itself the bottom.
+fn underlying_partitions(name: &str, partitions: &mut Vec<String>) -> Result<()> {
+    let slaves_dir = Path::new(SYSFS_BLOCK).join(name).join("slaves");
+    let slaves: Vec<String> = match std::fs::read_dir(&slaves_dir) {
+        Ok(entries) => entries
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+            .collect::<Result<_>>()?,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(error) => {
+            return Err(error).with_context(|| format!("Failed to list {}", slaves_dir.display()));
+        }
+    };
+
+    if slaves.is_empty() {
+        partitions.push(name.to_string());
+    }
+    for slave in slaves {
+        underlying_partitions(&slave, partitions)?;
+    }
     Ok(())
 }
 
+/// A node of a device path as efibootmgr prints it
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DevicePathNode {
+    /// `Name(arg,arg,...)`, e.g. `HD(1,GPT,<guid>,0x800,0x100000)`
+    Node { name: String, args: Vec<String> },
+    /// A file path without `File(...)` around it, e.g. `\EFI\refind\refind_x64.efi`
+    Path(String),
+}
+
+/// The nodes of a device path like `PciRoot(0x0)/Pci(0x1d,0x0)/HD(1,GPT,...)/File(\x.efi)`.
+///
+/// Parsing stops at the first thing that isn't a node, which is where firmware-specific
+/// optional data (`RC`, `..BO`, `dp: ...`) starts.
+fn parse_device_path(text: &str) -> Vec<DevicePathNode> {
+    let mut nodes = Vec::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        if rest.starts_with('\\') {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            nodes.push(DevicePathNode::Path(rest[..end].to_string()));
+            break;
+        }
+
+        let Some(open) = rest.find('(') else {
+            break;
+        };
+        let name = &rest[..open];
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+            break;
+        }
+        let mut depth = 0;
+        let Some(close) = rest[open..].char_indices().find_map(|(i, c)| {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            (depth == 0).then_some(open + i)
+        }) else {
+            break;
+        };
+
+        let inner = &rest[open + 1..close];
+        let args = if inner.is_empty() {
+            Vec::new()
+        } else {
+            inner.split(',').map(str::to_string).collect()
+        };
+        nodes.push(DevicePathNode::Node {
+            name: name.to_string(),
+            args,
+        });
+
+        match rest[close + 1..].strip_prefix('/') {
+            Some(next) => rest = next,
+            None => break,
+        }
+    }
+    nodes
+}
+
+/// Whether `label` is one of the NVRAM entry labels refindgen creates with `base`,
+/// `efiEntryLabel`
+fn is_managed_label(label: &str, base: &str) -> bool {
+    label == base
+        || label
+            .strip_prefix(base)
+            .and_then(|rest| rest.strip_prefix(" (disk"))
+            .and_then(|rest| rest.strip_suffix(')'))
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Every Boot#### entry in efibootmgr's output, active (`Boot0001*`) or not (`Boot0001 `).
+/// The label ends at the tab before the device path, or with older efibootmgr that uses
+/// spaces, at the first `Node(`.
+fn boot_entries(efibootmgr_output: &str) -> Result<Vec<BootEntry>> {
+    let entry_regex = Regex::new(r"(?m)^Boot([0-9a-fA-F]{4})[* ]? ?(.*)$")?;
+    let node_regex = Regex::new(r"\s+[A-Za-z]+\(")?;
+    Ok(entry_regex
+        .captures_iter(efibootmgr_output)
+        .map(|c| {
+            let rest = &c[2];
+            let (label, device_path) = match rest.split_once('\t') {
+                Some(split) => split,
+                None => match node_regex.find(rest) {
+                    Some(node) => (&rest[..node.start()], rest[node.start()..].trim_start()),
+                    None => (rest, ""),
+                },
+            };
+            BootEntry {
+                id: c[1].to_string(),
+                label: label.trim_end().to_string(),
+                device_path: device_path.to_string(),
+            }
+        })
+        .collect())
+}
+
+/// efibootmgr, which reads NVRAM freely but with `print_only` only prints the commands
+/// that would change it
+#[derive(Debug, Clone)]
+struct Efibootmgr {
+    path: PathBuf,
+    print_only: bool,
+}
+
+impl Efibootmgr {
+    /// `efibootmgr -v`, listing entries with their device paths
+    fn list(&self) -> Result<String> {
+        let output = Command::new(&self.path)
+            .arg("-v")
+            .output()
+            .context("Failed to run efibootmgr")?;
+
+        if !output.status.success() {
+            anyhow::bail!(
+                "efibootmgr failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    /// Run efibootmgr with `args`, which change NVRAM, or print the command line
+    fn run(&self, args: &[&str]) -> Result<()> {
+        if self.print_only {
+            let mut command = vec![command_line_word(&self.path.to_string_lossy())];
+            command.extend(args.iter().map(|arg| command_line_word(arg)));
+            crate::info!("would run: {}", command.join(" "));
+            return Ok(());
+        }
+
+        let output = Command::new(&self.path)
+            .args(args)
+            .env("LC_ALL", "C")
+            .output()
+            .context("Failed to run efibootmgr")?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "efibootmgr {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Nvram for Efibootmgr {
+    fn entries(&self) -> Result<Vec<BootEntry>> {
+        boot_entries(&self.list()?)
+    }
+
+    fn boot_order(&self) -> Result<Vec<String>> {
+        boot_order(&self.list()?)
+    }
+
+    fn delete(&self, entry_id: &str) -> Result<()> {
+        self.run(&["-b", entry_id, "-B"])
+            .with_context(|| format!("Failed to delete boot entry {}", entry_id))
+    }
+
+    fn create(
+        &self,
+        existing_id: Option<&str>,
+        target: &BootEntryTarget,
+        loader: &str,
+    ) -> Result<()> {
+        let mut args = vec!["-c"];
+        if let Some(entry_id) = existing_id {
+            // Recreate it with the same ID. A retry after a failed create finds it gone.
+            let exists = self
+                .entries()?
+                .iter()
+                .any(|entry| entry.id.eq_ignore_ascii_case(entry_id));
+            if exists {
+                self.delete(entry_id)?;
+            }
+            args.extend(["-b", entry_id]);
+        }
+        args.extend([
+            "-d",
+            &target.disk,
+            "-p",
+            &target.partition,
+            "-l",
+            loader,
+            "-L",
+            &target.label,
+        ]);
+        self.run(&args)
+            .with_context(|| format!("Failed to create boot entry {}", target.label))
+    }
+
+    fn set_boot_order(&self, order: &[String]) -> Result<()> {
+        validate_boot_order(order, &self.entries()?)?;
+        self.run(&["--bootorder", &order.join(",")])
+            .context("Failed to set the boot order")
+    }
+}
+
+/// `word` as it can be pasted into a shell, single-quoted unless it's plain
+fn command_line_word(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./,:=+".contains(c));
+    if plain {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// Replace the firmware BootOrder with `order`, a list of 4-digit hex entry IDs.
+///
+/// Refuses duplicates and IDs that have no Boot#### entry in NVRAM, since firmware
+/// behaviour on a dangling BootOrder is anyone's guess.
+pub fn set_boot_order(efibootmgr: &Path, order: &[String]) -> Result<()> {
+    Efibootmgr {
+        path: efibootmgr.to_path_buf(),
+        print_only: false,
+    }
+    .set_boot_order(order)
+}
+
 fn find_mounted_device(path: &Path) -> Result<String> {
     let path = std::fs::canonicalize(path)?;
     let mut current = path.as_path();
@@ -131,7 +981,69 @@ fn find_mounted_device(path: &Path) -> Result<String> {
     anyhow::bail!("Could not find device for mount point: {:?}", current)
 }
 
-fn is_mount_point(path: &Path) -> Result<bool> {
+/// PARTUUID of the partition mounted at `mount_point`, as rEFInd's `volume` takes it
+pub fn partition_uuid(mount_point: &Path) -> Result<String> {
+    let device = std::fs::canonicalize(find_mounted_device(mount_point)?)?;
+
+    device_partition_uuid(&device)?.with_context(|| {
+        format!(
+            "No PARTUUID for {} mounted at {}, it needs to be a GPT partition",
+            device.display(),
+            mount_point.display()
+        )
+    })
+}
+
+/// PARTUUID of the partition `device` (a canonical /dev path), if it has one
+fn device_partition_uuid(device: &Path) -> Result<Option<String>> {
+    let by_partuuid = Path::new("/dev/disk/by-partuuid");
+    let entries = std::fs::read_dir(by_partuuid)
+        .with_context(|| format!("Failed to read {}", by_partuuid.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        if std::fs::canonicalize(entry.path()).is_ok_and(|target| target == device) {
+            return Ok(Some(entry.file_name().to_string_lossy().to_string()));
+        }
+    }
+    Ok(None)
+}
+
+/// The path to look `device` up by when creating its NVRAM entry, and its PARTUUID: its
+/// `/dev/disk/by-partuuid` link, which names the same partition whatever order disks
+/// were found in, or `device` itself when it has no PARTUUID or `legacy` is set
+fn stable_partition_path(device: &Path, legacy: bool) -> (String, Option<String>) {
+    let by_name = device.to_string_lossy().to_string();
+    if legacy {
+        return (by_name, None);
+    }
+    match device_partition_uuid(device) {
+        Ok(Some(uuid)) => {
+            crate::info!(
+                "targeting ESP partition {} by PARTUUID {}",
+                device.display(),
+                uuid
+            );
+            (format!("/dev/disk/by-partuuid/{}", uuid), Some(uuid))
+        }
+        Ok(None) => {
+            crate::warn!(
+                "{} has no PARTUUID, targeting it by device name",
+                device.display()
+            );
+            (by_name, None)
+        }
+        Err(error) => {
+            crate::warn!(
+                "could not look up the PARTUUID of {}, targeting it by device name: {:#}",
+                device.display(),
+                error
+            );
+            (by_name, None)
+        }
+    }
+}
+
+pub(crate) fn is_mount_point(path: &Path) -> Result<bool> {
     let parent = match path.parent() {
         Some(p) => p,
         None => return Ok(true), // Root is always a mount point
@@ -144,42 +1056,706 @@ fn is_mount_point(path: &Path) -> Result<bool> {
     Ok(path_metadata.dev() != parent_metadata.dev())
 }
 
-fn find_disk_device(partition: &str) -> Result<String> {
-    // /dev/nvme0n1p1 -> /dev/nvme0n1
-    // /dev/sda1 -> /dev/sda
+/// Parent disk and partition number of `partition`, as efibootmgr's `-d` and `-p` take
+/// them. Asks the kernel through sysfs, then lsblk, falling back to parsing the device
+/// name when neither is available (e.g. in containers).
+fn disk_and_partition(partition: &str) -> Result<(String, String)> {
+    let sysfs_error = match sysfs_disk_and_partition(partition, Path::new(SYSFS_BLOCK)) {
+        Ok(found) => return Ok(found),
+        Err(error) => error,
+    };
+    match lsblk_disk_and_partition(partition) {
+        Ok(found) => Ok(found),
+        Err(error) => {
+            crate::warn!(
+                "neither sysfs nor lsblk could resolve {}, guessing from its name: {:#}; {:#}",
+                partition,
+                sysfs_error,
+                error
+            );
+            split_partition_name(partition)
+        }
+    }
+}
+
+/// Where the kernel lists block devices
+const SYSFS_BLOCK: &str = "/sys/class/block";
 
-    let partition = std::fs::canonicalize(partition)?;
-    let part_name = partition
+/// Parent disk and partition number of `partition` from `sysfs_block`
+/// (`/sys/class/block`): the partition's entry links into its disk's directory and holds
+/// its number in `partition`
+fn sysfs_disk_and_partition(partition: &str, sysfs_block: &Path) -> Result<(String, String)> {
+    let device = std::fs::canonicalize(partition)
+        .with_context(|| format!("Failed to resolve partition {}", partition))?;
+    let name = device
+        .file_name()
+        .with_context(|| format!("Invalid partition path: {}", partition))?;
+
+    let entry = std::fs::canonicalize(sysfs_block.join(name))
+        .with_context(|| format!("{} is not in {}", partition, sysfs_block.display()))?;
+    let number = std::fs::read_to_string(entry.join("partition"))
+        .with_context(|| format!("{} is not a partition", partition))?;
+    let disk = entry
+        .parent()
+        .and_then(Path::file_name)
+        .with_context(|| format!("No parent disk for {} in sysfs", partition))?;
+
+    Ok((
+        format!("/dev/{}", disk.to_string_lossy()),
+        number.trim().to_string(),
+    ))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct LsblkOutput {
+    blockdevices: Vec<LsblkDevice>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct LsblkDevice {
+    pkname: Option<String>,
+    /// A number, or a string on util-linux versions before 2.38
+    partn: Option<serde_json::Value>,
+}
+
+fn lsblk_disk_and_partition(partition: &str) -> Result<(String, String)> {
+    let output = Command::new("lsblk")
+        .args(["-J", "-o", "NAME,PKNAME,PARTN,PATH", partition])
+        .output()
+        .context("Failed to run lsblk")?;
+    if !output.status.success() {
+        anyhow::bail!("lsblk failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+    parse_lsblk(&String::from_utf8(output.stdout)?)
+}
+
+/// Parent disk and partition number from the output of
+/// `lsblk -J -o NAME,PKNAME,PARTN,PATH <partition>`
+fn parse_lsblk(json: &str) -> Result<(String, String)> {
+    let output: LsblkOutput = serde_json::from_str(json).context("Failed to parse lsblk output")?;
+    let device = output
+        .blockdevices
+        .into_iter()
+        .next()
+        .context("lsblk listed no device")?;
+
+    let disk = device.pkname.context("lsblk reports no parent disk")?;
+    let number = match device.partn {
+        Some(serde_json::Value::Number(number)) => number.to_string(),
+        Some(serde_json::Value::String(number)) if !number.is_empty() => number,
+        _ => anyhow::bail!("lsblk reports no partition number, is it a partition?"),
+    };
+    Ok((format!("/dev/{}", disk), number))
+}
+
+/// Parent disk and partition number of `partition` from its kernel name, see
+/// [`split_device_name`]
+fn split_partition_name(partition: &str) -> Result<(String, String)> {
+    let resolved = std::fs::canonicalize(partition)
+        .with_context(|| format!("Failed to resolve partition {}", partition))?;
+    let part_name = resolved
         .file_name()
         .and_then(|n| n.to_str())
-        .context("Invalid partition path")?;
+        .with_context(|| format!("Invalid partition path: {}", partition))?;
 
-    // Handle nvme devices (nvme0n1p1 -> nvme0n1)
-    if part_name.contains("nvme") {
-        let re = Regex::new(r"^(nvme\d+n\d+)p\d+$")?;
-        if let Some(caps) = re.captures(part_name) {
-            return Ok(format!("/dev/{}", &caps[1]));
+    split_device_name(part_name)?.with_context(|| {
+        format!(
+            "Could not determine disk device and partition number of {} ({})",
+            partition, part_name
+        )
+    })
+}
+
+/// `sda1` is `/dev/sda` and `1`, and disks whose name ends in a digit (`nvme0n1`,
+/// `mmcblk0`, `loop0`, `md127`) separate the partition number with a `p`, as in
+/// `mmcblk0p1`. Those disks themselves are `None`, not partition 0 of `mmcblk`.
+fn split_device_name(name: &str) -> Result<Option<(String, String)>> {
+    if Regex::new(r"^(nvme\d+n\d+|mmcblk\d+|loop\d+|md\d+)$")?.is_match(name) {
+        return Ok(None);
+    }
+    let patterns = [
+        r"^(nvme\d+n\d+|mmcblk\d+|loop\d+|md\d+)p(\d+)$",
+        r"^([a-z]+)(\d+)$",
+    ];
+    for pattern in patterns {
+        if let Some(caps) = Regex::new(pattern)?.captures(name) {
+            return Ok(Some((format!("/dev/{}", &caps[1]), caps[2].to_string())));
         }
     }
+    Ok(None)
+}
+
+/// Filesystem backing the ESP. The UEFI spec only guarantees FAT32 support.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EspFilesystemType {
+    Fat32,
+    Fat16,
+    Ext4,
+    Unknown(String),
+}
 
-    // Handle sd devices (sda1 -> sda) and other devices
-    let re = Regex::new(r"^([a-z]+)\d+$")?;
-    if let Some(caps) = re.captures(part_name) {
-        return Ok(format!("/dev/{}", &caps[1]));
+impl fmt::Display for EspFilesystemType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EspFilesystemType::Fat32 => write!(f, "FAT32"),
+            EspFilesystemType::Fat16 => write!(f, "FAT16"),
+            EspFilesystemType::Ext4 => write!(f, "ext4"),
+            EspFilesystemType::Unknown(fs_type) => write!(f, "{}", fs_type),
+        }
     }
+}
 
-    anyhow::bail!(
-        "Could not determine disk device for partition: {}",
-        partition.display()
-    )
+/// The first of `/boot`, `/boot/efi` and `/efi` that has a vfat filesystem mounted on it
+pub fn discover_efi_mount_point() -> Option<PathBuf> {
+    let mounts = std::fs::read_to_string("/proc/mounts").ok()?;
+    let vfat_mounts: Vec<&str> = mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let mount_point = fields.nth(1)?;
+            (fields.next()? == "vfat").then_some(mount_point)
+        })
+        .collect();
+
+    ["/boot", "/boot/efi", "/efi"]
+        .into_iter()
+        .find(|candidate| vfat_mounts.contains(candidate))
+        .map(PathBuf::from)
 }
 
-fn extract_partition_number(partition: &str, disk: &str) -> Result<String> {
-    // /dev/sda1 with disk /dev/sda -> "1"
-    // /dev/nvme0n1p1 with disk /dev/nvme0n1 -> "1"
+pub fn detect_esp_filesystem_type(mount_point: &Path) -> Result<EspFilesystemType> {
+    let mount_point = std::fs::canonicalize(mount_point)?;
+
+    // The mount entry with the longest matching prefix is the one backing the path
+    let mounts = std::fs::read_to_string("/proc/mounts")?;
+    let (device, fs_type) = mounts
+        .lines()
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            (parts.len() >= 3).then(|| (parts[0], parts[1], parts[2]))
+        })
+        .filter(|(_, target, _)| mount_point.starts_with(target))
+        .max_by_key(|(_, target, _)| target.len())
+        .map(|(device, _, fs_type)| (device, fs_type))
+        .with_context(|| format!("Could not find mount entry for {:?}", mount_point))?;
+
+    Ok(match fs_type {
+        // /proc/mounts reports "vfat" for every FAT width, so ask the boot sector
+        "vfat" | "msdos" => fat_variant(device)?,
+        "ext4" => EspFilesystemType::Ext4,
+        other => EspFilesystemType::Unknown(other.to_string()),
+    })
+}
 
-    let part = partition.trim_start_matches(disk);
-    let part = part.trim_start_matches('p'); // For nvme devices
+fn fat_variant(device: &str) -> Result<EspFilesystemType> {
+    let mut boot_sector = [0u8; 512];
+    std::fs::File::open(device)
+        .and_then(|mut f| f.read_exact(&mut boot_sector))
+        .with_context(|| format!("Failed to read boot sector of {}", device))?;
 
-    Ok(part.to_string())
+    // FAT32 keeps its type label at offset 82, FAT12/16 at offset 54
+    if &boot_sector[82..87] == b"FAT32" {
+        Ok(EspFilesystemType::Fat32)
+    } else if &boot_sector[54..59] == b"FAT16" {
+        Ok(EspFilesystemType::Fat16)
+    } else {
+        Ok(EspFilesystemType::Unknown("vfat".to_string()))
+    }
+}
+
+/// How the running system was booted, which decides whether NVRAM can be changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootMode {
+    /// Booted through UEFI with writable EFI variables
+    Uefi,
+    /// UEFI firmware, but EFI variables are missing or read-only (CSM, restricted firmware)
+    UefiCsm,
+    /// No UEFI runtime services at all
+    LegacyBios,
+}
+
+pub fn detect_boot_mode() -> BootMode {
+    use std::os::unix::ffi::OsStrExt;
+
+    if !Path::new("/sys/firmware/efi").is_dir() {
+        return BootMode::LegacyBios;
+    }
+
+    let efivars = Path::new("/sys/firmware/efi/efivars");
+    let has_variables = std::fs::read_dir(efivars).is_ok_and(|mut it| it.next().is_some());
+    let writable = std::ffi::CString::new(efivars.as_os_str().as_bytes())
+        .is_ok_and(|path| unsafe { libc::access(path.as_ptr(), libc::W_OK) } == 0);
+
+    if has_variables && writable {
+        BootMode::Uefi
+    } else {
+        BootMode::UefiCsm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(json: &str) -> (String, String) {
+        parse_lsblk(json).unwrap()
+    }
+
+    #[test]
+    fn lsblk_nvme() {
+        let json = r#"{
+   "blockdevices": [
+      {
+         "name": "nvme0n1p1",
+         "pkname": "nvme0n1",
+         "partn": 1,
+         "path": "/dev/nvme0n1p1"
+      }
+   ]
+}"#;
+        assert_eq!(parsed(json), ("/dev/nvme0n1".into(), "1".into()));
+    }
+
+    #[test]
+    fn lsblk_sata() {
+        let json = r#"{
+   "blockdevices": [
+      {"name":"sda2", "pkname":"sda", "partn":2, "path":"/dev/sda2"}
+   ]
+}"#;
+        assert_eq!(parsed(json), ("/dev/sda".into(), "2".into()));
+    }
+
+    #[test]
+    fn lsblk_mmc_before_util_linux_2_38() {
+        let json = r#"{
+   "blockdevices": [
+      {"name":"mmcblk0p1", "pkname":"mmcblk0", "partn":"1", "path":"/dev/mmcblk0p1"}
+   ]
+}"#;
+        assert_eq!(parsed(json), ("/dev/mmcblk0".into(), "1".into()));
+    }
+
+    #[test]
+    fn lsblk_md() {
+        let json = r#"{
+   "blockdevices": [
+      {
+         "name": "md127p1",
+         "pkname": "md127",
+         "partn": 1,
+         "path": "/dev/md127p1"
+      }
+   ]
+}"#;
+        assert_eq!(parsed(json), ("/dev/md127".into(), "1".into()));
+    }
+
+    #[test]
+    fn lsblk_refuses_whole_disks() {
+        let json = r#"{
+   "blockdevices": [
+      {"name":"sda", "pkname":null, "partn":null, "path":"/dev/sda"}
+   ]
+}"#;
+        assert!(parse_lsblk(json).is_err());
+        let json = r#"{
+   "blockdevices": [
+      {"name":"dm-3", "pkname":"nvme0n1p3", "partn":null, "path":"/dev/dm-3"}
+   ]
+}"#;
+        assert!(parse_lsblk(json).is_err());
+        assert!(parse_lsblk(r#"{"blockdevices": []}"#).is_err());
+        assert!(parse_lsblk("lsblk: /dev/sdz: not a block device").is_err());
+    }
+
+    #[test]
+    fn device_names_split_into_disk_and_partition() {
+        for (name, disk, partition) in [
+            ("sda1", "/dev/sda", "1"),
+            ("vdb12", "/dev/vdb", "12"),
+            ("nvme0n1p1", "/dev/nvme0n1", "1"),
+            ("nvme1n2p15", "/dev/nvme1n2", "15"),
+            ("mmcblk0p1", "/dev/mmcblk0", "1"),
+            ("md127p1", "/dev/md127", "1"),
+            ("loop0p3", "/dev/loop0", "3"),
+        ] {
+            assert_eq!(
+                split_device_name(name).unwrap(),
+                Some((disk.to_string(), partition.to_string())),
+                "{}",
+                name
+            );
+        }
+        for name in ["sda", "nvme0n1", "mmcblk0", "dm-3"] {
+            assert_eq!(split_device_name(name).unwrap(), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn unparsable_partition_names_are_quoted_in_the_error() {
+        let scratch = crate::fs::tests::ScratchDir::new();
+        let device = scratch.path().join("dm-3");
+        std::fs::write(&device, "").unwrap();
+        let device = device.to_str().unwrap();
+
+        let error = format!("{:#}", split_partition_name(device).unwrap_err());
+        assert!(error.contains(device), "{}", error);
+        assert!(error.contains("(dm-3)"), "{}", error);
+    }
+
+    const ESP_PARTUUID: &str = "3e1a9f52-7d4c-4c1e-8f0b-2a6d5c9e4b10";
+
+    /// `efibootmgr -v` on a machine with our entry and a few firmware ones
+    const EFIBOOTMGR_V: &str = "BootCurrent: 0001
+Timeout: 1 seconds
+BootOrder: 0001,0000,0002,0003
+Boot0000* UiApp\tFvVol(7cb8bdc9-f8eb-4f34-aaea-3ee4af6516a1)/FvFile(462caa21-7614-4503-836e-8ab6f4662331)
+Boot0001* rEFInd\tHD(1,GPT,3E1A9F52-7D4C-4C1E-8F0B-2A6D5C9E4B10,0x800,0x100000)/File(\\EFI\\REFIND\\BOOTX64.EFI)
+Boot0002* rEFInd\tHD(1,GPT,3e1a9f52-7d4c-4c1e-8f0b-2a6d5c9e4b10,0x800,0x100000)/File(\\EFI\\refind\\refind_x64.efi)
+Boot0003  rEFInd (disk2)\tHD(2,GPT,9b0c1d2e-3f40-4152-8364-758697a8b9ca,0x800,0x100000)/\\efi\\refind\\BOOTX64.EFI
+Boot0004* UEFI: PXE IPv4 Intel(R) Ethernet\tPciRoot(0x0)/Pci(0x1f,0x6)/MAC(54bf64000000,0)/IPv4(0.0.0.0:0<->0.0.0.0:0,0,0)..BO
+";
+
+    fn target(partition: &str, partuuid: Option<&str>) -> BootEntryTarget {
+        BootEntryTarget {
+            label: "rEFInd".into(),
+            device: PathBuf::from("/dev/nvme0n1p1"),
+            disk: "/dev/nvme0n1".into(),
+            partition: partition.into(),
+            partuuid: partuuid.map(str::to_string),
+        }
+    }
+
+    fn entry(id: &str) -> BootEntry {
+        boot_entries(EFIBOOTMGR_V)
+            .unwrap()
+            .into_iter()
+            .find(|entry| entry.id == id)
+            .unwrap()
+    }
+
+    fn loader() -> String {
+        efi_arch("x86_64").unwrap().loader()
+    }
+
+    #[test]
+    fn efibootmgr_entries_are_split_at_the_tab() {
+        let entries = boot_entries(EFIBOOTMGR_V).unwrap();
+        let labels: Vec<(&str, &str)> = entries
+            .iter()
+            .map(|entry| (entry.id.as_str(), entry.label.as_str()))
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                ("0000", "UiApp"),
+                ("0001", "rEFInd"),
+                ("0002", "rEFInd"),
+                ("0003", "rEFInd (disk2)"),
+                ("0004", "UEFI: PXE IPv4 Intel(R) Ethernet"),
+            ]
+        );
+        assert!(entry("0004").device_path.starts_with("PciRoot(0x0)/"));
+    }
+
+    #[test]
+    fn matching_entry_has_no_differences() {
+        let target = target("1", Some(ESP_PARTUUID));
+        let entry = entry("0001");
+        assert_eq!(entry.differences(&target, &loader()), Vec::<String>::new());
+        assert!(entry.points_at(&target, &loader()));
+    }
+
+    #[test]
+    fn differences_name_each_changed_field() {
+        let loader = loader();
+        assert_eq!(
+            entry("0002").differences(&target("1", Some(ESP_PARTUUID)), &loader),
+            [format!(
+                "loader \\EFI\\refind\\refind_x64.efi -> {}",
+                loader
+            )]
+        );
+        assert_eq!(
+            entry("0001").differences(
+                &target("2", Some("9b0c1d2e-3f40-4152-8364-758697a8b9ca")),
+                &loader
+            ),
+            [
+                "partition 1 -> 2".to_string(),
+                "PARTUUID 3e1a9f52-7d4c-4c1e-8f0b-2a6d5c9e4b10 -> \
+                 9b0c1d2e-3f40-4152-8364-758697a8b9ca"
+                    .to_string(),
+            ]
+        );
+        assert_eq!(
+            entry("0000").differences(&target("1", Some(ESP_PARTUUID)), &loader),
+            [
+                "partition none -> 1".to_string(),
+                format!("PARTUUID none -> {}", ESP_PARTUUID),
+                format!("loader none -> {}", loader),
+            ]
+        );
+    }
+
+    #[test]
+    fn bare_loader_paths_of_newer_efibootmgr_compare_equal() {
+        let target = target("2", Some("9b0c1d2e-3f40-4152-8364-758697a8b9ca"));
+        assert!(entry("0003").differences(&target, &loader()).is_empty());
+    }
+
+    #[test]
+    fn entries_without_our_partuuid_are_never_ours() {
+        assert!(!entry("0001").points_at(&target("1", None), &loader()));
+        assert_eq!(
+            entry("0001").differences(&target("1", None), &loader()),
+            Vec::<String>::new(),
+            "the legacy lookup compares partition numbers only"
+        );
+    }
+
+    #[test]
+    fn managed_labels() {
+        assert!(is_managed_label("rEFInd", "rEFInd"));
+        assert!(is_managed_label("rEFInd (disk2)", "rEFInd"));
+        assert!(!is_managed_label("rEFInd (disk)", "rEFInd"));
+        assert!(!is_managed_label("rEFInd (diskA)", "rEFInd"));
+        assert!(!is_managed_label("rEFInd backup", "rEFInd"));
+        assert!(!is_managed_label("refind", "rEFInd"));
+    }
+
+    #[test]
+    fn boot_order_is_stable_when_nothing_changed() {
+        let entries = boot_entries(EFIBOOTMGR_V).unwrap();
+        let order: Vec<String> = ["0001", "0000", "0002", "0003"].map(String::from).into();
+        let ours = vec!["0001".to_string()];
+        for position in [BootOrderPosition::First, BootOrderPosition::Keep] {
+            assert_eq!(
+                arrange_boot_order(&order, &order, &entries, &ours, position),
+                order
+            );
+        }
+        assert_eq!(
+            arrange_boot_order(&order, &order, &entries, &ours, BootOrderPosition::Last),
+            ["0000", "0002", "0003", "0001"]
+        );
+    }
+
+    fn node(name: &str, args: &[&str]) -> DevicePathNode {
+        DevicePathNode::Node {
+            name: name.into(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn device_paths_from_several_firmwares() {
+        let hd = node("HD", &["1", "GPT", ESP_PARTUUID, "0x800", "0x100000"]);
+        let file = node("File", &["\\EFI\\refind\\refind_x64.efi"]);
+
+        // OVMF, efibootmgr 17
+        assert_eq!(
+            parse_device_path(
+                "HD(1,GPT,3e1a9f52-7d4c-4c1e-8f0b-2a6d5c9e4b10,0x800,0x100000)\
+                 /File(\\EFI\\refind\\refind_x64.efi)"
+            ),
+            [hd.clone(), file.clone()]
+        );
+        // Lenovo ThinkPad: optional data glued to the path
+        assert_eq!(
+            parse_device_path(
+                "HD(1,GPT,3e1a9f52-7d4c-4c1e-8f0b-2a6d5c9e4b10,0x800,0x100000)\
+                 /File(\\EFI\\refind\\refind_x64.efi)RC"
+            ),
+            [hd.clone(), file.clone()]
+        );
+        // HP: the full path from the PCI root
+        assert_eq!(
+            parse_device_path(
+                "PciRoot(0x0)/Pci(0x1d,0x0)/Pci(0x0,0x0)/NVMe(0x1,00-25-38-B5-71-B0-8E-2A)\
+                 /HD(1,GPT,3e1a9f52-7d4c-4c1e-8f0b-2a6d5c9e4b10,0x800,0x100000)\
+                 /File(\\EFI\\refind\\refind_x64.efi)"
+            ),
+            [
+                node("PciRoot", &["0x0"]),
+                node("Pci", &["0x1d", "0x0"]),
+                node("Pci", &["0x0", "0x0"]),
+                node("NVMe", &["0x1", "00-25-38-B5-71-B0-8E-2A"]),
+                hd.clone(),
+                file.clone(),
+            ]
+        );
+        // efibootmgr 18 prints the file path bare, and optional data after a space
+        assert_eq!(
+            parse_device_path(
+                "HD(1,GPT,3e1a9f52-7d4c-4c1e-8f0b-2a6d5c9e4b10,0x800,0x100000)\
+                 /\\EFI\\refind\\refind_x64.efi dp: 04 01 2a 00"
+            ),
+            [
+                hd.clone(),
+                DevicePathNode::Path("\\EFI\\refind\\refind_x64.efi".into())
+            ]
+        );
+        // Dell network boot, with firmware data after the last node
+        assert_eq!(
+            parse_device_path("PciRoot(0x0)/Pci(0x1f,0x6)/MAC(54bf64000000,0)..BO").len(),
+            3
+        );
+        assert_eq!(parse_device_path(""), []);
+        assert_eq!(parse_device_path("not a path"), []);
+    }
+
+    #[test]
+    fn nested_parentheses_stay_in_one_node() {
+        assert_eq!(
+            parse_device_path("VenMsg(Uart(115200))/File(\\x.efi)"),
+            [
+                node("VenMsg", &["Uart(115200)"]),
+                node("File", &["\\x.efi"]),
+            ]
+        );
+        assert_eq!(parse_device_path("Pci(0x1f"), []);
+    }
+
+    #[test]
+    fn only_gpt_hard_drives_identify_a_partition() {
+        let mbr = BootEntry {
+            id: "0005".into(),
+            label: "rEFInd".into(),
+            device_path: "HD(1,MBR,0x1b2c3d4e,0x800,0x100000)/File(\\EFI\\refind\\BOOTX64.EFI)"
+                .into(),
+        };
+        assert_eq!(mbr.hard_drive(), None);
+        assert_eq!(mbr.loader().as_deref(), Some("\\EFI\\refind\\BOOTX64.EFI"));
+        assert_eq!(
+            entry("0001").hard_drive(),
+            Some(("1".to_string(), ESP_PARTUUID.to_string()))
+        );
+    }
+
+    #[test]
+    fn architectures_come_from_the_table() {
+        for (system, boot_file, refind_file) in [
+            ("x86_64-linux", "BOOTX64.EFI", "refind_x64.efi"),
+            ("i686-linux", "BOOTIA32.EFI", "refind_ia32.efi"),
+            ("aarch64-linux", "BOOTAA64.EFI", "refind_aa64.efi"),
+            ("riscv64-linux", "BOOTRISCV64.EFI", "refind_riscv64.efi"),
+            ("riscv64", "BOOTRISCV64.EFI", "refind_riscv64.efi"),
+        ] {
+            let arch = efi_arch(system).unwrap();
+            assert_eq!(
+                (arch.boot_file, arch.refind_file),
+                (boot_file, refind_file),
+                "{}",
+                system
+            );
+            assert_eq!(arch.install_path(), Path::new("efi/refind").join(boot_file));
+            assert_eq!(arch.loader(), format!("\\efi\\refind\\{}", boot_file));
+        }
+    }
+
+    #[test]
+    fn unknown_architectures_list_the_supported_ones() {
+        let error = efi_arch("mips64el-linux").unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Unsupported architecture: mips64el-linux \
+             (supported: x86_64, i686, aarch64, riscv64)"
+        );
+        assert!(efi_arch("").is_err());
+    }
+
+    /// A `/dev` and `/sys/class/block` under `scratch`, `partitions` given as
+    /// (disk, partition name, partition number)
+    fn fake_sysfs(scratch: &crate::fs::tests::ScratchDir, partitions: &[(&str, &str, &str)]) {
+        let block = scratch.path().join("sys/class/block");
+        std::fs::create_dir_all(&block).unwrap();
+        std::fs::create_dir_all(scratch.path().join("dev/disk/by-partuuid")).unwrap();
+        for (disk, name, number) in partitions {
+            let device = scratch
+                .path()
+                .join("sys/devices/pci0000:00/0000:00:1d.0")
+                .join(disk);
+            std::fs::create_dir_all(device.join(name)).unwrap();
+            std::fs::write(device.join(name).join("partition"), format!("{}\n", number)).unwrap();
+            std::os::unix::fs::symlink(
+                format!("../../devices/pci0000:00/0000:00:1d.0/{}/{}", disk, name),
+                block.join(name),
+            )
+            .unwrap();
+            std::os::unix::fs::symlink(
+                format!("../../devices/pci0000:00/0000:00:1d.0/{}", disk),
+                block.join(disk),
+            )
+            .unwrap();
+            std::fs::write(scratch.path().join("dev").join(name), "").unwrap();
+        }
+    }
+
+    #[test]
+    fn sysfs_names_the_disk_and_partition() {
+        let scratch = crate::fs::tests::ScratchDir::new();
+        let partitions = [
+            ("sda", "sda1", "1"),
+            ("nvme0n1", "nvme0n1p2", "2"),
+            ("mmcblk0", "mmcblk0p1", "1"),
+            ("vda", "vda15", "15"),
+            ("xvda", "xvda1", "1"),
+            ("md127", "md127p1", "1"),
+        ];
+        fake_sysfs(&scratch, &partitions);
+        let block = scratch.path().join("sys/class/block");
+
+        for (disk, name, number) in partitions {
+            let device = scratch.path().join("dev").join(name);
+            assert_eq!(
+                sysfs_disk_and_partition(device.to_str().unwrap(), &block).unwrap(),
+                (format!("/dev/{}", disk), number.to_string()),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn sysfs_follows_stable_device_links() {
+        let scratch = crate::fs::tests::ScratchDir::new();
+        fake_sysfs(&scratch, &[("nvme0n1", "nvme0n1p1", "1")]);
+        let link = scratch
+            .path()
+            .join("dev/disk/by-partuuid")
+            .join(ESP_PARTUUID);
+        std::os::unix::fs::symlink("../../nvme0n1p1", &link).unwrap();
+
+        assert_eq!(
+            sysfs_disk_and_partition(
+                link.to_str().unwrap(),
+                &scratch.path().join("sys/class/block")
+            )
+            .unwrap(),
+            ("/dev/nvme0n1".to_string(), "1".to_string())
+        );
+    }
+
+    #[test]
+    fn sysfs_refuses_disks_and_unknown_devices() {
+        let scratch = crate::fs::tests::ScratchDir::new();
+        fake_sysfs(&scratch, &[("sda", "sda1", "1")]);
+        let block = scratch.path().join("sys/class/block");
+        std::fs::write(scratch.path().join("dev/sda"), "").unwrap();
+        std::fs::write(scratch.path().join("dev/sdb1"), "").unwrap();
+
+        let whole_disk = scratch.path().join("dev/sda");
+        let error = sysfs_disk_and_partition(whole_disk.to_str().unwrap(), &block).unwrap_err();
+        assert!(
+            error.to_string().ends_with("is not a partition"),
+            "{}",
+            error
+        );
+
+        let unknown = scratch.path().join("dev/sdb1");
+        let error = sysfs_disk_and_partition(unknown.to_str().unwrap(), &block).unwrap_err();
+        assert!(error.to_string().contains("is not in"), "{}", error);
+    }
 }
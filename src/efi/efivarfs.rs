@@ -0,0 +1,562 @@
+//! Boot#### and BootOrder variables read and written through efivarfs, without
+//! efibootmgr.
+
+use anyhow::{Context, Result};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::{BootEntry, BootEntryTarget, Nvram, SYSFS_BLOCK, validate_boot_order};
+
+/// Where the kernel exposes EFI variables
+pub(super) const EFIVARS: &str = "/sys/firmware/efi/efivars";
+
+/// Vendor GUID of the Boot#### and BootOrder variables
+const GLOBAL_VARIABLE: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+/// NON_VOLATILE | BOOTSERVICE_ACCESS | RUNTIME_ACCESS, what firmware expects on boot
+/// variables
+const VARIABLE_ATTRIBUTES: u32 = 0x7;
+
+/// LOAD_OPTION_ACTIVE, without which firmware skips the entry
+const LOAD_OPTION_ACTIVE: u32 = 0x1;
+
+const MEDIA_DEVICE_PATH: u8 = 0x04;
+const MEDIA_HARD_DRIVE: u8 = 0x01;
+const MEDIA_FILE_PATH: u8 = 0x04;
+const END_DEVICE_PATH: u8 = 0x7f;
+const END_ENTIRE_DEVICE_PATH: u8 = 0xff;
+
+/// ext2-style inode flag efivarfs sets on every variable so a stray `rm` can't brick
+/// the firmware
+const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+/// The variables in an efivarfs mount, which reads freely but with `print_only` only
+/// reports the writes it would make
+#[derive(Debug, Clone)]
+pub(super) struct Efivarfs {
+    pub(super) dir: PathBuf,
+    pub(super) print_only: bool,
+}
+
+impl Efivarfs {
+    fn variable_path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}-{}", name, GLOBAL_VARIABLE))
+    }
+
+    /// A variable's data, without the attributes efivarfs puts in front. `None` when it
+    /// doesn't exist.
+    fn read_variable(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.variable_path(name);
+        let content = match std::fs::read(&path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => {
+                return Err(error).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+        if content.len() < 4 {
+            anyhow::bail!("{} is too short to be an EFI variable", path.display());
+        }
+        Ok(Some(content[4..].to_vec()))
+    }
+
+    /// Replace a variable's data. efivarfs takes a variable in a single write() of the
+    /// attributes followed by the data.
+    fn write_variable(&self, name: &str, data: &[u8]) -> Result<()> {
+        let path = self.variable_path(name);
+        if path.exists() {
+            make_mutable(&path)?;
+        }
+        let mut content = VARIABLE_ATTRIBUTES.to_le_bytes().to_vec();
+        content.extend_from_slice(data);
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&content))
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+}
+
+impl Nvram for Efivarfs {
+    fn entries(&self) -> Result<Vec<BootEntry>> {
+        let suffix = format!("-{}", GLOBAL_VARIABLE);
+        let dir = std::fs::read_dir(&self.dir)
+            .with_context(|| format!("Failed to list {}", self.dir.display()))?;
+
+        let mut entries = Vec::new();
+        for variable in dir {
+            let name = variable?.file_name().to_string_lossy().to_string();
+            let Some(id) = name
+                .strip_suffix(&suffix)
+                .and_then(|name| name.strip_prefix("Boot"))
+                .filter(|id| id.len() == 4 && id.bytes().all(|b| b.is_ascii_hexdigit()))
+            else {
+                continue;
+            };
+            let id = id.to_uppercase();
+            let Some(data) = self.read_variable(&format!("Boot{}", id))? else {
+                continue;
+            };
+            match decode_load_option(&data) {
+                Ok((label, device_path)) => entries.push(BootEntry {
+                    id,
+                    label,
+                    device_path,
+                }),
+                Err(error) => crate::warn!("skipping unreadable Boot{}: {:#}", id, error),
+            }
+        }
+        entries.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(entries)
+    }
+
+    fn boot_order(&self) -> Result<Vec<String>> {
+        let Some(data) = self.read_variable("BootOrder")? else {
+            return Ok(Vec::new());
+        };
+        Ok(data
+            .chunks_exact(2)
+            .map(|id| format!("{:04X}", u16::from_le_bytes([id[0], id[1]])))
+            .collect())
+    }
+
+    fn delete(&self, entry_id: &str) -> Result<()> {
+        let name = format!("Boot{}", entry_id.to_uppercase());
+        if self.print_only {
+            crate::info!("would delete {}", name);
+            return Ok(());
+        }
+
+        let path = self.variable_path(&name);
+        make_mutable(&path)
+            .and_then(|()| {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove {}", path.display()))
+            })
+            .with_context(|| format!("Failed to delete boot entry {}", entry_id))
+    }
+
+    fn create(
+        &self,
+        existing_id: Option<&str>,
+        target: &BootEntryTarget,
+        loader: &str,
+    ) -> Result<()> {
+        let id = match existing_id {
+            Some(id) => id.to_uppercase(),
+            None => {
+                let taken: Vec<String> = self.entries()?.into_iter().map(|e| e.id).collect();
+                (0..=u16::MAX)
+                    .map(|id| format!("{:04X}", id))
+                    .find(|id| !taken.contains(id))
+                    .context("Every Boot#### variable is taken")?
+            }
+        };
+
+        let device_path = target_device_path(target, loader)
+            .with_context(|| format!("Failed to build the device path of {}", target.label))?;
+        let data = encode_load_option(&target.label, &device_path);
+        if self.print_only {
+            let text = decode_device_path(&device_path)?;
+            crate::info!("would write Boot{} ({}): {}", id, target.label, text);
+            return Ok(());
+        }
+        self.write_variable(&format!("Boot{}", id), &data)
+            .with_context(|| format!("Failed to create boot entry {}", target.label))
+    }
+
+    fn set_boot_order(&self, order: &[String]) -> Result<()> {
+        validate_boot_order(order, &self.entries()?)?;
+
+        let mut data = Vec::with_capacity(order.len() * 2);
+        for id in order {
+            let id = u16::from_str_radix(id, 16)
+                .with_context(|| format!("Invalid boot entry ID {}", id))?;
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+        if self.print_only {
+            crate::info!("would write BootOrder: {}", order.join(","));
+            return Ok(());
+        }
+        self.write_variable("BootOrder", &data)
+            .context("Failed to set the boot order")
+    }
+}
+
+/// Clear the immutable flag efivarfs sets on variables, so they can be rewritten or
+/// removed
+#[cfg(target_os = "linux")]
+fn make_mutable(path: &Path) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to read the flags of {:?}", path));
+    }
+    if flags & FS_IMMUTABLE_FL == 0 {
+        return Ok(());
+    }
+    flags &= !FS_IMMUTABLE_FL;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to make {:?} writable", path));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn make_mutable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// The device path firmware follows to boot `loader` from `target`'s GPT partition:
+/// `HD(...)/File(...)`, like efibootmgr builds with `-d -p -l`
+fn target_device_path(target: &BootEntryTarget, loader: &str) -> Result<Vec<u8>> {
+    let partuuid = match target.partuuid {
+        Some(ref partuuid) => partuuid.clone(),
+        None => super::device_partition_uuid(&target.device)?.with_context(|| {
+            format!(
+                "{} has no PARTUUID; the efivarfs backend only handles GPT partitions, \
+                 set nvramBackend to efibootmgr",
+                target.device.display()
+            )
+        })?,
+    };
+
+    let name = target
+        .device
+        .file_name()
+        .context("Invalid partition path")?
+        .to_string_lossy()
+        .to_string();
+    let sysfs = Path::new(SYSFS_BLOCK).join(&name);
+    let read_number = |path: PathBuf| -> Result<u64> {
+        std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid number in {}", path.display()))
+    };
+    // sysfs counts 512-byte sectors, the device path counts the disk's logical blocks
+    let start = read_number(sysfs.join("start"))?;
+    let size = read_number(sysfs.join("size"))?;
+    let disk = Path::new(&target.disk)
+        .file_name()
+        .context("Invalid disk path")?
+        .to_string_lossy()
+        .to_string();
+    let block_size = read_number(
+        Path::new(SYSFS_BLOCK)
+            .join(disk)
+            .join("queue/logical_block_size"),
+    )
+    .unwrap_or(512)
+    .max(512);
+    let sectors_per_block = block_size / 512;
+    let partition: u32 = target
+        .partition
+        .parse()
+        .with_context(|| format!("Invalid partition number {}", target.partition))?;
+
+    let mut path = hard_drive_node(
+        partition,
+        start / sectors_per_block,
+        size / sectors_per_block,
+        &parse_guid(&partuuid)?,
+    );
+    path.extend(file_path_node(loader));
+    path.extend([END_DEVICE_PATH, END_ENTIRE_DEVICE_PATH, 4, 0]);
+    Ok(path)
+}
+
+/// A GPT hard drive media node (UEFI spec 10.3.5.1)
+fn hard_drive_node(partition: u32, start: u64, size: u64, guid: &[u8; 16]) -> Vec<u8> {
+    let mut node = vec![MEDIA_DEVICE_PATH, MEDIA_HARD_DRIVE];
+    node.extend_from_slice(&42u16.to_le_bytes());
+    node.extend_from_slice(&partition.to_le_bytes());
+    node.extend_from_slice(&start.to_le_bytes());
+    node.extend_from_slice(&size.to_le_bytes());
+    node.extend_from_slice(guid);
+    // GPT partition format, GUID signature
+    node.extend([0x02, 0x02]);
+    node
+}
+
+/// A file path media node, `path` in UTF-16 with a terminating NUL
+fn file_path_node(path: &str) -> Vec<u8> {
+    let name = utf16_nul(path);
+    let mut node = vec![MEDIA_DEVICE_PATH, MEDIA_FILE_PATH];
+    node.extend_from_slice(&((name.len() + 4) as u16).to_le_bytes());
+    node.extend(name);
+    node
+}
+
+fn utf16_nul(text: &str) -> Vec<u8> {
+    text.encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+/// An EFI_LOAD_OPTION (UEFI spec 3.1.3) for an active entry, without optional data
+fn encode_load_option(description: &str, device_path: &[u8]) -> Vec<u8> {
+    let mut option = LOAD_OPTION_ACTIVE.to_le_bytes().to_vec();
+    option.extend_from_slice(&(device_path.len() as u16).to_le_bytes());
+    option.extend(utf16_nul(description));
+    option.extend_from_slice(device_path);
+    option
+}
+
+/// Description and device path of an EFI_LOAD_OPTION, the path as text like efibootmgr
+/// prints it so entries from both backends compare the same way
+fn decode_load_option(data: &[u8]) -> Result<(String, String)> {
+    if data.len() < 6 {
+        anyhow::bail!("load option is too short");
+    }
+    let path_length = u16::from_le_bytes([data[4], data[5]]) as usize;
+
+    let mut description = Vec::new();
+    let mut rest = &data[6..];
+    loop {
+        let [low, high, tail @ ..] = rest else {
+            anyhow::bail!("load option description is not terminated");
+        };
+        rest = tail;
+        match u16::from_le_bytes([*low, *high]) {
+            0 => break,
+            unit => description.push(unit),
+        }
+    }
+    let device_path = rest
+        .get(..path_length)
+        .context("load option device path is truncated")?;
+
+    Ok((
+        String::from_utf16_lossy(&description),
+        decode_device_path(device_path)?,
+    ))
+}
+
+/// A binary device path as text: `HD(...)` and `File(...)` nodes spelled out, others as
+/// `Path(type,subtype)`
+fn decode_device_path(mut path: &[u8]) -> Result<String> {
+    let mut nodes = Vec::new();
+    while path.len() >= 4 {
+        let (kind, subtype) = (path[0], path[1]);
+        let length = u16::from_le_bytes([path[2], path[3]]) as usize;
+        if length < 4 || length > path.len() {
+            anyhow::bail!("device path node has invalid length {}", length);
+        }
+        let body = &path[4..length];
+        path = &path[length..];
+
+        match (kind, subtype) {
+            (END_DEVICE_PATH, END_ENTIRE_DEVICE_PATH) => break,
+            (MEDIA_DEVICE_PATH, MEDIA_HARD_DRIVE) if body.len() >= 38 => {
+                let number = u32::from_le_bytes(body[0..4].try_into()?);
+                let start = u64::from_le_bytes(body[4..12].try_into()?);
+                let size = u64::from_le_bytes(body[12..20].try_into()?);
+                let signature: [u8; 16] = body[20..36].try_into()?;
+                if body[37] == 0x02 {
+                    nodes.push(format!(
+                        "HD({},GPT,{},{:#x},{:#x})",
+                        number,
+                        format_guid(&signature),
+                        start,
+                        size
+                    ));
+                } else {
+                    let mbr = u32::from_le_bytes(signature[0..4].try_into()?);
+                    nodes.push(format!(
+                        "HD({},MBR,{:#010x},{:#x},{:#x})",
+                        number, mbr, start, size
+                    ));
+                }
+            }
+            (MEDIA_DEVICE_PATH, MEDIA_FILE_PATH) => {
+                let units: Vec<u16> = body
+                    .chunks_exact(2)
+                    .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                    .take_while(|&unit| unit != 0)
+                    .collect();
+                nodes.push(format!("File({})", String::from_utf16_lossy(&units)));
+            }
+            _ => nodes.push(format!("Path({},{})", kind, subtype)),
+        }
+    }
+    Ok(nodes.join("/"))
+}
+
+/// GUID bytes as stored in a device path: the first three fields little-endian, the
+/// rest in order
+fn parse_guid(text: &str) -> Result<[u8; 16]> {
+    let hex: String = text.chars().filter(|&c| c != '-').collect();
+    if hex.len() != 32 || text.len() != 36 {
+        anyhow::bail!("Invalid GUID {}", text);
+    }
+    let mut bytes = [0u8; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .with_context(|| format!("Invalid GUID {}", text))?;
+    }
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    Ok(bytes)
+}
+
+fn format_guid(bytes: &[u8; 16]) -> String {
+    let mut bytes = *bytes;
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::tests::ScratchDir;
+
+    const PARTUUID: &str = "5c1b9a0e-2f4d-4c8e-9b6a-1d2e3f4a5b6c";
+
+    /// The same entry as `efibootmgr -v` lists it
+    const EFIBOOTMGR: &str = "BootCurrent: 0003\n\
+        BootOrder: 0003,0000\n\
+        Boot0000* UEFI OS\tPciRoot(0x0)/Pci(0x1d,0x0)/NVMe(0x1,00-00-00-00-00-00-00-00)\n\
+        Boot0003* rEFInd\tHD(1,GPT,5c1b9a0e-2f4d-4c8e-9b6a-1d2e3f4a5b6c,0x800,0x100000)\
+        /File(\\EFI\\refind\\refind_x64.efi)\n";
+
+    fn refind_path() -> Vec<u8> {
+        let mut path = hard_drive_node(1, 0x800, 0x100000, &parse_guid(PARTUUID).unwrap());
+        path.extend(file_path_node("\\EFI\\refind\\refind_x64.efi"));
+        path.extend([END_DEVICE_PATH, END_ENTIRE_DEVICE_PATH, 4, 0]);
+        path
+    }
+
+    #[test]
+    fn guids_are_mixed_endian() {
+        let bytes = parse_guid(PARTUUID).unwrap();
+        assert_eq!(
+            bytes,
+            [
+                0x0e, 0x9a, 0x1b, 0x5c, 0x4d, 0x2f, 0x8e, 0x4c, 0x9b, 0x6a, 0x1d, 0x2e, 0x3f, 0x4a,
+                0x5b, 0x6c
+            ]
+        );
+        assert_eq!(format_guid(&bytes), PARTUUID);
+        assert_eq!(
+            format_guid(&parse_guid(&PARTUUID.to_uppercase()).unwrap()),
+            PARTUUID
+        );
+    }
+
+    #[test]
+    fn malformed_guids_are_refused() {
+        for guid in [
+            "",
+            "5c1b9a0e2f4d4c8e9b6a1d2e3f4a5b6c",
+            "5c1b9a0e-2f4d-4c8e-9b6a-1d2e3f4a5b6",
+            "zc1b9a0e-2f4d-4c8e-9b6a-1d2e3f4a5b6c",
+        ] {
+            assert!(parse_guid(guid).is_err(), "{:?}", guid);
+        }
+    }
+
+    #[test]
+    fn hard_drive_node_layout() {
+        let guid = parse_guid(PARTUUID).unwrap();
+        let node = hard_drive_node(2, 0x800, 0x100000, &guid);
+        assert_eq!(node.len(), 42);
+        assert_eq!(&node[..4], &[MEDIA_DEVICE_PATH, MEDIA_HARD_DRIVE, 42, 0]);
+        assert_eq!(&node[4..8], &2u32.to_le_bytes());
+        assert_eq!(&node[8..16], &0x800u64.to_le_bytes());
+        assert_eq!(&node[16..24], &0x100000u64.to_le_bytes());
+        assert_eq!(&node[24..40], &guid);
+        assert_eq!(&node[40..], &[0x02, 0x02]);
+    }
+
+    #[test]
+    fn load_option_round_trips() {
+        let data = encode_load_option("rEFInd", &refind_path());
+        assert_eq!(&data[..4], &LOAD_OPTION_ACTIVE.to_le_bytes());
+        assert_eq!(&data[4..6], &(refind_path().len() as u16).to_le_bytes());
+
+        let (label, device_path) = decode_load_option(&data).unwrap();
+        assert_eq!(label, "rEFInd");
+        assert_eq!(
+            device_path,
+            format!(
+                "HD(1,GPT,{},0x800,0x100000)/File(\\EFI\\refind\\refind_x64.efi)",
+                PARTUUID
+            )
+        );
+    }
+
+    #[test]
+    fn decoded_entries_match_efibootmgr() {
+        let scratch = ScratchDir::new();
+        let efivarfs = Efivarfs {
+            dir: scratch.path().to_path_buf(),
+            print_only: false,
+        };
+        efivarfs
+            .write_variable("Boot0003", &encode_load_option("rEFInd", &refind_path()))
+            .unwrap();
+        efivarfs
+            .write_variable("BootOrder", &[0x03, 0x00, 0x00, 0x00])
+            .unwrap();
+
+        let listed = crate::efi::boot_entries(EFIBOOTMGR).unwrap();
+        let refind = listed.iter().find(|entry| entry.id == "0003").unwrap();
+        let entries = efivarfs.entries().unwrap();
+        assert_eq!(entries, vec![refind.clone()]);
+        assert_eq!(
+            entries[0].hard_drive(),
+            Some(("1".to_string(), PARTUUID.to_string()))
+        );
+        assert_eq!(
+            entries[0].loader().as_deref(),
+            Some("\\EFI\\refind\\refind_x64.efi")
+        );
+        assert_eq!(efivarfs.boot_order().unwrap(), vec!["0003", "0000"]);
+    }
+
+    #[test]
+    fn truncated_load_options_are_refused() {
+        let data = encode_load_option("rEFInd", &refind_path());
+        assert!(decode_load_option(&data[..4]).is_err());
+        assert!(
+            decode_load_option(&data[..10]).is_err(),
+            "unterminated label"
+        );
+        assert!(
+            decode_load_option(&data[..data.len() - 1]).is_err(),
+            "truncated device path"
+        );
+    }
+
+    #[test]
+    fn unknown_nodes_are_named_by_type() {
+        let mut path = vec![0x02, 0x01, 12, 0, 0xd0, 0x41, 0x03, 0x0a, 0, 0, 0, 0];
+        path.extend(file_path_node("\\shim.efi"));
+        path.extend([END_DEVICE_PATH, END_ENTIRE_DEVICE_PATH, 4, 0]);
+        assert_eq!(
+            decode_device_path(&path).unwrap(),
+            "Path(2,1)/File(\\shim.efi)"
+        );
+        assert!(decode_device_path(&[0x04, 0x04, 2, 0]).is_err());
+    }
+}
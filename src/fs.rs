@@ -1,95 +1,1667 @@
 use anyhow::{Context, Result};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
 use walkdir::WalkDir;
 
+/// Attempts per copy before a transient error is given up on
+pub const DEFAULT_COPY_RETRIES: u32 = 3;
+
+/// How one run copies files onto the ESP, built from its [`crate::InstallOptions`]
+#[derive(Debug, Clone)]
+pub struct CopySettings {
+    /// Attempts per copy before a transient error is given up on, at least one
+    pub retries: u32,
+    /// Bytes moved per read/write
+    pub chunk_size: usize,
+    /// Set to make copies in progress stop at their next chunk, e.g. from a signal
+    /// handler. Cancelled copies fail and leave only a temp file behind.
+    pub cancel: Arc<AtomicBool>,
+    /// Give every file written the mtime Nix gives store paths (1s after the epoch), so
+    /// identical ESPs are identical down to their timestamps
+    pub reproducible: bool,
+}
+
+impl Default for CopySettings {
+    fn default() -> Self {
+        Self {
+            retries: DEFAULT_COPY_RETRIES,
+            chunk_size: DEFAULT_COPY_CHUNK_SIZE,
+            cancel: Arc::default(),
+            reproducible: false,
+        }
+    }
+}
+
+/// Temp files older than this are left over from a crashed run rather than a concurrent one
+pub const STALE_TEMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// `.<name>.<pid>.<random>.tmp` next to `dest`, unique per process and call
+fn temp_path_for(dest: &Path) -> PathBuf {
+    static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos());
+    let random = xxhash_rust::xxh3::xxh3_64_with_seed(
+        &COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes(),
+        u64::from(nanos),
+    );
+
+    let name = dest
+        .file_name()
+        .map_or_else(String::new, |n| n.to_string_lossy().to_string());
+    dest.with_file_name(format!(
+        ".{}.{}.{:08x}.tmp",
+        name,
+        std::process::id(),
+        random as u32
+    ))
+}
+
+/// Whether `path` is named like a temp file from [`temp_path_for`]
+pub fn is_temp_file(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    let Some(rest) = name.strip_prefix('.').and_then(|n| n.strip_suffix(".tmp")) else {
+        return false;
+    };
+    let mut fields = rest.rsplitn(3, '.');
+    let (Some(random), Some(pid), Some(_)) = (fields.next(), fields.next(), fields.next()) else {
+        return false;
+    };
+    random.len() == 8
+        && random.bytes().all(|b| b.is_ascii_hexdigit())
+        && !pid.is_empty()
+        && pid.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Remove temp files under `dir` last modified more than `older_than` ago, left behind
+/// by runs that crashed mid-copy. Returns how many were removed.
+pub fn sweep_temp_files(
+    filesystem: &dyn Filesystem,
+    dir: &Path,
+    older_than: Duration,
+) -> Result<usize> {
+    if !filesystem.exists(dir) {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+    for (path, is_dir) in filesystem.walk(dir)? {
+        if is_dir || !is_temp_file(&path) {
+            continue;
+        }
+        let stale = filesystem
+            .metadata(&path)
+            .ok()
+            .and_then(|m| m.modified().ok())
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age > older_than);
+        if stale {
+            filesystem
+                .remove_file(&path)
+                .with_context(|| format!("Failed to remove stale temp file: {:?}", path))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// What [`FileTracker::cleanup`] removed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CleanupSummary {
+    pub files_removed: usize,
+    pub bytes_freed: u64,
+}
+
+impl std::ops::AddAssign for CleanupSummary {
+    fn add_assign(&mut self, other: Self) {
+        self.files_removed += other.files_removed;
+        self.bytes_freed += other.bytes_freed;
+    }
+}
+
+/// Whether `error` comes from a filesystem running out of space (ENOSPC)
+pub fn is_out_of_space(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|io| io.kind() == std::io::ErrorKind::StorageFull)
+    })
+}
+
+/// `path` with ASCII letters lowercased, for comparing paths on FAT, where `EFI/refind`
+/// and `efi/refind` are the same directory. Only for comparisons; display and file
+/// operations keep the original.
+pub fn path_key(path: &Path) -> PathBuf {
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+    PathBuf::from(std::ffi::OsString::from_vec(
+        path.as_os_str().as_bytes().to_ascii_lowercase(),
+    ))
+}
+
+/// The filesystem operations the install pipeline performs on the ESP, so it can run
+/// against a synthetic tree instead of the real system.
+///
+/// Paths are always the absolute paths the real system would see. Syncs, device lookups
+/// and external tools like sbsign are handed [`Filesystem::real_path`] instead.
+pub trait Filesystem: std::fmt::Debug + Send + Sync {
+    fn read_link(&self, path: &Path) -> std::io::Result<PathBuf>;
+    fn read_to_string(&self, path: &Path) -> std::io::Result<String>;
+    /// Metadata of `path`, following symlinks
+    fn metadata(&self, path: &Path) -> std::io::Result<std::fs::Metadata>;
+    /// Metadata of `path` itself, not following a final symlink
+    fn symlink_metadata(&self, path: &Path) -> std::io::Result<std::fs::Metadata>;
+    /// `path` with every symlink resolved
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf>;
+    /// Open `path` for reading
+    fn open(&self, path: &Path) -> std::io::Result<std::fs::File>;
+    /// Entries directly in `dir`
+    fn read_dir(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>>;
+    /// Everything below `dir`, not `dir` itself, with whether each is a directory.
+    /// Symlinks are followed, as store trees are often built from them.
+    fn walk(&self, dir: &Path) -> Result<Vec<(PathBuf, bool)>>;
+    /// Copy `source` over `dest` atomically, creating parent directories
+    fn copy(&self, source: &Path, dest: &Path, settings: &CopySettings) -> Result<CopyStats>;
+    /// [`Filesystem::copy`], then read `dest` back and check it against `source`
+    fn copy_verified(
+        &self,
+        source: &Path,
+        dest: &Path,
+        settings: &CopySettings,
+    ) -> Result<CopyStats>;
+    /// Write `data` over `dest` atomically, creating parent directories
+    fn write(&self, dest: &Path, data: &[u8], settings: &CopySettings) -> Result<()>;
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()>;
+    fn hard_link(&self, original: &Path, link: &Path) -> std::io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()>;
+    fn remove_file(&self, path: &Path) -> std::io::Result<()>;
+    fn remove_dir(&self, path: &Path) -> std::io::Result<()>;
+    /// Bytes available to unprivileged users on the filesystem holding `path` (statvfs)
+    fn available_space(&self, path: &Path) -> Result<u64>;
+    /// Where `path` really is, for external tools that are handed it
+    fn real_path(&self, path: &Path) -> PathBuf;
+
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|m| m.is_file())
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|m| m.is_dir())
+    }
+}
+
+/// The system's own filesystem
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFs;
+
+impl Filesystem for RealFs {
+    fn read_link(&self, path: &Path) -> std::io::Result<PathBuf> {
+        std::fs::read_link(path)
+    }
+
+    fn read_to_string(&self, path: &Path) -> std::io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn metadata(&self, path: &Path) -> std::io::Result<std::fs::Metadata> {
+        std::fs::metadata(path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> std::io::Result<std::fs::Metadata> {
+        std::fs::symlink_metadata(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        std::fs::canonicalize(path)
+    }
+
+    fn open(&self, path: &Path) -> std::io::Result<std::fs::File> {
+        std::fs::File::open(path)
+    }
+
+    fn read_dir(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+        std::fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn walk(&self, dir: &Path) -> Result<Vec<(PathBuf, bool)>> {
+        let mut entries = Vec::new();
+        for entry in WalkDir::new(dir).min_depth(1).follow_links(true) {
+            let entry = entry?;
+            entries.push((entry.path().to_path_buf(), entry.file_type().is_dir()));
+        }
+        Ok(entries)
+    }
+
+    fn copy(&self, source: &Path, dest: &Path, settings: &CopySettings) -> Result<CopyStats> {
+        copy_atomic(source, dest, settings)
+    }
+
+    fn copy_verified(
+        &self,
+        source: &Path,
+        dest: &Path,
+        settings: &CopySettings,
+    ) -> Result<CopyStats> {
+        copy_verified(source, dest, settings)
+    }
+
+    fn write(&self, dest: &Path, data: &[u8], settings: &CopySettings) -> Result<()> {
+        write_atomic(dest, data, settings)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> std::io::Result<()> {
+        std::fs::hard_link(original, link)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::remove_dir(path)
+    }
+
+    fn available_space(&self, path: &Path) -> Result<u64> {
+        available_space(path)
+    }
+
+    fn real_path(&self, path: &Path) -> PathBuf {
+        path.to_path_buf()
+    }
+}
+
+/// A directory standing in for `/`: every absolute path is looked up below `root`, so a
+/// synthetic ESP and store can be built in a temp directory
+#[derive(Debug, Clone)]
+pub struct RootedFs {
+    root: PathBuf,
+}
+
+impl RootedFs {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Where `path` really is
+    pub fn host_path(&self, path: &Path) -> PathBuf {
+        self.root.join(path.strip_prefix("/").unwrap_or(path))
+    }
+
+    /// The path the pipeline knows `host` by
+    fn guest_path(&self, host: &Path) -> PathBuf {
+        Path::new("/").join(host.strip_prefix(&self.root).unwrap_or(host))
+    }
+}
+
+impl Filesystem for RootedFs {
+    fn read_link(&self, path: &Path) -> std::io::Result<PathBuf> {
+        std::fs::read_link(self.host_path(path))
+    }
+
+    fn read_to_string(&self, path: &Path) -> std::io::Result<String> {
+        std::fs::read_to_string(self.host_path(path))
+    }
+
+    fn metadata(&self, path: &Path) -> std::io::Result<std::fs::Metadata> {
+        std::fs::metadata(self.host_path(path))
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> std::io::Result<std::fs::Metadata> {
+        std::fs::symlink_metadata(self.host_path(path))
+    }
+
+    /// Symlinks resolve on the host, so ones inside the tree should be relative
+    fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+        let root = std::fs::canonicalize(&self.root)?;
+        let resolved = std::fs::canonicalize(self.host_path(path))?;
+        Ok(Path::new("/").join(resolved.strip_prefix(&root).unwrap_or(&resolved)))
+    }
+
+    fn open(&self, path: &Path) -> std::io::Result<std::fs::File> {
+        std::fs::File::open(self.host_path(path))
+    }
+
+    fn read_dir(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+        RealFs
+            .read_dir(&self.host_path(dir))
+            .map(|paths| paths.iter().map(|path| self.guest_path(path)).collect())
+    }
+
+    fn walk(&self, dir: &Path) -> Result<Vec<(PathBuf, bool)>> {
+        Ok(RealFs
+            .walk(&self.host_path(dir))?
+            .into_iter()
+            .map(|(path, is_dir)| (self.guest_path(&path), is_dir))
+            .collect())
+    }
+
+    fn copy(&self, source: &Path, dest: &Path, settings: &CopySettings) -> Result<CopyStats> {
+        copy_atomic(&self.host_path(source), &self.host_path(dest), settings)
+    }
+
+    fn copy_verified(
+        &self,
+        source: &Path,
+        dest: &Path,
+        settings: &CopySettings,
+    ) -> Result<CopyStats> {
+        copy_verified(&self.host_path(source), &self.host_path(dest), settings)
+    }
+
+    fn write(&self, dest: &Path, data: &[u8], settings: &CopySettings) -> Result<()> {
+        write_atomic(&self.host_path(dest), data, settings)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::create_dir_all(self.host_path(path))
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> std::io::Result<()> {
+        std::fs::hard_link(self.host_path(original), self.host_path(link))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        std::fs::rename(self.host_path(from), self.host_path(to))
+    }
+
+    fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::remove_file(self.host_path(path))
+    }
+
+    fn remove_dir(&self, path: &Path) -> std::io::Result<()> {
+        std::fs::remove_dir(self.host_path(path))
+    }
+
+    fn available_space(&self, path: &Path) -> Result<u64> {
+        available_space(&self.host_path(path))
+    }
+
+    fn real_path(&self, path: &Path) -> PathBuf {
+        self.host_path(path)
+    }
+}
+
+/// Files staged under a base dir, so that the ones nothing uses anymore can be removed.
+///
+/// Only the managed subdirectories given to [`FileTracker::new`] are scanned, so
+/// anything else under the base dir (themes, icons, drivers) is never deleted. Paths are
+/// compared case-insensitively, as the ESP is FAT.
+#[derive(Debug, Clone)]
 pub struct FileTracker {
-    files: HashMap<PathBuf, bool>,
+    filesystem: Arc<dyn Filesystem>,
+    copies: CopySettings,
+    copied: CopyStats,
+    base_dir: PathBuf,
+    /// Tracked files by [`path_key`], with their path as first seen and whether they're used
+    files: HashMap<PathBuf, (PathBuf, bool)>,
+    dirs: HashSet<PathBuf>,
 }
 
 impl FileTracker {
-    pub fn new(base_dir: &Path) -> Result<Self> {
-        let mut files = HashMap::new();
-
-        if base_dir.exists() {
-            for entry in WalkDir::new(base_dir) {
-                let entry = entry?;
-                if entry.file_type().is_file() {
-                    files.insert(entry.path().to_path_buf(), false);
+    /// Track the files in `managed`, subdirectories of `base_dir` holding only files
+    /// we staged
+    pub fn new(base_dir: &Path, managed: &[&str]) -> Result<Self> {
+        Self::with_filesystem(Arc::new(RealFs), base_dir, managed)
+    }
+
+    /// The filesystem the tracked files are on
+    pub fn filesystem(&self) -> &dyn Filesystem {
+        self.filesystem.as_ref()
+    }
+
+    /// How files staged for the tracked entries are copied
+    pub fn copy_settings(&self) -> &CopySettings {
+        &self.copies
+    }
+
+    /// Stage files for the tracked entries with `copies` instead of the defaults
+    pub fn with_copy_settings(mut self, copies: CopySettings) -> Self {
+        self.copies = copies;
+        self
+    }
+
+    /// Count a copy made for the tracked entries
+    pub fn record_copy(&mut self, stats: CopyStats) {
+        self.copied += stats;
+    }
+
+    /// All copies recorded with [`FileTracker::record_copy`] taken together
+    pub fn copied(&self) -> CopyStats {
+        self.copied
+    }
+
+    /// [`FileTracker::new`] on `filesystem` instead of the real one
+    pub fn with_filesystem(
+        filesystem: Arc<dyn Filesystem>,
+        base_dir: &Path,
+        managed: &[&str],
+    ) -> Result<Self> {
+        let mut tracker = Self {
+            filesystem,
+            copies: CopySettings::default(),
+            copied: CopyStats::default(),
+            base_dir: base_dir.to_path_buf(),
+            files: HashMap::new(),
+            dirs: HashSet::new(),
+        };
+
+        for subdir in managed {
+            let subdir = base_dir.join(subdir);
+            if !tracker.filesystem.is_dir(&subdir) {
+                continue;
+            }
+            tracker.track_directory(&subdir);
+            for (path, is_dir) in tracker.filesystem.walk(&subdir)? {
+                if is_dir {
+                    tracker.track_directory(&path);
+                } else if tracker.filesystem.is_file(&path) && !is_temp_file(&path) {
+                    tracker.track(&path, false);
                 }
             }
         }
 
-        Ok(Self { files })
+        Ok(tracker)
     }
 
+    /// Also track files a previous run recorded as its own, wherever they are, so
+    /// they're removed if this run doesn't use them again
+    pub fn track_previous<'a>(&mut self, paths: impl IntoIterator<Item = &'a PathBuf>) {
+        for path in paths {
+            if self.filesystem.is_file(path) && !is_temp_file(path) {
+                self.track(path, false);
+            }
+        }
+    }
+
+    /// Files marked used, in path order
+    pub fn used_files(&self) -> Vec<PathBuf> {
+        let mut used: Vec<PathBuf> = self
+            .files
+            .values()
+            .filter(|(_, used)| *used)
+            .map(|(path, _)| path.clone())
+            .collect();
+        used.sort();
+        used
+    }
+
+    /// Keep `path` through cleanup. Our own temp files are never tracked.
     pub fn mark_used(&mut self, path: &Path) {
-        self.files.insert(path.to_path_buf(), true);
+        if !is_temp_file(path) {
+            self.track(path, true);
+        }
+    }
+
+    fn track(&mut self, path: &Path, used: bool) {
+        self.files
+            .entry(path_key(path))
+            .and_modify(|(_, tracked)| *tracked |= used)
+            .or_insert_with(|| (path.to_path_buf(), used));
     }
 
-    pub fn cleanup(&self) -> Result<()> {
-        for (path, used) in &self.files {
-            if !used && path.exists() {
-                std::fs::remove_file(path)
-                    .with_context(|| format!("Failed to remove unused file: {:?}", path))?;
+    /// Whether `path` was already marked used during this run
+    pub fn is_used(&self, path: &Path) -> bool {
+        self.files
+            .get(&path_key(path))
+            .is_some_and(|(_, used)| *used)
+    }
+
+    /// Whether `path` is under the base dir, not counting the base dir itself
+    fn is_below_base(&self, path: &Path) -> bool {
+        let (path, base) = (path_key(path), path_key(&self.base_dir));
+        path.starts_with(&base) && path != base
+    }
+
+    /// Record a directory under the base dir for [`cleanup`](Self::cleanup) to remove if empty
+    pub fn track_directory(&mut self, path: &Path) {
+        if self.is_below_base(path) {
+            self.dirs.insert(path.to_path_buf());
+        }
+    }
+
+    /// Files [`cleanup`](Self::cleanup) would remove, in path order. Touches nothing.
+    pub fn plan_cleanup(&self) -> Vec<PathBuf> {
+        let mut plan: Vec<PathBuf> = self
+            .files
+            .values()
+            .filter(|(path, used)| !used && self.filesystem.exists(path))
+            .map(|(path, _)| path.clone())
+            .collect();
+        plan.sort();
+        plan
+    }
+
+    /// Remove files not marked used, then the directories under the base dir left empty:
+    /// tracked ones and the parents of removed files, deepest first, walking up from
+    /// each but never past the base dir
+    pub fn cleanup(&self) -> Result<CleanupSummary> {
+        let mut summary = CleanupSummary::default();
+        let mut candidates = self.dirs.clone();
+        for path in self.plan_cleanup() {
+            let size = self.filesystem.metadata(&path).map_or(0, |m| m.len());
+            self.filesystem
+                .remove_file(&path)
+                .with_context(|| format!("Failed to remove unused file: {:?}", path))?;
+            crate::info!("removed {}", path.display());
+            summary.files_removed += 1;
+            summary.bytes_freed += size;
+            if let Some(parent) = path.parent() {
+                candidates.insert(parent.to_path_buf());
             }
         }
-        Ok(())
+
+        let mut candidates: Vec<PathBuf> = candidates.into_iter().collect();
+        candidates.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+        for dir in &candidates {
+            let mut current = dir.as_path();
+            while self.is_below_base(current) {
+                let is_empty = self
+                    .filesystem
+                    .read_dir(current)
+                    .is_ok_and(|entries| entries.is_empty());
+                if !is_empty {
+                    break;
+                }
+                self.filesystem
+                    .remove_dir(current)
+                    .with_context(|| format!("Failed to remove empty directory: {:?}", current))?;
+                let Some(parent) = current.parent() else {
+                    break;
+                };
+                current = parent;
+            }
+        }
+        Ok(summary)
     }
 }
 
-/// Copy file atomically (write to .tmp then rename)
-pub fn copy_atomic(source: &Path, dest: &Path) -> Result<()> {
+/// Every file below `source`, following symlinks, paired with the same path below `dest`,
+/// in destination order
+pub fn tree_copies(
+    filesystem: &dyn Filesystem,
+    source: &Path,
+    dest: &Path,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut copies = Vec::new();
+    let entries = filesystem
+        .walk(source)
+        .with_context(|| format!("Failed to read {}", source.display()))?;
+    for (path, is_dir) in entries {
+        if !is_dir {
+            let relative = path.strip_prefix(source)?;
+            copies.push((path.clone(), dest.join(relative)));
+        }
+    }
+    copies.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(copies)
+}
+
+/// Copy file atomically (write to a temp file next to it, then rename)
+pub fn copy_atomic(source: &Path, dest: &Path, settings: &CopySettings) -> Result<CopyStats> {
     // Ensure parent directory exists
     if let Some(parent) = dest.parent() {
         std::fs::create_dir_all(parent)
             .with_context(|| format!("Failed to create directory: {:?}", parent))?;
     }
 
-    let temp_dest = dest.with_extension("tmp");
+    let temp_dest = temp_path_for(dest);
 
-    // Copy to temporary file
-    std::fs::copy(source, &temp_dest)
-        .with_context(|| format!("Failed to copy {:?} to {:?}", source, temp_dest))?;
+    // Copy to temporary file; a partial one would only take up space
+    let stats = with_retries(settings.retries, || {
+        copy_core(
+            source,
+            &temp_dest,
+            settings.chunk_size,
+            &settings.cancel,
+            settings.reproducible,
+            &mut |_| {},
+            &mut |_, _| {},
+        )
+    })
+    .inspect_err(|_| {
+        let _ = std::fs::remove_file(&temp_dest);
+    })
+    .with_context(|| format!("Failed to copy {:?} to {:?}", source, temp_dest))?;
 
-    // Atomic rename
-    std::fs::rename(&temp_dest, dest)
-        .with_context(|| format!("Failed to rename {:?} to {:?}", temp_dest, dest))?;
+    rename_durably(&temp_dest, dest, settings.reproducible)?;
+    Ok(stats)
+}
 
-    Ok(())
+/// [`copy_atomic`], then verify the destination hashes the same as the source did
+/// before copying. A mismatching destination is removed.
+pub fn copy_atomic_xxhash(
+    source: &Path,
+    dest: &Path,
+    settings: &CopySettings,
+) -> Result<CopyStats> {
+    let expected = crate::hash::xxh3_file(source)?;
+
+    let stats = copy_atomic(source, dest, settings)?;
+
+    let actual = crate::hash::xxh3_file(dest)?;
+    if actual != expected {
+        let _ = std::fs::remove_file(dest);
+        anyhow::bail!(
+            "Copy of {:?} to {:?} is corrupt (xxh3 {:016x}, expected {:016x})",
+            source,
+            dest,
+            actual,
+            expected
+        );
+    }
+
+    Ok(stats)
 }
 
-/// Write data atomically (write to .tmp then rename)
-pub fn write_atomic(dest: &Path, data: &[u8]) -> Result<()> {
+/// Copy atomically like [`copy_atomic`], hashing the source as it's copied, then read the
+/// destination back from the device after the rename and compare BLAKE3 digests. A
+/// mismatching destination is removed.
+pub fn copy_verified(source: &Path, dest: &Path, settings: &CopySettings) -> Result<CopyStats> {
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {:?}", parent))?;
+    }
+
+    let temp_dest = temp_path_for(dest);
+
+    let (stats, expected) = with_retries(settings.retries, || {
+        let mut hasher = blake3::Hasher::new();
+        let stats = copy_core(
+            source,
+            &temp_dest,
+            settings.chunk_size,
+            &settings.cancel,
+            settings.reproducible,
+            &mut |chunk| {
+                hasher.update(chunk);
+            },
+            &mut |_, _| {},
+        )?;
+        Ok((stats, hasher.finalize()))
+    })
+    .inspect_err(|_| {
+        let _ = std::fs::remove_file(&temp_dest);
+    })
+    .with_context(|| format!("Failed to copy {:?} to {:?}", source, temp_dest))?;
+
+    rename_durably(&temp_dest, dest, settings.reproducible)?;
+
+    let actual = crate::hash::blake3_file_uncached(dest)?;
+    if actual != expected {
+        let _ = std::fs::remove_file(dest);
+        anyhow::bail!(
+            "Copy of {:?} to {:?} is corrupt (blake3 {}, expected {})",
+            source,
+            dest,
+            actual,
+            expected
+        );
+    }
+
+    Ok(stats)
+}
+
+/// With `reproducible`, give `file` the mtime Nix gives store paths
+fn normalize_mtime(file: &std::fs::File, reproducible: bool) -> std::io::Result<()> {
+    if !reproducible {
+        return Ok(());
+    }
+    file.set_modified(std::time::UNIX_EPOCH + Duration::from_secs(1))
+}
+
+/// Bytes [`copy_atomic`] and [`copy_verified`] move per read/write unless
+/// [`CopySettings::chunk_size`] says otherwise
+pub const DEFAULT_COPY_CHUNK_SIZE: usize = 1 << 20;
+
+/// Bytes moved by a copy and the time it took
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopyStats {
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+impl CopyStats {
+    /// Bytes per second, 0 if nothing took measurable time
+    pub fn throughput(&self) -> f64 {
+        match self.duration.as_secs_f64() {
+            0.0 => 0.0,
+            secs => self.bytes as f64 / secs,
+        }
+    }
+}
+
+impl std::ops::AddAssign for CopyStats {
+    fn add_assign(&mut self, other: Self) {
+        self.bytes += other.bytes;
+        self.duration += other.duration;
+    }
+}
+
+/// Copy `source` to `dest` `chunk_size` bytes at a time, calling `on_progress` with the
+/// bytes copied so far and the total after each chunk. Fails once `cancel` is set.
+pub fn copy_chunked(
+    source: &Path,
+    dest: &Path,
+    chunk_size: usize,
+    cancel: &AtomicBool,
+    mut on_progress: impl FnMut(u64, u64),
+) -> std::io::Result<CopyStats> {
+    copy_core(
+        source,
+        dest,
+        chunk_size,
+        cancel,
+        false,
+        &mut |_| {},
+        &mut on_progress,
+    )
+}
+
+/// The copy loop behind every copy: shows each chunk to `inspect` before writing it, and
+/// syncs `dest` at the end so the data isn't left in the page cache. With `reproducible`,
+/// `dest` gets the store mtime.
+fn copy_core(
+    source: &Path,
+    dest: &Path,
+    chunk_size: usize,
+    cancel: &AtomicBool,
+    reproducible: bool,
+    inspect: &mut dyn FnMut(&[u8]),
+    on_progress: &mut dyn FnMut(u64, u64),
+) -> std::io::Result<CopyStats> {
+    use std::io::{Read, Write};
+
+    let started = std::time::Instant::now();
+    let mut input = std::fs::File::open(source)?;
+    let total = input.metadata()?.len();
+    let mut output = std::fs::File::create(dest)?;
+    let mut buf = vec![0u8; chunk_size.max(1)];
+    let mut copied = 0;
+    loop {
+        if cancel.load(Ordering::Relaxed) {
+            return Err(std::io::Error::other("copy cancelled"));
+        }
+        let read = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        };
+        inspect(&buf[..read]);
+        output.write_all(&buf[..read])?;
+        copied += read as u64;
+        on_progress(copied, total);
+    }
+    normalize_mtime(&output, reproducible)?;
+    output.sync_all()?;
+
+    Ok(CopyStats {
+        bytes: copied,
+        duration: started.elapsed(),
+    })
+}
+
+/// Run `op` up to `attempts` times while it fails transiently
+fn with_retries<T>(
+    attempts: u32,
+    mut op: impl FnMut() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(error)
+                if attempt < attempts
+                    && matches!(
+                        error.kind(),
+                        std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock
+                    ) =>
+            {
+                attempt += 1;
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A copy of a file in the temp directory, readable only by us, deleted on drop
+pub struct PrivateTempFile {
+    path: PathBuf,
+}
+
+impl PrivateTempFile {
+    pub fn copy_of(source: &Path) -> Result<Self> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "refindgen-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut dest = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| format!("Failed to create temp file: {:?}", path))?;
+        let temp = Self { path };
+
+        let mut source =
+            std::fs::File::open(source).with_context(|| format!("Failed to open {:?}", source))?;
+        std::io::copy(&mut source, &mut dest)
+            .with_context(|| format!("Failed to copy to temp file: {:?}", temp.path))?;
+
+        Ok(temp)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PrivateTempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Write data atomically (write to a temp file next to it, then rename)
+pub fn write_atomic(dest: &Path, data: &[u8], settings: &CopySettings) -> Result<()> {
     use std::io::Write;
 
     if let Some(parent) = dest.parent() {
         std::fs::create_dir_all(parent)?;
     }
 
-    let temp_dest = dest.with_extension("tmp");
+    let temp_dest = temp_path_for(dest);
 
     let mut file = std::fs::File::create(&temp_dest)
         .with_context(|| format!("Failed to create temp file: {:?}", temp_dest))?;
 
     file.write_all(data)?;
+    normalize_mtime(&file, settings.reproducible)?;
     file.sync_all()?;
     drop(file);
 
-    std::fs::rename(&temp_dest, dest)
-        .with_context(|| format!("Failed to rename {:?} to {:?}", temp_dest, dest))?;
+    rename_durably(&temp_dest, dest, settings.reproducible)
+}
 
-    Ok(())
+/// Have `write` create a file at a temp path beside `dest`, then sync it and rename it over
+/// `dest`, for files made by other programs. The temp file is removed if `write` fails.
+pub fn replace_atomic_with(
+    dest: &Path,
+    settings: &CopySettings,
+    write: impl FnOnce(&Path) -> Result<()>,
+) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let temp_dest = temp_path_for(dest);
+    let written = write(&temp_dest).and_then(|()| {
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&temp_dest)
+            .with_context(|| format!("Failed to open {:?}", temp_dest))?;
+        normalize_mtime(&file, settings.reproducible)?;
+        file.sync_all()?;
+        Ok(())
+    });
+    if let Err(error) = written {
+        let _ = std::fs::remove_file(&temp_dest);
+        return Err(error);
+    }
+
+    rename_durably(&temp_dest, dest, settings.reproducible)
+}
+
+/// Rename `temp` over `dest`, then fsync the directory so the rename itself survives a
+/// crash. `temp`'s data must already be synced.
+///
+/// Temp files are created next to their destination, but should `temp` end up on another
+/// filesystem anyway (EXDEV), it's copied to a temp file beside `dest` and renamed from
+/// there, keeping the store mtime with `reproducible`.
+fn rename_durably(temp: &Path, dest: &Path, reproducible: bool) -> Result<()> {
+    match std::fs::rename(temp, dest) {
+        Err(error) if error.kind() == std::io::ErrorKind::CrossesDevices => {
+            rename_across_devices(temp, dest, reproducible)?
+        }
+        Err(error) => {
+            // A temp file nothing will rename only takes up space
+            let _ = std::fs::remove_file(temp);
+            return Err(error)
+                .with_context(|| format!("Failed to rename {:?} to {:?}", temp, dest));
+        }
+        Ok(()) => {}
+    }
+
+    let parent = match dest.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    std::fs::File::open(parent)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Failed to sync directory {:?}", parent))
+}
+
+/// Copy `temp` to a synced temp file beside `dest`, rename that over `dest` and remove
+/// `temp`
+fn rename_across_devices(temp: &Path, dest: &Path, reproducible: bool) -> Result<()> {
+    let local = temp_path_for(dest);
+    let result = std::fs::copy(temp, &local)
+        .and_then(|_| std::fs::OpenOptions::new().write(true).open(&local))
+        .and_then(|file| {
+            normalize_mtime(&file, reproducible)?;
+            file.sync_all()
+        })
+        .and_then(|_| std::fs::rename(&local, dest));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&local);
+    }
+    result.with_context(|| format!("Failed to move {:?} across devices to {:?}", temp, dest))?;
+
+    std::fs::remove_file(temp).with_context(|| format!("Failed to remove {:?}", temp))
 }
 
-/// Sync filesystem using syncfs()
+/// Flush every dirty file of the filesystem holding `mount_point` to disk, via syncfs().
+///
+/// Elsewhere than Linux, falls back to sync(), which flushes all filesystems.
 pub fn sync_filesystem(mount_point: &Path) -> Result<()> {
     let file = std::fs::File::open(mount_point)
         .with_context(|| format!("Failed to open mount point: {:?}", mount_point))?;
 
-    file.sync_all().map_err(|error| {
+    syncfs(&file).map_err(|error| {
         anyhow::anyhow!("Failed to sync filesystem at {:?}: {}", mount_point, error)
     })?;
 
     Ok(())
 }
+
+#[cfg(target_os = "linux")]
+fn syncfs(file: &std::fs::File) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if unsafe { libc::syncfs(file.as_raw_fd()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn syncfs(file: &std::fs::File) -> std::io::Result<()> {
+    unsafe { libc::sync() };
+    file.sync_all()
+}
+
+/// Bytes available on the filesystem holding `path`, via statvfs()
+pub fn available_space(path: &Path) -> Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to statvfs {:?}", path));
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A directory under the system temp dir, removed with everything in it on drop
+    pub(crate) struct ScratchDir(PathBuf);
+
+    impl ScratchDir {
+        pub(crate) fn new() -> Self {
+            Self::new_in(&std::env::temp_dir())
+        }
+
+        /// A scratch directory under `parent` instead of the system temp dir
+        pub(crate) fn new_in(parent: &Path) -> Self {
+            static COUNTER: AtomicU32 = AtomicU32::new(0);
+            let path = parent.join(format!(
+                "refindgen-test-{}-{}",
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        pub(crate) fn path(&self) -> &Path {
+            &self.0
+        }
+
+        /// A [`RootedFs`] with this directory as `/`
+        pub(crate) fn rooted(&self) -> Arc<RootedFs> {
+            Arc::new(RootedFs::new(&self.0))
+        }
+    }
+
+    impl Drop for ScratchDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Write `content` to the guest path `path`, creating its parents
+    pub(crate) fn put(filesystem: &dyn Filesystem, path: &str, content: &str) {
+        filesystem
+            .write(
+                Path::new(path),
+                content.as_bytes(),
+                &CopySettings::default(),
+            )
+            .unwrap();
+    }
+
+    const BASE: &str = "/boot/efi/refind";
+
+    fn tracker(filesystem: &Arc<RootedFs>) -> FileTracker {
+        FileTracker::with_filesystem(filesystem.clone(), Path::new(BASE), &["kernels", "tools"])
+            .unwrap()
+    }
+
+    #[test]
+    fn rooted_fs_maps_paths_below_root() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        put(filesystem.as_ref(), "/boot/a/file", "x");
+
+        assert!(scratch.path().join("boot/a/file").is_file());
+        assert_eq!(
+            filesystem.host_path(Path::new("/boot/a/file")),
+            scratch.path().join("boot/a/file")
+        );
+        assert_eq!(
+            filesystem.read_dir(Path::new("/boot/a")).unwrap(),
+            vec![PathBuf::from("/boot/a/file")]
+        );
+        assert_eq!(
+            filesystem.walk(Path::new("/boot")).unwrap().len(),
+            2,
+            "the directory and the file"
+        );
+    }
+
+    #[test]
+    fn rooted_fs_canonicalizes_to_guest_paths() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        put(filesystem.as_ref(), "/store/abc-linux/bzImage", "kernel");
+        std::fs::create_dir_all(scratch.path().join("system/kernel-link")).unwrap();
+        std::os::unix::fs::symlink(
+            "../../store/abc-linux/bzImage",
+            scratch.path().join("system/kernel-link/kernel"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            filesystem
+                .canonicalize(Path::new("/system/kernel-link/kernel"))
+                .unwrap(),
+            PathBuf::from("/store/abc-linux/bzImage")
+        );
+    }
+
+    #[test]
+    fn cleanup_removes_unused_files_only() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        put(
+            filesystem.as_ref(),
+            "/boot/efi/refind/kernels/old/bzImage",
+            "old",
+        );
+        put(
+            filesystem.as_ref(),
+            "/boot/efi/refind/kernels/new/bzImage",
+            "new",
+        );
+        put(
+            filesystem.as_ref(),
+            "/boot/efi/refind/icons/os_nixos.png",
+            "icon",
+        );
+
+        let mut tracker = tracker(&filesystem);
+        tracker.mark_used(Path::new("/boot/efi/refind/kernels/new/bzImage"));
+        assert_eq!(
+            tracker.plan_cleanup(),
+            vec![PathBuf::from("/boot/efi/refind/kernels/old/bzImage")]
+        );
+
+        let summary = tracker.cleanup().unwrap();
+        assert_eq!(
+            summary,
+            CleanupSummary {
+                files_removed: 1,
+                bytes_freed: 3,
+            }
+        );
+        assert!(!filesystem.exists(Path::new("/boot/efi/refind/kernels/old")));
+        assert!(filesystem.is_file(Path::new("/boot/efi/refind/kernels/new/bzImage")));
+        assert!(filesystem.is_file(Path::new("/boot/efi/refind/icons/os_nixos.png")));
+        assert!(filesystem.is_dir(Path::new("/boot/efi/refind/kernels")));
+    }
+
+    #[test]
+    fn cleanup_never_leaves_the_base_dir() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        put(
+            filesystem.as_ref(),
+            "/boot/efi/refind/tools/memtest.efi",
+            "memtest",
+        );
+
+        let tracker = tracker(&filesystem);
+        tracker.cleanup().unwrap();
+        assert!(!filesystem.exists(Path::new("/boot/efi/refind/tools")));
+        assert!(filesystem.is_dir(Path::new(BASE)));
+    }
+
+    #[test]
+    fn cleanup_removes_nested_empty_directories() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        put(
+            filesystem.as_ref(),
+            "/boot/efi/refind/kernels/gen/secrets/initrd",
+            "secrets",
+        );
+        put(
+            filesystem.as_ref(),
+            "/boot/efi/refind/kernels/gen/bzImage",
+            "old",
+        );
+        put(filesystem.as_ref(), "/boot/efi/refind/kernels/keep", "kept");
+        filesystem
+            .create_dir_all(Path::new("/boot/efi/refind/kernels/stale/empty"))
+            .unwrap();
+
+        let mut tracker = tracker(&filesystem);
+        tracker.mark_used(Path::new("/boot/efi/refind/kernels/keep"));
+        tracker.cleanup().unwrap();
+        assert!(!filesystem.exists(Path::new("/boot/efi/refind/kernels/gen")));
+        assert!(!filesystem.exists(Path::new("/boot/efi/refind/kernels/stale")));
+        assert!(filesystem.is_file(Path::new("/boot/efi/refind/kernels/keep")));
+    }
+
+    #[test]
+    fn cleanup_keeps_directories_with_untracked_files() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        put(
+            filesystem.as_ref(),
+            "/boot/efi/refind/kernels/gen/bzImage",
+            "old",
+        );
+
+        let tracker = tracker(&filesystem);
+        put(
+            filesystem.as_ref(),
+            "/boot/efi/refind/kernels/gen/notes.txt",
+            "not ours",
+        );
+        tracker.cleanup().unwrap();
+        assert!(!filesystem.exists(Path::new("/boot/efi/refind/kernels/gen/bzImage")));
+        assert!(filesystem.is_file(Path::new("/boot/efi/refind/kernels/gen/notes.txt")));
+    }
+
+    #[test]
+    fn marks_are_case_insensitive() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        put(
+            filesystem.as_ref(),
+            "/boot/efi/refind/kernels/gen/bzImage",
+            "kernel",
+        );
+
+        let mut tracker = tracker(&filesystem);
+        tracker.mark_used(Path::new("/boot/EFI/refind/kernels/gen/BZIMAGE"));
+        assert!(tracker.plan_cleanup().is_empty());
+        assert!(tracker.is_used(Path::new("/boot/efi/refind/kernels/gen/bzImage")));
+    }
+
+    #[test]
+    fn previous_files_outside_managed_dirs_are_cleaned_up() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        put(
+            filesystem.as_ref(),
+            "/boot/efi/refind/themes/old.png",
+            "theme",
+        );
+        put(
+            filesystem.as_ref(),
+            "/boot/efi/refind/themes/kept.png",
+            "theme",
+        );
+
+        let mut tracker = tracker(&filesystem);
+        assert!(tracker.plan_cleanup().is_empty());
+        tracker.track_previous(&[
+            PathBuf::from("/boot/efi/refind/themes/old.png"),
+            PathBuf::from("/boot/efi/refind/themes/kept.png"),
+            PathBuf::from("/boot/efi/refind/themes/gone.png"),
+        ]);
+        tracker.mark_used(Path::new("/boot/efi/refind/themes/kept.png"));
+        assert_eq!(
+            tracker.plan_cleanup(),
+            vec![PathBuf::from("/boot/efi/refind/themes/old.png")]
+        );
+    }
+
+    #[test]
+    fn temp_files_are_never_tracked() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        let temp = temp_path_for(Path::new("/boot/efi/refind/kernels/gen/bzImage"));
+        assert!(is_temp_file(&temp));
+        put(filesystem.as_ref(), temp.to_str().unwrap(), "partial");
+
+        let tracker = tracker(&filesystem);
+        assert!(tracker.plan_cleanup().is_empty());
+        assert!(!is_temp_file(Path::new(
+            "/boot/efi/refind/kernels/gen/bzImage"
+        )));
+    }
+
+    /// Files in `dir` named like our temp files
+    fn temp_files(dir: &Path) -> Vec<PathBuf> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| is_temp_file(path))
+            .collect()
+    }
+
+    #[test]
+    fn copy_atomic_replaces_the_destination() {
+        let scratch = ScratchDir::new();
+        let source = scratch.path().join("source");
+        let dest = scratch.path().join("esp/nested/dest");
+        std::fs::write(&source, "first").unwrap();
+        copy_atomic(&source, &dest, &CopySettings::default()).unwrap();
+        std::fs::write(&source, "second").unwrap();
+        copy_atomic(&source, &dest, &CopySettings::default()).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "second");
+        assert!(temp_files(dest.parent().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn copy_atomic_xxhash_copies_and_checks_the_result() {
+        let scratch = ScratchDir::new();
+        let source = scratch.path().join("bzImage");
+        let dest = scratch.path().join("esp/kernels/bzImage");
+        std::fs::write(&source, vec![7u8; 100_000]).unwrap();
+        copy_atomic_xxhash(&source, &dest, &CopySettings::default()).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), vec![7u8; 100_000]);
+
+        let error = copy_atomic_xxhash(
+            &scratch.path().join("missing"),
+            &dest,
+            &CopySettings::default(),
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains("missing"), "{:#}", error);
+        assert_eq!(std::fs::read(&dest).unwrap().len(), 100_000);
+    }
+
+    #[test]
+    fn copy_atomic_fails_on_a_missing_source() {
+        let scratch = ScratchDir::new();
+        let dest = scratch.path().join("dest");
+        std::fs::write(&dest, "kept").unwrap();
+
+        let error = copy_atomic(
+            &scratch.path().join("missing"),
+            &dest,
+            &CopySettings::default(),
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains("Failed to copy"));
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "kept");
+        assert!(temp_files(scratch.path()).is_empty());
+    }
+
+    #[test]
+    fn copy_atomic_fails_when_the_parent_is_a_file() {
+        let scratch = ScratchDir::new();
+        let source = scratch.path().join("source");
+        std::fs::write(&source, "data").unwrap();
+        std::fs::write(scratch.path().join("file"), "").unwrap();
+
+        let error = copy_atomic(
+            &source,
+            &scratch.path().join("file/dest"),
+            &CopySettings::default(),
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains("Failed to create directory"));
+    }
+
+    #[test]
+    fn copy_atomic_fails_when_the_destination_is_a_directory() {
+        let scratch = ScratchDir::new();
+        let source = scratch.path().join("source");
+        std::fs::write(&source, "data").unwrap();
+        let dest = scratch.path().join("dest");
+        std::fs::create_dir_all(dest.join("inside")).unwrap();
+
+        assert!(copy_atomic(&source, &dest, &CopySettings::default()).is_err());
+        assert!(dest.join("inside").is_dir());
+        assert!(temp_files(scratch.path()).is_empty());
+    }
+
+    #[test]
+    fn with_retries_retries_only_transient_errors() {
+        let mut calls = 0;
+        let result: std::io::Result<()> = with_retries(2, || {
+            calls += 1;
+            Err(std::io::ErrorKind::Interrupted.into())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 2);
+
+        calls = 0;
+        let result: std::io::Result<()> = with_retries(3, || {
+            calls += 1;
+            Err(std::io::ErrorKind::NotFound.into())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1, "other errors fail at once");
+    }
+
+    #[test]
+    fn copy_chunked_reports_progress_and_stops_when_cancelled() {
+        let scratch = ScratchDir::new();
+        let source = scratch.path().join("initrd");
+        let dest = scratch.path().join("copy");
+        std::fs::write(&source, vec![1u8; 10_000]).unwrap();
+
+        let cancel = AtomicBool::new(false);
+        let mut progress = Vec::new();
+        let stats = copy_chunked(&source, &dest, 4096, &cancel, |copied, total| {
+            progress.push((copied, total))
+        })
+        .unwrap();
+        assert_eq!(progress, [(4096, 10_000), (8192, 10_000), (10_000, 10_000)]);
+        assert_eq!(stats.bytes, 10_000);
+
+        cancel.store(true, Ordering::Relaxed);
+        let error = copy_chunked(&source, &dest, 4096, &cancel, |_, _| {}).unwrap_err();
+        assert!(error.to_string().contains("cancelled"), "{}", error);
+    }
+
+    #[test]
+    fn copy_verified_copies_through_rooted_fs() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        put(filesystem.as_ref(), "/store/kernel", "kernel image");
+        filesystem
+            .copy_verified(
+                Path::new("/store/kernel"),
+                Path::new("/boot/kernel"),
+                &CopySettings::default(),
+            )
+            .unwrap();
+
+        assert_eq!(
+            filesystem
+                .read_to_string(Path::new("/boot/kernel"))
+                .unwrap(),
+            "kernel image"
+        );
+        assert!(
+            filesystem
+                .copy_verified(
+                    Path::new("/store/missing"),
+                    Path::new("/boot/missing"),
+                    &CopySettings::default(),
+                )
+                .is_err()
+        );
+        assert!(!filesystem.exists(Path::new("/boot/missing")));
+    }
+
+    /// A writable tmpfs mount, if the machine running the tests has one
+    fn tmpfs_mount() -> Option<PathBuf> {
+        let mounts = std::fs::read_to_string("/proc/mounts").ok()?;
+        mounts
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let (mount_point, fs_type) = (fields.nth(1)?, fields.next()?);
+                (fs_type == "tmpfs").then(|| PathBuf::from(mount_point))
+            })
+            .find(|mount_point| {
+                let probe = mount_point.join(format!(".refindgen-probe-{}", std::process::id()));
+                let writable = std::fs::write(&probe, b"").is_ok();
+                let _ = std::fs::remove_file(&probe);
+                writable
+            })
+    }
+
+    #[test]
+    fn sync_filesystem_flushes_a_tmpfs() {
+        let Some(mount_point) = tmpfs_mount() else {
+            eprintln!("no writable tmpfs, skipping");
+            return;
+        };
+        let scratch = ScratchDir::new_in(&mount_point);
+        std::fs::write(scratch.path().join("refind.conf"), "timeout 5\n").unwrap();
+
+        sync_filesystem(scratch.path()).unwrap();
+        sync_filesystem(&mount_point).unwrap();
+    }
+
+    #[test]
+    fn sync_filesystem_names_a_missing_mount_point() {
+        let scratch = ScratchDir::new();
+        let missing = scratch.path().join("esp");
+        let error = format!("{:#}", sync_filesystem(&missing).unwrap_err());
+        assert!(error.contains(&format!("{:?}", missing)), "{}", error);
+    }
+
+    #[test]
+    fn cleanup_only_touches_the_managed_directories() {
+        let scratch = ScratchDir::new();
+        let base = scratch.path().join("efi/refind");
+        let user_files = [
+            "themes/minimal/theme.conf",
+            "icons/os_custom.png",
+            "drivers_x64/ext4_x64.efi",
+            "manual.conf",
+        ];
+        for path in user_files
+            .iter()
+            .chain(&["kernels/old-bzImage", "kernels/new-bzImage"])
+        {
+            let path = base.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "content").unwrap();
+        }
+
+        let mut tracker = FileTracker::new(&base, &["kernels", "tools"]).unwrap();
+        tracker.mark_used(&base.join("kernels/new-bzImage"));
+        let summary = tracker.cleanup().unwrap();
+
+        assert_eq!(summary.files_removed, 1);
+        assert!(!base.join("kernels/old-bzImage").exists());
+        assert!(base.join("kernels/new-bzImage").exists());
+        for path in user_files {
+            assert!(base.join(path).exists(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn write_atomic_creates_and_replaces() {
+        let scratch = ScratchDir::new();
+        let dest = scratch.path().join("efi/refind/refind.conf");
+
+        write_atomic(&dest, b"timeout 5\n", &CopySettings::default()).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"timeout 5\n");
+        write_atomic(&dest, b"timeout 10\n", &CopySettings::default()).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"timeout 10\n");
+        assert!(temp_files(dest.parent().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn rename_durably_moves_the_temp_file_over_the_destination() {
+        let scratch = ScratchDir::new();
+        let dest = scratch.path().join("refind.conf");
+        let temp = temp_path_for(&dest);
+        std::fs::write(&dest, "old").unwrap();
+        std::fs::write(&temp, "new").unwrap();
+
+        rename_durably(&temp, &dest, false).unwrap();
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "new");
+        assert!(!temp.exists());
+
+        assert!(
+            rename_durably(&temp, &dest, false).is_err(),
+            "temp file is gone"
+        );
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "new");
+    }
+
+    #[test]
+    fn tracker_seeded_in_other_casing_keeps_what_it_found() {
+        let scratch = ScratchDir::new();
+        let base = scratch.path().join("efi/refind");
+        std::fs::create_dir_all(base.join("kernels")).unwrap();
+        let kernel = base.join("kernels/ABC-Linux-6.6-bzImage");
+        let initrd = base.join("kernels/DEF-initrd-initrd");
+        std::fs::write(&kernel, "kernel").unwrap();
+        std::fs::write(&initrd, "initrd").unwrap();
+
+        let mut tracker = FileTracker::new(&base, &["kernels", "tools"]).unwrap();
+        tracker.track_previous(std::slice::from_ref(&kernel));
+        tracker.mark_used(
+            &scratch
+                .path()
+                .join("EFI/Refind/KERNELS/abc-linux-6.6-bzimage"),
+        );
+        assert_eq!(
+            tracker.used_files(),
+            vec![kernel],
+            "the casing on disk, tracked once"
+        );
+        assert_eq!(tracker.plan_cleanup(), vec![initrd]);
+        assert!(
+            tracker.is_used(
+                &scratch
+                    .path()
+                    .join("efi/refind/kernels/abc-LINUX-6.6-BZIMAGE")
+            )
+        );
+        assert_eq!(
+            path_key(Path::new("/boot/EFI/Refind")),
+            PathBuf::from("/boot/efi/refind")
+        );
+    }
+
+    #[test]
+    fn reproducible_writes_get_the_store_mtime() {
+        let scratch = ScratchDir::new();
+        let source = scratch.path().join("bzImage");
+        std::fs::write(&source, "kernel").unwrap();
+        let copied = scratch.path().join("kernels/bzImage");
+        let written = scratch.path().join("refind.conf");
+
+        let settings = CopySettings {
+            reproducible: true,
+            ..Default::default()
+        };
+        copy_atomic(&source, &copied, &settings).unwrap();
+        write_atomic(&written, b"x", &settings).unwrap();
+
+        for path in [copied, written] {
+            let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+            assert_eq!(mtime, std::time::UNIX_EPOCH + Duration::from_secs(1));
+        }
+    }
+
+    #[test]
+    fn replace_atomic_with_removes_the_temp_file_on_failure() {
+        let scratch = ScratchDir::new();
+        let dest = scratch.path().join("refind_x64.efi");
+        std::fs::write(&dest, "signed").unwrap();
+
+        let error = replace_atomic_with(&dest, &CopySettings::default(), |temp| {
+            std::fs::write(temp, "partial")?;
+            anyhow::bail!("sbsign failed")
+        })
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains("sbsign failed"));
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "signed");
+        assert!(temp_files(scratch.path()).is_empty());
+
+        replace_atomic_with(&dest, &CopySettings::default(), |temp| {
+            Ok(std::fs::write(temp, "resigned")?)
+        })
+        .unwrap();
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "resigned");
+        assert!(temp_files(scratch.path()).is_empty());
+    }
+
+    #[test]
+    fn rename_durably_copies_across_devices() {
+        use std::os::unix::fs::MetadataExt;
+
+        let scratch = ScratchDir::new();
+        let Some(other) = tmpfs_mount()
+            .map(|mount_point| ScratchDir::new_in(&mount_point))
+            .filter(|other| {
+                let device = |dir: &ScratchDir| std::fs::metadata(dir.path()).unwrap().dev();
+                device(other) != device(&scratch)
+            })
+        else {
+            eprintln!("no tmpfs on another device than the temp dir, skipping");
+            return;
+        };
+        let dest = scratch.path().join("refind.conf");
+        let temp = temp_path_for(&other.path().join("refind.conf"));
+        std::fs::write(&dest, "old").unwrap();
+        std::fs::write(&temp, "new").unwrap();
+        assert_eq!(
+            std::fs::rename(&temp, &dest).unwrap_err().kind(),
+            std::io::ErrorKind::CrossesDevices
+        );
+
+        rename_durably(&temp, &dest, false).unwrap();
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "new");
+        assert!(!temp.exists());
+        assert!(temp_files(scratch.path()).is_empty());
+    }
+}
@@ -1,18 +1,37 @@
 use anyhow::{Context, Result};
-use chrono::{DateTime, Local};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Mutex;
 
-use crate::{bootspec::BootSpec, config::InstallConfig, fs};
+use crate::{
+    bootspec::BootSpec,
+    config::{InstallConfig, KernelLayout, LuksParamStyle, SharedFiles},
+    fs::{self, Filesystem},
+};
 
-pub fn get_system_path(profile: &str, generation: Option<u64>, spec: Option<&str>) -> PathBuf {
-    let profiles_dir = PathBuf::from("/nix/var/nix/profiles");
+/// Where NixOS keeps system profiles unless the Nix state directory is relocated
+pub const DEFAULT_PROFILES_ROOT: &str = "/nix/var/nix/profiles";
 
+/// `$NIX_STATE_DIR/profiles` if `NIX_STATE_DIR` is set, otherwise [`DEFAULT_PROFILES_ROOT`]
+pub fn default_profiles_root() -> PathBuf {
+    match std::env::var_os("NIX_STATE_DIR") {
+        Some(state_dir) if !state_dir.is_empty() => PathBuf::from(state_dir).join("profiles"),
+        _ => PathBuf::from(DEFAULT_PROFILES_ROOT),
+    }
+}
+
+pub fn get_system_path(
+    profiles_root: &Path,
+    profile: &str,
+    generation: Option<u64>,
+    spec: Option<&str>,
+) -> PathBuf {
     let mut path = if profile == "system" {
         if let Some(g) = generation {
-            profiles_dir.join(format!("system-{}-link", g))
+            profiles_root.join(format!("system-{}-link", g))
         } else {
-            profiles_dir.join("system")
+            profiles_root.join("system")
         }
     } else {
         let basename = if let Some(g) = generation {
@@ -20,7 +39,7 @@ pub fn get_system_path(profile: &str, generation: Option<u64>, spec: Option<&str
         } else {
             profile.to_string()
         };
-        profiles_dir.join("system-profiles").join(basename)
+        profiles_root.join("system-profiles").join(basename)
     };
 
     if let Some(s) = spec {
@@ -30,118 +49,518 @@ pub fn get_system_path(profile: &str, generation: Option<u64>, spec: Option<&str
     path
 }
 
-pub fn get_profiles() -> Result<Vec<String>> {
-    let profiles_dir = PathBuf::from("/nix/var/nix/profiles/system-profiles");
+pub fn get_profiles(filesystem: &dyn Filesystem, profiles_root: &Path) -> Result<Vec<String>> {
+    let profiles_dir = profiles_root.join("system-profiles");
 
-    if !profiles_dir.is_dir() {
+    if !filesystem.is_dir(&profiles_dir) {
         return Ok(Vec::new());
     }
 
-    let entries = std::fs::read_dir(&profiles_dir).context("Failed to read profiles directory")?;
+    let entries = filesystem
+        .read_dir(&profiles_dir)
+        .context("Failed to read profiles directory")?;
 
-    let mut profiles = Vec::new();
+    let mut names = BTreeSet::new();
     for entry in entries {
-        let entry = entry?;
-        let name = entry.file_name().to_string_lossy().to_string();
-        if !name.ends_with("-link") {
-            profiles.push(name);
+        names.insert(
+            entry
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string(),
+        );
+    }
+
+    // Everything that isn't a generation link of another entry is a profile, so profiles
+    // may contain dashes, digits, or even end in "-link"
+    Ok(names
+        .iter()
+        .filter(|name| {
+            parse_generation_link(name).is_none_or(|(profile, _)| !names.contains(profile))
+        })
+        .cloned()
+        .collect())
+}
+
+/// Split a `<profile>-<number>-link` name into profile and generation number.
+///
+/// The number is the last dash-separated field, so `test-2-15-link` is generation 15 of
+/// `test-2`. Whether the profile exists is up to the caller.
+pub fn parse_generation_link(name: &str) -> Option<(&str, u64)> {
+    let (profile, number) = name.strip_suffix("-link")?.rsplit_once('-')?;
+    if profile.is_empty() || number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((profile, number.parse().ok()?))
+}
+
+/// Name shown for a profile in menu titles: its configured label, or the raw name.
+/// Only for display; paths always use the raw name.
+pub fn profile_display_name<'a>(labels: &'a HashMap<String, String>, profile: &'a str) -> &'a str {
+    labels.get(profile).map(String::as_str).unwrap_or(profile)
+}
+
+/// Menu title of a generation: its number, the profile's display name unless it's the
+/// system profile, then `text` (a description or the bootspec label)
+pub fn generation_title(generation: u64, profile: Option<&str>, text: &str) -> String {
+    match profile {
+        Some(profile) => format!("Generation {} ({}) {}", generation, profile, text),
+        None => format!("Generation {} {}", generation, text),
+    }
+}
+
+/// Menu title of `profile`'s generation `generation`: `label` when given and not empty,
+/// else `description`. Installed and rendered entries are both titled this way.
+pub fn entry_title(
+    generation: u64,
+    profile: &str,
+    profile_labels: &HashMap<String, String>,
+    label: Option<&str>,
+    description: &str,
+) -> String {
+    let profile_name = (profile != "system")
+        .then(|| sanitize_title(profile_display_name(profile_labels, profile)));
+    let text = match label.map(str::trim) {
+        Some(label) if !label.is_empty() => sanitize_title(label),
+        _ => description.to_string(),
+    };
+    generation_title(generation, profile_name.as_deref(), &text)
+}
+
+/// What a generation is, for entries not titled with its label: its NixOS and kernel
+/// versions and when `link` to it was made
+pub fn describe_generation(
+    filesystem: &dyn Filesystem,
+    link: &Path,
+    bootspec: &BootSpec,
+) -> String {
+    let nixos_version = filesystem
+        .read_to_string(&bootspec.toplevel.join("nixos-version"))
+        .unwrap_or_else(|_| "Unknown".to_string())
+        .trim()
+        .to_string();
+
+    let kernel_version = bootspec
+        .kernel_version(filesystem)
+        .unwrap_or_else(|_| "unknown".to_string());
+
+    #[cfg(target_os = "linux")]
+    let sec = filesystem
+        .symlink_metadata(link)
+        .map(|md| std::os::unix::fs::MetadataExt::ctime(&md))
+        .ok();
+    #[cfg(not(target_os = "linux"))]
+    let sec = None;
+
+    let date = sec
+        .and_then(|sec| chrono::DateTime::from_timestamp(sec, 0))
+        .map(|dt| dt.date_naive().to_string())
+        .unwrap_or_else(|| "unknown-date".to_string());
+
+    format!(
+        "NixOS {}, Linux Kernel {}, Built on {}",
+        nixos_version, kernel_version, date
+    )
+}
+
+/// Make a profile or specialisation name safe to interpolate into a quoted menu title.
+///
+/// rEFInd titles can't contain double quotes, and braces or control characters break the
+/// surrounding stanza, so those are replaced. Warns once per name that had to change.
+pub fn sanitize_title(name: &str) -> String {
+    static WARNED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+    let sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            '"' => '\'',
+            '{' => '(',
+            '}' => ')',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect();
+
+    if sanitized != name && WARNED.lock().unwrap().insert(name.to_string()) {
+        crate::warn!("{:?} is shown as {:?} in menu titles", name, sanitized);
+    }
+
+    sanitized
+}
+
+/// Warn about `what` (e.g. "profile label") given for profiles that don't exist, which
+/// are most likely typos
+pub fn warn_unknown_profiles<'a>(
+    what: &str,
+    names: impl IntoIterator<Item = &'a String>,
+    profiles: &[String],
+) {
+    let mut unknown: Vec<&String> = names
+        .into_iter()
+        .filter(|name| *name != "system" && !profiles.contains(name))
+        .collect();
+    unknown.sort();
+
+    for name in unknown {
+        crate::warn!("{} given for unknown profile '{}'", what, name);
+    }
+}
+
+/// A generation link of a profile, on the filesystem it was discovered on. Nothing is
+/// read from it until asked.
+#[derive(Debug, Clone)]
+pub struct GenerationRef<'a> {
+    filesystem: &'a dyn Filesystem,
+    profiles_root: PathBuf,
+    pub profile: String,
+    pub number: u64,
+}
+
+impl GenerationRef<'_> {
+    /// The `<profile>-<number>-link` symlink
+    pub fn path(&self) -> PathBuf {
+        get_system_path(&self.profiles_root, &self.profile, Some(self.number), None)
+    }
+
+    pub fn load_bootspec(&self) -> Result<BootSpec> {
+        BootSpec::load(self.filesystem, &self.path())
+    }
+
+    /// Menu entry details as the dry-run renders them, without copying anything
+    pub fn load_details(
+        &self,
+        efi_mount: &Path,
+        layout: KernelLayout,
+    ) -> Result<crate::render::GenDetails> {
+        let g = crate::render::Gen {
+            profile: (self.profile != "system").then(|| self.profile.clone()),
+            number: self.number,
+        };
+        crate::render::generation_details(
+            self.filesystem,
+            &g,
+            &self.profiles_root,
+            efi_mount,
+            layout,
+            &[],
+            &[],
+        )
+    }
+}
+
+/// Lazy enumeration of the generations under a profiles root
+pub struct Generations;
+
+impl Generations {
+    /// Every generation of the system profile, then of each profile in `system-profiles/`
+    /// in name order. Within a profile, generations come newest first.
+    ///
+    /// A profile's directory is only listed once iteration reaches it, and generations are
+    /// never stat-ed or loaded; use [`GenerationRef::load_bootspec`] for that. A profile
+    /// that can't be listed yields a single `Err` and iteration carries on with the next one.
+    pub fn discover<'a>(
+        filesystem: &'a dyn Filesystem,
+        profiles_root: &Path,
+    ) -> impl Iterator<Item = Result<GenerationRef<'a>>> + 'a {
+        let root = profiles_root.to_path_buf();
+        let profiles_root = root.clone();
+
+        let named =
+            std::iter::once_with(move || get_profiles(filesystem, &root)).flat_map(|profiles| {
+                match profiles {
+                    Ok(mut profiles) => {
+                        profiles.sort();
+                        profiles.into_iter().map(Ok).collect()
+                    }
+                    Err(error) => vec![Err(error)],
+                }
+            });
+
+        std::iter::once(Ok("system".to_string()))
+            .chain(named)
+            .flat_map(move |profile| {
+                let listed = profile.and_then(|profile| {
+                    let numbers = list_generation_numbers(filesystem, &profiles_root, &profile)?;
+                    Ok((profile, numbers))
+                });
+                match listed {
+                    Ok((profile, numbers)) => numbers
+                        .into_iter()
+                        .map(|number| {
+                            Ok(GenerationRef {
+                                filesystem,
+                                profiles_root: profiles_root.clone(),
+                                profile: profile.clone(),
+                                number,
+                            })
+                        })
+                        .collect(),
+                    Err(error) => vec![Err(error)],
+                }
+            })
+    }
+}
+
+/// Generation numbers of a profile, newest first, from its `<profile>-<number>-link` names
+fn list_generation_numbers(
+    filesystem: &dyn Filesystem,
+    profiles_root: &Path,
+    profile: &str,
+) -> Result<Vec<u64>> {
+    let profile_path = get_system_path(profiles_root, profile, None, None);
+    let dir = profile_path
+        .parent()
+        .context("Profile path has no parent")?;
+    let basename = profile_path.file_name().unwrap().to_string_lossy();
+
+    let entries = filesystem
+        .read_dir(dir)
+        .with_context(|| format!("Failed to list generations in {}", dir.display()))?;
+
+    let mut numbers = Vec::new();
+    for entry in entries {
+        let name = entry
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string();
+        if let Some((owner, number)) = parse_generation_link(&name)
+            && owner == basename
+        {
+            numbers.push(number);
         }
     }
+    numbers.sort_by_key(|number| std::cmp::Reverse(*number));
+
+    Ok(numbers)
+}
+
+/// Subdirectories of `efi/refind` holding nothing but files refindgen staged, the only
+/// places unused files are removed from
+pub const MANAGED_DIRS: &[&str] = &["kernels", "tools"];
 
-    Ok(profiles)
+/// Directory on the ESP that a generation's kernels and initrds are staged into
+#[derive(Debug, Clone)]
+pub struct KernelDir {
+    pub path: PathBuf,
+    pub uri: String,
+    /// PARTUUID of the volume `uri` is on, when it isn't the ESP rEFInd runs from
+    pub volume: Option<String>,
 }
 
-pub fn get_generations(profile: &str, config: &InstallConfig) -> Result<Vec<u64>> {
-    let nix_env = config.nix_path.join("bin/nix-env");
-    let profile_path = get_system_path(profile, None, None);
+impl KernelDir {
+    pub fn new(refind_dir: &Path, layout: KernelLayout, profile: &str, generation: u64) -> Self {
+        let path = refind_dir.join("kernels");
+        let uri = "/efi/refind/kernels".to_string();
 
-    let output = Command::new("sudo")
-        .arg(nix_env)
+        match layout {
+            KernelLayout::Flat => Self {
+                path,
+                uri,
+                volume: None,
+            },
+            KernelLayout::PerGeneration => {
+                let subdir = format!("{}-{}", profile, generation);
+                Self {
+                    path: path.join(&subdir),
+                    uri: format!("{}/{}", uri, subdir),
+                    volume: None,
+                }
+            }
+        }
+    }
+}
+
+/// Journal lines logged by nixos-rebuild about activating a generation.
+///
+/// Returns nothing when systemd isn't running as PID 1 or nothing matched.
+pub fn get_generation_activation_log(generation: u64) -> Result<Vec<String>> {
+    if !Path::new("/run/systemd/system").is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let output = Command::new("journalctl")
+        .args(["-u", "nixos-rebuild", "--no-pager", "--grep"])
+        .arg(format!("Generation {}", generation))
+        .output()
+        .context("Failed to run journalctl")?;
+
+    // journalctl exits non-zero when --grep matches nothing
+    if !output.status.success() && !output.stderr.is_empty() {
+        anyhow::bail!(
+            "journalctl failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.starts_with("-- "))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Package changes between two generations of the system profile, as printed by
+/// `nix store diff-closures`
+pub fn get_profile_diff_closures(
+    from_gen: u64,
+    to_gen: u64,
+    config: &InstallConfig,
+) -> Result<String> {
+    diff_closures(
+        &nix_binary(Some(&config.nix_path)),
+        &get_system_path(&config.profiles_root, "system", Some(from_gen), None),
+        &get_system_path(&config.profiles_root, "system", Some(to_gen), None),
+    )
+}
+
+/// The `nix` command of the Nix package at `nix_path`, else the one on `PATH`
+pub fn nix_binary(nix_path: Option<&Path>) -> PathBuf {
+    match nix_path {
+        Some(nix_path) => nix_path.join("bin/nix"),
+        None => PathBuf::from("nix"),
+    }
+}
+
+/// `nix store diff-closures from to`, one changed package per line
+pub fn diff_closures(nix: &Path, from: &Path, to: &Path) -> Result<String> {
+    let output = Command::new(nix)
         .args([
-            "--list-generations",
-            "-p",
-            profile_path.to_str().unwrap(),
-            // "--option",
-            // "build-users-group",
-            // "",
+            "--extra-experimental-features",
+            "nix-command",
+            "store",
+            "diff-closures",
         ])
+        .args([from, to])
         .output()
-        .with_context(|| {
-            format!(
-                "Failed to run nix-env for profile path {}",
-                profile_path.display()
-            )
-        })?;
+        .with_context(|| format!("Failed to run {} store diff-closures", nix.display()))?;
 
     if !output.status.success() {
         anyhow::bail!(
-            "nix-env failed: {}",
+            "nix store diff-closures failed: {}",
             String::from_utf8_lossy(&output.stderr)
         );
     }
 
-    let stdout = String::from_utf8(output.stdout)?;
-    let mut generations: Vec<u64> = stdout
-        .lines()
-        .filter_map(|line| line.split_whitespace().next().and_then(|s| s.parse().ok()))
-        .collect();
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
 
-    // Keep only the last N generations
-    if generations.len() > config.max_generations {
-        let count = generations.len();
-        generations = generations
-            .into_iter()
-            .skip(count - config.max_generations)
-            .collect();
+/// Bytes taken by `toplevel` and everything it references, from `nix path-info -S`.
+///
+/// Without a working nix command, falls back to the files under `toplevel` itself,
+/// which undercounts but still ranks generations.
+pub fn closure_size(nix: &Path, toplevel: &Path) -> Result<u64> {
+    let output = Command::new(nix)
+        .args([
+            "--extra-experimental-features",
+            "nix-command",
+            "path-info",
+            "-S",
+        ])
+        .arg(toplevel)
+        .output();
+
+    if let Ok(output) = output
+        && output.status.success()
+        && let Some(size) = String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .nth(1)
+            .and_then(|size| size.parse().ok())
+    {
+        return Ok(size);
     }
 
-    Ok(generations)
+    let mut size = 0;
+    for entry in walkdir::WalkDir::new(toplevel) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Human-readable size, e.g. `1.4 GiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
 }
 
 pub fn generate_config_entry(
     profile: &str,
     generation: u64,
-    group_name: &str,
+    config: &InstallConfig,
     refind_dir: &Path,
+    volume: Option<&str>,
     file_tracker: &mut fs::FileTracker,
 ) -> Result<String> {
-    let gen_path = get_system_path(profile, Some(generation), None);
-    let bootspec = BootSpec::load(&gen_path)?;
+    let config = &config.for_profile(profile);
+    let gen_path = get_system_path(&config.profiles_root, profile, Some(generation), None);
+    let bootspec = BootSpec::load(file_tracker.filesystem(), &gen_path)?;
+    let kernel_dir = KernelDir {
+        volume: volume.map(str::to_string),
+        ..KernelDir::new(refind_dir, config.kernel_layout, profile, generation)
+    };
+
+    let entry_id = format!("{}-{}", profile, generation);
 
-    // Get generation timestamp
-    let metadata = std::fs::symlink_metadata(&gen_path)
-        .with_context(|| format!("Failed to get symlink metadata for {}", gen_path.display()))?;
-    let mtime = metadata.modified()?;
-    let datetime: DateTime<Local> = mtime.into();
-    let timestamp = datetime.format("%Y-%m-%d %H:%M:%S").to_string();
+    // NixOS' own label when asked for and present, else the synthesized description
+    let title = entry_title(
+        generation,
+        profile,
+        &config.profile_labels,
+        config.use_bootspec_label.then_some(bootspec.label.as_str()),
+        &describe_generation(file_tracker.filesystem(), &gen_path, &bootspec),
+    );
 
     let mut entry = String::new();
 
-    if !bootspec.specialisations.is_empty() {
+    if config.include_specialisations && !bootspec.specialisations.is_empty() {
         // Has specialisations - create nested menu
+        entry.push_str(&format!("menuentry \"{}\" {{\n", title));
         entry.push_str(&format!(
-            "menuentry \"NixOS {} Generation {}\" {{\n",
-            group_name, generation
+            "  ostype {}\n",
+            entry_ostype(config.ostype.as_deref(), &bootspec.system)
         ));
+        if let Some(ref icon) = config.icon {
+            entry.push_str(&format!("  icon {}\n", icon));
+        }
+        if let Some(ref volume) = kernel_dir.volume {
+            entry.push_str(&format!("  volume {}\n", volume));
+        }
 
         // Default entry
         entry.push_str(&format_boot_entry(
             true,
             &bootspec,
             "Default",
-            &timestamp,
-            refind_dir,
+            &entry_id,
+            &kernel_dir,
+            config,
             file_tracker,
         )?);
 
-        // Specialisation entries
-        for (spec_name, spec_bootspec) in &bootspec.specialisations {
+        // Specialisation entries, in name order so the config is stable
+        let mut specialisations: Vec<_> = bootspec.specialisations.iter().collect();
+        specialisations.sort_by(|a, b| a.0.cmp(b.0));
+        for (spec_name, spec_bootspec) in specialisations {
             entry.push_str(&format_boot_entry(
                 true,
                 spec_bootspec,
-                spec_name,
-                &timestamp,
-                refind_dir,
+                &sanitize_title(spec_name),
+                &format!("{}-{}", entry_id, file_name_safe(spec_name)),
+                &kernel_dir,
+                config,
                 file_tracker,
             )?);
         }
@@ -152,9 +571,10 @@ pub fn generate_config_entry(
         entry.push_str(&format_boot_entry(
             false,
             &bootspec,
-            &format!("NixOS {} Generation {}", group_name, generation),
-            &timestamp,
-            refind_dir,
+            &title,
+            &entry_id,
+            &kernel_dir,
+            config,
             file_tracker,
         )?);
     }
@@ -162,47 +582,537 @@ pub fn generate_config_entry(
     Ok(entry)
 }
 
+/// `name` with everything but ASCII alphanumerics, `.`, `-` and `_` replaced by `_`
+fn file_name_safe(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// rEFInd `ostype` for a Nix system double, e.g. `x86_64-linux` -> `Linux`
+pub fn derive_ostype_from_system(system: &str) -> String {
+    match system.rsplit('-').next() {
+        Some("darwin") => "MacOS",
+        Some("windows") => "Windows",
+        _ => "Linux",
+    }
+    .to_string()
+}
+
+/// The `ostype` of an entry booting `system`: the configured one, else derived from it
+pub fn entry_ostype(configured: Option<&str>, system: &str) -> String {
+    match configured {
+        Some(ostype) => ostype.to_string(),
+        None => derive_ostype_from_system(system),
+    }
+}
+
+/// `entry_id` names files unique to this entry, like an initrd with secrets appended
 fn format_boot_entry(
     is_submenu: bool,
     bootspec: &BootSpec,
     label: &str,
-    _timestamp: &str,
-    refind_dir: &Path,
+    entry_id: &str,
+    kernel_dir: &KernelDir,
+    config: &InstallConfig,
     file_tracker: &mut fs::FileTracker,
 ) -> Result<String> {
     let mut entry = String::new();
 
     let prefix = if is_submenu { "sub" } else { "" };
     entry.push_str(&format!("{}menuentry \"{}\" {{\n", prefix, label));
+    // Submenu entries take the icon and volume of the menuentry they're in
+    if !is_submenu {
+        entry.push_str(&format!(
+            "  ostype {}\n",
+            entry_ostype(config.ostype.as_deref(), &bootspec.system)
+        ));
+        if let Some(ref icon) = config.icon {
+            entry.push_str(&format!("  icon {}\n", icon));
+        }
+        if let Some(ref volume) = kernel_dir.volume {
+            entry.push_str(&format!("  volume {}\n", volume));
+        }
+    }
 
     // Copy kernel and get URI
-    let kernel_uri = copy_kernel_to_efi(&bootspec.kernel, refind_dir, file_tracker)?;
+    let kernel_uri = copy_kernel_to_efi(&bootspec.kernel, kernel_dir, config, file_tracker)?;
     entry.push_str(&format!("  loader {}\n", kernel_uri));
 
-    // Copy initrd if present
+    // Copy initrds, early ones (microcode) first
+    let mut initrd_uris = Vec::new();
+    for early in early_initrds(&config.early_initrds, bootspec) {
+        initrd_uris.push(copy_kernel_to_efi(
+            &early,
+            kernel_dir,
+            config,
+            file_tracker,
+        )?);
+    }
     if let Some(ref initrd) = bootspec.initrd {
-        let initrd_uri = copy_kernel_to_efi(initrd, refind_dir, file_tracker)?;
-        entry.push_str(&format!("  initrd {}\n", initrd_uri));
+        initrd_uris.push(match bootspec.initrd_secrets {
+            Some(ref script) => {
+                stage_initrd_with_secrets(initrd, script, entry_id, kernel_dir, file_tracker)?
+            }
+            None => copy_kernel_to_efi(initrd, kernel_dir, config, file_tracker)?,
+        });
     }
 
-    // Build kernel parameters
-    let mut params = vec![format!("init={}", bootspec.init.display())];
-    params.extend(bootspec.kernel_params.iter().cloned());
-    let params_str = params.join(" ");
-
-    entry.push_str(&format!("  options \"{}\"\n", params_str));
+    if let [initrd_uri] = initrd_uris.as_slice() {
+        entry.push_str(&format!("  initrd {}\n", initrd_uri));
+    }
+    let params = kernel_params(
+        bootspec,
+        &luks_kernel_params(&config.luks_devices, config.luks_param_style),
+    );
+    entry.push_str(&format!(
+        "  options {}\n",
+        entry_options(&params, &config.extra_kernel_params, &initrd_uris)?
+    ));
+    if config.enable_and_lock_vmx {
+        entry.push_str("  enable_and_lock_vmx true\n");
+    }
     entry.push_str("}\n");
 
     Ok(entry)
 }
 
-fn copy_kernel_to_efi(
-    source: &Path,
+/// The quoted value of an entry's `options` line: `kernel_params` with those of
+/// `extra_kernel_params` it lacks appended, after an `initrd=` for each of `initrd_uris`
+/// when there are several. A single initrd gets an `initrd` line instead.
+pub fn entry_options(
+    kernel_params: &str,
+    extra_kernel_params: &[String],
+    initrd_uris: &[String],
+) -> Result<String> {
+    let mut options = kernel_params.trim().to_string();
+    for param in extra_kernel_params {
+        if !options.split(' ').any(|existing| existing == param) {
+            options = format!("{} {}", options, param);
+        }
+    }
+    if let uris @ [_, _, ..] = initrd_uris {
+        options = format!("{} {}", initrd_options(uris), options);
+    }
+    quote_options(&options)
+}
+
+/// Where memtest86plus' EFI binary ends up in a system closure that includes it
+const MEMTEST_CANDIDATES: &[&str] = &[
+    "sw/share/memtest86plus/memtest.efi",
+    "sw/lib/memtest86plus/memtest.efi",
+];
+
+/// The memtest86plus binary to offer: `configured`, or one found in `bootspec`'s toplevel
+pub fn find_memtest(
+    filesystem: &dyn Filesystem,
+    configured: Option<&Path>,
+    bootspec: &BootSpec,
+) -> Result<Option<PathBuf>> {
+    if let Some(path) = configured {
+        if !filesystem.is_file(path) {
+            anyhow::bail!("memtest86Path does not exist: {}", path.display());
+        }
+        return Ok(Some(path.to_path_buf()));
+    }
+
+    Ok(MEMTEST_CANDIDATES
+        .iter()
+        .map(|candidate| bootspec.toplevel.join(candidate))
+        .find(|path| filesystem.is_file(path)))
+}
+
+/// A "MemTest86+" menu entry, staging the binary under `efi/refind/tools`.
+///
+/// Returns nothing when memtest isn't part of the configuration; a previously staged
+/// copy is then left unmarked and removed by the tracker's cleanup.
+pub fn generate_memtest_entry(
+    config: &InstallConfig,
+    bootspec: &BootSpec,
     refind_dir: &Path,
     file_tracker: &mut fs::FileTracker,
+) -> Result<Option<String>> {
+    let filesystem = file_tracker.filesystem();
+    let Some(memtest) = find_memtest(filesystem, config.memtest86_path.as_deref(), bootspec)?
+    else {
+        return Ok(None);
+    };
+
+    let loader = copy_kernel_to_efi(&memtest, &tools_dir(refind_dir), config, file_tracker)?;
+    Ok(Some(memtest_entry(&loader)))
+}
+
+/// Directory on the ESP that tools like memtest are staged into
+pub fn tools_dir(refind_dir: &Path) -> KernelDir {
+    KernelDir {
+        path: refind_dir.join("tools"),
+        uri: "/efi/refind/tools".to_string(),
+        volume: None,
+    }
+}
+
+/// The "MemTest86+" menu entry for a memtest binary staged at `loader`
+pub fn memtest_entry(loader: &str) -> String {
+    format!(
+        "menuentry \"MemTest86+\" {{\n  loader {}\n  icon /efi/refind/icons/tool_memtest.png\n}}\n",
+        loader
+    )
+}
+
+/// Stage `initrd` with the secrets appended by the generation's `append-initrd-secrets`
+/// script, under a name unique to the entry since secrets differ between generations.
+///
+/// Rebuilt on every run so changed secrets are picked up. The intermediate copy lives
+/// outside the ESP with 0600 permissions and is removed even on failure.
+fn stage_initrd_with_secrets(
+    initrd: &Path,
+    script: &Path,
+    entry_id: &str,
+    kernel_dir: &KernelDir,
+    file_tracker: &mut fs::FileTracker,
 ) -> Result<String> {
+    let file_name = format!("{}-initrd-secrets", entry_id);
+    let dest_path = kernel_dir.path.join(&file_name);
+
+    let filesystem = file_tracker.filesystem();
+    let temp = fs::PrivateTempFile::copy_of(&filesystem.real_path(initrd))?;
+    let status = Command::new(filesystem.real_path(script))
+        .arg(temp.path())
+        .status()
+        .with_context(|| format!("Failed to run {}", script.display()))?;
+    if !status.success() {
+        anyhow::bail!("{} failed with {}", script.display(), status);
+    }
+
+    let with_secrets = std::fs::read(temp.path())
+        .with_context(|| format!("Failed to read {}", temp.path().display()))?;
+    filesystem.write(&dest_path, &with_secrets, file_tracker.copy_settings())?;
+    file_tracker.mark_used(&dest_path);
+
+    Ok(format!("{}/{}", kernel_dir.uri, file_name))
+}
+
+/// A menu entry that has the firmware boot NVRAM entry `bootnum`, so Windows can be
+/// offered without knowing where its boot manager lives
+pub fn generate_firmware_bootnum_entry(bootnum: &str) -> Result<String> {
+    if bootnum.len() != 4 || !bootnum.bytes().all(|b| b.is_ascii_hexdigit()) {
+        anyhow::bail!(
+            "windowsFirmwareBootnum must be 4 hex digits like 0000, got '{}'",
+            bootnum
+        );
+    }
+
+    Ok(format!(
+        "menuentry \"Windows (via firmware)\" {{\n  firmware_bootnum {}\n}}\n",
+        bootnum
+    ))
+}
+
+/// Write a refind.conf that only offers a rescue shell, for when no NixOS entry could be
+/// built. Boots the newest kernel a previous run left in `efi/refind/kernels`, with the
+/// initrd staged beside it for the same kernel.
+///
+/// Newest goes by mtime, then by kernel version, as `--reproducible` gives every staged
+/// file the same mtime.
+pub fn write_fallback_config(
+    filesystem: &dyn Filesystem,
+    copies: &fs::CopySettings,
+    efi_mount: &Path,
+) -> Result<()> {
+    let refind_dir = efi_mount.join("efi/refind");
+    let kernels_dir = refind_dir.join("kernels");
+
+    let mut kernels = Vec::new();
+    let mut initrds = Vec::new();
+    let files = filesystem
+        .walk(&kernels_dir)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(_, is_dir)| !is_dir);
+    for (path, _) in files {
+        let Some((package, file)) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(staged_package)
+        else {
+            continue;
+        };
+        let package = package.to_string();
+        let modified = filesystem.metadata(&path).and_then(|m| m.modified()).ok();
+        if file == "initrd" {
+            initrds.push((modified, package, path));
+        } else if matches!(
+            check_kernel_compression_format(filesystem, &path),
+            Ok(KernelFormat::PeEfi)
+        ) {
+            kernels.push((modified, package, path));
+        }
+    }
+
+    // Copies of one kernel in several generation directories tie on both, the newest
+    // generation's wins
+    type Staged = (Option<std::time::SystemTime>, String, PathBuf);
+    fn newest(a: &Staged, b: &Staged) -> std::cmp::Ordering {
+        a.0.cmp(&b.0)
+            .then_with(|| natural_cmp(&a.1, &b.1))
+            .then_with(|| natural_cmp(&a.2.to_string_lossy(), &b.2.to_string_lossy()))
+    }
+    let (_, kernel_package, kernel) =
+        kernels
+            .iter()
+            .max_by(|a, b| newest(a, b))
+            .with_context(|| {
+                format!(
+                    "No staged kernel in {} to boot a rescue shell with",
+                    kernels_dir.display()
+                )
+            })?;
+
+    // The initrd NixOS built for this kernel is `initrd-<kernel package>`. A generation's
+    // own directory holds only its initrd, whatever it's called.
+    let beside: Vec<_> = initrds
+        .iter()
+        .filter(|(_, _, path)| path.parent() == kernel.parent())
+        .collect();
+    let initrd = beside
+        .iter()
+        .filter(|(_, package, _)| package.ends_with(&format!("-{}", kernel_package)))
+        .max_by(|a, b| newest(a, b))
+        .or_else(|| {
+            let per_generation = kernel.parent() != Some(kernels_dir.as_path());
+            (per_generation && beside.len() == 1).then(|| &beside[0])
+        })
+        .map(|(_, _, path)| path);
+    if initrd.is_none() {
+        crate::warn!(
+            "no initrd staged for {}, the rescue shell boots without one",
+            kernel.display()
+        );
+    }
+
+    let uri = |path: &Path| -> Result<String> {
+        let relative = path.strip_prefix(efi_mount)?;
+        Ok(format!("/{}", relative.display()))
+    };
+    let mut content = String::from("timeout 30\n\n");
+    content.push_str("menuentry \"NixOS rescue shell\" {\n");
+    content.push_str("  ostype Linux\n");
+    content.push_str(&format!("  loader {}\n", uri(kernel)?));
+    if let Some(initrd) = initrd {
+        content.push_str(&format!("  initrd {}\n", uri(initrd)?));
+    }
+    content.push_str(&format!("  options {}\n", quote_options("init=/bin/sh")?));
+    content.push_str("}\n");
+
+    filesystem.write(&refind_dir.join("refind.conf"), content.as_bytes(), copies)
+}
+
+/// The package name and store file name of a file [`kernel_destination`] named, e.g.
+/// `linux-6.6.30` and `bzImage` for `<hash>-linux-6.6.30-bzImage`
+fn staged_package(file_name: &str) -> Option<(&str, &str)> {
+    let (_, rest) = file_name.split_once('-')?;
+    rest.rsplit_once('-')
+}
+
+/// Compare with runs of digits as numbers, so `linux-6.10` comes after `linux-6.9`
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    fn runs(s: &str) -> impl Iterator<Item = &str> {
+        let mut rest = s;
+        std::iter::from_fn(move || {
+            let first = rest.chars().next()?;
+            let end = rest
+                .find(|c: char| c.is_ascii_digit() != first.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let (run, tail) = rest.split_at(end);
+            rest = tail;
+            Some(run)
+        })
+    }
+
+    let key = |run: &str| {
+        let digits = run.trim_start_matches('0');
+        if run.starts_with(|c: char| c.is_ascii_digit()) {
+            (0, digits.len(), digits.to_string())
+        } else {
+            (1, 0, run.to_string())
+        }
+    };
+    runs(a).map(key).cmp(runs(b).map(key))
+}
+
+/// Files a generation stages on the ESP, as (source, destination) pairs
+pub fn staged_files(
+    filesystem: &dyn Filesystem,
+    profile: &str,
+    generation: u64,
+    config: &InstallConfig,
+    refind_dir: &Path,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    fn collect(
+        filesystem: &dyn Filesystem,
+        bootspec: &BootSpec,
+        config: &InstallConfig,
+        kernel_dir: &KernelDir,
+        files: &mut Vec<(PathBuf, PathBuf)>,
+    ) -> Result<()> {
+        let sources = std::iter::once(bootspec.kernel.clone())
+            .chain(early_initrds(&config.early_initrds, bootspec))
+            .chain(bootspec.initrd.clone());
+        for source in sources {
+            let (dest, _) = kernel_destination(filesystem, &source, kernel_dir)?;
+            files.push((source, dest));
+        }
+        if config.include_specialisations {
+            for spec in bootspec.specialisations.values() {
+                collect(filesystem, spec, config, kernel_dir, files)?;
+            }
+        }
+        Ok(())
+    }
+
+    let config = &config.for_profile(profile);
+    let bootspec = BootSpec::load(
+        filesystem,
+        &get_system_path(&config.profiles_root, profile, Some(generation), None),
+    )?;
+    let kernel_dir = KernelDir::new(refind_dir, config.kernel_layout, profile, generation);
+
+    let mut files = Vec::new();
+    collect(filesystem, &bootspec, config, &kernel_dir, &mut files)?;
+    Ok(files)
+}
+
+/// Initrds loaded before a bootspec's own: the configured ones, then any from its
+/// boot.json extension
+pub fn early_initrds(configured: &[PathBuf], bootspec: &BootSpec) -> Vec<PathBuf> {
+    configured
+        .iter()
+        .cloned()
+        .chain(bootspec.early_initrds())
+        .collect()
+}
+
+/// `initrd=` kernel options for several initrds, loaded in order. rEFInd's `initrd`
+/// line takes a single file, so entries with more than one pass them all this way.
+pub fn initrd_options(uris: &[String]) -> String {
+    uris.iter()
+        .map(|uri| format!("initrd={}", uri.replace('/', "\\")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Kernel command line for a bootspec: `init=<init>` followed by its kernel params, then
+/// those of `luks_params` (from [`luks_kernel_params`]) that they don't already cover
+pub fn kernel_params(bootspec: &BootSpec, luks_params: &[String]) -> String {
+    let mut params = vec![format!("init={}", bootspec.init.display())];
+    params.extend(bootspec.kernel_params.iter().cloned());
+    for param in luks_params {
+        let keys = luks_param_keys(param);
+        let covered = params.iter().any(|existing| {
+            existing == param
+                || luks_param_keys(existing)
+                    .iter()
+                    .any(|key| keys.contains(key))
+        });
+        if !covered {
+            params.push(param.clone());
+        }
+    }
+    params.join(" ")
+}
+
+/// Kernel parameters unlocking `luksDevices`, (name, device) pairs, in `style`. Devices
+/// `rd.luks.name` can't name by UUID are left out.
+pub fn luks_kernel_params(devices: &[(String, String)], style: LuksParamStyle) -> Vec<String> {
+    devices
+        .iter()
+        .filter_map(|(name, device)| match style {
+            LuksParamStyle::RdLuksName => {
+                luks_device_uuid(device).map(|uuid| format!("rd.luks.name={}={}", uuid, name))
+            }
+            LuksParamStyle::LuksDevice => Some(format!("luks.{}.device={}", name, device)),
+            LuksParamStyle::Off => None,
+        })
+        .collect()
+}
+
+/// The UUID in a LUKS device spec: `/dev/disk/by-uuid/<uuid>`, `UUID=<uuid>` or a bare
+/// UUID
+pub fn luks_device_uuid(device: &str) -> Option<String> {
+    let uuid = device
+        .strip_prefix("/dev/disk/by-uuid/")
+        .or_else(|| device.strip_prefix("UUID="))
+        .unwrap_or(device);
+    let is_uuid = uuid.len() == 36
+        && uuid.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        });
+    is_uuid.then(|| uuid.to_lowercase())
+}
+
+/// What a LUKS kernel parameter unlocks, as `uuid:<uuid>` and `name:<name>` keys, so an
+/// injected parameter is dropped when the bootspec already unlocks the same device or
+/// maps the same name, in either spelling. Empty for other parameters.
+fn luks_param_keys(param: &str) -> Vec<String> {
+    let uuid_key = |uuid: &str| format!("uuid:{}", uuid.trim_start_matches("luks-").to_lowercase());
+    if let Some(rest) = param.strip_prefix("rd.luks.name=") {
+        return match rest.split_once('=') {
+            Some((uuid, name)) => vec![uuid_key(uuid), format!("name:{}", name)],
+            None => vec![uuid_key(rest)],
+        };
+    }
+    if let Some(uuid) = param.strip_prefix("rd.luks.uuid=") {
+        return vec![uuid_key(uuid)];
+    }
+    if let Some((name, _)) = param
+        .strip_prefix("luks.")
+        .and_then(|rest| rest.split_once(".device="))
+    {
+        return vec![format!("name:{}", name)];
+    }
+    Vec::new()
+}
+
+/// `options` as a refind.conf token.
+///
+/// rEFInd splits unquoted values at whitespace, `=` and `,` and turns `/` into `\`, so
+/// anything but a plain word is quoted, with embedded quotes doubled. Control characters
+/// can't be represented at all and are refused, naming the parameter they're in.
+pub fn quote_options(options: &str) -> Result<String> {
+    if let Some(param) = options
+        .split(' ')
+        .find(|param| param.chars().any(char::is_control))
+    {
+        anyhow::bail!(
+            "kernel parameter {:?} contains a control character, which refind.conf can't represent",
+            param
+        );
+    }
+
+    let plain = !options.is_empty()
+        && !options
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '=' | ',' | '/' | '#'));
+    if plain {
+        return Ok(options.to_string());
+    }
+    Ok(format!("\"{}\"", options.replace('"', "\"\"")))
+}
+
+/// Where a store file is staged in `kernel_dir`, and the URI rEFInd loads it from
+pub fn kernel_destination(
+    filesystem: &dyn Filesystem,
+    source: &Path,
+    kernel_dir: &KernelDir,
+) -> Result<(PathBuf, String)> {
     // Get package ID and suffix from store path
-    let source = std::fs::canonicalize(source)?;
+    let source = filesystem
+        .canonicalize(source)
+        .with_context(|| format!("Failed to resolve {}", source.display()))?;
     let parent = source.parent().context("No parent directory")?;
     let package_id = parent
         .file_name()
@@ -214,16 +1124,917 @@ fn copy_kernel_to_efi(
         .context("Invalid filename")?;
 
     let dest_filename = format!("{}-{}", package_id, suffix);
-    let dest_path = refind_dir.join("kernels").join(&dest_filename);
+    let dest_path = kernel_dir.path.join(&dest_filename);
+
+    // URI relative to EFI mount
+    Ok((dest_path, format!("{}/{}", kernel_dir.uri, dest_filename)))
+}
+
+fn copy_kernel_to_efi(
+    source: &Path,
+    kernel_dir: &KernelDir,
+    config: &InstallConfig,
+    file_tracker: &mut fs::FileTracker,
+) -> Result<String> {
+    let (dest_path, uri) = kernel_destination(file_tracker.filesystem(), source, kernel_dir)?;
 
-    // Copy if not exists
-    if !dest_path.exists() {
-        std::fs::create_dir_all(dest_path.parent().unwrap())?;
-        fs::copy_atomic(&source, &dest_path)?;
+    // Entries sharing a file only need it checked once per run
+    if !file_tracker.is_used(&dest_path) {
+        let copied = stage_file(
+            file_tracker.filesystem(),
+            source,
+            &dest_path,
+            config,
+            file_tracker.copy_settings(),
+        )?;
+        file_tracker.record_copy(copied);
     }
 
     file_tracker.mark_used(&dest_path);
+    file_tracker.mark_used(&sha256_sidecar(&dest_path));
+
+    Ok(uri)
+}
+
+/// Make `dest` a complete copy of the store file `source`, unless it already is. With
+/// `secureBoot` set, EFI binaries are signed copies instead. Returns what was copied.
+pub fn stage_file(
+    filesystem: &dyn Filesystem,
+    source: &Path,
+    dest: &Path,
+    config: &InstallConfig,
+    copies: &fs::CopySettings,
+) -> Result<fs::CopyStats> {
+    let sidecar_path = sha256_sidecar(dest);
+    let format = check_kernel_compression_format(filesystem, source)?;
+    let signer = match config.secure_boot {
+        Some(ref secure_boot) if format == KernelFormat::PeEfi => {
+            Some(crate::secureboot::cert_fingerprint(secure_boot)?)
+        }
+        _ => None,
+    };
+    let matches = staged_copy_matches(
+        filesystem,
+        copies,
+        source,
+        dest,
+        &sidecar_path,
+        signer.as_deref(),
+    )?;
+    if matches {
+        return Ok(fs::CopyStats::default());
+    }
 
-    // Return URI relative to EFI mount
-    Ok(format!("/efi/refind/kernels/{}", dest_filename))
+    if format == KernelFormat::ElfVmlinux {
+        anyhow::bail!(
+            "{} is an uncompressed ELF vmlinux without an EFI stub, rEFInd can't boot it",
+            source.display()
+        );
+    }
+
+    let parent = dest.parent().context("No parent directory")?;
+    filesystem
+        .create_dir_all(parent)
+        .with_context(|| format!("Failed to create {}", parent.display()))?;
+
+    let mut copied = fs::CopyStats::default();
+    if let (Some(secure_boot), Some(_)) = (&config.secure_boot, &signer) {
+        // sbsign and sbverify only know real paths
+        crate::secureboot::sign(
+            secure_boot,
+            &filesystem.real_path(source),
+            &filesystem.real_path(dest),
+            copies,
+        )?;
+    } else {
+        let linked = match config.shared_files {
+            SharedFiles::Hardlink => link_staged_copy(filesystem, dest),
+            SharedFiles::Duplicate => false,
+        };
+        if !linked {
+            copied = copy_to_esp(filesystem, config, copies, source, dest)?;
+        }
+    }
+    write_sha256_sidecar(
+        filesystem,
+        copies,
+        &sidecar_path,
+        &sha256(filesystem, source)?,
+        dest,
+        signer.as_deref(),
+    )?;
+    Ok(copied)
+}
+
+/// Whether `dest`, an EFI binary staged by [`stage_file`], is signed by the current
+/// `secureBoot` certificate, going by its sidecar
+pub fn is_signed_copy_current(
+    filesystem: &dyn Filesystem,
+    dest: &Path,
+    config: &InstallConfig,
+) -> Result<bool> {
+    let Some(ref secure_boot) = config.secure_boot else {
+        return Ok(false);
+    };
+    if !filesystem.exists(dest) {
+        return Ok(false);
+    }
+    let signer = crate::secureboot::cert_fingerprint(secure_boot)?;
+    Ok(current_sidecar(filesystem, dest, &sha256_sidecar(dest))?
+        .is_some_and(|(_, signed_by)| signed_by.as_deref() == Some(signer.as_str())))
+}
+
+/// Stage `files`, as (source, destination) pairs from [`staged_files`], on up to `jobs`
+/// threads ahead of building entries, which then find them in place.
+///
+/// Shared destinations are copied once. Returns what was copied and the failures, in
+/// destination order. They aren't fatal here; building the entry retries the copy and
+/// decides whether to skip it.
+pub fn stage_files_parallel(
+    filesystem: &dyn Filesystem,
+    files: &[(PathBuf, PathBuf)],
+    config: &InstallConfig,
+    copies: &fs::CopySettings,
+    jobs: usize,
+) -> (fs::CopyStats, Vec<(PathBuf, anyhow::Error)>) {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let unique: BTreeMap<&Path, &Path> = files
+        .iter()
+        .map(|(source, dest)| (dest.as_path(), source.as_path()))
+        .collect();
+    let unique: Vec<(&Path, &Path)> = unique.into_iter().collect();
+
+    let next = AtomicUsize::new(0);
+    let copied = Mutex::new(fs::CopyStats::default());
+    let failures = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.clamp(1, unique.len().max(1)) {
+            scope.spawn(|| {
+                while let Some(&(dest, source)) = unique.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    match stage_file(filesystem, source, dest, config, copies) {
+                        Ok(stats) => *copied.lock().unwrap() += stats,
+                        Err(error) => failures.lock().unwrap().push((dest.to_path_buf(), error)),
+                    }
+                }
+            });
+        }
+    });
+
+    let mut failures = failures.into_inner().unwrap();
+    failures.sort_by(|(a, _), (b, _)| a.cmp(b));
+    (copied.into_inner().unwrap(), failures)
+}
+
+/// Copy a file onto the ESP, verified unless `verifyCopies` is off
+pub fn copy_to_esp(
+    filesystem: &dyn Filesystem,
+    config: &InstallConfig,
+    copies: &fs::CopySettings,
+    source: &Path,
+    dest: &Path,
+) -> Result<fs::CopyStats> {
+    if config.verify_copies {
+        filesystem.copy_verified(source, dest, copies)
+    } else {
+        filesystem.copy(source, dest, copies)
+    }
+}
+
+/// Whether `dest` already has `source`'s content: the same size and XXH3
+pub fn same_content(filesystem: &dyn Filesystem, source: &Path, dest: &Path) -> Result<bool> {
+    let Ok(dest_metadata) = filesystem.metadata(dest) else {
+        return Ok(false);
+    };
+    let source_metadata = filesystem
+        .metadata(source)
+        .with_context(|| format!("Failed to stat {}", source.display()))?;
+    if !dest_metadata.is_file() || dest_metadata.len() != source_metadata.len() {
+        return Ok(false);
+    }
+    let xxh3 = |path: &Path| {
+        filesystem
+            .open(path)
+            .and_then(crate::hash::xxh3_reader)
+            .with_context(|| format!("Failed to read {}", path.display()))
+    };
+    Ok(xxh3(source)? == xxh3(dest)?)
+}
+
+/// SHA-256 of a file on `filesystem`
+fn sha256(filesystem: &dyn Filesystem, path: &Path) -> Result<String> {
+    filesystem
+        .open(path)
+        .and_then(crate::hash::sha256_reader)
+        .with_context(|| format!("Failed to read {}", path.display()))
+}
+
+/// `<dest>.sha256`, holding the SHA-256 of the store file `dest` was copied from, then
+/// the size and mtime `dest` had when it last matched that hash, then for a signed copy
+/// the SHA-256 of the certificate it was signed with
+pub fn sha256_sidecar(dest: &Path) -> PathBuf {
+    let mut sidecar = dest.as_os_str().to_owned();
+    sidecar.push(".sha256");
+    PathBuf::from(sidecar)
+}
+
+/// Size and mtime (ns since the epoch) of a file, which change whenever it's rewritten
+fn size_and_mtime(filesystem: &dyn Filesystem, path: &Path) -> Result<(u64, u128)> {
+    let metadata = filesystem
+        .metadata(path)
+        .with_context(|| format!("Failed to stat {}", path.display()))?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    Ok((metadata.len(), mtime))
+}
+
+fn write_sha256_sidecar(
+    filesystem: &dyn Filesystem,
+    copies: &fs::CopySettings,
+    sidecar: &Path,
+    sha256: &str,
+    dest: &Path,
+    signed_by: Option<&str>,
+) -> Result<()> {
+    let (size, mtime) = size_and_mtime(filesystem, dest)?;
+    let mut content = format!("{} {} {}", sha256, size, mtime);
+    if let Some(signer) = signed_by {
+        content.push_str(&format!(" {}", signer));
+    }
+    content.push('\n');
+    filesystem.write(sidecar, content.as_bytes(), copies)
+}
+
+/// The hash in `dest`'s sidecar and the certificate it was signed with, if any, when
+/// `dest` still has the size and mtime recorded with them
+fn current_sidecar(
+    filesystem: &dyn Filesystem,
+    dest: &Path,
+    sidecar: &Path,
+) -> Result<Option<(String, Option<String>)>> {
+    // Sidecars from before sizes and mtimes were recorded hold just the hash
+    let recorded = filesystem.read_to_string(sidecar).unwrap_or_default();
+    let mut fields = recorded.split_whitespace();
+    let recorded_hash = fields.next();
+    let recorded_stat = (
+        fields.next().and_then(|s| s.parse::<u64>().ok()),
+        fields.next().and_then(|s| s.parse::<u128>().ok()),
+    );
+
+    let signed_by = fields.next().map(str::to_string);
+
+    let (size, mtime) = size_and_mtime(filesystem, dest)?;
+    Ok(recorded_hash
+        .filter(|_| recorded_stat == (Some(size), Some(mtime)))
+        .map(|hash| (hash.to_string(), signed_by)))
+}
+
+/// SHA-256 of a file refindgen put on the ESP, from its sidecar while that's current. A
+/// signed copy's content isn't its source's, so it's always hashed.
+pub fn staged_sha256(filesystem: &dyn Filesystem, path: &Path) -> Result<String> {
+    match current_sidecar(filesystem, path, &sha256_sidecar(path))? {
+        Some((hash, None)) => Ok(hash),
+        _ => sha256(filesystem, path),
+    }
+}
+
+/// Whether `dest` is a complete copy of `source`. Catches copies cut short by an
+/// earlier run.
+///
+/// Sizes are compared first. When `dest` still has the size and mtime its sidecar
+/// recorded, the recorded hash is trusted; store files never change, so `source` needs no
+/// hashing either. Otherwise both are hashed and, if they match, the sidecar refreshed.
+///
+/// A copy that should be signed by `signer`, a certificate's SHA-256, differs from
+/// `source`, so only a current sidecar naming that certificate vouches for it.
+fn staged_copy_matches(
+    filesystem: &dyn Filesystem,
+    copies: &fs::CopySettings,
+    source: &Path,
+    dest: &Path,
+    sidecar: &Path,
+    signer: Option<&str>,
+) -> Result<bool> {
+    if !filesystem.exists(dest) {
+        return Ok(false);
+    }
+    if let Some(signer) = signer {
+        return Ok(current_sidecar(filesystem, dest, sidecar)?
+            .is_some_and(|(_, signed_by)| signed_by.as_deref() == Some(signer)));
+    }
+    if size_and_mtime(filesystem, dest)?.0 != size_and_mtime(filesystem, source)?.0 {
+        return Ok(false);
+    }
+    if let Some((_, signed_by)) = current_sidecar(filesystem, dest, sidecar)? {
+        return Ok(signed_by.is_none());
+    }
+
+    let expected = sha256(filesystem, source)?;
+    if sha256(filesystem, dest)? != expected {
+        return Ok(false);
+    }
+    write_sha256_sidecar(filesystem, copies, sidecar, &expected, dest, None)?;
+    Ok(true)
+}
+
+/// Image format of a kernel, as far as its magic bytes tell
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KernelFormat {
+    /// PE/COFF with an EFI stub (x86 bzImage, EFI-stub arm64 Image, UKIs)
+    PeEfi,
+    /// Uncompressed ELF vmlinux, not loadable by firmware
+    ElfVmlinux,
+    /// arm64 Image without the EFI stub
+    Arm64Image,
+    /// None of the above; holds the first bytes of the file
+    Unknown(Vec<u8>),
+}
+
+pub fn check_kernel_compression_format(
+    filesystem: &dyn Filesystem,
+    kernel: &Path,
+) -> Result<KernelFormat> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = filesystem
+        .open(kernel)
+        .with_context(|| format!("Failed to open kernel {}", kernel.display()))?;
+    let mut header = Vec::with_capacity(64);
+    file.by_ref().take(64).read_to_end(&mut header)?;
+
+    if header.starts_with(b"\x7fELF") {
+        return Ok(KernelFormat::ElfVmlinux);
+    }
+
+    // MZ stub whose e_lfanew (at 0x3c) points to a "PE\0\0" signature
+    if header.starts_with(b"MZ") && header.len() >= 0x40 {
+        let pe_offset = u32::from_le_bytes(header[0x3c..0x40].try_into().unwrap());
+        let mut signature = [0u8; 4];
+        if file.seek(SeekFrom::Start(pe_offset.into())).is_ok()
+            && file.read_exact(&mut signature).is_ok()
+            && signature == *b"PE\0\0"
+        {
+            return Ok(KernelFormat::PeEfi);
+        }
+    }
+
+    // arm64 Image header magic, see Documentation/arch/arm64/booting.rst
+    if header.get(56..60) == Some(b"ARM\x64") {
+        return Ok(KernelFormat::Arm64Image);
+    }
+
+    header.truncate(16);
+    Ok(KernelFormat::Unknown(header))
+}
+
+/// Hard-link `dest` to a copy of the same file already staged for another generation.
+///
+/// Staged file names are unique per store path, so a sibling generation directory holding
+/// a file of the same name holds the same content. Returns false if there is nothing to
+/// link to or the filesystem refuses (FAT has no hard links), in which case the caller copies.
+fn link_staged_copy(filesystem: &dyn Filesystem, dest: &Path) -> bool {
+    let (Some(gen_dir), Some(file_name)) = (dest.parent(), dest.file_name()) else {
+        return false;
+    };
+    let Some(kernels_dir) = gen_dir.parent() else {
+        return false;
+    };
+    let Ok(entries) = filesystem.read_dir(kernels_dir) else {
+        return false;
+    };
+
+    entries
+        .into_iter()
+        .map(|entry| entry.join(file_name))
+        .find(|candidate| candidate != dest && filesystem.is_file(candidate))
+        .is_some_and(|existing| filesystem.hard_link(&existing, dest).is_ok())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::fs::tests::{ScratchDir, put};
+
+    /// Profiles root of the trees [`add_generation`] builds
+    pub(crate) const PROFILES: &str = "/nix/var/nix/profiles";
+
+    /// Generation `number` of `profile` under [`PROFILES`], booting `linux` with `initrd`,
+    /// both store path names. Returns its toplevel.
+    pub(crate) fn add_generation(
+        scratch: &ScratchDir,
+        profile: &str,
+        number: u64,
+        linux: &str,
+        initrd: &str,
+    ) -> String {
+        let filesystem = scratch.rooted();
+        let toplevel = format!("/store/{}-{}-nixos-system", profile, number);
+        put(
+            filesystem.as_ref(),
+            &format!("/store/{}/bzImage", linux),
+            linux,
+        );
+        put(
+            filesystem.as_ref(),
+            &format!("/store/{}/initrd", initrd),
+            initrd,
+        );
+        let boot_json = serde_json::json!({
+            "org.nixos.bootspec.v1": {
+                "system": "x86_64-linux",
+                "init": format!("{}/init", toplevel),
+                "kernel": format!("/store/{}/bzImage", linux),
+                "kernelParams": ["quiet"],
+                "label": format!("NixOS 24.05 (Linux {})", number),
+                "toplevel": toplevel,
+                "initrd": format!("/store/{}/initrd", initrd),
+            }
+        });
+        put(
+            filesystem.as_ref(),
+            &format!("{}/boot.json", toplevel),
+            &boot_json.to_string(),
+        );
+
+        // Relative, so the link resolves inside the tree
+        let link = get_system_path(Path::new(PROFILES), profile, Some(number), None);
+        let dir = link.parent().unwrap();
+        std::fs::create_dir_all(filesystem.host_path(dir)).unwrap();
+        let up = "../".repeat(dir.components().count() - 1);
+        std::os::unix::fs::symlink(
+            format!("{}{}", up.trim_end_matches('/'), toplevel),
+            filesystem.host_path(&link),
+        )
+        .unwrap();
+        select_generation(scratch, profile, number);
+        toplevel
+    }
+
+    /// Point `profile` at its generation `number`, as activating it does
+    pub(crate) fn select_generation(scratch: &ScratchDir, profile: &str, number: u64) {
+        let filesystem = scratch.rooted();
+        let link = get_system_path(Path::new(PROFILES), profile, Some(number), None);
+        let current =
+            filesystem.host_path(&get_system_path(Path::new(PROFILES), profile, None, None));
+        let _ = std::fs::remove_file(&current);
+        std::os::unix::fs::symlink(link.file_name().unwrap(), current).unwrap();
+    }
+
+    #[test]
+    fn generation_links_split_at_the_last_number() {
+        for (name, parsed) in [
+            ("a-1-5-link", Some(("a-1", 5))),
+            ("kexec-64-3-link", Some(("kexec-64", 3))),
+            ("test-7-link", Some(("test", 7))),
+            ("system-120-link", Some(("system", 120))),
+            ("a-1", None),
+            ("kexec-64", None),
+            ("test", None),
+            ("test-link", None),
+            ("-3-link", None),
+            ("test--link", None),
+            ("test-3a-link", None),
+            ("test-99999999999999999999-link", None),
+        ] {
+            assert_eq!(parse_generation_link(name), parsed, "{}", name);
+        }
+    }
+
+    #[test]
+    fn profiles_may_end_in_numbers() {
+        let scratch = ScratchDir::new();
+        add_generation(&scratch, "system", 1, "linux", "initrd");
+        add_generation(&scratch, "a-1", 5, "linux", "initrd");
+        add_generation(&scratch, "kexec-64", 2, "linux", "initrd");
+        add_generation(&scratch, "kexec-64", 3, "linux", "initrd");
+        add_generation(&scratch, "test", 7, "linux", "initrd");
+        let filesystem = scratch.rooted();
+
+        assert_eq!(
+            get_profiles(filesystem.as_ref(), Path::new(PROFILES)).unwrap(),
+            ["a-1", "kexec-64", "test"]
+        );
+        let generations: Vec<_> = Generations::discover(filesystem.as_ref(), Path::new(PROFILES))
+            .map(|g| {
+                let g = g.unwrap();
+                (g.profile, g.number)
+            })
+            .collect();
+        assert_eq!(
+            generations,
+            [
+                ("system".to_string(), 1),
+                ("a-1".to_string(), 5),
+                ("kexec-64".to_string(), 3),
+                ("kexec-64".to_string(), 2),
+                ("test".to_string(), 7),
+            ]
+        );
+    }
+
+    #[test]
+    fn generations_come_by_profile_newest_first() {
+        let scratch = ScratchDir::new();
+        for number in [2, 10, 9, 5_000_000_000] {
+            add_generation(&scratch, "system", number, "linux", "initrd");
+        }
+        add_generation(&scratch, "zeta", 1, "linux", "initrd");
+        add_generation(&scratch, "alpha", 3, "linux", "initrd");
+        add_generation(&scratch, "alpha", 20, "linux", "initrd");
+
+        let generations: Vec<_> =
+            Generations::discover(scratch.rooted().as_ref(), Path::new(PROFILES))
+                .map(|g| {
+                    let g = g.unwrap();
+                    (g.profile, g.number)
+                })
+                .collect();
+        let expected = [
+            ("system", 5_000_000_000),
+            ("system", 10),
+            ("system", 9),
+            ("system", 2),
+            ("alpha", 20),
+            ("alpha", 3),
+            ("zeta", 1),
+        ];
+        assert_eq!(
+            generations,
+            expected.map(|(profile, number)| (profile.to_string(), number))
+        );
+    }
+
+    #[test]
+    fn unlistable_profiles_are_one_error() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        let mut generations = Generations::discover(filesystem.as_ref(), Path::new("/missing"));
+
+        let error = generations.next().unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "Failed to list generations in /missing");
+        assert!(generations.next().is_none());
+    }
+
+    #[test]
+    fn broken_generations_fail_when_loaded() {
+        let scratch = ScratchDir::new();
+        add_generation(&scratch, "system", 1, "linux", "initrd");
+        let broken = add_generation(&scratch, "system", 2, "linux", "initrd");
+        put(
+            scratch.rooted().as_ref(),
+            &format!("{}/boot.json", broken),
+            "{",
+        );
+
+        // Discovery doesn't read generations, so the broken one is listed all the same
+        let filesystem = scratch.rooted();
+        let generations: Vec<_> = Generations::discover(filesystem.as_ref(), Path::new(PROFILES))
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(generations.len(), 2);
+
+        let error = generations[0].load_bootspec().unwrap_err();
+        assert!(
+            error.to_string().starts_with("Invalid boot.json at"),
+            "{:#}",
+            error
+        );
+        assert!(
+            generations[0]
+                .load_details(Path::new("/boot"), KernelLayout::Flat)
+                .is_err()
+        );
+        assert_eq!(generations[1].number, 1);
+        assert_eq!(
+            generations[1].load_bootspec().unwrap().label,
+            "NixOS 24.05 (Linux 1)"
+        );
+    }
+
+    #[test]
+    fn titles_lose_quotes_braces_and_control_characters() {
+        let (titles, warned) = crate::log::tests::warnings(|| {
+            [
+                "gaming",
+                "my \"work\" box",
+                "{nested}",
+                "two\nlines\tand tab",
+                "my \"work\" box",
+            ]
+            .map(sanitize_title)
+        });
+
+        assert_eq!(
+            titles,
+            [
+                "gaming",
+                "my 'work' box",
+                "(nested)",
+                "two lines and tab",
+                "my 'work' box"
+            ]
+        );
+        assert_eq!(
+            warned,
+            [
+                r#""my \"work\" box" is shown as "my 'work' box" in menu titles"#,
+                r#""{nested}" is shown as "(nested)" in menu titles"#,
+                r#""two\nlines\tand tab" is shown as "two lines and tab" in menu titles"#,
+            ],
+            "warned once per name"
+        );
+    }
+
+    #[test]
+    fn options_are_quoted_unless_plain() {
+        for (options, quoted) in [
+            ("quiet", "quiet"),
+            ("", "\"\""),
+            ("quiet splash", "\"quiet splash\""),
+            ("init=/bin/sh", "\"init=/bin/sh\""),
+            ("console=tty0,115200", "\"console=tty0,115200\""),
+            ("/bin/sh", "\"/bin/sh\""),
+            ("#1", "\"#1\""),
+            (r#"label="my disk""#, r#""label=""my disk""""#),
+        ] {
+            assert_eq!(quote_options(options).unwrap(), quoted, "{}", options);
+        }
+    }
+
+    #[test]
+    fn control_characters_in_options_are_refused() {
+        for options in ["quiet\tsplash", "root=/dev/sda1 init=/bin/sh\n", "a=\u{7}"] {
+            let message = quote_options(options).unwrap_err().to_string();
+            assert!(
+                message.contains("contains a control character"),
+                "{}",
+                message
+            );
+        }
+        let message = quote_options("quiet root=/dev/sda1\r")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            message.starts_with(r#"kernel parameter "root=/dev/sda1\r""#),
+            "names the parameter: {}",
+            message
+        );
+    }
+
+    const LUKS_UUID: &str = "0f3b2c1d-4e5f-4a6b-8c7d-9e0f1a2b3c4d";
+
+    fn bootspec(kernel_params: &[&str]) -> BootSpec {
+        BootSpec {
+            system: "x86_64-linux".into(),
+            init: PathBuf::from("/nix/store/abc-nixos-system/init"),
+            kernel: PathBuf::from("/nix/store/abc-linux/bzImage"),
+            kernel_params: kernel_params
+                .iter()
+                .map(|param| param.to_string())
+                .collect(),
+            label: "NixOS".into(),
+            toplevel: PathBuf::from("/nix/store/abc-nixos-system"),
+            initrd: None,
+            initrd_secrets: None,
+            specialisations: HashMap::new(),
+            extensions: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn luks_params_in_each_style() {
+        let devices = [
+            (
+                "cryptroot".to_string(),
+                format!("/dev/disk/by-uuid/{}", LUKS_UUID),
+            ),
+            ("cryptswap".to_string(), "/dev/nvme0n1p3".to_string()),
+        ];
+        assert_eq!(
+            luks_kernel_params(&devices, LuksParamStyle::RdLuksName),
+            [format!("rd.luks.name={}=cryptroot", LUKS_UUID)],
+            "a device without a UUID can't be named"
+        );
+        assert_eq!(
+            luks_kernel_params(&devices, LuksParamStyle::LuksDevice),
+            [
+                format!("luks.cryptroot.device=/dev/disk/by-uuid/{}", LUKS_UUID),
+                "luks.cryptswap.device=/dev/nvme0n1p3".to_string(),
+            ]
+        );
+        assert!(luks_kernel_params(&devices, LuksParamStyle::Off).is_empty());
+    }
+
+    #[test]
+    fn luks_device_uuids() {
+        for device in [
+            LUKS_UUID.to_string(),
+            LUKS_UUID.to_uppercase(),
+            format!("UUID={}", LUKS_UUID),
+            format!("/dev/disk/by-uuid/{}", LUKS_UUID),
+        ] {
+            assert_eq!(
+                luks_device_uuid(&device).as_deref(),
+                Some(LUKS_UUID),
+                "{}",
+                device
+            );
+        }
+        assert_eq!(luks_device_uuid("/dev/sda2"), None);
+        assert_eq!(luks_device_uuid("0f3b2c1d4e5f4a6b8c7d9e0f1a2b3c4d"), None);
+    }
+
+    #[test]
+    fn luks_params_are_appended_once() {
+        let injected = [format!("rd.luks.name={}=cryptroot", LUKS_UUID)];
+        assert_eq!(
+            kernel_params(&bootspec(&["quiet"]), &injected),
+            format!(
+                "init=/nix/store/abc-nixos-system/init quiet rd.luks.name={}=cryptroot",
+                LUKS_UUID
+            )
+        );
+
+        // The bootspec already unlocks the device or maps the name, in any spelling
+        for existing in [
+            format!("rd.luks.name={}=cryptroot", LUKS_UUID),
+            format!("rd.luks.name={}=root", LUKS_UUID.to_uppercase()),
+            format!("rd.luks.uuid=luks-{}", LUKS_UUID),
+            "rd.luks.name=11111111-2222-3333-4444-555555555555=cryptroot".to_string(),
+            "luks.cryptroot.device=/dev/sda2".to_string(),
+        ] {
+            assert_eq!(
+                kernel_params(&bootspec(&[&existing]), &injected),
+                format!("init=/nix/store/abc-nixos-system/init {}", existing),
+                "{}",
+                existing
+            );
+        }
+    }
+
+    #[test]
+    fn unrelated_params_dont_cover_luks_ones() {
+        let injected = ["luks.cryptroot.device=/dev/sda2".to_string()];
+        assert_eq!(
+            kernel_params(
+                &bootspec(&["root=/dev/mapper/cryptroot", "luks=yes"]),
+                &injected
+            ),
+            "init=/nix/store/abc-nixos-system/init root=/dev/mapper/cryptroot luks=yes \
+             luks.cryptroot.device=/dev/sda2"
+        );
+    }
+
+    /// The smallest file [`check_kernel_compression_format`] takes for a PE kernel
+    fn pe_kernel() -> Vec<u8> {
+        let mut image = vec![0u8; 0x44];
+        image[..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&0x40u32.to_le_bytes());
+        image[0x40..].copy_from_slice(b"PE\0\0");
+        image
+    }
+
+    /// Stage `name` below the ESP's kernels dir, with the mtime `--reproducible` gives
+    fn stage(filesystem: &dyn Filesystem, name: &str, content: &[u8]) {
+        let path = Path::new("/boot/efi/refind/kernels").join(name);
+        filesystem
+            .write(&path, content, &fs::CopySettings::default())
+            .unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(filesystem.real_path(&path))
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1))
+            .unwrap();
+    }
+
+    fn fallback(filesystem: &dyn Filesystem) -> String {
+        write_fallback_config(filesystem, &fs::CopySettings::default(), Path::new("/boot"))
+            .unwrap();
+        filesystem
+            .read_to_string(Path::new("/boot/efi/refind/refind.conf"))
+            .unwrap()
+    }
+
+    #[test]
+    fn fallback_pairs_the_newest_kernel_with_its_initrd() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        stage(filesystem.as_ref(), "aaaa-linux-6.9-bzImage", &pe_kernel());
+        stage(filesystem.as_ref(), "bbbb-linux-6.10-bzImage", &pe_kernel());
+        stage(
+            filesystem.as_ref(),
+            "cccc-initrd-linux-6.9-initrd",
+            b"initrd",
+        );
+        stage(
+            filesystem.as_ref(),
+            "dddd-initrd-linux-6.10-initrd",
+            b"initrd",
+        );
+        stage(
+            filesystem.as_ref(),
+            "eeee-initrd-linux-6.9-initrd.sha256",
+            b"sha",
+        );
+
+        let conf = fallback(filesystem.as_ref());
+        assert!(conf.starts_with("timeout 30\n"));
+        assert!(conf.contains("  loader /efi/refind/kernels/bbbb-linux-6.10-bzImage\n"));
+        assert!(conf.contains("  initrd /efi/refind/kernels/dddd-initrd-linux-6.10-initrd\n"));
+        assert!(conf.contains("  options \"init=/bin/sh\"\n"));
+    }
+
+    #[test]
+    fn fallback_takes_the_initrd_of_the_kernels_generation() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        stage(
+            filesystem.as_ref(),
+            "system-2/aaaa-linux-6.6-bzImage",
+            &pe_kernel(),
+        );
+        stage(
+            filesystem.as_ref(),
+            "system-2/bbbb-initrd-2-initrd",
+            b"initrd",
+        );
+        stage(
+            filesystem.as_ref(),
+            "system-3/aaaa-linux-6.6-bzImage",
+            &pe_kernel(),
+        );
+        stage(
+            filesystem.as_ref(),
+            "system-3/cccc-initrd-3-initrd",
+            b"initrd",
+        );
+
+        let conf = fallback(filesystem.as_ref());
+        assert!(conf.contains("  loader /efi/refind/kernels/system-3/aaaa-linux-6.6-bzImage\n"));
+        assert!(conf.contains("  initrd /efi/refind/kernels/system-3/cccc-initrd-3-initrd\n"));
+    }
+
+    #[test]
+    fn fallback_goes_by_file_name_not_directory() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        stage(
+            filesystem.as_ref(),
+            "initrd-lab-1/aaaa-linux-6.6-bzImage",
+            &pe_kernel(),
+        );
+        stage(
+            filesystem.as_ref(),
+            "initrd-lab-1/bbbb-initrd-linux-6.6-initrd",
+            b"initrd",
+        );
+
+        let conf = fallback(filesystem.as_ref());
+        assert!(
+            conf.contains("  loader /efi/refind/kernels/initrd-lab-1/aaaa-linux-6.6-bzImage\n")
+        );
+        assert!(conf.contains("initrd-lab-1/bbbb-initrd-linux-6.6-initrd\n"));
+    }
+
+    #[test]
+    fn fallback_leaves_out_an_initrd_for_another_kernel() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        stage(filesystem.as_ref(), "aaaa-linux-6.6-bzImage", &pe_kernel());
+        stage(
+            filesystem.as_ref(),
+            "bbbb-initrd-linux-6.1-initrd",
+            b"initrd",
+        );
+
+        let conf = fallback(filesystem.as_ref());
+        assert!(conf.contains("  loader /efi/refind/kernels/aaaa-linux-6.6-bzImage\n"));
+        assert!(!conf.contains("  initrd "));
+    }
+
+    #[test]
+    fn fallback_needs_a_kernel() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        stage(
+            filesystem.as_ref(),
+            "bbbb-initrd-linux-6.6-initrd",
+            b"initrd",
+        );
+        let copies = fs::CopySettings::default();
+        assert!(write_fallback_config(filesystem.as_ref(), &copies, Path::new("/boot")).is_err());
+    }
+
+    #[test]
+    fn natural_order_compares_numbers() {
+        use std::cmp::Ordering;
+
+        assert_eq!(natural_cmp("linux-6.9", "linux-6.10"), Ordering::Less);
+        assert_eq!(natural_cmp("linux-6.10", "linux-6.10"), Ordering::Equal);
+        assert_eq!(natural_cmp("linux-6.010", "linux-6.9"), Ordering::Greater);
+        assert_eq!(natural_cmp("linux-6.6", "linux-6.6-rc1"), Ordering::Less);
+    }
 }
@@ -0,0 +1,123 @@
+//! Content hashes for verifying staged files.
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::io::Read;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use xxhash_rust::xxh3::Xxh3;
+
+/// XXH3 (64-bit) of a file's contents, read in chunks
+pub fn xxh3_file(path: &Path) -> Result<u64> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    xxh3_reader(file).with_context(|| format!("Failed to read {:?}", path))
+}
+
+/// XXH3 (64-bit) of everything `reader` yields, read in chunks
+pub fn xxh3_reader(mut reader: impl Read) -> std::io::Result<u64> {
+    let mut hasher = Xxh3::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher.digest())
+}
+
+/// BLAKE3 of a file as the device holds it rather than as the page cache does: the file
+/// is synced and its cached pages dropped before it's read
+pub fn blake3_file_uncached(path: &Path) -> Result<blake3::Hash> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    file.sync_all()
+        .with_context(|| format!("Failed to sync {:?}", path))?;
+    // Only advice, so a kernel that ignores it costs the check, not the copy
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+    let mut hasher = blake3::Hasher::new();
+    hasher
+        .update_reader(file)
+        .with_context(|| format!("Failed to read {:?}", path))?;
+    Ok(hasher.finalize())
+}
+
+/// Lowercase hex SHA-256 of a file's contents, read in chunks
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    sha256_reader(file).with_context(|| format!("Failed to read {:?}", path))
+}
+
+/// Lowercase hex SHA-256 of everything `reader` yields, read in chunks
+pub fn sha256_reader(mut reader: impl Read) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sha256(data: &[u8]) -> String {
+        sha256_reader(data).unwrap()
+    }
+
+    fn counting(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn xxh3_reader_matches_one_shot() {
+        // Longer than one read, so the streaming state carries across chunks
+        let data = counting(200_000);
+        assert_eq!(
+            xxh3_reader(&data[..]).unwrap(),
+            xxhash_rust::xxh3::xxh3_64(&data)
+        );
+        assert_eq!(xxh3_reader(&b""[..]).unwrap(), 0x2d06800538d394c2);
+    }
+
+    #[test]
+    fn blake3_file_uncached_matches_one_shot() {
+        let scratch = crate::fs::tests::ScratchDir::new();
+        let path = scratch.path().join("data");
+        let data = counting(200_000);
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(blake3_file_uncached(&path).unwrap(), blake3::hash(&data));
+        assert!(blake3_file_uncached(&scratch.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn sha256_vectors() {
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(
+            sha256(&vec![b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+}
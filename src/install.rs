@@ -0,0 +1,1453 @@
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+
+use crate::{
+    bootspec::BootSpec,
+    config::{ExtraConfig, InstallConfig, LuksParamStyle},
+    efi, fs, generation,
+    manifest::{self, Manifest},
+    preflight, render,
+};
+
+/// Settings for an install run that don't come from the install config
+#[derive(Debug, Clone, Default)]
+pub struct InstallOptions {
+    /// MiB of ESP space to leave free when deciding how many generations fit
+    pub esp_reserve_mib: u64,
+    /// Fail on the first generation that can't be loaded instead of skipping it
+    pub strict: bool,
+    /// Handcrafted refind.conf whose refindgen block is replaced, instead of writing
+    /// a whole new config
+    pub merge_with: Option<PathBuf>,
+    /// Attempts per ESP file copy on transient errors, instead of
+    /// [`fs::DEFAULT_COPY_RETRIES`]
+    pub copy_retries: Option<u32>,
+    /// Bytes per read/write of ESP copies, instead of [`fs::DEFAULT_COPY_CHUNK_SIZE`]
+    pub copy_chunk_size: Option<usize>,
+    /// Set to make ESP copies in progress stop at their next chunk and fail the run, e.g.
+    /// from a signal handler
+    pub cancel_copies: Arc<AtomicBool>,
+    /// Write a rescue-shell refind.conf when the menu can't be built, instead of leaving
+    /// the previous config in place
+    pub fallback_config: bool,
+    /// Drop the oldest generations when the ESP is too small for all of them, instead of
+    /// failing before anything is copied
+    pub auto_trim: bool,
+    /// Kernels and initrds copied at once, instead of one per CPU
+    pub jobs: Option<usize>,
+    /// Remove unused files even if `staleFileRetentionDays` would keep them
+    pub force_cleanup: bool,
+    /// Give every written file a fixed mtime, so identical installs produce identical trees
+    pub reproducible: bool,
+    /// Write even if the ESP isn't a read-write FAT mount point
+    pub skip_esp_checks: bool,
+    /// Print the efibootmgr commands or EFI variable writes that would change NVRAM
+    /// instead of making them
+    pub print_efibootmgr: bool,
+    /// Install files only and leave NVRAM alone, e.g. when preparing a disk offline
+    pub skip_nvram: bool,
+    /// Also delete NVRAM entries with our label that boot another ESP
+    pub prune_foreign: bool,
+    /// Hand-written config added after the install config's `extraConfig`, in order
+    pub extra_config: Vec<ExtraConfig>,
+}
+
+impl InstallOptions {
+    /// How this run copies files onto the ESP
+    pub fn copy_settings(&self) -> fs::CopySettings {
+        let mut copies = fs::CopySettings::default();
+        if let Some(attempts) = self.copy_retries {
+            copies.retries = attempts.max(1);
+        }
+        if let Some(bytes) = self.copy_chunk_size {
+            copies.chunk_size = bytes.max(4096);
+        }
+        copies.cancel = self.cancel_copies.clone();
+        copies.reproducible = self.reproducible;
+        copies
+    }
+}
+
+/// What an install run did
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    /// Generations left out of the menu because they failed to load, with the reason
+    pub skipped: Vec<String>,
+    /// Unused files removed from the ESP
+    pub cleanup: fs::CleanupSummary,
+    /// All copies to the ESP taken together
+    pub copied: fs::CopyStats,
+    /// ESP this report is about
+    pub efi_mount_point: PathBuf,
+    /// What was done on each of `additionalEspMounts` that succeeded, in order
+    pub additional_esps: Vec<Report>,
+}
+
+/// Installs rEFInd to the ESP: stages kernels, writes refind.conf, sets up the NVRAM
+/// entry and removes files no generation uses anymore.
+#[derive(Debug, Clone)]
+pub struct Installer {
+    config: InstallConfig,
+    options: InstallOptions,
+    filesystem: Arc<dyn fs::Filesystem>,
+}
+
+impl Installer {
+    pub fn new(config: InstallConfig) -> Self {
+        Self {
+            config,
+            options: InstallOptions::default(),
+            filesystem: Arc::new(fs::RealFs),
+        }
+    }
+
+    /// Read generations, stage and remove files and check free space through `filesystem`
+    /// instead of the real one
+    pub fn filesystem(mut self, filesystem: Arc<dyn fs::Filesystem>) -> Self {
+        self.filesystem = filesystem;
+        self
+    }
+
+    pub fn options(mut self, options: InstallOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Run the install and sync the ESP
+    pub fn run(&self) -> Result<Report> {
+        self.preflight()?;
+
+        let mut report = install_bootloader(&self.config, &self.options, &self.filesystem)?;
+
+        fs::sync_filesystem(&self.filesystem.real_path(&self.config.efi_mount_point))?;
+        if let Some(ref boot) = self.config.boot_mount_point {
+            fs::sync_filesystem(&self.filesystem.real_path(boot))?;
+        }
+
+        // Mirrors of the ESP are nice to have unless the install is strict
+        for (index, mount) in self.config.additional_esp_mounts.iter().enumerate() {
+            let config = self.config.for_additional_esp(mount, index);
+            crate::info!("Installing to additional ESP {}...", mount.display());
+            match self.install_additional_esp(&config) {
+                Ok(additional) => report.additional_esps.push(additional),
+                Err(error) if self.options.strict => {
+                    return Err(error.context(format!(
+                        "Failed to install to additional ESP {}",
+                        mount.display()
+                    )));
+                }
+                Err(error) => crate::warn!(
+                    "could not install to additional ESP {}: {:#}",
+                    mount.display(),
+                    error
+                ),
+            }
+        }
+
+        Ok(report)
+    }
+
+    fn install_additional_esp(&self, config: &InstallConfig) -> Result<Report> {
+        if !self.options.skip_esp_checks {
+            preflight::check_mounts(config)?;
+        }
+        let report = install_bootloader(config, &self.options, &self.filesystem)?;
+        fs::sync_filesystem(&self.filesystem.real_path(&config.efi_mount_point))?;
+        Ok(report)
+    }
+
+    fn preflight(&self) -> Result<()> {
+        if self.writes_nvram() {
+            preflight::check_firmware()?;
+        }
+        if self.options.skip_esp_checks {
+            return Ok(());
+        }
+        preflight::check_mounts(&self.config)
+    }
+
+    /// Whether the install will create or update NVRAM entries
+    fn writes_nvram(&self) -> bool {
+        self.config.can_touch_efi_variables
+            && !self.config.efi_removable
+            && !self.options.skip_nvram
+    }
+
+    /// Put the most recent refind.conf backup back in place and sync the ESP. The backup
+    /// is consumed, so rolling back again goes one config further back.
+    pub fn rollback(&self) -> Result<PathBuf> {
+        if !self.options.skip_esp_checks {
+            preflight::check_mounts(&self.config)?;
+        }
+        let config_path = self.config.efi_mount_point.join("efi/refind/refind.conf");
+        let filesystem = self.filesystem.as_ref();
+        let backup = config_backups(filesystem, &config_path)?
+            .pop()
+            .context("No refind.conf backup to roll back to")?;
+
+        filesystem.copy(&backup, &config_path, &self.options.copy_settings())?;
+        filesystem
+            .remove_file(&backup)
+            .with_context(|| format!("Failed to remove backup: {:?}", backup))?;
+        fs::sync_filesystem(&filesystem.real_path(&self.config.efi_mount_point))?;
+
+        crate::info!("Restored refind.conf from {}", backup.display());
+        Ok(backup)
+    }
+}
+
+fn install_bootloader(
+    config: &InstallConfig,
+    options: &InstallOptions,
+    filesystem: &Arc<dyn fs::Filesystem>,
+) -> Result<Report> {
+    let refind_dir = config.efi_mount_point.join("efi/refind");
+
+    // Kernels live on the XBOOTLDR partition when there is one, which entries then name
+    let (kernel_root, volume) = match config.boot_mount_point {
+        Some(ref boot) => (
+            boot.join("efi/refind"),
+            Some(efi::partition_uuid(&filesystem.real_path(boot))?),
+        ),
+        None => (refind_dir.clone(), None),
+    };
+
+    // Temp files of crashed runs would otherwise linger forever
+    for dir in [&refind_dir, &kernel_root] {
+        let removed = fs::sweep_temp_files(filesystem.as_ref(), dir, fs::STALE_TEMP_AGE)?;
+        if removed > 0 {
+            crate::info!(
+                "Removed {} stale temp file(s) from {}",
+                removed,
+                dir.display()
+            );
+        }
+    }
+
+    // Track all files for cleanup, including any a previous run recorded as its own
+    let copies = options.copy_settings();
+    let mut file_tracker = fs::FileTracker::with_filesystem(
+        filesystem.clone(),
+        &kernel_root,
+        generation::MANAGED_DIRS,
+    )?
+    .with_copy_settings(copies.clone());
+    let manifest_path = refind_dir.join(manifest::MANIFEST_NAME);
+    let previous = Manifest::load(filesystem.as_ref(), &manifest_path).unwrap_or_else(|error| {
+        crate::warn!("ignoring the previous manifest: {:#}", error);
+        None
+    });
+    if let Some(ref previous) = previous {
+        file_tracker.track_previous(previous.files.keys());
+    }
+
+    if config.luks_param_style == LuksParamStyle::RdLuksName {
+        for (name, device) in &config.luks_devices {
+            if generation::luks_device_uuid(device).is_none() {
+                crate::warn!(
+                    "LUKS device {} ({}) is not given by UUID, leaving it out of rd.luks.name.\n  \
+                     Use /dev/disk/by-uuid/... or luksParamStyle \"luksDevice\".",
+                    name,
+                    device
+                );
+            }
+        }
+    }
+
+    // Warn about ESPs firmware isn't guaranteed to read
+    match efi::detect_esp_filesystem_type(&filesystem.real_path(&config.efi_mount_point)) {
+        Ok(efi::EspFilesystemType::Fat32) => {}
+        Ok(fs_type) => crate::warn!(
+            "ESP at {} is {}, not FAT32.\n  Firmware compatibility is not guaranteed.",
+            config.efi_mount_point.display(),
+            fs_type
+        ),
+        Err(error) => crate::warn!("could not detect ESP filesystem type: {:#}", error),
+    }
+
+    // Create refind directory if needed
+    filesystem
+        .create_dir_all(&refind_dir)
+        .context("Failed to create refind directory")?;
+
+    // Collect all generations by profile, the system profile first. Each profile's come
+    // newest first, which the maxGenerations cut below relies on.
+    let mut all_generations: Vec<(String, Vec<u64>)> = Vec::new();
+    for generation in generation::Generations::discover(filesystem.as_ref(), &config.profiles_root)
+    {
+        let generation = generation?;
+        match all_generations.last_mut() {
+            Some((profile, numbers)) if *profile == generation.profile => {
+                numbers.push(generation.number)
+            }
+            _ => all_generations.push((generation.profile, vec![generation.number])),
+        }
+    }
+
+    // A pinned default generation must still exist, unless the newest may stand in for it
+    let pinned = match config.default_generation {
+        Some(ref pin) => {
+            let exists = all_generations
+                .iter()
+                .any(|(profile, numbers)| *profile == pin.profile && numbers.contains(&pin.number));
+            if exists {
+                Some((pin.profile.clone(), pin.number))
+            } else if pin.fallback_to_newest {
+                crate::warn!(
+                    "defaultGeneration {} generation {} no longer exists, defaulting to the newest",
+                    pin.profile,
+                    pin.number
+                );
+                None
+            } else {
+                anyhow::bail!(
+                    "defaultGeneration {} generation {} no longer exists.\n  \
+                     Pin another generation, or set fallbackToNewest to boot the newest.",
+                    pin.profile,
+                    pin.number
+                );
+            }
+        }
+        None => None,
+    };
+
+    for (profile, numbers) in &mut all_generations {
+        // Keep only the newest N generations (0 keeps all)
+        let max_generations = config.for_profile(profile).max_generations;
+        if max_generations > 0 {
+            numbers.truncate(max_generations);
+        }
+        // The pinned default stays however old it is
+        if let Some((ref pinned_profile, number)) = pinned
+            && pinned_profile == profile
+            && !numbers.contains(&number)
+        {
+            numbers.push(number);
+        }
+        numbers.reverse();
+    }
+    if all_generations
+        .first()
+        .is_none_or(|(profile, _)| profile != "system")
+    {
+        anyhow::bail!("No generations found for the system profile");
+    }
+
+    let profiles = generation::get_profiles(filesystem.as_ref(), &config.profiles_root)?;
+    generation::warn_unknown_profiles("profile label", config.profile_labels.keys(), &profiles);
+    generation::warn_unknown_profiles("profile overrides", config.profiles.keys(), &profiles);
+
+    // Get last generation for default selection
+    let last_gen = *all_generations[0]
+        .1
+        .last()
+        .context("No generations found")?;
+    let last_gen_path =
+        generation::get_system_path(&config.profiles_root, "system", Some(last_gen), None);
+    let last_bootspec = BootSpec::load(filesystem.as_ref(), &last_gen_path)?;
+
+    // The generation booted by default: the pinned one, else the newest system generation
+    let (default_profile, default_gen) = pinned.unwrap_or_else(|| ("system".to_string(), last_gen));
+    let default_bootspec = if (default_profile.as_str(), default_gen) == ("system", last_gen) {
+        last_bootspec.clone()
+    } else {
+        let path = generation::get_system_path(
+            &config.profiles_root,
+            &default_profile,
+            Some(default_gen),
+            None,
+        );
+        BootSpec::load(filesystem.as_ref(), &path)?
+    };
+    let default = (default_profile.as_str(), default_gen);
+
+    // Drop old generations whose kernels won't fit on the ESP
+    fit_generations_to_esp(
+        config,
+        filesystem.as_ref(),
+        &mut all_generations,
+        default,
+        &kernel_root,
+        options.esp_reserve_mib,
+        options.auto_trim,
+    )?;
+
+    // Copy kernels and initrds up front, in parallel
+    let mut files = Vec::new();
+    for (profile, generations) in &all_generations {
+        for &generation in generations {
+            // Generations that fail to load are reported when building entries
+            if let Ok(staged) = generation::staged_files(
+                filesystem.as_ref(),
+                profile,
+                generation,
+                config,
+                &kernel_root,
+            ) {
+                files.extend(staged);
+            }
+        }
+    }
+    let jobs = options.jobs.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    });
+    let (mut copied, mut failed) =
+        generation::stage_files_parallel(filesystem.as_ref(), &files, config, &copies, jobs);
+
+    // A full ESP may be full of files only generations no longer in the menu use
+    let mut cleanup = fs::CleanupSummary::default();
+    if failed.iter().any(|(_, error)| fs::is_out_of_space(error)) {
+        let mut early = file_tracker.clone();
+        for (_, dest) in &files {
+            early.mark_used(dest);
+            early.mark_used(&generation::sha256_sidecar(dest));
+        }
+        // Only staged kernels and initrds are up for removal: refind.conf, rEFInd, the
+        // theme and additionalFiles stay, so a retry that still fails leaves a bootable ESP.
+        // Secrets initrds and tools are staged per entry later, their names aren't known yet.
+        for path in early.plan_cleanup() {
+            let managed = generation::MANAGED_DIRS
+                .iter()
+                .any(|dir| path.starts_with(kernel_root.join(dir)));
+            let per_entry = path.to_string_lossy().ends_with("-initrd-secrets")
+                || path.starts_with(kernel_root.join("tools"));
+            if !managed || per_entry {
+                early.mark_used(&path);
+            }
+        }
+        keep_files_in_grace_period(config, options, previous.as_ref(), &mut early);
+        cleanup = early.cleanup()?;
+        crate::info!(
+            "ESP is full, removed {} unused file(s) to free {}",
+            cleanup.files_removed,
+            generation::format_size(cleanup.bytes_freed)
+        );
+
+        let retry: Vec<(PathBuf, PathBuf)> = files
+            .iter()
+            .filter(|(_, dest)| failed.iter().any(|(failed, _)| failed == dest))
+            .cloned()
+            .collect();
+        let retried;
+        (retried, failed) =
+            generation::stage_files_parallel(filesystem.as_ref(), &retry, config, &copies, jobs);
+        copied += retried;
+        if failed.iter().any(|(_, error)| fs::is_out_of_space(error)) {
+            let mut needed = 0;
+            for (source, dest) in &retry {
+                if failed.iter().any(|(failed, _)| failed == dest) {
+                    needed += filesystem.metadata(source).map_or(0, |m| m.len());
+                }
+            }
+            let available = filesystem.available_space(config.kernel_mount_point())?;
+            anyhow::bail!(
+                "Not enough space on ESP even after removing {} unused file(s) ({} freed): {} more needed",
+                cleanup.files_removed,
+                generation::format_size(cleanup.bytes_freed),
+                generation::format_size(needed.saturating_sub(available))
+            );
+        }
+    }
+    for (dest, error) in failed {
+        crate::warn!("could not stage {}: {:#}", dest.display(), error);
+    }
+
+    // Build configuration file
+    let built = build_config_entries(
+        config,
+        &all_generations,
+        default,
+        &kernel_root,
+        volume.as_deref(),
+        &mut file_tracker,
+        options.strict,
+    );
+    let (mut entries, skipped, default_position) = match built {
+        Ok(built) => built,
+        Err(error) if options.fallback_config => {
+            // Staged files are left alone, the fallback entry boots one of them
+            generation::write_fallback_config(
+                filesystem.as_ref(),
+                &copies,
+                &config.efi_mount_point,
+            )
+            .context("Failed to write fallback config")?;
+            crate::warn!("wrote a rescue-shell refind.conf, no NixOS entries could be built");
+            return Err(error);
+        }
+        Err(error) => return Err(error),
+    };
+    if let Some(memtest) =
+        generation::generate_memtest_entry(config, &last_bootspec, &refind_dir, &mut file_tracker)?
+    {
+        entries.push_str(&memtest);
+    }
+    if let Some(ref bootnum) = config.windows_firmware_bootnum {
+        entries.push_str(&generation::generate_firmware_bootnum_entry(bootnum)?);
+    }
+    let theme_include = config
+        .theme
+        .as_ref()
+        .map(|theme| theme.include_line())
+        .unwrap_or_default();
+    let config_content = match options.merge_with {
+        Some(ref existing) => render::merge_refind_conf(
+            &render::read_merge_target(existing)?,
+            &(theme_include + &entries),
+        )?,
+        None => {
+            // The install config's extraConfig, then the CLI's
+            let mut sources = vec![config.extra_config.clone()];
+            sources.extend(options.extra_config.iter().cloned());
+            let (before, after) =
+                render::extra_config_sections(&sources, config.extra_config_placement)?;
+            theme_include
+                + &build_config_header(
+                    config,
+                    &before,
+                    &default_bootspec,
+                    default_profile.as_str(),
+                    default_position,
+                )
+                + &entries
+                + &after
+        }
+    };
+
+    // Write config atomically, keeping the previous one to roll back to
+    let config_path = refind_dir.join("refind.conf");
+    backup_config(
+        filesystem.as_ref(),
+        &copies,
+        &config_path,
+        config_content.as_bytes(),
+        config.config_backups,
+    )?;
+    filesystem.write(&config_path, config_content.as_bytes(), &copies)?;
+    file_tracker.mark_used(&config_path);
+
+    // Copy additional files, leaving ones already in place alone; the manifest records
+    // them, so a file dropped from the config is cleaned up
+    for (source, dest) in config.additional_file_copies()? {
+        if !generation::same_content(filesystem.as_ref(), &source, &dest)? {
+            copied +=
+                generation::copy_to_esp(filesystem.as_ref(), config, &copies, &source, &dest)?;
+        }
+        file_tracker.mark_used(&dest);
+    }
+
+    // Copy the theme the same way. Its directory is ours, so whatever else is in there goes
+    // too, and the manifest has the whole theme cleaned up once it's dropped.
+    if let Some(theme_dir) = config.theme_dir().filter(|dir| filesystem.is_dir(dir)) {
+        let entries = filesystem
+            .walk(&theme_dir)
+            .with_context(|| format!("Failed to read {}", theme_dir.display()))?;
+        for (path, is_dir) in entries {
+            if !is_dir {
+                file_tracker.track_previous([&path]);
+            }
+        }
+    }
+    for (source, dest) in config.theme_copies(filesystem.as_ref())? {
+        if !generation::same_content(filesystem.as_ref(), &source, &dest)? {
+            copied +=
+                generation::copy_to_esp(filesystem.as_ref(), config, &copies, &source, &dest)?;
+        }
+        file_tracker.mark_used(&dest);
+    }
+
+    // Install EFI binary
+    install_efi_binary(config, &config_path, &mut file_tracker)?;
+
+    // Everything the firmware will read must be on disk before NVRAM points at it
+    fs::sync_filesystem(&filesystem.real_path(&config.efi_mount_point))?;
+    if let Some(ref boot) = config.boot_mount_point {
+        fs::sync_filesystem(&filesystem.real_path(boot))?;
+    }
+
+    // Setup EFI boot variables if needed; containers and image builds can't
+    if options.skip_nvram {
+        crate::info!("--skip-nvram given, leaving NVRAM boot entries alone");
+    } else if !config.can_touch_efi_variables {
+        crate::info!("canTouchEfiVariables is false, leaving NVRAM boot entries alone");
+        if !config.efi_removable {
+            crate::warn!(
+                "boot.loader.efi.canTouchEfiVariables is set to false while not using efiInstallAsRemovable.\n  This may render the system unbootable."
+            );
+        }
+    } else if config.efi_removable {
+        crate::info!(
+            "note: boot.loader.refind.efiInstallAsRemovable is true, no need to add EFI entry."
+        );
+    } else {
+        efi::setup_efi_boot_entry(config, options.print_efibootmgr, options.prune_foreign)?;
+    }
+
+    // Unused files still in their grace period stay, and stay recorded
+    keep_files_in_grace_period(config, options, previous.as_ref(), &mut file_tracker);
+
+    // Cleanup unused files
+    crate::info!("Removing unused boot files...");
+    cleanup += file_tracker.cleanup()?;
+    if cleanup.files_removed > 0 {
+        crate::info!(
+            "Removed {} unused file(s), freeing {}",
+            cleanup.files_removed,
+            generation::format_size(cleanup.bytes_freed)
+        );
+    }
+
+    // Record what's ours for the next run
+    let mut manifest = Manifest::new();
+    for path in file_tracker.used_files() {
+        let hash = generation::staged_sha256(filesystem.as_ref(), &path)?;
+        manifest.record(path, hash, previous.as_ref());
+    }
+    manifest.write(filesystem.as_ref(), &copies, &manifest_path)?;
+
+    copied += file_tracker.copied();
+    if copied.bytes > 0 {
+        crate::info!(
+            "Copied {} in {:.1}s ({}/s)",
+            generation::format_size(copied.bytes),
+            copied.duration.as_secs_f64(),
+            generation::format_size(copied.throughput() as u64)
+        );
+    }
+
+    Ok(Report {
+        skipped,
+        cleanup,
+        copied,
+        efi_mount_point: config.efi_mount_point.clone(),
+        additional_esps: Vec::new(),
+    })
+}
+
+/// Copy `config_path` to a timestamped `refind.conf.<UTC time>.bak` beside it, unless it's
+/// missing or already holds `new_content`, then remove all but the `keep` newest backups.
+fn backup_config(
+    filesystem: &dyn fs::Filesystem,
+    copies: &fs::CopySettings,
+    config_path: &Path,
+    new_content: &[u8],
+    keep: usize,
+) -> Result<()> {
+    if keep == 0 {
+        return Ok(());
+    }
+    match filesystem.read_to_string(config_path) {
+        Ok(current) if current.as_bytes() == new_content => return Ok(()),
+        Ok(_) => {}
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => {
+            return Err(error).with_context(|| format!("Failed to read {:?}", config_path));
+        }
+    }
+
+    let stamp = chrono::Utc::now().format("%Y%m%dT%H%M%S");
+    let backup = config_path.with_file_name(format!("refind.conf.{}.bak", stamp));
+    filesystem.copy(config_path, &backup, copies)?;
+
+    let backups = config_backups(filesystem, config_path)?;
+    for old in &backups[..backups.len().saturating_sub(keep)] {
+        filesystem
+            .remove_file(old)
+            .with_context(|| format!("Failed to remove old backup: {:?}", old))?;
+    }
+    Ok(())
+}
+
+/// Backups of `config_path` made by [`backup_config`], oldest first
+fn config_backups(filesystem: &dyn fs::Filesystem, config_path: &Path) -> Result<Vec<PathBuf>> {
+    let dir = config_path.parent().context("refind.conf has no parent")?;
+    let mut backups = Vec::new();
+    for path in filesystem
+        .read_dir(dir)
+        .with_context(|| format!("Failed to read directory: {:?}", dir))?
+    {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with("refind.conf.") && name.ends_with(".bak") {
+            backups.push(path);
+        }
+    }
+    // The timestamps sort chronologically as text
+    backups.sort();
+    Ok(backups)
+}
+
+/// Mark unused files first staged less than `staleFileRetentionDays` ago as used, unless
+/// cleanup is forced. Ages come from the previous run's manifest.
+fn keep_files_in_grace_period(
+    config: &InstallConfig,
+    options: &InstallOptions,
+    previous: Option<&Manifest>,
+    file_tracker: &mut fs::FileTracker,
+) {
+    let Some(previous) = previous else {
+        return;
+    };
+    if config.stale_file_retention_days == 0 || options.force_cleanup {
+        return;
+    }
+
+    let grace = config.stale_file_retention_days * 24 * 60 * 60;
+    let now = manifest::now();
+    for path in file_tracker.plan_cleanup() {
+        let Some(entry) = previous.get(&path) else {
+            continue;
+        };
+        let age = now.saturating_sub(entry.created);
+        if age < grace {
+            crate::info!(
+                "keeping unused {} for {} more day(s)",
+                path.display(),
+                (grace - age).div_ceil(24 * 60 * 60)
+            );
+            file_tracker.mark_used(&path);
+        }
+    }
+}
+
+/// Check that every file that still needs staging fits in the free space of the kernel
+/// volume (normally the ESP) minus `reserve_mib`, before anything is copied.
+///
+/// If not, fails naming the generations to drop, oldest first, or with `auto_trim` drops
+/// them. The default generation, given as profile and number, is never dropped.
+fn fit_generations_to_esp(
+    config: &InstallConfig,
+    filesystem: &dyn fs::Filesystem,
+    all_generations: &mut [(String, Vec<u64>)],
+    default: (&str, u64),
+    refind_dir: &Path,
+    reserve_mib: u64,
+    auto_trim: bool,
+) -> Result<()> {
+    let available = filesystem.available_space(config.kernel_mount_point())?;
+    let budget = available.saturating_sub(reserve_mib * 1024 * 1024);
+
+    let mut staged = HashMap::new();
+    for (profile, generations) in all_generations.iter() {
+        for &generation in generations {
+            // Generations that fail to load are reported when building entries
+            if let Ok(files) =
+                generation::staged_files(filesystem, profile, generation, config, refind_dir)
+            {
+                staged.insert((profile.clone(), generation), files);
+            }
+        }
+    }
+
+    // Bytes of files not yet on the ESP, counting shared destinations once
+    let required = |staged: &HashMap<(String, u64), Vec<(PathBuf, PathBuf)>>| -> Result<u64> {
+        let mut seen = HashSet::new();
+        let mut total = 0;
+        for (source, dest) in staged.values().flatten() {
+            if !filesystem.exists(dest) && seen.insert(dest) {
+                total += filesystem
+                    .metadata(source)
+                    .with_context(|| format!("Failed to stat {}", source.display()))?
+                    .len();
+            }
+        }
+        Ok(total)
+    };
+
+    let mut needed = required(&staged)?;
+    if needed <= budget {
+        return Ok(());
+    }
+    let all_needed = needed;
+
+    // Oldest generations go first, by profile link age, then by number for links made in
+    // the same instant
+    let mut droppable: Vec<(String, u64)> = staged
+        .keys()
+        .filter(|(profile, generation)| (profile.as_str(), *generation) != default)
+        .cloned()
+        .collect();
+    droppable.sort_by_key(|(profile, generation)| {
+        let modified = filesystem
+            .symlink_metadata(&generation::get_system_path(
+                &config.profiles_root,
+                profile,
+                Some(*generation),
+                None,
+            ))
+            .and_then(|m| m.modified())
+            .ok();
+        (modified, *generation, profile.clone())
+    });
+
+    let mut dropped = Vec::new();
+    let mut droppable = droppable.into_iter();
+    while needed > budget {
+        let Some(key) = droppable.next() else {
+            anyhow::bail!(
+                "Not enough space on ESP: the default generation needs {} bytes ({}), {} bytes ({}) available",
+                needed,
+                generation::format_size(needed),
+                budget,
+                generation::format_size(budget)
+            );
+        };
+        staged.remove(&key);
+        dropped.push(key);
+        needed = required(&staged)?;
+    }
+
+    let dropped_list: Vec<String> = dropped
+        .iter()
+        .map(|(profile, generation)| format!("  {} generation {}", profile, generation))
+        .collect();
+    if !auto_trim {
+        anyhow::bail!(
+            "Not enough space on ESP: staging all generations needs {} bytes ({}), {} bytes ({}) available.\n\
+             Drop these generations, or pass --auto-trim to leave them out of the menu:\n{}",
+            all_needed,
+            generation::format_size(all_needed),
+            budget,
+            generation::format_size(budget),
+            dropped_list.join("\n")
+        );
+    }
+    crate::warn!(
+        "not enough space on the ESP for all generations, dropping:\n{}",
+        dropped_list.join("\n")
+    );
+
+    for (profile, generations) in all_generations.iter_mut() {
+        generations.retain(|g| !dropped.contains(&(profile.clone(), *g)));
+    }
+
+    Ok(())
+}
+
+/// Global settings at the top of refind.conf. `default_position` is the index of the
+/// default generation's menuentry among the NixOS entries.
+fn build_config_header(
+    config: &InstallConfig,
+    extra_config: &str,
+    default_bootspec: &BootSpec,
+    default_profile: &str,
+    default_position: usize,
+) -> String {
+    let mut content = String::new();
+
+    // Add extra config
+    content.push_str(extra_config);
+    content.push('\n');
+
+    // Add timeout and default selection
+    // rEFInd's 0 waits for a choice and -1 boots the default right away
+    let timeout = match config.timeout {
+        None => 0,
+        Some(0) => -1,
+        Some(seconds) => i64::from(seconds),
+    };
+    content.push_str(&format!("timeout {}\n", timeout));
+    content.push_str(&render::console_directives(
+        config.text_only,
+        config.text_mode,
+        extra_config,
+    ));
+
+    let nested = config.for_profile(default_profile).include_specialisations
+        && !default_bootspec.specialisations.is_empty();
+    let default_selection = if !nested { 2 } else { 3 } + default_position;
+    content.push_str(&format!("default_selection {}\n\n", default_selection));
+
+    content
+}
+
+/// Build the NixOS entries of refind.conf. Generations that fail are skipped and described
+/// in the returned list, unless `strict` is set or it's the default generation, which is
+/// always fatal. Also returns the index of the default generation's menuentry.
+fn build_config_entries(
+    config: &InstallConfig,
+    all_generations: &[(String, Vec<u64>)],
+    default: (&str, u64),
+    refind_dir: &Path,
+    volume: Option<&str>,
+    file_tracker: &mut fs::FileTracker,
+    strict: bool,
+) -> Result<(String, Vec<String>, usize)> {
+    let mut skipped = Vec::new();
+    let mut content = String::new();
+    let mut built = 0;
+    let mut default_position = 0;
+
+    content.push_str("# NixOS boot entries start here\n");
+
+    // Generate entries for each profile and generation
+    for (profile, generations) in all_generations {
+        let config = &config.for_profile(profile);
+
+        let mut sorted_gens = generations.clone();
+        sorted_gens.sort_by(|a, b| b.cmp(a)); // Reverse sort
+
+        for generation in sorted_gens {
+            let entry = generation::generate_config_entry(
+                profile,
+                generation,
+                config,
+                refind_dir,
+                volume,
+                file_tracker,
+            );
+            match entry {
+                Ok(entry) => {
+                    if (profile.as_str(), generation) == default {
+                        default_position = built;
+                    }
+                    content.push_str(&entry);
+                    built += 1;
+                }
+                Err(error) if strict || (profile.as_str(), generation) == default => {
+                    return Err(error).with_context(|| {
+                        format!(
+                            "Failed to build entry for {} generation {}",
+                            profile, generation
+                        )
+                    });
+                }
+                Err(error) => skipped.push(format!(
+                    "{} generation {}: {:#}",
+                    profile, generation, error
+                )),
+            }
+        }
+    }
+
+    content.push_str("\n# NixOS boot entries end here\n");
+
+    Ok((content, skipped, default_position))
+}
+
+/// Copy rEFInd to `efi/refind`, and with `efiRemovable` also to the removable-media path
+/// `efi/boot/BOOT<arch>.EFI`. rEFInd reads refind.conf from its own directory, so the
+/// fallback gets a copy of `config_path` beside it.
+fn install_efi_binary(
+    config: &InstallConfig,
+    config_path: &Path,
+    file_tracker: &mut fs::FileTracker,
+) -> Result<()> {
+    // The binary goes where NVRAM entries point, icons and drivers beside it, where
+    // rEFInd looks for them
+    let refind_dir = config.efi_mount_point.join("efi/refind");
+    let mut files = config.refind_copies(file_tracker.filesystem())?;
+
+    // The removable-media path gets the same files, the config and the theme it includes
+    if config.efi_removable {
+        let fallback_dir = config.efi_mount_point.join("efi/boot");
+        let mut fallback = vec![(config_path.to_path_buf(), refind_dir.join("refind.conf"))];
+        fallback.extend(files.iter().cloned());
+        fallback.extend(config.theme_copies(file_tracker.filesystem())?);
+        for (source, dest) in fallback {
+            let dest = fallback_dir.join(dest.strip_prefix(&refind_dir)?);
+            files.push((source, dest));
+        }
+    }
+
+    for (source, dest) in files {
+        // Under Secure Boot, rEFInd and its drivers are signed like kernels
+        let filesystem = file_tracker.filesystem();
+        let copies = file_tracker.copy_settings();
+        let is_efi_binary = config.secure_boot.is_some()
+            && generation::check_kernel_compression_format(filesystem, &source)?
+                == generation::KernelFormat::PeEfi;
+        if is_efi_binary {
+            let copied = generation::stage_file(filesystem, &source, &dest, config, copies)?;
+            file_tracker.record_copy(copied);
+            file_tracker.mark_used(&generation::sha256_sidecar(&dest));
+        } else if !generation::same_content(filesystem, &source, &dest)? {
+            let copied = generation::copy_to_esp(filesystem, config, copies, &source, &dest)?;
+            file_tracker.record_copy(copied);
+        }
+        file_tracker.mark_used(&dest);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{
+        Filesystem, RootedFs,
+        tests::{ScratchDir, put},
+    };
+    use crate::generation::tests::{PROFILES, add_generation};
+    use std::time::{Duration, SystemTime};
+
+    /// A profiles root, store and rEFInd package under `scratch`, with an empty `/boot`
+    /// as the ESP
+    fn system(scratch: &ScratchDir) -> InstallConfig {
+        let filesystem = scratch.rooted();
+        put(
+            filesystem.as_ref(),
+            "/refind/share/refind/refind_x64.efi",
+            "refind",
+        );
+        std::fs::create_dir_all(scratch.path().join("boot")).unwrap();
+        serde_json::from_value(serde_json::json!({
+            "nixPath": "/nix",
+            "refindPath": "/refind",
+            "efiMountPoint": "/boot",
+            "profilesRoot": PROFILES,
+            "hostArchitecture": "x86_64",
+            "timeout": 5,
+        }))
+        .unwrap()
+    }
+
+    fn install(scratch: &ScratchDir, config: &InstallConfig) -> Report {
+        install_with(scratch, config, InstallOptions::default())
+    }
+
+    /// Install with `options`, besides the ones every test install needs
+    fn install_with(
+        scratch: &ScratchDir,
+        config: &InstallConfig,
+        options: InstallOptions,
+    ) -> Report {
+        Installer::new(config.clone())
+            .filesystem(scratch.rooted())
+            .options(InstallOptions {
+                skip_esp_checks: true,
+                skip_nvram: true,
+                ..options
+            })
+            .run()
+            .unwrap()
+    }
+
+    fn refind_conf(scratch: &ScratchDir) -> String {
+        std::fs::read_to_string(scratch.path().join("boot/efi/refind/refind.conf")).unwrap()
+    }
+
+    fn staged_kernels(scratch: &ScratchDir) -> Vec<String> {
+        let mut files: Vec<String> = scratch
+            .rooted()
+            .walk(Path::new("/boot/efi/refind/kernels"))
+            .unwrap()
+            .into_iter()
+            .filter(|(path, is_dir)| !is_dir && path.extension().is_none_or(|ext| ext != "sha256"))
+            .map(|(path, _)| path.file_name().unwrap().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn generates_config_for_every_generation() {
+        let scratch = ScratchDir::new();
+        let config = system(&scratch);
+        add_generation(&scratch, "system", 1, "aaaa-linux-6.6", "bbbb-initrd");
+        add_generation(&scratch, "system", 2, "cccc-linux-6.9", "dddd-initrd");
+
+        let report = install(&scratch, &config);
+        assert!(report.skipped.is_empty());
+
+        let conf = refind_conf(&scratch);
+        assert!(conf.contains("timeout 5\n"));
+        let second = conf
+            .find("menuentry \"Generation 2 NixOS Unknown,")
+            .unwrap();
+        let first = conf
+            .find("menuentry \"Generation 1 NixOS Unknown,")
+            .unwrap();
+        assert!(second < first, "newest generation first:\n{}", conf);
+        assert!(conf.contains("cccc-linux-6.9-bzImage"));
+        assert!(conf.contains("bbbb-initrd-initrd"));
+        assert!(conf.contains("quiet"));
+
+        assert_eq!(
+            staged_kernels(&scratch),
+            [
+                "aaaa-linux-6.6-bzImage",
+                "bbbb-initrd-initrd",
+                "cccc-linux-6.9-bzImage",
+                "dddd-initrd-initrd",
+            ]
+        );
+        let arch = efi::efi_arch("x86_64").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(scratch.path().join("boot").join(arch.install_path())).unwrap(),
+            "refind"
+        );
+        let manifest = Manifest::load(
+            scratch.rooted().as_ref(),
+            &Path::new("/boot/efi/refind").join(manifest::MANIFEST_NAME),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(
+            manifest
+                .get(Path::new("/boot/efi/refind/refind.conf"))
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn reports_only_what_was_copied() {
+        let scratch = ScratchDir::new();
+        let config = system(&scratch);
+        add_generation(&scratch, "system", 1, "aaaa-linux-6.6", "bbbb-initrd");
+
+        let first = install(&scratch, &config);
+        assert!(first.copied.bytes > 0);
+        let again = install(&scratch, &config);
+        assert_eq!(again.copied.bytes, 0, "everything was already in place");
+    }
+
+    #[test]
+    fn rerun_removes_files_of_dropped_generations() {
+        let scratch = ScratchDir::new();
+        let config = system(&scratch);
+        add_generation(&scratch, "system", 1, "aaaa-linux-6.6", "bbbb-initrd");
+        add_generation(&scratch, "system", 2, "cccc-linux-6.9", "bbbb-initrd");
+        install(&scratch, &config);
+
+        std::fs::remove_file(scratch.path().join(&PROFILES[1..]).join("system-1-link")).unwrap();
+        let report = install(&scratch, &config);
+
+        assert_eq!(
+            staged_kernels(&scratch),
+            ["bbbb-initrd-initrd", "cccc-linux-6.9-bzImage"]
+        );
+        assert!(report.cleanup.files_removed >= 1);
+        assert!(!refind_conf(&scratch).contains("Generation 1"));
+    }
+
+    #[test]
+    fn files_refindgen_did_not_write_are_never_removed() {
+        let scratch = ScratchDir::new();
+        let config = system(&scratch);
+        add_generation(&scratch, "system", 1, "aaaa-linux-6.6", "bbbb-initrd");
+        add_generation(&scratch, "system", 2, "cccc-linux-6.9", "dddd-initrd");
+        let filesystem = scratch.rooted();
+        let user_files = [
+            "/boot/efi/refind/themes/minimal/theme.conf",
+            "/boot/efi/refind/icons/os_custom.png",
+            "/boot/efi/refind/drivers_x64/ext4_x64.efi",
+            "/boot/efi/refind/manual.conf",
+            "/boot/efi/Microsoft/Boot/bootmgfw.efi",
+            "/boot/loader/loader.conf",
+        ];
+        for path in user_files {
+            put(filesystem.as_ref(), path, "not ours");
+        }
+
+        install(&scratch, &config);
+        std::fs::remove_file(scratch.path().join(&PROFILES[1..]).join("system-1-link")).unwrap();
+        let report = install(&scratch, &config);
+
+        assert!(report.cleanup.files_removed >= 1);
+        for path in user_files {
+            assert_eq!(
+                filesystem.read_to_string(Path::new(path)).unwrap(),
+                "not ours",
+                "{}",
+                path
+            );
+        }
+    }
+
+    /// Contents and mtime of a file, `None` for a directory
+    type Entry = Option<(Vec<u8>, SystemTime)>;
+
+    /// Every file below `/boot` with its contents and mtime, directories by path only.
+    /// Manifest creation times are wall-clock times retention goes by, so they're masked.
+    fn esp_tree(scratch: &ScratchDir) -> Vec<(PathBuf, Entry)> {
+        let filesystem = scratch.rooted();
+        let mut tree: Vec<_> = filesystem
+            .walk(Path::new("/boot"))
+            .unwrap()
+            .into_iter()
+            .map(|(path, is_dir)| {
+                if is_dir {
+                    return (path, None);
+                }
+                let mut content = std::fs::read(filesystem.real_path(&path)).unwrap();
+                if path.file_name() == Some(manifest::MANIFEST_NAME.as_ref()) {
+                    let mut manifest: serde_json::Value = serde_json::from_slice(&content).unwrap();
+                    for entry in manifest["files"].as_object_mut().unwrap().values_mut() {
+                        entry["created"] = 0.into();
+                    }
+                    content = manifest.to_string().into_bytes();
+                }
+                let mtime = filesystem.metadata(&path).unwrap().modified().unwrap();
+                (path, Some((content, mtime)))
+            })
+            .collect();
+        tree.sort_by(|a, b| a.0.cmp(&b.0));
+        tree
+    }
+
+    #[test]
+    fn reproducible_installs_are_identical() {
+        let runs: Vec<_> = (0..2)
+            .map(|_| {
+                let scratch = ScratchDir::new();
+                let config = system(&scratch);
+                add_generation(&scratch, "system", 1, "aaaa-linux-6.6", "bbbb-initrd");
+                add_generation(&scratch, "system", 2, "cccc-linux-6.9", "dddd-initrd");
+                install_with(
+                    &scratch,
+                    &config,
+                    InstallOptions {
+                        reproducible: true,
+                        ..Default::default()
+                    },
+                );
+                let tree = esp_tree(&scratch);
+                (scratch, tree)
+            })
+            .collect();
+
+        let (first, second) = (&runs[0].1, &runs[1].1);
+        assert!(first.len() > 5, "{:?}", first);
+        assert_eq!(first, second);
+        for (path, file) in first {
+            if let Some((_, mtime)) = file {
+                assert_eq!(
+                    *mtime,
+                    SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+                    "{}",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn removable_install_names_the_binary_from_the_arch_table() {
+        let scratch = ScratchDir::new();
+        let mut config = system(&scratch);
+        config.host_architecture = "riscv64-linux".into();
+        config.efi_removable = true;
+        put(
+            scratch.rooted().as_ref(),
+            "/refind/share/refind/refind_riscv64.efi",
+            "refind riscv64",
+        );
+        add_generation(&scratch, "system", 1, "aaaa-linux-6.6", "bbbb-initrd");
+
+        install(&scratch, &config);
+        for path in [
+            "boot/efi/refind/BOOTRISCV64.EFI",
+            "boot/efi/boot/BOOTRISCV64.EFI",
+        ] {
+            assert_eq!(
+                std::fs::read_to_string(scratch.path().join(path)).unwrap(),
+                "refind riscv64",
+                "{}",
+                path
+            );
+        }
+        assert!(scratch.path().join("boot/efi/boot/refind.conf").is_file());
+    }
+
+    #[test]
+    fn broken_generations_are_skipped() {
+        let scratch = ScratchDir::new();
+        let config = system(&scratch);
+        add_generation(&scratch, "system", 1, "aaaa-linux-6.6", "bbbb-initrd");
+        add_generation(&scratch, "system", 2, "cccc-linux-6.9", "dddd-initrd");
+        std::fs::remove_file(scratch.path().join("store/aaaa-linux-6.6/bzImage")).unwrap();
+
+        let report = install(&scratch, &config);
+        assert_eq!(report.skipped.len(), 1, "{:?}", report.skipped);
+        assert!(report.skipped[0].starts_with("system generation 1"));
+        assert!(!refind_conf(&scratch).contains("Generation 1"));
+    }
+
+    #[test]
+    fn config_header_selects_the_default_entry() {
+        let scratch = ScratchDir::new();
+        let mut config = system(&scratch);
+        config.timeout = Some(0);
+        add_generation(&scratch, "system", 1, "aaaa-linux-6.6", "bbbb-initrd");
+        let bootspec = BootSpec::load(
+            scratch.rooted().as_ref(),
+            &Path::new(PROFILES).join("system-1-link"),
+        )
+        .unwrap();
+
+        let header = build_config_header(&config, "# extra\n", &bootspec, "system", 1);
+        assert!(header.starts_with("# extra\n"));
+        assert!(header.contains("timeout -1\n"));
+        assert!(header.contains("default_selection 3\n"));
+    }
+
+    /// A [`RootedFs`] reporting `available` bytes free
+    #[derive(Debug)]
+    struct SmallEsp {
+        inner: RootedFs,
+        available: u64,
+    }
+
+    impl Filesystem for SmallEsp {
+        fn read_link(&self, path: &Path) -> std::io::Result<PathBuf> {
+            self.inner.read_link(path)
+        }
+
+        fn read_to_string(&self, path: &Path) -> std::io::Result<String> {
+            self.inner.read_to_string(path)
+        }
+
+        fn metadata(&self, path: &Path) -> std::io::Result<std::fs::Metadata> {
+            self.inner.metadata(path)
+        }
+
+        fn symlink_metadata(&self, path: &Path) -> std::io::Result<std::fs::Metadata> {
+            self.inner.symlink_metadata(path)
+        }
+
+        fn canonicalize(&self, path: &Path) -> std::io::Result<PathBuf> {
+            self.inner.canonicalize(path)
+        }
+
+        fn open(&self, path: &Path) -> std::io::Result<std::fs::File> {
+            self.inner.open(path)
+        }
+
+        fn read_dir(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+            self.inner.read_dir(dir)
+        }
+
+        fn walk(&self, dir: &Path) -> Result<Vec<(PathBuf, bool)>> {
+            self.inner.walk(dir)
+        }
+
+        fn copy(
+            &self,
+            source: &Path,
+            dest: &Path,
+            settings: &fs::CopySettings,
+        ) -> Result<fs::CopyStats> {
+            self.inner.copy(source, dest, settings)
+        }
+
+        fn copy_verified(
+            &self,
+            source: &Path,
+            dest: &Path,
+            settings: &fs::CopySettings,
+        ) -> Result<fs::CopyStats> {
+            self.inner.copy_verified(source, dest, settings)
+        }
+
+        fn write(&self, dest: &Path, data: &[u8], settings: &fs::CopySettings) -> Result<()> {
+            self.inner.write(dest, data, settings)
+        }
+
+        fn create_dir_all(&self, path: &Path) -> std::io::Result<()> {
+            self.inner.create_dir_all(path)
+        }
+
+        fn hard_link(&self, original: &Path, link: &Path) -> std::io::Result<()> {
+            self.inner.hard_link(original, link)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+            self.inner.rename(from, to)
+        }
+
+        fn remove_file(&self, path: &Path) -> std::io::Result<()> {
+            self.inner.remove_file(path)
+        }
+
+        fn remove_dir(&self, path: &Path) -> std::io::Result<()> {
+            self.inner.remove_dir(path)
+        }
+
+        fn available_space(&self, _: &Path) -> Result<u64> {
+            Ok(self.available)
+        }
+
+        fn real_path(&self, path: &Path) -> PathBuf {
+            self.inner.real_path(path)
+        }
+    }
+
+    fn fit(
+        scratch: &ScratchDir,
+        available: u64,
+        auto_trim: bool,
+    ) -> Result<Vec<(String, Vec<u64>)>> {
+        let config = system(scratch);
+        let filesystem = SmallEsp {
+            inner: RootedFs::new(scratch.path()),
+            available,
+        };
+        let mut generations = vec![("system".to_string(), vec![1, 2, 3])];
+        fit_generations_to_esp(
+            &config,
+            &filesystem,
+            &mut generations,
+            ("system", 3),
+            Path::new("/boot/efi/refind"),
+            0,
+            auto_trim,
+        )?;
+        Ok(generations)
+    }
+
+    /// Three generations with 10-byte kernels sharing a 6-byte initrd
+    fn three_generations(scratch: &ScratchDir) {
+        add_generation(scratch, "system", 1, "aaaaaaaaa1", "initrd");
+        add_generation(scratch, "system", 2, "aaaaaaaaa2", "initrd");
+        add_generation(scratch, "system", 3, "aaaaaaaaa3", "initrd");
+    }
+
+    #[test]
+    fn copy_settings_come_from_the_options() {
+        assert_eq!(
+            InstallOptions::default().copy_settings().retries,
+            fs::DEFAULT_COPY_RETRIES
+        );
+        let options = InstallOptions {
+            copy_retries: Some(0),
+            reproducible: true,
+            ..Default::default()
+        };
+        let copies = options.copy_settings();
+        assert_eq!(copies.retries, 1, "every copy is tried once");
+        assert!(copies.reproducible);
+    }
+
+    #[test]
+    fn fit_keeps_everything_that_fits() {
+        let scratch = ScratchDir::new();
+        three_generations(&scratch);
+        assert_eq!(
+            fit(&scratch, 36, false).unwrap(),
+            [("system".to_string(), vec![1, 2, 3])]
+        );
+    }
+
+    #[test]
+    fn fit_names_the_oldest_generations_to_drop() {
+        let scratch = ScratchDir::new();
+        three_generations(&scratch);
+        let error = fit(&scratch, 30, false).unwrap_err().to_string();
+        assert!(error.contains("system generation 1"), "{}", error);
+        assert!(!error.contains("system generation 2"), "{}", error);
+    }
+
+    #[test]
+    fn fit_trims_but_keeps_the_default() {
+        let scratch = ScratchDir::new();
+        three_generations(&scratch);
+        assert_eq!(
+            fit(&scratch, 16, true).unwrap(),
+            [("system".to_string(), vec![3])]
+        );
+        assert!(fit(&scratch, 15, true).is_err());
+    }
+}
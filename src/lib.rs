@@ -0,0 +1,36 @@
+//! Generate rEFInd configs from NixOS generations and install them to the ESP.
+//!
+//! [`Generator`] renders a config without touching the disk; [`Installer`] stages
+//! kernels, writes refind.conf and manages the NVRAM entry from an [`InstallConfig`].
+//!
+//! The library never prints. Warnings and progress go through [`log`]; install a sink
+//! with [`log::set_sink`] to see them.
+//!
+//! ```no_run
+//! use refindgen::{Generator, GeneratorOptions};
+//!
+//! let config = Generator::new(GeneratorOptions::default()).render()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+pub mod bootspec;
+pub mod config;
+pub mod efi;
+pub mod fs;
+pub mod generation;
+pub mod hash;
+pub mod log;
+pub mod manifest;
+pub mod preflight;
+pub mod secureboot;
+
+mod install;
+mod render;
+
+pub use bootspec::BootSpec;
+pub use config::InstallConfig;
+pub use generation::{GenerationRef, Generations};
+pub use install::{InstallOptions, Installer, Report};
+pub use render::{
+    ConfigGenObserver, Gen, GenDetails, Generator, GeneratorOptions, StagedFileCollector,
+};
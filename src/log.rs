@@ -0,0 +1,77 @@
+//! Minimal logging facade, so library code never writes to stdout/stderr itself.
+//!
+//! Messages are dropped until a sink is installed with [`set_sink`]; the CLI installs
+//! one that prints them.
+
+use std::fmt;
+use std::sync::RwLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// Progress and informational notes
+    Info,
+    /// Something the user should look at, but that didn't stop the run
+    Warn,
+}
+
+type Sink = Box<dyn Fn(Level, &str) + Send + Sync>;
+
+static SINK: RwLock<Option<Sink>> = RwLock::new(None);
+
+/// Route all library messages to `sink`, replacing any previous one
+pub fn set_sink(sink: impl Fn(Level, &str) + Send + Sync + 'static) {
+    *SINK.write().unwrap() = Some(Box::new(sink));
+}
+
+#[doc(hidden)]
+pub fn emit(level: Level, args: fmt::Arguments<'_>) {
+    if let Some(ref sink) = *SINK.read().unwrap() {
+        sink(level, &args.to_string());
+    }
+}
+
+/// Log an informational message
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Info, format_args!($($arg)*))
+    };
+}
+
+/// Log a warning
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log::emit($crate::log::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    thread_local! {
+        static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+    }
+
+    /// Run `f`, returning the warnings it logged on this thread
+    pub(crate) fn warnings<R>(f: impl FnOnce() -> R) -> (R, Vec<String>) {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            set_sink(|level, message| {
+                if level == Level::Warn {
+                    CAPTURED.with_borrow_mut(|captured| {
+                        if let Some(captured) = captured {
+                            captured.push(message.to_string());
+                        }
+                    });
+                }
+            })
+        });
+
+        CAPTURED.set(Some(Vec::new()));
+        let result = f();
+        (result, CAPTURED.take().unwrap_or_default())
+    }
+}
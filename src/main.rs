@@ -1,562 +1,497 @@
-mod bootspec;
-mod config;
-mod efi;
-mod fs;
-mod generation;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, parser::ValueSource};
+use refindgen::{
+    Generator, GeneratorOptions, InstallOptions, Installer, StagedFileCollector,
+    config::{self, ExtraConfig, InstallConfig},
+    efi, fs, generation,
+    log::{self, Level},
+};
+
+/// Install rEFInd and generate its config from NixOS generations.
+///
+/// Without flags, installs using the JSON or TOML config at $CONFIG_PATH (substituted by Nix).
+/// Flags that override a config setting win over its REFINDGEN_* variable, which wins over
+/// the file; see `--show-config`.
+#[derive(Parser, Debug)]
+#[command(name = "refindgen")]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Print the generated rEFInd config instead of installing.
+    ///
+    /// Pure dry-run: no writes, no copies, no syncs.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// ESP mount root (where /efi lives). Overrides the install config's `efiMountPoint`.
+    ///
+    /// Defaults to /boot, unless /boot has no efi/refind and a vfat filesystem is
+    /// mounted at /boot, /boot/efi or /efi.
+    #[arg(long)]
+    efi_mount: Option<PathBuf>,
+
+    /// Seconds to show menu before defaulting (omit to keep rEFInd's default). Overrides
+    /// the install config's `timeout`, where 0 boots the default right away.
+    #[arg(long)]
+    timeout: Option<u32>,
+
+    /// File of extra rEFInd config to add verbatim. Repeatable. Extra config is
+    /// concatenated in a fixed order: the install config's `extraConfig`, then each
+    /// `--extra-config` file, then each `--extra-config-inline` text, as given.
+    #[arg(long, value_name = "FILE")]
+    extra_config: Vec<PathBuf>,
+
+    /// Extra rEFInd config to add verbatim, as text. Repeatable; see `--extra-config`
+    /// for the order.
+    #[arg(long, value_name = "TEXT")]
+    extra_config_inline: Vec<String>,
+
+    /// Where `menuentry` blocks from the extra config go relative to the NixOS
+    /// entries. Overrides the install config's `extraConfigPlacement`.
+    #[arg(long, value_enum)]
+    extra_config_placement: Option<config::ExtraConfigPlacement>,
+
+    /// MiB of ESP space to leave free for rEFInd itself when deciding how many
+    /// generations fit.
+    #[arg(long, value_name = "MiB", default_value_t = 0)]
+    esp_reserve: u64,
+
+    /// How kernels and initrds are laid out on the ESP.
+    ///
+    /// With per-generation, store paths shared between generations are duplicated into
+    /// each generation directory, or hard-linked when the install config sets
+    /// `sharedFiles = "hardlink"` (FAT ESPs have no hard links and fall back to copying).
+    /// Overrides the install config's `kernelLayout` when given.
+    #[arg(long, value_enum, default_value_t = config::KernelLayout::Flat)]
+    kernel_layout: config::KernelLayout,
+
+    /// Initrd to load before each generation's own, e.g. CPU microcode. Repeatable;
+    /// loaded in the order given. Overrides the install config's `earlyInitrds`.
+    #[arg(long, value_name = "PATH")]
+    early_initrd: Vec<PathBuf>,
+
+    /// Show a profile under a different name in menu titles. Repeatable. Overrides the
+    /// install config's `profileLabels`.
+    #[arg(long, value_name = "NAME=LABEL", value_parser = parse_profile_label)]
+    profile_label: Vec<(String, String)>,
+
+    /// Append the first journal line about each generation's activation to its
+    /// description (dry-run only).
+    #[arg(long)]
+    include_activation_log: bool,
+
+    /// Append the first package change since the previous generation, from
+    /// `nix store diff-closures`, to each generation's description (dry-run only).
+    #[arg(long)]
+    changelog_in_description: bool,
+
+    /// Append each generation's closure size to its description (dry-run only).
+    /// Slow on cold caches.
+    #[arg(long)]
+    with_sizes: bool,
+
+    /// Title entries with the label NixOS wrote into boot.json, falling back to the
+    /// synthesized description for generations without one. Overrides the install
+    /// config's `useBootspecLabel`.
+    #[arg(long)]
+    use_bootspec_label: bool,
+
+    /// Also write a POSIX sh file exporting REFINDGEN_* variables describing the
+    /// default generation, for post-install hooks to source.
+    #[arg(long, value_name = "OUTPUT_PATH")]
+    generate_shell_config: Option<PathBuf>,
+
+    /// Fail on the first generation that can't be loaded instead of skipping it.
+    #[arg(long)]
+    strict: bool,
+
+    /// Keep a handcrafted refind.conf: output it with only the block between
+    /// `# refindgen-begin` and `# refindgen-end` replaced by the generated NixOS
+    /// entries (appended at the end if there is no such block yet).
+    #[arg(long, value_name = "EXISTING_PATH")]
+    refind_conf_merge: Option<PathBuf>,
+
+    /// When the NixOS entries can't be built, write a refind.conf with only a rescue
+    /// shell entry (`init=/bin/sh`) instead of keeping the previous one.
+    #[arg(long)]
+    enable_fallback_config: bool,
+
+    /// When the ESP can't hold every generation, leave the oldest out of the menu instead
+    /// of failing before anything is copied.
+    #[arg(long)]
+    auto_trim: bool,
+
+    /// Give every file written to the ESP the mtime of store paths (1s after the epoch),
+    /// so installs of the same system produce identical trees. The manifest still
+    /// records real staging times for `staleFileRetentionDays`.
+    #[arg(long)]
+    reproducible: bool,
+
+    /// Install files only, without creating or updating NVRAM boot entries, e.g. when
+    /// preparing a disk offline or on a machine not booted through UEFI.
+    #[arg(long)]
+    skip_nvram: bool,
+
+    /// Install, but print the efibootmgr commands or EFI variable writes that would
+    /// create, delete or reorder NVRAM entries instead of making them.
+    #[arg(long)]
+    print_efibootmgr: bool,
+
+    /// Also delete NVRAM entries labelled like rEFInd's that boot a different ESP, which
+    /// are otherwise left alone as another install's.
+    #[arg(long)]
+    prune_foreign: bool,
+
+    /// Label of rEFInd's NVRAM entry. Overrides the install config's `efiEntryLabel`.
+    #[arg(long, value_name = "LABEL")]
+    efi_entry_label: Option<String>,
+
+    /// Write even if the ESP (or bootMountPoint) isn't a mount point, isn't FAT or
+    /// isn't mounted read-write.
+    #[arg(long)]
+    skip_esp_checks: bool,
+
+    /// Remove unused staged files right away, ignoring `staleFileRetentionDays`.
+    #[arg(long)]
+    force_clean: bool,
+
+    /// Kernels and initrds to copy to the ESP at once. Defaults to the number of CPUs.
+    #[arg(long, value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Attempts per ESP file copy when it fails with a transient error
+    /// (interrupted or would block), 100ms apart. Defaults to 3.
+    #[arg(long, value_name = "N")]
+    copy_retries: Option<u32>,
+
+    /// Bytes per read/write when copying to the ESP. Defaults to 1 MiB.
+    #[arg(long, value_name = "BYTES")]
+    copy_chunk_size: Option<usize>,
+
+    /// Directory holding the `system` profile and `system-profiles/`.
+    ///
+    /// Defaults to `$NIX_STATE_DIR/profiles`, or /nix/var/nix/profiles when
+    /// NIX_STATE_DIR isn't set. Overrides the install config's `profilesRoot`.
+    #[arg(long, value_name = "PATH")]
+    profiles_root: Option<PathBuf>,
+
+    /// Print every setting of the install config at $CONFIG_PATH with its effective value
+    /// and where it came from (default, config file, REFINDGEN_* variable or flag), then
+    /// exit.
+    #[arg(long)]
+    show_config: bool,
+
+    /// Print an example install config with every field explained, then exit.
+    #[arg(long)]
+    print_example_config: bool,
+
+    /// Print version, build date, git commit and features as JSON, then exit.
+    #[arg(long)]
+    version_json: bool,
+}
 
-fn main() -> Result<()> {
-    // Load configuration from JSON file (path substituted by Nix)
-    let config_path = std::env::var("CONFIG_PATH")?;
-    let config = config::InstallConfig::load(&config_path)
-        .context("Failed to load install configuration")?;
-
-    install_bootloader(&config)?;
-
-    // Always sync filesystem, even on error
-    fs::sync_filesystem(&config.efi_mount_point)?;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Restore the most recent refind.conf backup (see `configBackups`) and sync the ESP.
+    Rollback,
+}
 
-    Ok(())
+fn parse_profile_label(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(name, label)| (name.to_string(), label.to_string()))
+        .ok_or_else(|| format!("expected NAME=LABEL, got '{}'", s))
 }
 
-fn install_bootloader(config: &config::InstallConfig) -> Result<()> {
-    let refind_dir = config.efi_mount_point.join("efi/refind");
+fn main() -> Result<()> {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+
+    log::set_sink(|level, message| match level {
+        Level::Info => println!("{}", message),
+        Level::Warn => eprintln!("warning: {}", message),
+    });
+
+    if cli.version_json {
+        let features: Vec<&str> = env!("REFINDGEN_FEATURES")
+            .split(',')
+            .filter(|f| !f.is_empty())
+            .collect();
+        let manifest = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "build_date": env!("REFINDGEN_BUILD_DATE"),
+            "git_commit": env!("REFINDGEN_GIT_COMMIT"),
+            "features": features,
+        });
+        println!("{}", serde_json::to_string_pretty(&manifest)?);
+        return Ok(());
+    }
 
-    // Track all files for cleanup
-    let mut file_tracker = fs::FileTracker::new(&refind_dir)?;
+    if cli.print_example_config {
+        print!("{}", InstallConfig::example());
+        return Ok(());
+    }
 
-    // Create refind directory if needed
-    std::fs::create_dir_all(&refind_dir).context("Failed to create refind directory")?;
+    if cli.show_config {
+        print!("{}", load_config(&matches)?.show()?);
+        return Ok(());
+    }
 
-    // Collect all generations from all profiles
-    let mut all_generations = Vec::new();
+    if cli.dry_run {
+        // The install config, when there is one, supplies every setting it has, with
+        // REFINDGEN_* variables and flags layered over it, and adds additionalFiles and theme
+        let install_config = match std::env::var_os("CONFIG_PATH") {
+            Some(_) => Some(load_config(&matches)?.config),
+            None => None,
+        };
+        let options = dry_run_options(&cli, install_config.as_ref());
+        let efi_mount = options.efi_mount.clone();
+        let generator = Generator::new(options);
+        let staged = StagedFileCollector::new(&efi_mount);
+        println!("{}", generator.render_with_observer(&staged)?);
+
+        let plan = staged.cleanup_plan()?;
+        if !plan.is_empty() {
+            eprintln!("an install would remove {} unused file(s):", plan.len());
+            for path in &plan {
+                eprintln!("  {}", path.display());
+            }
+        }
 
-    // System profile
-    let system_gens = generation::get_generations("system", config)?;
-    all_generations.push(("system".to_string(), system_gens));
+        if let Some(ref config) = install_config {
+            print_copies("additional", &config.additional_file_copies()?);
+            print_copies("theme", &config.theme_copies(&fs::RealFs)?);
+            if config.secure_boot.is_some() {
+                print_signing(config, &staged)?;
+            }
+        }
 
-    // Named profiles
-    for profile in generation::get_profiles()? {
-        let gens = generation::get_generations(&profile, config)?;
-        all_generations.push((profile, gens));
+        if let Some(ref output) = cli.generate_shell_config {
+            write_shell_config(output, &generator)?;
+        }
+        return Ok(());
     }
 
-    // Get last generation for default selection
-    let last_gen = *all_generations[0]
-        .1
-        .last()
-        .context("No generations found")?;
-    let last_gen_path = generation::get_system_path("system", Some(last_gen), None);
-    let last_bootspec = bootspec::BootSpec::load(&last_gen_path)?;
-
-    // Build configuration file
-    let config_content = build_config_file(
-        config,
-        &all_generations,
-        &last_bootspec,
-        &refind_dir,
-        &mut file_tracker,
-    )?;
-
-    // Write config atomically
-    let config_path = refind_dir.join("refind.conf");
-    fs::write_atomic(&config_path, config_content.as_bytes())?;
-    file_tracker.mark_used(&config_path);
-
-    // Copy additional files
-    for (dest, source) in &config.additional_files {
-        let dest_path = refind_dir.join(dest);
-        fs::copy_atomic(source, &dest_path)?;
-        file_tracker.mark_used(&dest_path);
+    // Load configuration from JSON file (path substituted by Nix)
+    let config = load_config(&matches)?.config;
+    if let Some(Command::Rollback) = cli.command {
+        Installer::new(config)
+            .options(InstallOptions {
+                skip_esp_checks: cli.skip_esp_checks,
+                ..Default::default()
+            })
+            .rollback()?;
+        return Ok(());
     }
-
-    // Install EFI binary
-    install_efi_binary(config, &mut file_tracker)?;
-
-    // Setup EFI boot variables if needed
-    if config.can_touch_efi_variables {
-        if config.efi_removable {
-            println!(
-                "note: boot.loader.refind.efiInstallAsRemovable is true, no need to add EFI entry."
-            );
-        } else {
-            efi::setup_efi_boot_entry(config)?;
+    config.validate()?;
+
+    let generator = Generator::new(GeneratorOptions {
+        efi_mount: config.efi_mount_point.clone(),
+        kernel_layout: config.kernel_layout,
+        early_initrds: config.early_initrds.clone(),
+        luks_params: generation::luks_kernel_params(&config.luks_devices, config.luks_param_style),
+        profiles_root: config.profiles_root.clone(),
+        default_generation: config.default_generation.clone(),
+        ..Default::default()
+    });
+
+    let report = Installer::new(config)
+        .options(InstallOptions {
+            esp_reserve_mib: cli.esp_reserve,
+            strict: cli.strict,
+            merge_with: cli.refind_conf_merge.clone(),
+            copy_retries: cli.copy_retries,
+            copy_chunk_size: cli.copy_chunk_size,
+            fallback_config: cli.enable_fallback_config,
+            auto_trim: cli.auto_trim,
+            jobs: cli.jobs,
+            force_cleanup: cli.force_clean,
+            reproducible: cli.reproducible,
+            skip_esp_checks: cli.skip_esp_checks,
+            print_efibootmgr: cli.print_efibootmgr,
+            skip_nvram: cli.skip_nvram,
+            prune_foreign: cli.prune_foreign,
+            extra_config: cli_extra_config(&cli),
+            ..Default::default()
+        })
+        .run()?;
+
+    if !report.skipped.is_empty() {
+        eprintln!(
+            "warning: skipped {} generation(s) that could not be loaded:",
+            report.skipped.len()
+        );
+        for failure in &report.skipped {
+            eprintln!("  {}", failure);
         }
-    } else if !config.efi_removable {
-        println!("warning: boot.loader.efi.canTouchEfiVariables is set to false while not using efiInstallAsRemovable.");
-        println!("  This may render the system unbootable.");
     }
 
-    // Cleanup unused files
-    println!("Removing unused boot files...");
-    file_tracker.cleanup()?;
+    for additional in &report.additional_esps {
+        println!(
+            "also installed to {} ({} unused file(s) removed)",
+            additional.efi_mount_point.display(),
+            additional.cleanup.files_removed
+        );
+    }
+
+    if let Some(ref output) = cli.generate_shell_config {
+        write_shell_config(output, &generator)?;
+    }
 
     Ok(())
 }
 
-fn build_config_file(
-    config: &config::InstallConfig,
-    all_generations: &[(String, Vec<u64>)],
-    last_bootspec: &bootspec::BootSpec,
-    refind_dir: &std::path::Path,
-    file_tracker: &mut fs::FileTracker,
-) -> Result<String> {
-    let mut content = String::new();
-
-    // Add extra config
-    content.push_str(&config.extra_config);
-    content.push('\n');
-
-    // Add timeout and default selection
-    content.push_str(&format!("timeout {}\n", config.timeout));
-
-    let default_selection = if last_bootspec.specialisations.is_empty() {
-        2
-    } else {
-        3
-    };
-    content.push_str(&format!("default_selection {}\n\n", default_selection));
-
-    content.push_str("# NixOS boot entries start here\n");
+fn default_efi_mount() -> PathBuf {
+    let boot = PathBuf::from("/boot");
+    if boot.join("efi/refind").is_dir() {
+        return boot;
+    }
+    efi::discover_efi_mount_point().unwrap_or(boot)
+}
 
-    // Generate entries for each profile and generation
-    for (profile, generations) in all_generations {
-        let group_name = if profile == "system" {
-            "default profile".to_string()
-        } else {
-            format!("profile '{}'", profile)
-        };
+/// List the `what` files (e.g. "theme") an install would copy, as (source, destination)
+/// pairs, marking the ones already in place
+fn print_copies(what: &str, copies: &[(PathBuf, PathBuf)]) {
+    if !copies.is_empty() {
+        eprintln!("an install would copy {} {} file(s):", copies.len(), what);
+    }
+    for (source, dest) in copies {
+        let unchanged = generation::same_content(&fs::RealFs, source, dest).unwrap_or(false);
+        eprintln!(
+            "  {} -> {}{}",
+            source.display(),
+            dest.display(),
+            if unchanged { " (unchanged)" } else { "" }
+        );
+    }
+}
 
-        let mut sorted_gens = generations.clone();
-        sorted_gens.sort_by(|a, b| b.cmp(a)); // Reverse sort
-
-        for generation in sorted_gens {
-            let entry = generation::generate_config_entry(
-                profile,
-                generation,
-                &group_name,
-                refind_dir,
-                file_tracker,
-            )?;
-            content.push_str(&entry);
+/// List the EFI binaries an install would sign for Secure Boot: the staged kernels, rEFInd
+/// and its drivers, marking the ones already signed with the current certificate
+fn print_signing(config: &InstallConfig, staged: &StagedFileCollector) -> Result<()> {
+    let mut binaries = staged.loaders();
+    for (source, dest) in config.refind_copies(&fs::RealFs)? {
+        if generation::check_kernel_compression_format(&fs::RealFs, &source)?
+            == generation::KernelFormat::PeEfi
+        {
+            binaries.push(dest);
         }
     }
 
-    content.push_str("\n# NixOS boot entries end here\n");
-
-    Ok(content)
+    eprintln!("an install would sign {} file(s):", binaries.len());
+    for path in &binaries {
+        let signed = generation::is_signed_copy_current(&fs::RealFs, path, config)?;
+        eprintln!(
+            "  {}{}",
+            path.display(),
+            if signed { " (already signed)" } else { "" }
+        );
+    }
+    Ok(())
 }
 
-fn install_efi_binary(
-    config: &config::InstallConfig,
-    file_tracker: &mut fs::FileTracker,
-) -> Result<()> {
-    // Determine EFI file based on architecture
-    let (boot_file, efi_file) = match config.host_architecture.as_str() {
-        arch if arch.starts_with("x86_64") => ("BOOTX64.EFI", "refind_x64.efi"),
-        arch if arch.starts_with("i686") => ("BOOTIA32.EFI", "refind_ia32.efi"),
-        arch if arch.starts_with("aarch64") => ("BOOTAA64.EFI", "refind_aa64.efi"),
-        arch => anyhow::bail!("Unsupported architecture: {}", arch),
+/// Generator options for `--dry-run`. With an install config, its settings are taken
+/// from it, as resolved from the file, REFINDGEN_* variables and flags; without one, from
+/// the flags alone.
+fn dry_run_options(cli: &Cli, install_config: Option<&InstallConfig>) -> GeneratorOptions {
+    let options = GeneratorOptions {
+        extra_config: cli_extra_config(cli),
+        include_activation_log: cli.include_activation_log,
+        changelog_in_description: cli.changelog_in_description,
+        with_sizes: cli.with_sizes,
+        strict: cli.strict,
+        merge_with: cli.refind_conf_merge.clone(),
+        ..Default::default()
+    };
+    let Some(config) = install_config else {
+        return GeneratorOptions {
+            efi_mount: cli.efi_mount.clone().unwrap_or_else(default_efi_mount),
+            timeout: cli.timeout,
+            extra_config_placement: cli.extra_config_placement.unwrap_or_default(),
+            kernel_layout: cli.kernel_layout,
+            early_initrds: cli.early_initrd.clone(),
+            profile_labels: cli.profile_label.iter().cloned().collect(),
+            use_bootspec_label: cli.use_bootspec_label,
+            profiles_root: cli
+                .profiles_root
+                .clone()
+                .unwrap_or_else(generation::default_profiles_root),
+            ..options
+        };
     };
 
-    let efi_source = config.refind_path.join("share/refind").join(efi_file);
+    // The install config's own extraConfig comes first, as it does when installing
+    let installed = GeneratorOptions::for_install(config);
+    let mut extra_config = installed.extra_config.clone();
+    extra_config.extend(options.extra_config.iter().cloned());
+    // Entries name the XBOOTLDR partition kernels are on, as installed ones do
+    let volume =
+        config
+            .boot_mount_point
+            .as_deref()
+            .and_then(|boot| match efi::partition_uuid(boot) {
+                Ok(uuid) => Some(uuid),
+                Err(error) => {
+                    refindgen::warn!("entries will lack a volume line: {:#}", error);
+                    None
+                }
+            });
+    GeneratorOptions {
+        extra_config,
+        volume,
+        include_activation_log: options.include_activation_log,
+        changelog_in_description: options.changelog_in_description,
+        with_sizes: options.with_sizes,
+        strict: options.strict,
+        merge_with: options.merge_with,
+        ..installed
+    }
+}
 
-    let dest_subdir = if config.efi_removable {
-        "boot"
-    } else {
-        "refind"
-    };
-    let dest_path = config
-        .efi_mount_point
-        .join("efi")
-        .join(dest_subdir)
-        .join(boot_file);
+/// The extra config sources given on the command line, files before inline text
+fn cli_extra_config(cli: &Cli) -> Vec<ExtraConfig> {
+    let files = cli.extra_config.iter().cloned().map(ExtraConfig::File);
+    let inline = cli
+        .extra_config_inline
+        .iter()
+        .cloned()
+        .map(ExtraConfig::Inline);
+    files.chain(inline).collect()
+}
 
-    fs::copy_atomic(&efi_source, &dest_path)?;
-    file_tracker.mark_used(&dest_path);
+/// The install config at $CONFIG_PATH, with the settings given on the command line and
+/// in REFINDGEN_* variables layered over it
+fn load_config(matches: &ArgMatches) -> Result<config::ResolvedConfig> {
+    let config_path = std::env::var("CONFIG_PATH").context("CONFIG_PATH is not set")?;
+    InstallConfig::load_layered(&config_path, &cli_settings(matches), &|name| {
+        std::env::var(name).ok()
+    })
+    .context("Failed to load install configuration")
+}
 
-    Ok(())
+/// The [`config::SETTINGS`] given on the command line, with the values typed for each
+fn cli_settings(matches: &ArgMatches) -> Vec<(&'static config::Setting, Vec<String>)> {
+    config::SETTINGS
+        .iter()
+        .filter_map(|setting| {
+            let id = setting.flag?;
+            if matches.value_source(id) != Some(ValueSource::CommandLine) {
+                return None;
+            }
+            let values = matches
+                .get_raw(id)?
+                .map(|value| value.to_string_lossy().into_owned())
+                .collect();
+            Some((setting, values))
+        })
+        .collect()
+}
+
+fn write_shell_config(output: &Path, generator: &Generator) -> Result<()> {
+    let content = generator.shell_config()?;
+    fs::write_atomic(output, content.as_bytes(), &fs::CopySettings::default())
+        .with_context(|| format!("Failed to write shell config to {}", output.display()))
 }
-// use std::{
-//     ffi::OsStr,
-//     fs::{self, symlink_metadata, File},
-//     io::Read,
-//     path::{Path, PathBuf},
-//     process::Command,
-// };
-//
-// use anyhow::{Context, Result};
-// use clap::Parser;
-//
-// pub mod bootspec;
-// pub mod config;
-//
-// /// Generate a rEFInd config from NixOS generations and dump it as a String.
-// /// - Main entry shows only the newest/default generation
-// /// - Submenu lists all generations
-// ///
-// /// Pure dry-run: no writes, no copies, no syncs.
-// #[derive(Parser, Debug)]
-// #[command(name = "nixos-generate-refind-conf")]
-// #[command(version, about)]
-// struct Cli {
-//     /// ESP mount root (where /efi lives). Often /boot.
-//     #[arg(long, default_value = "/boot")]
-//     efi_mount: PathBuf,
-//
-//     /// Seconds to show menu before defaulting (omit to keep rEFInd's default)
-//     #[arg(long)]
-//     timeout: Option<u32>,
-//
-//     /// Extra rEFInd config to append verbatim (path to a file)
-//     #[arg(long)]
-//     extra_config: Option<PathBuf>,
-// }
-//
-// #[derive(Clone, Debug)]
-// struct Gen {
-//     profile: Option<String>,
-//     number: u32,
-// }
-//
-// #[derive(Clone, Debug)]
-// struct GenDetails {
-//     profile: Option<String>,
-//     number: u32,
-//     loader: String,
-//     initrd: String,
-//     kernel_params: String,
-//     description: String,
-// }
-//
-// fn main() -> Result<()> {
-//     let cli = Cli::parse();
-//
-//     let s = generate_config_string(&cli.efi_mount, cli.timeout, cli.extra_config.as_deref())?;
-//
-//     println!("{s}");
-//     Ok(())
-// }
-//
-// /// Public, reusable entry point. Produces the rEFInd config as a String.
-// /// Auto-discovers the "default" generation. Pure dry-run.
-// fn generate_config_string(
-//     efi_mount: &Path,
-//     timeout: Option<u32>,
-//     extra_config_path: Option<&Path>,
-// ) -> Result<String> {
-//     // Gather generations (system + profiles)
-//     let mut gens = get_generations(None)?;
-//     for p in get_profiles()? {
-//         gens.extend(get_generations(Some(&p))?);
-//     }
-//     if gens.is_empty() {
-//         anyhow::bail!("No NixOS generations found.");
-//     }
-//
-//     // Pick default target path via heuristic, then map to a generation.
-//     let default_target = discover_default_target();
-//     let default = if let Some(target) = default_target {
-//         find_generation_by_target(&gens, &target)?.unwrap_or_else(|| newest_generation(&gens))
-//     } else {
-//         newest_generation(&gens)
-//     };
-//
-//     // Build submenu for all generations, newest -> oldest
-//     let mut rev = gens.clone();
-//     rev.sort_by(|a, b| b.number.cmp(&a.number));
-//
-//     let mut submenu = String::new();
-//     for g in &rev {
-//         let d = generation_details(g, efi_mount)?;
-//         submenu.push_str(&submenu_entry(&d));
-//         submenu.push('\n');
-//     }
-//
-//     // Main entry: default (or newest)
-//     let main_details = generation_details(&default, efi_mount)?;
-//
-//     // Assemble full config
-//     build_config_text(timeout, extra_config_path, &main_details, &submenu)
-// }
-//
-// /// Try to discover the “default” system target path:
-// /// 1) /nix/var/nix/profiles/system (current profile selection)
-// /// 2) /run/current-system (booted)
-// /// If neither resolves, return None (caller will fall back to newest).
-// fn discover_default_target() -> Option<PathBuf> {
-//     let candidates = [
-//         Path::new("/nix/var/nix/profiles/system"),
-//         Path::new("/run/current-system"),
-//     ];
-//     for p in candidates {
-//         if let Ok(t) = fs::read_link(p) {
-//             return Some(t);
-//         }
-//     }
-//     None
-// }
-//
-// /// Read generations via `nix-env --list-generations -p <profile_path>`
-// fn get_generations(profile: Option<&str>) -> Result<Vec<Gen>> {
-//     let prof_path = match profile {
-//         Some(p) => format!("/nix/var/nix/profiles/system-profiles/{p}"),
-//         None => "/nix/var/nix/profiles/system".to_string(),
-//     };
-//
-//     let output = Command::new("sudo")
-//         .args([
-//             "nix-env",
-//             "--list-generations",
-//             "-p",
-//             &prof_path,
-//             // "--option",
-//             // "build-users-group",
-//             // "",
-//         ])
-//         .output()
-//         .with_context(|| "failed to execute nix-env")?;
-//
-//     if !output.status.success() {
-//         anyhow::bail!("nix-env --list-generations failed for {}", prof_path);
-//     }
-//
-//     let s = String::from_utf8_lossy(&output.stdout);
-//     let mut gens = Vec::new();
-//     for line in s.lines() {
-//         println!("Gen: {line}");
-//         if let Some((first, _)) = line.trim().split_once(' ') {
-//             if let Ok(n) = first.trim().parse::<u32>() {
-//                 gens.push(Gen {
-//                     profile: profile.map(str::to_string),
-//                     number: n,
-//                 });
-//             }
-//         }
-//     }
-//     Ok(gens)
-// }
-//
-// fn get_profiles() -> Result<Vec<String>> {
-//     let dir = Path::new("/nix/var/nix/profiles/system-profiles");
-//     if !dir.is_dir() {
-//         return Ok(vec![]);
-//     }
-//     let mut out = vec![];
-//     for entry in fs::read_dir(dir)? {
-//         let entry = entry?;
-//         let name = entry.file_name();
-//         let name = name.to_string_lossy();
-//         if !name.ends_with("-link") {
-//             out.push(name.to_string());
-//         }
-//     }
-//     Ok(out)
-// }
-//
-// fn newest_generation(gens: &[Gen]) -> Gen {
-//     gens.iter()
-//         .cloned()
-//         .max_by_key(|g| g.number)
-//         .expect("non-empty")
-// }
-//
-// /// Match a discovered default *target path* to a generation’s system link target.
-// fn find_generation_by_target(gens: &[Gen], target: &Path) -> Result<Option<Gen>> {
-//     for g in gens {
-//         let link = system_dir(&g.profile, g.number);
-//         let link_target = fs::read_link(&link).unwrap_or(link.clone());
-//         if path_eq(&link_target, target) {
-//             return Ok(Some(g.clone()));
-//         }
-//     }
-//     Ok(None)
-// }
-//
-// /// `/nix/var/nix/profiles/system[-profiles/<profile>]-<number>-link`
-// fn system_dir(profile: &Option<String>, number: u32) -> PathBuf {
-//     match profile {
-//         Some(p) => Path::new("/nix/var/nix/profiles")
-//             .join("system-profiles")
-//             .join(format!("{p}-{number}-link")),
-//         None => Path::new("/nix/var/nix/profiles").join(format!("system-{number}-link")),
-//     }
-// }
-//
-// fn path_eq(a: &Path, b: &Path) -> bool {
-//     fn canon(p: &Path) -> PathBuf {
-//         fs::canonicalize(p).unwrap_or_else(|_| p.to_path_buf())
-//     }
-//     canon(a) == canon(b)
-// }
-//
-// /// Build details for a generation; **no copying** (dry-run).
-// fn generation_details(g: &Gen, efi_mount: &Path) -> Result<GenDetails> {
-//     // Resolve store paths for kernel & initrd
-//     let kernel_store = profile_path(&g.profile, g.number, "kernel")?;
-//     let initrd_store = profile_path(&g.profile, g.number, "initrd")?;
-//
-//     // Compute where they'd be staged (but don't copy)
-//     let (loader_rel, _loader_abs) = efi_target_for_store(&kernel_store, efi_mount);
-//     let (initrd_rel, _initrd_abs) = efi_target_for_store(&initrd_store, efi_mount);
-//
-//     // Generation dir (link target of system link itself)
-//     let gen_dir = fs::read_link(system_dir(&g.profile, g.number))
-//         .unwrap_or_else(|_| system_dir(&g.profile, g.number));
-//
-//     // kernel params: systemConfig=... init=.../init + contents of kernel-params
-//     let mut kernel_params = format!(
-//         "systemConfig={} init={}/init ",
-//         gen_dir.display(),
-//         gen_dir.display()
-//     );
-//     let params_file = gen_dir.join("kernel-params");
-//     if let Ok(mut f) = File::open(&params_file) {
-//         let mut s = String::new();
-//         f.read_to_string(&mut s)?;
-//         kernel_params.push_str(&s);
-//     }
-//
-//     // human description
-//     let description = describe_generation(&gen_dir).unwrap_or_else(|_| "Unknown".to_string());
-//
-//     Ok(GenDetails {
-//         profile: g.profile.clone(),
-//         number: g.number,
-//         loader: loader_rel,
-//         initrd: initrd_rel,
-//         kernel_params,
-//         description,
-//     })
-// }
-//
-// fn profile_path(profile: &Option<String>, number: u32, name: &str) -> Result<PathBuf> {
-//     let target = system_dir(profile, number).join(name);
-//     let link =
-//         fs::read_link(&target).with_context(|| format!("readlink {} failed", target.display()))?;
-//     Ok(link)
-// }
-//
-// /// Map a store path (/nix/store/<hash>-<name>/…/<file>) to:
-// ///  - rEFInd-visible path: /efi/nixos/<name>-<file>.efi (string in config)
-// ///  - absolute path on ESP: <efi_mount>/efi/nixos/<name>-<file>.efi (not used here)
-// fn efi_target_for_store(store_file: &Path, efi_mount: &Path) -> (String, PathBuf) {
-//     let file_name = store_file
-//         .file_name()
-//         .and_then(OsStr::to_str)
-//         .unwrap_or("image");
-//     let store_dir = store_file
-//         .parent()
-//         .and_then(|p| p.file_name())
-//         .and_then(OsStr::to_str)
-//         .unwrap_or("store");
-//
-//     let rel = format!("/efi/nixos/{}-{}.efi", store_dir, file_name);
-//     let abs = efi_mount.join(rel.trim_start_matches('/'));
-//     (rel, abs)
-// }
-//
-// fn describe_generation(gen_dir: &Path) -> Result<String> {
-//     let nixos_version = fs::read_to_string(gen_dir.join("nixos-version"))
-//         .unwrap_or_else(|_| "Unknown".to_string())
-//         .trim()
-//         .to_string();
-//
-//     let kernel_path = gen_dir.join("kernel");
-//     let kernel_real = fs::canonicalize(&kernel_path).unwrap_or(kernel_path);
-//     let modules_dir = kernel_real
-//         .parent()
-//         .unwrap_or(&kernel_real)
-//         .join("lib/modules");
-//     let kernel_version = fs::read_dir(&modules_dir)
-//         .ok()
-//         .and_then(|mut it| it.next())
-//         .and_then(|e| e.ok())
-//         .and_then(|e| e.file_name().into_string().ok())
-//         .unwrap_or_else(|| "unknown".to_string());
-//
-//     let md = symlink_metadata(gen_dir)?;
-//     #[cfg(target_os = "linux")]
-//     let sec = { std::os::unix::fs::MetadataExt::ctime(&md) };
-//     #[cfg(not(target_os = "linux"))]
-//     let sec = 0;
-//
-//     let date = chrono::NaiveDateTime::from_timestamp_opt(sec, 0)
-//         .map(|dt| dt.date().to_string())
-//         .unwrap_or_else(|| "unknown-date".to_string());
-//
-//     Ok(format!(
-//         "NixOS {}, Linux Kernel {}, Built on {}",
-//         nixos_version, kernel_version, date
-//     ))
-// }
-//
-// fn build_config_text(
-//     timeout: Option<u32>,
-//     extra_config_path: Option<&Path>,
-//     main_details: &GenDetails,
-//     submenu: &str,
-// ) -> Result<String> {
-//     let mut out = String::new();
-//     if let Some(secs) = timeout {
-//         out.push_str(&format!("timeout {}\n", secs));
-//     }
-//     if let Some(p) = extra_config_path {
-//         let mut s = String::new();
-//         File::open(p)
-//             .with_context(|| format!("open extra config {}", p.display()))?
-//             .read_to_string(&mut s)?;
-//         out.push_str(&s);
-//         if !out.ends_with('\n') {
-//             out.push('\n');
-//         }
-//     }
-//     out.push_str(&menu_entry(main_details, submenu));
-//     Ok(out)
-// }
-//
-// fn menu_entry(main: &GenDetails, submenu_entries: &str) -> String {
-//     format!(
-//         r#"
-// menuentry "NixOS" {{
-//     loader {}
-//     initrd {}
-//     options "{}"
-// {}}}
-// "#,
-//         main.loader,
-//         main.initrd,
-//         escape_quotes(main.kernel_params.trim()),
-//         indent(submenu_entries.trim_end(), 4),
-//     )
-// }
-//
-// fn submenu_entry(d: &GenDetails) -> String {
-//     format!(
-//         r#"
-// submenuentry "Generation {} {}" {{
-//     loader {}
-//     initrd {}
-//     options "{}"
-// }}
-// "#,
-//         d.number,
-//         d.description,
-//         d.loader,
-//         d.initrd,
-//         escape_quotes(d.kernel_params.trim()),
-//     )
-// }
-//
-// fn escape_quotes(s: &str) -> String {
-//     s.replace('"', r#""""#)
-// }
-//
-// fn indent(s: &str, n: usize) -> String {
-//     let pad = " ".repeat(n);
-//     s.lines()
-//         .map(|l| format!("{pad}{l}"))
-//         .collect::<Vec<_>>()
-//         .join("\n")
-// }
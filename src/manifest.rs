@@ -0,0 +1,263 @@
+//! Record of the files refindgen put on the ESP, kept across runs so files from older
+//! runs can be told apart from ones the user placed.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::fs;
+
+/// File name of the manifest, next to refind.conf
+pub const MANIFEST_NAME: &str = "refindgen-manifest.json";
+
+/// Format version this build writes. Bump it when the format changes and teach
+/// [`migrate`] to upgrade the previous one.
+pub const MANIFEST_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub version: u32,
+    /// refindgen version that wrote the manifest
+    pub tool_version: String,
+    /// Every file the run created or kept
+    pub files: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestEntry {
+    /// SHA-256 of the file's contents
+    pub sha256: String,
+    /// Unix time the file was first recorded, kept across runs. FAT mtimes are too coarse
+    /// and firmware may touch them.
+    pub created: u64,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Load the manifest at `path`, upgrading older formats. Nothing is loaded on a
+    /// first run, when there is no manifest yet.
+    pub fn load(filesystem: &dyn fs::Filesystem, path: &Path) -> Result<Option<Self>> {
+        let content = match filesystem.read_to_string(path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => {
+                return Err(error).with_context(|| format!("Failed to read {}", path.display()));
+            }
+        };
+
+        let value: serde_json::Value = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        let version = value
+            .get("version")
+            .and_then(serde_json::Value::as_u64)
+            .with_context(|| format!("{} has no format version", path.display()))?;
+        if version > u64::from(MANIFEST_VERSION) {
+            anyhow::bail!(
+                "{} has format {}, this refindgen only reads up to {}",
+                path.display(),
+                version,
+                MANIFEST_VERSION
+            );
+        }
+
+        let value = migrate(value, version as u32)?;
+        serde_json::from_value(value)
+            .map(Some)
+            .with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// The entry for `path`, compared case-insensitively like paths on FAT
+    pub fn get(&self, path: &Path) -> Option<&ManifestEntry> {
+        let key = fs::path_key(path);
+        self.files
+            .iter()
+            .find(|(recorded, _)| fs::path_key(recorded) == key)
+            .map(|(_, entry)| entry)
+    }
+
+    /// Record `path`, keeping its creation time from `previous` if it was recorded there
+    pub fn record(&mut self, path: PathBuf, sha256: String, previous: Option<&Manifest>) {
+        let created = previous
+            .and_then(|previous| previous.get(&path))
+            .map_or_else(now, |entry| entry.created);
+        self.files.insert(path, ManifestEntry { sha256, created });
+    }
+
+    pub fn write(
+        &self,
+        filesystem: &dyn fs::Filesystem,
+        copies: &fs::CopySettings,
+        path: &Path,
+    ) -> Result<()> {
+        let mut content = serde_json::to_string_pretty(self)?;
+        content.push('\n');
+        filesystem.write(path, content.as_bytes(), copies)
+    }
+}
+
+type Migration = fn(serde_json::Value) -> Result<serde_json::Value>;
+
+/// Upgrades between formats: `MIGRATIONS[i]` turns format `i + 1` into format `i + 2`.
+/// Add one whenever [`MANIFEST_VERSION`] is bumped.
+const MIGRATIONS: &[Migration] = &[v1_to_v2];
+const _: () = assert!(MIGRATIONS.len() as u32 + 1 == MANIFEST_VERSION);
+
+/// Format 1 kept only each file's hash. Creation times start now, which only delays when
+/// retention lets a file go.
+fn v1_to_v2(mut value: serde_json::Value) -> Result<serde_json::Value> {
+    let files = value
+        .get_mut("files")
+        .and_then(serde_json::Value::as_object_mut)
+        .context("manifest has no files")?;
+    for entry in files.values_mut() {
+        let sha256 = entry.as_str().context("manifest entry is not a hash")?;
+        *entry = serde_json::json!({ "sha256": sha256, "created": now() });
+    }
+    Ok(value)
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Upgrade a manifest of format `version` to [`MANIFEST_VERSION`], one format at a time
+fn migrate(mut value: serde_json::Value, version: u32) -> Result<serde_json::Value> {
+    if version == 0 {
+        anyhow::bail!("manifest format 0 doesn't exist");
+    }
+    for step in &MIGRATIONS[version as usize - 1..] {
+        value = step(value)?;
+    }
+    value["version"] = MANIFEST_VERSION.into();
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::tests::{ScratchDir, put};
+
+    const PATH: &str = "/boot/efi/refind/refindgen-manifest.json";
+
+    fn load(content: &str) -> Result<Option<Manifest>> {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        put(filesystem.as_ref(), PATH, content);
+        Manifest::load(filesystem.as_ref(), Path::new(PATH))
+    }
+
+    #[test]
+    fn missing_manifest_is_a_first_run() {
+        let scratch = ScratchDir::new();
+        assert_eq!(
+            Manifest::load(scratch.rooted().as_ref(), Path::new(PATH)).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn version_1_is_migrated() {
+        let before = now();
+        let manifest = load(
+            r#"{
+  "version": 1,
+  "toolVersion": "0.1.0",
+  "files": {
+    "/boot/efi/refind/refind.conf": "aa",
+    "/boot/efi/refind/kernels/abc-linux-6.6-bzImage": "bb"
+  }
+}"#,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(manifest.version, MANIFEST_VERSION);
+        assert_eq!(manifest.tool_version, "0.1.0");
+        let entry = manifest
+            .get(Path::new("/boot/efi/refind/kernels/abc-linux-6.6-bzImage"))
+            .unwrap();
+        assert_eq!(entry.sha256, "bb");
+        assert!(entry.created >= before);
+        assert_eq!(manifest.files.len(), 2);
+    }
+
+    #[test]
+    fn current_version_round_trips() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        let mut manifest = Manifest::new();
+        manifest.record(
+            PathBuf::from("/boot/efi/refind/refind.conf"),
+            "aa".into(),
+            None,
+        );
+        manifest
+            .write(
+                filesystem.as_ref(),
+                &fs::CopySettings::default(),
+                Path::new(PATH),
+            )
+            .unwrap();
+
+        assert_eq!(
+            Manifest::load(filesystem.as_ref(), Path::new(PATH)).unwrap(),
+            Some(manifest)
+        );
+    }
+
+    #[test]
+    fn record_keeps_the_creation_time() {
+        let mut previous = Manifest::new();
+        previous.files.insert(
+            PathBuf::from("/boot/EFI/refind/refind.conf"),
+            ManifestEntry {
+                sha256: "aa".into(),
+                created: 42,
+            },
+        );
+
+        let mut manifest = Manifest::new();
+        manifest.record(
+            PathBuf::from("/boot/efi/refind/refind.conf"),
+            "bb".into(),
+            Some(&previous),
+        );
+        let entry = manifest
+            .get(Path::new("/boot/efi/refind/refind.conf"))
+            .unwrap();
+        assert_eq!(entry.created, 42, "matched case-insensitively");
+        assert_eq!(entry.sha256, "bb");
+    }
+
+    #[test]
+    fn unreadable_versions_are_refused() {
+        let newer = load(r#"{"version": 3, "toolVersion": "9.0.0", "files": {}}"#).unwrap_err();
+        assert!(
+            format!("{:#}", newer).contains("this refindgen only reads up to 2"),
+            "{:#}",
+            newer
+        );
+        assert!(load(r#"{"version": 0, "toolVersion": "0.1.0", "files": {}}"#).is_err());
+        assert!(load(r#"{"toolVersion": "0.1.0", "files": {}}"#).is_err());
+        assert!(load(r#"{"version": 1, "toolVersion": "0.1.0", "files": {"/a": 1}}"#).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! Checks that the ESP is what it claims to be before anything writes to it. A path that
+//! isn't a mount point would have kernels land on the root filesystem, where firmware
+//! never looks.
+
+use anyhow::{Context, Result};
+use std::path::Path;
+
+use crate::{config::InstallConfig, efi};
+
+/// Filesystems firmware can be expected to read an ESP from
+const ESP_FILESYSTEMS: &[&str] = &["vfat", "msdos", "exfat"];
+
+/// Check the ESP, and the XBOOTLDR partition if there is one, before writing to them.
+///
+/// Both must be mounted read-write on their own; the ESP must also be FAT (or exFAT). The
+/// XBOOTLDR partition may be anything rEFInd has a driver for.
+pub fn check_mounts(config: &InstallConfig) -> Result<()> {
+    check_mount(&config.efi_mount_point, true)?;
+    if let Some(ref boot) = config.boot_mount_point {
+        check_mount(boot, false)?;
+    }
+    Ok(())
+}
+
+fn check_mount(mount_point: &Path, require_fat: bool) -> Result<()> {
+    let path = std::fs::canonicalize(mount_point)
+        .with_context(|| format!("{} does not exist", mount_point.display()))?;
+    if !efi::is_mount_point(&path)? {
+        anyhow::bail!(
+            "{} is not a mount point, files written there would end up on the filesystem \
+             below it (pass --skip-esp-checks if this is intended)",
+            mount_point.display()
+        );
+    }
+
+    let mounts = std::fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    // Later entries are mounted over earlier ones
+    let (fs_type, options) = mounts
+        .lines()
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            (parts.len() >= 4 && Path::new(&unescape_mount_field(parts[1])) == path)
+                .then(|| (parts[2], parts[3]))
+        })
+        .next_back()
+        .with_context(|| format!("{} is not listed in /proc/mounts", mount_point.display()))?;
+
+    if require_fat && !ESP_FILESYSTEMS.contains(&fs_type) {
+        anyhow::bail!(
+            "{} is {}, not FAT; firmware can't read it (pass --skip-esp-checks if this is intended)",
+            mount_point.display(),
+            fs_type
+        );
+    }
+    if !options.split(',').any(|option| option == "rw") {
+        anyhow::bail!(
+            "{} is mounted read-only ({}), remount it read-write first",
+            mount_point.display(),
+            options
+        );
+    }
+
+    Ok(())
+}
+
+/// Check that NVRAM entries can be written at all: the system was booted through UEFI
+/// and efivarfs is mounted. Without this, efibootmgr fails only after files were copied.
+pub fn check_firmware() -> Result<()> {
+    if !Path::new("/sys/firmware/efi").is_dir() {
+        anyhow::bail!(
+            "this system was not booted through UEFI (no /sys/firmware/efi), so NVRAM boot \
+             entries can't be written. Boot it through UEFI, set efiInstallAsRemovable or \
+             canTouchEfiVariables = false, or pass --skip-nvram to only install files"
+        );
+    }
+
+    let mounts = std::fs::read_to_string("/proc/mounts").context("Failed to read /proc/mounts")?;
+    let efivarfs = mounts.lines().any(|line| {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        parts.len() >= 3 && parts[2] == "efivarfs"
+    });
+    if !efivarfs {
+        anyhow::bail!(
+            "efivarfs is not mounted, so NVRAM boot entries can't be written. Mount it with \
+             `mount -t efivarfs efivarfs /sys/firmware/efi/efivars`, or pass --skip-nvram to \
+             only install files"
+        );
+    }
+
+    Ok(())
+}
+
+/// Undo the octal escapes (`\040` for a space) /proc/mounts uses in paths
+fn unescape_mount_field(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes.get(i + 1..i + 4).filter(|digits| {
+            bytes[i] == b'\\' && digits.iter().all(|digit| (b'0'..=b'7').contains(digit))
+        });
+        match escape {
+            Some(digits) => {
+                out.push(
+                    digits
+                        .iter()
+                        .fold(0u8, |acc, digit| acc * 8 + (digit - b'0')),
+                );
+                i += 4;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,1697 @@
+use anyhow::{Context, Result};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::{
+    bootspec::BootSpec,
+    config::{
+        DefaultGeneration, ExtraConfig, ExtraConfigPlacement, InstallConfig, KernelLayout,
+        ProfileOverrides,
+    },
+    fs::{self, Filesystem},
+    generation,
+};
+
+/// A generation of the system profile (`profile: None`) or a named profile
+#[derive(Clone, Debug)]
+pub struct Gen {
+    pub profile: Option<String>,
+    pub number: u64,
+}
+
+/// Everything a menu entry needs to boot a generation
+#[derive(Clone, Debug)]
+pub struct GenDetails {
+    pub profile: Option<String>,
+    pub number: u64,
+    /// URI of the staged kernel on the ESP
+    pub loader: String,
+    /// URI of the staged initrd on the ESP
+    pub initrd: Option<String>,
+    /// URIs of initrds loaded before `initrd`, e.g. CPU microcode
+    pub early_initrds: Vec<String>,
+    pub kernel_params: String,
+    /// Nix system double from boot.json, e.g. `x86_64-linux`
+    pub system: String,
+    pub description: String,
+    /// Label from boot.json, empty for generations that predate bootspec
+    pub label: String,
+    pub specialisations: Vec<(String, GenDetails)>,
+    /// Closure size of the generation's toplevel in bytes, when sizes were asked for
+    pub closure_size: Option<u64>,
+}
+
+/// Hooks into config rendering, for progress reporting, metrics or cancellation. All
+/// methods default to doing nothing.
+pub trait ConfigGenObserver {
+    /// Before a generation's details are loaded. Breaking cancels rendering, which then
+    /// fails without a config.
+    fn on_generation_start(&self, _g: &Gen) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+    /// After a generation's details were loaded
+    fn on_generation_done(&self, _d: &GenDetails) {}
+    /// A generation failed to load; it is skipped unless running strict
+    fn on_generation_error(&self, _g: &Gen, _err: &anyhow::Error) {}
+    /// The finished config, before it is returned
+    fn on_config_complete(&self, _config: &str) {}
+}
+
+/// Observer recording the ESP files rendered entries load, to tell which staged files an
+/// install would delete as unused
+#[derive(Debug)]
+pub struct StagedFileCollector {
+    efi_mount: PathBuf,
+    paths: RefCell<BTreeSet<PathBuf>>,
+    loaders: RefCell<BTreeSet<PathBuf>>,
+}
+
+impl StagedFileCollector {
+    pub fn new(efi_mount: &Path) -> Self {
+        Self {
+            efi_mount: efi_mount.to_path_buf(),
+            paths: RefCell::new(BTreeSet::new()),
+            loaders: RefCell::new(BTreeSet::new()),
+        }
+    }
+
+    fn record(&self, d: &GenDetails) {
+        self.loaders
+            .borrow_mut()
+            .insert(self.efi_mount.join(d.loader.trim_start_matches('/')));
+        let mut paths = self.paths.borrow_mut();
+        for uri in std::iter::once(&d.loader).chain(initrd_uris(d).iter()) {
+            let path = self.efi_mount.join(uri.trim_start_matches('/'));
+            let mut sidecar = path.clone().into_os_string();
+            sidecar.push(".sha256");
+            paths.insert(path);
+            paths.insert(PathBuf::from(sidecar));
+        }
+        drop(paths);
+        for (_, spec) in &d.specialisations {
+            self.record(spec);
+        }
+    }
+
+    /// Staged kernels of the recorded entries, in path order
+    pub fn loaders(&self) -> Vec<PathBuf> {
+        self.loaders.borrow().iter().cloned().collect()
+    }
+
+    /// Staged files under `efi/refind` that no recorded entry loads, which an install
+    /// would remove
+    pub fn cleanup_plan(&self) -> Result<Vec<PathBuf>> {
+        let refind_dir = self.efi_mount.join("efi/refind");
+        let mut tracker = crate::fs::FileTracker::new(&refind_dir, generation::MANAGED_DIRS)?;
+        for path in self.paths.borrow().iter() {
+            tracker.mark_used(path);
+        }
+        Ok(tracker.plan_cleanup())
+    }
+}
+
+impl ConfigGenObserver for StagedFileCollector {
+    fn on_generation_done(&self, d: &GenDetails) {
+        self.record(d);
+    }
+}
+
+/// Settings for [`Generator`]
+#[derive(Debug, Clone)]
+pub struct GeneratorOptions {
+    /// ESP mount root (where /efi lives)
+    pub efi_mount: PathBuf,
+    /// Seconds to show the menu before booting the default, or rEFInd's default
+    pub timeout: Option<u32>,
+    /// Show the menu as text instead of graphics
+    pub text_only: bool,
+    /// Firmware text mode to switch to, or rEFInd's default
+    pub text_mode: Option<u32>,
+    /// Hand-written config added verbatim, concatenated in order
+    pub extra_config: Vec<ExtraConfig>,
+    /// Where `menuentry` blocks from `extra_config` go relative to the NixOS entry
+    pub extra_config_placement: ExtraConfigPlacement,
+    pub kernel_layout: KernelLayout,
+    /// Initrds loaded before each generation's own, e.g. CPU microcode
+    pub early_initrds: Vec<PathBuf>,
+    /// Kernel parameters unlocking LUKS devices, added to every entry whose own
+    /// parameters don't already cover them. See [`generation::luks_kernel_params`].
+    pub luks_params: Vec<String>,
+    /// Names shown in menu titles instead of raw profile names
+    pub profile_labels: HashMap<String, String>,
+    /// Append the first journal line about each generation's activation to its description
+    pub include_activation_log: bool,
+    /// Append the first package change since the profile's previous generation to each
+    /// description
+    pub changelog_in_description: bool,
+    /// Compute each generation's closure size and append it to its description.
+    /// Expensive on cold caches.
+    pub with_sizes: bool,
+    /// Title entries with the boot.json label instead of the synthesized description
+    pub use_bootspec_label: bool,
+    /// Fail on the first generation that can't be loaded instead of skipping it
+    pub strict: bool,
+    /// Handcrafted refind.conf whose refindgen block is replaced, instead of rendering
+    /// a whole new config
+    pub merge_with: Option<PathBuf>,
+    /// Directory holding the `system` profile and `system-profiles/`
+    pub profiles_root: PathBuf,
+    /// Generation the main entry boots instead of the one the system profile selects
+    pub default_generation: Option<DefaultGeneration>,
+    /// Kernel parameters added to every entry that lacks them
+    pub extra_kernel_params: Vec<String>,
+    /// Settings shadowed per profile, see [`InstallConfig::for_profile`]
+    pub profiles: HashMap<String, ProfileOverrides>,
+    /// `ostype` of the main entry, else derived from the default generation's system
+    pub ostype: Option<String>,
+    pub icon: Option<String>,
+    /// PARTUUID of the volume kernels are staged on, when it isn't the ESP
+    pub volume: Option<String>,
+    pub enable_and_lock_vmx: bool,
+    /// memtest86plus binary to offer, else one found in the newest system generation
+    pub memtest86_path: Option<PathBuf>,
+    /// Firmware boot entry to offer as Windows
+    pub windows_firmware_bootnum: Option<String>,
+    /// Nix package whose `nix` computes changelogs and closure sizes, else the one on `PATH`
+    pub nix_path: Option<PathBuf>,
+}
+
+impl GeneratorOptions {
+    /// The options an install with `config` renders with. `volume` is left unset, as
+    /// finding it needs the partition table.
+    pub fn for_install(config: &InstallConfig) -> Self {
+        let mut profile_labels = config.profile_labels.clone();
+        for (profile, overrides) in &config.profiles {
+            if let Some(ref label) = overrides.label {
+                profile_labels.insert(profile.clone(), label.clone());
+            }
+        }
+        Self {
+            efi_mount: config.efi_mount_point.clone(),
+            timeout: config.timeout,
+            text_only: config.text_only,
+            text_mode: config.text_mode,
+            extra_config: vec![config.extra_config.clone()],
+            extra_config_placement: config.extra_config_placement,
+            kernel_layout: config.kernel_layout,
+            early_initrds: config.early_initrds.clone(),
+            luks_params: generation::luks_kernel_params(
+                &config.luks_devices,
+                config.luks_param_style,
+            ),
+            profile_labels,
+            use_bootspec_label: config.use_bootspec_label,
+            profiles_root: config.profiles_root.clone(),
+            default_generation: config.default_generation.clone(),
+            extra_kernel_params: config.extra_kernel_params.clone(),
+            profiles: config.profiles.clone(),
+            ostype: config.ostype.clone(),
+            icon: config.icon.clone(),
+            enable_and_lock_vmx: config.enable_and_lock_vmx,
+            memtest86_path: config.memtest86_path.clone(),
+            windows_firmware_bootnum: config.windows_firmware_bootnum.clone(),
+            nix_path: Some(config.nix_path.clone()),
+            ..Default::default()
+        }
+    }
+
+    /// Kernel parameters added to `profile`'s entries
+    fn extra_kernel_params(&self, profile: Option<&str>) -> &[String] {
+        self.profiles
+            .get(profile.unwrap_or("system"))
+            .and_then(|overrides| overrides.extra_kernel_params.as_deref())
+            .unwrap_or(&self.extra_kernel_params)
+    }
+
+    /// Icon of `profile`'s entries
+    fn icon(&self, profile: Option<&str>) -> Option<&str> {
+        self.profiles
+            .get(profile.unwrap_or("system"))
+            .and_then(|overrides| overrides.icon.as_deref())
+            .or(self.icon.as_deref())
+    }
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        Self {
+            efi_mount: PathBuf::from("/boot"),
+            timeout: None,
+            text_only: false,
+            text_mode: None,
+            extra_config: Vec::new(),
+            extra_config_placement: ExtraConfigPlacement::default(),
+            kernel_layout: KernelLayout::default(),
+            early_initrds: Vec::new(),
+            luks_params: Vec::new(),
+            profile_labels: HashMap::new(),
+            include_activation_log: false,
+            changelog_in_description: false,
+            with_sizes: false,
+            use_bootspec_label: false,
+            strict: false,
+            merge_with: None,
+            profiles_root: generation::default_profiles_root(),
+            default_generation: None,
+            extra_kernel_params: Vec::new(),
+            profiles: HashMap::new(),
+            ostype: None,
+            icon: None,
+            volume: None,
+            enable_and_lock_vmx: false,
+            memtest86_path: None,
+            windows_firmware_bootnum: None,
+            nix_path: None,
+        }
+    }
+}
+
+/// Renders a rEFInd config from the generations on this machine without touching the ESP.
+///
+/// - Main entry shows only the default generation
+/// - Submenu lists all generations
+#[derive(Debug, Clone)]
+pub struct Generator {
+    options: GeneratorOptions,
+    filesystem: Arc<dyn Filesystem>,
+}
+
+impl Generator {
+    pub fn new(options: GeneratorOptions) -> Self {
+        Self {
+            options,
+            filesystem: Arc::new(fs::RealFs),
+        }
+    }
+
+    /// Read profiles, generations and boot.json through `filesystem` instead of the real
+    /// one
+    pub fn filesystem(mut self, filesystem: Arc<dyn Filesystem>) -> Self {
+        self.filesystem = filesystem;
+        self
+    }
+
+    /// The rEFInd config as a String. Pure dry-run: no writes, no copies, no syncs.
+    pub fn render(&self) -> Result<String> {
+        generate_config_string(self.filesystem.as_ref(), &self.options, None)
+    }
+
+    /// Like [`Generator::render`], reporting progress to `observer`
+    pub fn render_with_observer(&self, observer: &dyn ConfigGenObserver) -> Result<String> {
+        generate_config_string(self.filesystem.as_ref(), &self.options, Some(observer))
+    }
+
+    /// A POSIX sh script exporting REFINDGEN_* variables about the default generation
+    pub fn shell_config(&self) -> Result<String> {
+        shell_config(self.filesystem.as_ref(), &self.options)
+    }
+}
+
+/// Produces the rEFInd config as a String.
+/// Auto-discovers the "default" generation. Pure dry-run.
+fn generate_config_string(
+    filesystem: &dyn Filesystem,
+    options: &GeneratorOptions,
+    observer: Option<&dyn ConfigGenObserver>,
+) -> Result<String> {
+    let root = &options.profiles_root;
+    let (gens, default) =
+        discover_generations(filesystem, root, options.default_generation.as_ref())?;
+    generation::warn_unknown_profiles(
+        "profile label",
+        options.profile_labels.keys(),
+        &generation::get_profiles(filesystem, root)?,
+    );
+    let targets = discover_system_targets(filesystem, root);
+
+    // Build submenu for all generations, newest -> oldest
+    let mut rev = gens.clone();
+    rev.sort_by_key(|g| std::cmp::Reverse(g.number));
+
+    let mut submenu = String::new();
+    let mut skipped = Vec::new();
+    // Generations often share a toplevel (rollbacks, re-activations)
+    let mut closure_sizes: HashMap<PathBuf, Option<u64>> = HashMap::new();
+    for (i, g) in rev.iter().enumerate() {
+        if let Some(observer) = observer
+            && observer.on_generation_start(g).is_break()
+        {
+            let profile = g.profile.as_deref().unwrap_or("system");
+            anyhow::bail!("Cancelled at {} generation {}", profile, g.number);
+        }
+        let details = generation_details(
+            filesystem,
+            g,
+            root,
+            &options.efi_mount,
+            options.kernel_layout,
+            &options.early_initrds,
+            &options.luks_params,
+        );
+        if let (Some(observer), Err(error)) = (observer, &details) {
+            observer.on_generation_error(g, error);
+        }
+        let mut d = match details {
+            Ok(d) => d,
+            Err(error) if options.strict => return Err(error),
+            Err(error) => {
+                let profile = g.profile.as_deref().unwrap_or("system");
+                skipped.push(format!("{} generation {}: {:#}", profile, g.number, error));
+                continue;
+            }
+        };
+
+        if options.include_activation_log {
+            // Without journalctl the description goes without, like without a journal
+            match generation::get_generation_activation_log(g.number) {
+                Ok(lines) => {
+                    if let Some(line) = lines.first() {
+                        d.description
+                            .push_str(&format!(", {}", generation::sanitize_title(line)));
+                    }
+                }
+                Err(error) => {
+                    crate::warn!("no activation log for generation {}: {:#}", g.number, error)
+                }
+            }
+        }
+
+        if options.with_sizes {
+            let size = closure_size(
+                filesystem,
+                &generation::nix_binary(options.nix_path.as_deref()),
+                &system_dir(root, &g.profile, g.number),
+                &mut closure_sizes,
+            );
+            d.closure_size = size;
+            if let Some(size) = size {
+                d.description
+                    .push_str(&format!(", {}", generation::format_size(size)));
+            }
+        }
+
+        if options.changelog_in_description
+            && let Some(previous) = rev[i + 1..].iter().find(|p| p.profile == g.profile)
+        {
+            let diff = generation::diff_closures(
+                &generation::nix_binary(options.nix_path.as_deref()),
+                &filesystem.real_path(&system_dir(root, &previous.profile, previous.number)),
+                &filesystem.real_path(&system_dir(root, &g.profile, g.number)),
+            );
+            match diff {
+                Ok(diff) => {
+                    if let Some(line) = diff.lines().find(|line| !line.trim().is_empty()) {
+                        d.description
+                            .push_str(&format!(", {}", generation::sanitize_title(line.trim())));
+                    }
+                }
+                Err(error) => crate::warn!("no changelog for generation {}: {:#}", g.number, error),
+            }
+        }
+
+        // Booted/running state is only shown, it never picks the default
+        let link = system_dir(root, &g.profile, g.number);
+        if targets
+            .booted
+            .as_deref()
+            .is_some_and(|t| path_eq(filesystem, &link, t))
+        {
+            d.description.push_str(" (booted)");
+        } else if targets
+            .current
+            .as_deref()
+            .is_some_and(|t| path_eq(filesystem, &link, t))
+        {
+            d.description.push_str(" (running)");
+        }
+
+        if let Some(observer) = observer {
+            observer.on_generation_done(&d);
+        }
+        submenu.push_str(&submenu_entry(&d, options)?);
+        submenu.push('\n');
+    }
+
+    // Main entry: default (or newest). Without it there is no config to speak of.
+    let main_details = generation_details(
+        filesystem,
+        &default,
+        root,
+        &options.efi_mount,
+        options.kernel_layout,
+        &options.early_initrds,
+        &options.luks_params,
+    )
+    .context("Failed to load the default generation")?;
+
+    if !skipped.is_empty() {
+        crate::warn!(
+            "skipped {} generation(s) that could not be loaded:\n  {}",
+            skipped.len(),
+            skipped.join("\n  ")
+        );
+    }
+
+    // Assemble full config
+    let entries =
+        menu_entry(options, &main_details, &submenu)? + &tool_entries(filesystem, options, &gens)?;
+    let config = match options.merge_with {
+        Some(ref existing) => merge_refind_conf(&read_merge_target(existing)?, &entries)?,
+        None => build_config_text(options, &entries)?,
+    };
+
+    if let Some(observer) = observer {
+        observer.on_config_complete(&config);
+    }
+    Ok(config)
+}
+
+const MERGE_BEGIN: &str = "# refindgen-begin";
+const MERGE_END: &str = "# refindgen-end";
+
+pub(crate) fn read_merge_target(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config to merge into: {}", path.display()))
+}
+
+/// Replace the refindgen-managed block of a handcrafted config with `generated`,
+/// keeping everything outside the markers. Appends the block if there is none.
+pub(crate) fn merge_refind_conf(existing: &str, generated: &str) -> Result<String> {
+    let block = format!("{}\n{}\n{}\n", MERGE_BEGIN, generated.trim(), MERGE_END);
+
+    let mut out = String::new();
+    let mut inserted = false;
+    let mut in_block = false;
+    for line in existing.lines() {
+        if in_block {
+            in_block = line.trim() != MERGE_END;
+            continue;
+        }
+        if line.trim() == MERGE_BEGIN {
+            in_block = true;
+            if !inserted {
+                out.push_str(&block);
+                inserted = true;
+            }
+            continue;
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+
+    if in_block {
+        anyhow::bail!("'{}' without a matching '{}'", MERGE_BEGIN, MERGE_END);
+    }
+    if !inserted {
+        if !out.is_empty() && !out.ends_with("\n\n") {
+            out.push('\n');
+        }
+        out.push_str(&block);
+    }
+
+    Ok(out)
+}
+
+/// Closure size of the generation at `link`, looked up in `cache` by toplevel first.
+/// Warns and gives `None` when it can't be had, e.g. for a dangling link.
+fn closure_size(
+    filesystem: &dyn Filesystem,
+    nix: &Path,
+    link: &Path,
+    cache: &mut HashMap<PathBuf, Option<u64>>,
+) -> Option<u64> {
+    let toplevel = match filesystem.canonicalize(link) {
+        Ok(toplevel) => toplevel,
+        Err(error) => {
+            crate::warn!("no size for {}: {}", link.display(), error);
+            return None;
+        }
+    };
+    *cache.entry(toplevel).or_insert_with_key(|toplevel| {
+        match generation::closure_size(nix, &filesystem.real_path(toplevel)) {
+            Ok(size) => Some(size),
+            Err(error) => {
+                crate::warn!("no size for {}: {:#}", link.display(), error);
+                None
+            }
+        }
+    })
+}
+
+/// All generations (system + profiles), and the one booted by default: `pinned` when
+/// given, else the one the system profile selects.
+fn discover_generations(
+    filesystem: &dyn Filesystem,
+    profiles_root: &Path,
+    pinned: Option<&DefaultGeneration>,
+) -> Result<(Vec<Gen>, Gen)> {
+    let gens = generation::Generations::discover(filesystem, profiles_root)
+        .map(|g| {
+            g.map(|g| Gen {
+                profile: (g.profile != "system").then_some(g.profile),
+                number: g.number,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if gens.is_empty() {
+        anyhow::bail!("No NixOS generations found.");
+    }
+
+    if let Some(pin) = pinned {
+        let profile = (pin.profile != "system").then(|| pin.profile.clone());
+        let found = gens
+            .iter()
+            .find(|g| g.profile == profile && g.number == pin.number);
+        match found {
+            Some(g) => return Ok((gens.clone(), g.clone())),
+            None if pin.fallback_to_newest => crate::warn!(
+                "defaultGeneration {} generation {} no longer exists, defaulting to the newest",
+                pin.profile,
+                pin.number
+            ),
+            None => anyhow::bail!(
+                "defaultGeneration {} generation {} no longer exists.\n  \
+                 Pin another generation, or set fallbackToNewest to boot the newest.",
+                pin.profile,
+                pin.number
+            ),
+        }
+    }
+
+    // The main entry boots what the system profile selects, not what happens to be running
+    let default = match discover_system_targets(filesystem, profiles_root).selected {
+        Some(target) => find_generation_by_target(filesystem, profiles_root, &gens, &target)?
+            .unwrap_or_else(|| newest_generation(&gens)),
+        None => newest_generation(&gens),
+    };
+
+    Ok((gens, default))
+}
+
+/// POSIX sh exporting variables about the default generation.
+fn shell_config(filesystem: &dyn Filesystem, options: &GeneratorOptions) -> Result<String> {
+    let (gens, default) = discover_generations(
+        filesystem,
+        &options.profiles_root,
+        options.default_generation.as_ref(),
+    )?;
+    let details = generation_details(
+        filesystem,
+        &default,
+        &options.profiles_root,
+        &options.efi_mount,
+        options.kernel_layout,
+        &options.early_initrds,
+        &options.luks_params,
+    )?;
+
+    let mut vars = vec![
+        ("REFINDGEN_DEFAULT_GEN", default.number.to_string()),
+        (
+            "REFINDGEN_DEFAULT_PROFILE",
+            default
+                .profile
+                .clone()
+                .unwrap_or_else(|| "system".to_string()),
+        ),
+        ("REFINDGEN_GENERATION_COUNT", gens.len().to_string()),
+        ("REFINDGEN_KERNEL_PATH", details.loader),
+    ];
+    if let Some(initrd) = details.initrd {
+        vars.push(("REFINDGEN_INITRD_PATH", initrd));
+    }
+    vars.push(("REFINDGEN_KERNEL_PARAMS", details.kernel_params));
+
+    let mut content = String::from("# Generated by refindgen\n");
+    for (name, value) in vars {
+        content.push_str(&format!("export {}={}\n", name, shell_quote(&value)));
+    }
+
+    Ok(content)
+}
+
+/// Single-quote a value for POSIX sh
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// The system paths that matter for the default entry, each fully resolved.
+///
+/// These differ after `nixos-rebuild boot` (selected is newer than current and booted)
+/// and after `nixos-rebuild switch` (current moved on, booted did not).
+struct SystemTargets {
+    /// What boots next: <profiles root>/system
+    selected: Option<PathBuf>,
+    /// What is running now: /run/current-system
+    current: Option<PathBuf>,
+    /// What the machine booted into, unaffected by later switches: /run/booted-system
+    booted: Option<PathBuf>,
+}
+
+/// Links NixOS keeps to the running and the booted system
+const CURRENT_SYSTEM: &str = "/run/current-system";
+const BOOTED_SYSTEM: &str = "/run/booted-system";
+
+/// The three targets as resolved on `filesystem`, each `None` where its link is missing
+fn discover_system_targets(filesystem: &dyn Filesystem, profiles_root: &Path) -> SystemTargets {
+    let resolve = |p: &Path| filesystem.canonicalize(p).ok();
+    SystemTargets {
+        selected: resolve(&profiles_root.join("system")),
+        current: resolve(Path::new(CURRENT_SYSTEM)),
+        booted: resolve(Path::new(BOOTED_SYSTEM)),
+    }
+}
+
+fn newest_generation(gens: &[Gen]) -> Gen {
+    gens.iter()
+        .cloned()
+        .max_by_key(|g| g.number)
+        .expect("non-empty")
+}
+
+/// Match a discovered default *target path* to a generation's system link target.
+fn find_generation_by_target(
+    filesystem: &dyn Filesystem,
+    profiles_root: &Path,
+    gens: &[Gen],
+    target: &Path,
+) -> Result<Option<Gen>> {
+    for g in gens {
+        if path_eq(
+            filesystem,
+            &system_dir(profiles_root, &g.profile, g.number),
+            target,
+        ) {
+            return Ok(Some(g.clone()));
+        }
+    }
+    Ok(None)
+}
+
+/// `<profiles root>/system[-profiles/<profile>]-<number>-link`
+fn system_dir(profiles_root: &Path, profile: &Option<String>, number: u64) -> PathBuf {
+    generation::get_system_path(
+        profiles_root,
+        profile.as_deref().unwrap_or("system"),
+        Some(number),
+        None,
+    )
+}
+
+fn path_eq(filesystem: &dyn Filesystem, a: &Path, b: &Path) -> bool {
+    let canon = |p: &Path| {
+        filesystem
+            .canonicalize(p)
+            .unwrap_or_else(|_| p.to_path_buf())
+    };
+    canon(a) == canon(b)
+}
+
+/// Build details for a generation; **no copying** (dry-run).
+///
+/// Boot parameters come from boot.json, exactly as the install path sees them.
+/// Generations without boot.json fall back to the legacy `kernel`/`initrd`/`kernel-params` files.
+pub(crate) fn generation_details(
+    filesystem: &dyn Filesystem,
+    g: &Gen,
+    profiles_root: &Path,
+    efi_mount: &Path,
+    layout: KernelLayout,
+    early_initrds: &[PathBuf],
+    luks_params: &[String],
+) -> Result<GenDetails> {
+    let link = system_dir(profiles_root, &g.profile, g.number);
+    let bootspec = BootSpec::load(filesystem, &link)?;
+
+    let description = generation::describe_generation(filesystem, &link, &bootspec);
+
+    // Compute where they'd be staged (but don't copy)
+    let kernel_dir = generation::KernelDir::new(
+        &efi_mount.join("efi/refind"),
+        layout,
+        g.profile.as_deref().unwrap_or("system"),
+        g.number,
+    );
+
+    details_from_bootspec(
+        filesystem,
+        g,
+        &bootspec,
+        description,
+        &kernel_dir,
+        early_initrds,
+        luks_params,
+    )
+}
+
+fn details_from_bootspec(
+    filesystem: &dyn Filesystem,
+    g: &Gen,
+    bootspec: &BootSpec,
+    description: String,
+    kernel_dir: &generation::KernelDir,
+    configured_early: &[PathBuf],
+    luks_params: &[String],
+) -> Result<GenDetails> {
+    let (_, loader) = generation::kernel_destination(filesystem, &bootspec.kernel, kernel_dir)?;
+    let early_initrds = generation::early_initrds(configured_early, bootspec)
+        .iter()
+        .map(|early| Ok(generation::kernel_destination(filesystem, early, kernel_dir)?.1))
+        .collect::<Result<Vec<_>>>()?;
+    let initrd = match bootspec.initrd {
+        Some(ref initrd) => Some(generation::kernel_destination(filesystem, initrd, kernel_dir)?.1),
+        None => None,
+    };
+
+    let mut specialisations = Vec::new();
+    for (name, spec) in &bootspec.specialisations {
+        let details = details_from_bootspec(
+            filesystem,
+            g,
+            spec,
+            description.clone(),
+            kernel_dir,
+            configured_early,
+            luks_params,
+        )?;
+        specialisations.push((name.clone(), details));
+    }
+    specialisations.sort_by(|a, b| a.0.cmp(&b.0));
+
+    Ok(GenDetails {
+        profile: g.profile.clone(),
+        number: g.number,
+        loader,
+        initrd,
+        early_initrds,
+        kernel_params: generation::kernel_params(bootspec, luks_params),
+        system: bootspec.system.clone(),
+        description,
+        label: bootspec.label.trim().to_string(),
+        specialisations,
+        closure_size: None,
+    })
+}
+
+/// The MemTest86+ and Windows entries an install adds after the NixOS ones
+fn tool_entries(
+    filesystem: &dyn Filesystem,
+    options: &GeneratorOptions,
+    gens: &[Gen],
+) -> Result<String> {
+    let mut out = String::new();
+    // Found in the newest system generation, like the install does
+    let newest = gens
+        .iter()
+        .filter(|g| g.profile.is_none())
+        .max_by_key(|g| g.number);
+    if let Some(newest) = newest {
+        let bootspec = BootSpec::load(
+            filesystem,
+            &system_dir(&options.profiles_root, &None, newest.number),
+        )?;
+        let memtest =
+            generation::find_memtest(filesystem, options.memtest86_path.as_deref(), &bootspec)?;
+        if let Some(memtest) = memtest {
+            let tools_dir = generation::tools_dir(&options.efi_mount.join("efi/refind"));
+            let (_, loader) = generation::kernel_destination(filesystem, &memtest, &tools_dir)?;
+            out.push_str(&generation::memtest_entry(&loader));
+        }
+    }
+    if let Some(ref bootnum) = options.windows_firmware_bootnum {
+        out.push_str(&generation::generate_firmware_bootnum_entry(bootnum)?);
+    }
+    Ok(out)
+}
+
+fn build_config_text(options: &GeneratorOptions, entries: &str) -> Result<String> {
+    let mut out = String::new();
+    if let Some(secs) = options.timeout {
+        out.push_str(&format!("timeout {}\n", secs));
+    }
+    let (before, after) =
+        extra_config_sections(&options.extra_config, options.extra_config_placement)?;
+    out.push_str(&console_directives(
+        options.text_only,
+        options.text_mode,
+        &(before.clone() + &after),
+    ));
+    out.push_str(&before);
+    out.push_str(entries);
+    if !after.is_empty() {
+        out.push('\n');
+        out.push_str(&after);
+    }
+    Ok(out)
+}
+
+/// Hand-written config from `sources`, concatenated in order, split into what goes
+/// before and after the NixOS entries
+pub(crate) fn extra_config_sections(
+    sources: &[ExtraConfig],
+    placement: ExtraConfigPlacement,
+) -> Result<(String, String)> {
+    let mut text = String::new();
+    for source in sources {
+        let part = source.read()?;
+        text.push_str(&part);
+        if !part.is_empty() && !part.ends_with('\n') {
+            text.push('\n');
+        }
+    }
+    Ok(ConfigSectionParser::parse(&text).place(placement))
+}
+
+/// The global `textonly` and `textmode` lines for `text_only` and `text_mode`, warning
+/// about each one `extra_config` sets as well
+pub(crate) fn console_directives(
+    text_only: bool,
+    text_mode: Option<u32>,
+    extra_config: &str,
+) -> String {
+    let mut out = String::new();
+    if text_only {
+        out.push_str("textonly\n");
+    }
+    if let Some(mode) = text_mode {
+        out.push_str(&format!("textmode {}\n", mode));
+    }
+
+    // rEFInd reads directives case-insensitively, and the last one wins
+    for (directive, field) in [("textonly", "textOnly"), ("textmode", "textMode")] {
+        let ours = out.lines().any(|line| line.starts_with(directive));
+        let theirs = extra_config.lines().any(|line| {
+            line.split_whitespace()
+                .next()
+                .is_some_and(|word| word.eq_ignore_ascii_case(directive))
+        });
+        if ours && theirs {
+            crate::warn!(
+                "extraConfig sets {} as well as {}, rEFInd uses whichever comes last",
+                directive,
+                field
+            );
+        }
+    }
+    out
+}
+
+/// Splits hand-written rEFInd config into its `menuentry` blocks and everything else
+#[derive(Debug, Default)]
+pub(crate) struct ConfigSectionParser {
+    /// Global settings and comments, in order
+    settings: String,
+    /// Complete `menuentry ... { ... }` blocks, in order
+    menu_entries: String,
+}
+
+impl ConfigSectionParser {
+    pub(crate) fn parse(text: &str) -> Self {
+        let mut sections = Self::default();
+        let mut depth = 0usize;
+        for line in text.lines() {
+            let trimmed = line.trim_start();
+            if depth == 0 && !trimmed.starts_with("menuentry") {
+                sections.settings.push_str(line);
+                sections.settings.push('\n');
+                continue;
+            }
+
+            sections.menu_entries.push_str(line);
+            sections.menu_entries.push('\n');
+            depth += line.matches('{').count();
+            depth = depth.saturating_sub(line.matches('}').count());
+        }
+        sections
+    }
+
+    /// Text to put before and after the NixOS entries
+    pub(crate) fn place(self, placement: ExtraConfigPlacement) -> (String, String) {
+        match placement {
+            ExtraConfigPlacement::Before => (self.settings + &self.menu_entries, String::new()),
+            ExtraConfigPlacement::After => (self.settings, self.menu_entries),
+        }
+    }
+}
+
+fn menu_entry(
+    options: &GeneratorOptions,
+    main: &GenDetails,
+    submenu_entries: &str,
+) -> Result<String> {
+    let ostype = generation::entry_ostype(options.ostype.as_deref(), &main.system);
+    let mut head = format!("    ostype {}\n", ostype);
+    if let Some(icon) = options.icon(main.profile.as_deref()) {
+        head.push_str(&format!("    icon {}\n", icon));
+    }
+    if let Some(ref volume) = options.volume {
+        head.push_str(&format!("    volume {}\n", volume));
+    }
+    Ok(format!(
+        r#"
+menuentry "NixOS" {{
+{}    loader {}
+{}    options {}
+{}{}}}
+"#,
+        head,
+        main.loader,
+        initrd_line(main),
+        options_value(options, main)?,
+        vmx_line(options),
+        indent(submenu_entries.trim_end(), 4),
+    ))
+}
+
+fn submenu_entry(d: &GenDetails, options: &GeneratorOptions) -> Result<String> {
+    let title = generation::entry_title(
+        d.number,
+        d.profile.as_deref().unwrap_or("system"),
+        &options.profile_labels,
+        options.use_bootspec_label.then_some(d.label.as_str()),
+        &d.description,
+    );
+
+    let mut out = submenu_block(&title, d, options)?;
+    for (name, spec) in &d.specialisations {
+        let spec_title = format!("{} [{}]", title, generation::sanitize_title(name));
+        out.push_str(&submenu_block(&spec_title, spec, options)?);
+    }
+    Ok(out)
+}
+
+fn submenu_block(title: &str, d: &GenDetails, options: &GeneratorOptions) -> Result<String> {
+    Ok(format!(
+        r#"
+submenuentry "{}" {{
+    loader {}
+{}    options {}
+{}}}
+"#,
+        title,
+        d.loader,
+        initrd_line(d),
+        options_value(options, d)?,
+        vmx_line(options),
+    ))
+}
+
+/// All initrds of an entry, early ones first
+fn initrd_uris(d: &GenDetails) -> Vec<String> {
+    d.early_initrds.iter().chain(&d.initrd).cloned().collect()
+}
+
+/// The `initrd` line for a single initrd; several go into the options instead
+fn initrd_line(d: &GenDetails) -> String {
+    match initrd_uris(d).as_slice() {
+        [initrd] => format!("    initrd {}\n", initrd),
+        _ => String::new(),
+    }
+}
+
+/// The quoted `options` value, built the same way as the install's
+fn options_value(options: &GeneratorOptions, d: &GenDetails) -> Result<String> {
+    generation::entry_options(
+        &d.kernel_params,
+        options.extra_kernel_params(d.profile.as_deref()),
+        &initrd_uris(d),
+    )
+}
+
+fn vmx_line(options: &GeneratorOptions) -> &'static str {
+    if options.enable_and_lock_vmx {
+        "    enable_and_lock_vmx true\n"
+    } else {
+        ""
+    }
+}
+
+fn indent(s: &str, n: usize) -> String {
+    let pad = " ".repeat(n);
+    s.lines()
+        .map(|l| format!("{pad}{l}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::tests::ScratchDir;
+    use crate::generation::tests::{PROFILES, add_generation, select_generation};
+    use crate::log::tests::warnings;
+
+    #[test]
+    fn generator_reads_through_its_filesystem() {
+        let scratch = ScratchDir::new();
+        add_generation(&scratch, "system", 1, "aaa-linux-6.1", "bbb-initrd");
+        let toplevel = add_generation(&scratch, "system", 2, "ccc-linux-6.6", "bbb-initrd");
+        crate::fs::tests::put(
+            scratch.rooted().as_ref(),
+            &format!("{}/nixos-version", toplevel),
+            "24.05.1234",
+        );
+
+        let config = Generator::new(GeneratorOptions {
+            efi_mount: PathBuf::from("/boot"),
+            profiles_root: PathBuf::from(PROFILES),
+            ..Default::default()
+        })
+        .filesystem(scratch.rooted())
+        .render()
+        .unwrap();
+        let titles: Vec<_> = config
+            .lines()
+            .filter(|line| line.contains("submenuentry"))
+            .collect();
+        assert_eq!(titles.len(), 2, "{}", config);
+        assert!(
+            titles[0].contains("Generation 2 NixOS 24.05.1234,"),
+            "{}",
+            config
+        );
+        assert!(
+            titles[1].contains("Generation 1 NixOS Unknown,"),
+            "{}",
+            config
+        );
+        assert!(config.contains("loader /efi/refind/kernels/ccc-linux-6.6-bzImage"));
+        assert!(config.contains("loader /efi/refind/kernels/aaa-linux-6.1-bzImage"));
+        assert!(config.contains(r#"options "init=/store/system-1-nixos-system/init quiet""#));
+    }
+
+    /// The `options` lines of `config`, in order, excluding the one of the main entry
+    fn submenu_options(config: &str) -> Vec<String> {
+        let mut options = Vec::new();
+        let mut in_submenu = false;
+        for line in config.lines().map(str::trim) {
+            in_submenu |= line.starts_with("submenuentry");
+            if in_submenu && line.starts_with("options ") {
+                options.push(line.to_string());
+            }
+        }
+        options
+    }
+
+    /// Point `link` at `target`, both inside `scratch`
+    fn link_to(scratch: &ScratchDir, link: &str, target: &str) {
+        let link = scratch.rooted().host_path(Path::new(link));
+        std::fs::create_dir_all(link.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(format!("..{}", target), link).unwrap();
+    }
+
+    #[test]
+    fn selected_running_and_booted_systems_can_differ() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        let booted = add_generation(&scratch, "system", 1, "aaa-linux", "aaa-initrd");
+        let running = add_generation(&scratch, "system", 2, "bbb-linux", "bbb-initrd");
+        add_generation(&scratch, "system", 3, "ccc-linux", "ccc-initrd");
+        add_generation(&scratch, "system", 4, "ddd-linux", "ddd-initrd");
+        // nixos-rebuild boot into 3 after switching to 2, then 4 built but not selected
+        select_generation(&scratch, "system", 3);
+        link_to(&scratch, CURRENT_SYSTEM, &running);
+        link_to(&scratch, BOOTED_SYSTEM, &booted);
+
+        let targets = discover_system_targets(filesystem.as_ref(), Path::new(PROFILES));
+        assert_eq!(
+            targets.selected.as_deref(),
+            Some(Path::new("/store/system-3-nixos-system"))
+        );
+        assert_eq!(
+            targets.current.as_deref(),
+            Some(Path::new(running.as_str()))
+        );
+        assert_eq!(targets.booted.as_deref(), Some(Path::new(booted.as_str())));
+
+        let config = Generator::new(GeneratorOptions {
+            efi_mount: PathBuf::from("/boot"),
+            profiles_root: PathBuf::from(PROFILES),
+            ..Default::default()
+        })
+        .filesystem(filesystem)
+        .render()
+        .unwrap();
+        // The main entry boots the selected generation, not the newest or the running one
+        let main = config.split("submenuentry").next().unwrap();
+        assert!(
+            main.contains("loader /efi/refind/kernels/ccc-linux-bzImage"),
+            "{}",
+            config
+        );
+
+        let titles: Vec<_> = config
+            .lines()
+            .filter(|line| line.contains("submenuentry"))
+            .collect();
+        assert_eq!(titles.len(), 4, "{}", config);
+        assert!(!titles[0].contains(" ("), "{}", titles[0]);
+        assert!(!titles[1].contains(" ("), "{}", titles[1]);
+        assert!(titles[2].ends_with(" (running)\" {"), "{}", titles[2]);
+        assert!(titles[3].ends_with(" (booted)\" {"), "{}", titles[3]);
+    }
+
+    #[test]
+    fn missing_system_links_are_no_targets() {
+        let scratch = ScratchDir::new();
+        add_generation(&scratch, "system", 1, "aaa-linux", "aaa-initrd");
+        let targets = discover_system_targets(scratch.rooted().as_ref(), Path::new(PROFILES));
+        assert!(targets.selected.is_some());
+        assert!(targets.current.is_none());
+        assert!(targets.booted.is_none());
+    }
+
+    #[test]
+    fn options_match_the_installed_entries() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        crate::fs::tests::put(filesystem.as_ref(), "/store/ucode/ucode.img", "ucode");
+        add_generation(&scratch, "system", 1, "aaa-linux", "aaa-initrd");
+        add_generation(&scratch, "system", 2, "bbb-linux", "bbb-initrd");
+        add_generation(&scratch, "work", 3, "ccc-linux", "ccc-initrd");
+        let config: InstallConfig = serde_json::from_value(serde_json::json!({
+            "nixPath": "/nix",
+            "refindPath": "/refind",
+            "efiMountPoint": "/boot",
+            "profilesRoot": PROFILES,
+            "hostArchitecture": "x86_64",
+            "extraKernelParams": ["quiet", "console=ttyS0"],
+            "earlyInitrds": ["/store/ucode/ucode.img"],
+            "luksDevices": [["root", "/dev/disk/by-uuid/0f1e2d3c-4b5a-6978-8796-a5b4c3d2e1f0"]],
+            "profiles": {"work": {"extraKernelParams": ["mitigations=off"]}},
+        }))
+        .unwrap();
+
+        let rendered = Generator::new(GeneratorOptions::for_install(&config))
+            .filesystem(filesystem.clone())
+            .render()
+            .unwrap();
+
+        let refind_dir = Path::new("/boot/efi/refind");
+        let mut tracker =
+            fs::FileTracker::with_filesystem(filesystem, refind_dir, generation::MANAGED_DIRS)
+                .unwrap();
+        let installed: Vec<_> = [("work", 3), ("system", 2), ("system", 1)]
+            .into_iter()
+            .map(|(profile, number)| {
+                let entry = generation::generate_config_entry(
+                    profile,
+                    number,
+                    &config.for_profile(profile),
+                    refind_dir,
+                    None,
+                    &mut tracker,
+                )
+                .unwrap();
+                let options = entry
+                    .lines()
+                    .map(str::trim)
+                    .find(|line| line.starts_with("options "));
+                options.unwrap().to_string()
+            })
+            .collect();
+
+        assert_eq!(submenu_options(&rendered), installed, "{}", rendered);
+        assert!(installed[0].contains("mitigations=off"), "{:?}", installed);
+        assert!(installed[1].contains("console=ttyS0"), "{:?}", installed);
+        assert!(installed[2].contains("rd.luks.name="), "{:?}", installed);
+    }
+
+    #[test]
+    fn titles_match_the_installed_entries() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        add_generation(&scratch, "system", 1, "aaa-linux", "aaa-initrd");
+        add_generation(&scratch, "work", 2, "bbb-linux", "bbb-initrd");
+        add_generation(&scratch, "kiosk", 3, "ccc-linux", "ccc-initrd");
+
+        for use_bootspec_label in [false, true] {
+            let config: InstallConfig = serde_json::from_value(serde_json::json!({
+                "nixPath": "/nix",
+                "refindPath": "/refind",
+                "efiMountPoint": "/boot",
+                "profilesRoot": PROFILES,
+                "hostArchitecture": "x86_64",
+                "useBootspecLabel": use_bootspec_label,
+                "profileLabels": {"work": "Work"},
+                "profiles": {"kiosk": {"label": "Kiosk"}},
+            }))
+            .unwrap();
+
+            let rendered = Generator::new(GeneratorOptions::for_install(&config))
+                .filesystem(filesystem.clone())
+                .render()
+                .unwrap();
+            let rendered: Vec<_> = rendered
+                .lines()
+                .filter_map(|line| line.trim().strip_prefix("submenuentry "))
+                .map(|line| line.trim_end_matches(" {").to_string())
+                .collect();
+
+            let refind_dir = Path::new("/boot/efi/refind");
+            let mut tracker = fs::FileTracker::with_filesystem(
+                filesystem.clone(),
+                refind_dir,
+                generation::MANAGED_DIRS,
+            )
+            .unwrap();
+            let installed: Vec<_> = [("kiosk", 3), ("work", 2), ("system", 1)]
+                .into_iter()
+                .map(|(profile, number)| {
+                    let entry = generation::generate_config_entry(
+                        profile,
+                        number,
+                        &config.for_profile(profile),
+                        refind_dir,
+                        None,
+                        &mut tracker,
+                    )
+                    .unwrap();
+                    let title = entry.lines().next().unwrap().strip_prefix("menuentry ");
+                    title.unwrap().trim_end_matches(" {").to_string()
+                })
+                .collect();
+
+            assert_eq!(rendered, installed);
+            assert!(
+                installed[0].starts_with("\"Generation 3 (Kiosk) "),
+                "{:?}",
+                installed
+            );
+            assert!(
+                installed[1].starts_with("\"Generation 2 (Work) "),
+                "{:?}",
+                installed
+            );
+            let text = if use_bootspec_label {
+                "NixOS 24.05 (Linux 1)"
+            } else {
+                "NixOS Unknown, Linux Kernel unknown, Built on "
+            };
+            assert!(
+                installed[2].starts_with(&format!("\"Generation 1 {}", text)),
+                "{:?}",
+                installed
+            );
+        }
+    }
+
+    #[test]
+    fn install_settings_reach_the_rendered_entries() {
+        let scratch = ScratchDir::new();
+        let filesystem = scratch.rooted();
+        let toplevel = add_generation(&scratch, "system", 1, "aaa-linux", "aaa-initrd");
+        crate::fs::tests::put(
+            filesystem.as_ref(),
+            &format!("{}/sw/share/memtest86plus/memtest.efi", toplevel),
+            "memtest",
+        );
+        let config: InstallConfig = serde_json::from_value(serde_json::json!({
+            "nixPath": "/nix",
+            "refindPath": "/refind",
+            "efiMountPoint": "/boot",
+            "profilesRoot": PROFILES,
+            "hostArchitecture": "x86_64",
+            "ostype": "NixOS",
+            "icon": "/efi/refind/icons/os_nixos.png",
+            "enableAndLockVmx": true,
+            "windowsFirmwareBootnum": "0003",
+        }))
+        .unwrap();
+
+        let rendered = Generator::new(GeneratorOptions {
+            volume: Some("0f1e2d3c".to_string()),
+            ..GeneratorOptions::for_install(&config)
+        })
+        .filesystem(filesystem)
+        .render()
+        .unwrap();
+
+        for line in [
+            "    ostype NixOS\n",
+            "    icon /efi/refind/icons/os_nixos.png\n",
+            "    volume 0f1e2d3c\n",
+            "        enable_and_lock_vmx true\n",
+            "menuentry \"MemTest86+\" {\n  loader /efi/refind/tools/",
+            "menuentry \"Windows (via firmware)\" {\n  firmware_bootnum 0003\n}\n",
+        ] {
+            assert!(rendered.contains(line), "{:?} in\n{}", line, rendered);
+        }
+    }
+
+    /// Generation `number` of `profile` under `root/profiles`, with real absolute links
+    /// into `root/store` as Nix makes them, and a `rescue` specialisation if asked for
+    fn real_generation(root: &Path, profile: &str, number: u64, rescue: bool) -> PathBuf {
+        let store = root.join("store");
+        let write_bootspec = |toplevel: &Path, linux: &str, params: &str| {
+            let linux = store.join(linux);
+            std::fs::create_dir_all(&linux).unwrap();
+            std::fs::write(linux.join("bzImage"), "kernel").unwrap();
+            std::fs::create_dir_all(toplevel).unwrap();
+            let boot_json = serde_json::json!({
+                "org.nixos.bootspec.v1": {
+                    "system": "x86_64-linux",
+                    "init": toplevel.join("init"),
+                    "kernel": linux.join("bzImage"),
+                    "kernelParams": [params],
+                    "label": format!("NixOS ({} {})", profile, number),
+                    "toplevel": toplevel,
+                }
+            });
+            boot_json
+        };
+
+        let toplevel = store.join(format!("{}-{}-system", profile, number));
+        let mut boot_json = write_bootspec(&toplevel, &format!("{}-linux", number), "quiet");
+        if rescue {
+            let spec = store.join(format!("{}-{}-rescue", profile, number));
+            let spec_json = write_bootspec(&spec, "rescue-linux", "systemd.unit=rescue.target");
+            boot_json["org.nixos.specialisation.v1"] = serde_json::json!({ "rescue": spec_json });
+        }
+        std::fs::write(toplevel.join("boot.json"), boot_json.to_string()).unwrap();
+
+        let profiles = root.join("profiles");
+        let link = generation::get_system_path(&profiles, profile, Some(number), None);
+        std::fs::create_dir_all(link.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(&toplevel, &link).unwrap();
+        let current = generation::get_system_path(&profiles, profile, None, None);
+        let _ = std::fs::remove_file(&current);
+        std::os::unix::fs::symlink(link.file_name().unwrap(), current).unwrap();
+        toplevel
+    }
+
+    fn real_options(root: &Path) -> GeneratorOptions {
+        GeneratorOptions {
+            efi_mount: root.join("boot"),
+            profiles_root: root.join("profiles"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn pipeline_renders_a_profiles_root_on_disk() {
+        let scratch = ScratchDir::new();
+        let root = scratch.path();
+        real_generation(root, "system", 1, false);
+        real_generation(root, "system", 2, false);
+        real_generation(root, "work", 3, true);
+        // Rolled back: the system profile selects generation 1 again
+        std::fs::remove_file(root.join("profiles/system")).unwrap();
+        std::os::unix::fs::symlink("system-1-link", root.join("profiles/system")).unwrap();
+
+        let (config, warned) = warnings(|| {
+            Generator::new(GeneratorOptions {
+                use_bootspec_label: true,
+                ..real_options(root)
+            })
+            .render()
+            .unwrap()
+        });
+        assert!(warned.is_empty(), "{:?}", warned);
+
+        let main = config.split("submenuentry").next().unwrap();
+        assert!(
+            main.contains("loader /efi/refind/kernels/1-linux-bzImage"),
+            "{}",
+            config
+        );
+        let titles: Vec<_> = config
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("submenuentry "))
+            .collect();
+        assert_eq!(
+            titles,
+            [
+                "\"Generation 3 (work) NixOS (work 3)\" {",
+                "\"Generation 3 (work) NixOS (work 3) [rescue]\" {",
+                "\"Generation 2 NixOS (system 2)\" {",
+                "\"Generation 1 NixOS (system 1)\" {",
+            ]
+        );
+        assert!(config.contains("loader /efi/refind/kernels/rescue-linux-bzImage"));
+        assert!(config.contains(&format!(
+            "options \"init={}/init systemd.unit=rescue.target\"",
+            root.join("store/work-3-rescue").display()
+        )));
+    }
+
+    #[test]
+    fn pipeline_reports_staged_files_to_observers() {
+        let scratch = ScratchDir::new();
+        let root = scratch.path();
+        real_generation(root, "system", 1, false);
+        real_generation(root, "system", 2, true);
+        let kernels = root.join("boot/efi/refind/kernels");
+        std::fs::create_dir_all(&kernels).unwrap();
+        std::fs::write(kernels.join("1-linux-bzImage"), "kernel").unwrap();
+        std::fs::write(kernels.join("0-linux-bzImage"), "kernel").unwrap();
+
+        let collector = StagedFileCollector::new(&root.join("boot"));
+        Generator::new(real_options(root))
+            .render_with_observer(&collector)
+            .unwrap();
+
+        assert_eq!(
+            collector.loaders(),
+            ["1-linux-bzImage", "2-linux-bzImage", "rescue-linux-bzImage"].map(|k| kernels.join(k))
+        );
+        assert_eq!(
+            collector.cleanup_plan().unwrap(),
+            [kernels.join("0-linux-bzImage")]
+        );
+    }
+
+    #[test]
+    fn shell_config_describes_the_default_generation() {
+        let scratch = ScratchDir::new();
+        let root = scratch.path();
+        let toplevel = real_generation(root, "system", 4, false);
+        real_generation(root, "work", 5, false);
+
+        let script = Generator::new(real_options(root)).shell_config().unwrap();
+        assert_eq!(
+            script,
+            format!(
+                "# Generated by refindgen\n\
+                 export REFINDGEN_DEFAULT_GEN='4'\n\
+                 export REFINDGEN_DEFAULT_PROFILE='system'\n\
+                 export REFINDGEN_GENERATION_COUNT='2'\n\
+                 export REFINDGEN_KERNEL_PATH='/efi/refind/kernels/4-linux-bzImage'\n\
+                 export REFINDGEN_KERNEL_PARAMS='init={}/init quiet'\n",
+                toplevel.display()
+            )
+        );
+    }
+
+    #[test]
+    fn broken_generations_are_skipped_unless_strict() {
+        let scratch = ScratchDir::new();
+        add_generation(&scratch, "system", 1, "aaa-linux", "aaa-initrd");
+        let broken = add_generation(&scratch, "system", 2, "bbb-linux", "bbb-initrd");
+        add_generation(&scratch, "system", 5_000_000_000, "ccc-linux", "ccc-initrd");
+        crate::fs::tests::put(
+            scratch.rooted().as_ref(),
+            &format!("{}/boot.json", broken),
+            "[]",
+        );
+        let generator = |strict| {
+            Generator::new(GeneratorOptions {
+                efi_mount: PathBuf::from("/boot"),
+                profiles_root: PathBuf::from(PROFILES),
+                strict,
+                ..Default::default()
+            })
+            .filesystem(scratch.rooted())
+        };
+
+        let (config, warned) = warnings(|| generator(false).render().unwrap());
+        assert_eq!(
+            warned,
+            [
+                "skipped 1 generation(s) that could not be loaded:\n  system generation 2: \
+                 Invalid boot.json at \"/nix/var/nix/profiles/system-2-link/boot.json\": \
+                 boot.json: expected an object"
+            ]
+        );
+        let titles: Vec<_> = config
+            .lines()
+            .filter(|line| line.contains("submenuentry"))
+            .collect();
+        assert_eq!(titles.len(), 2, "{}", config);
+        assert!(titles[0].contains("Generation 5000000000 "), "{}", config);
+        assert!(titles[1].contains("Generation 1 "), "{}", config);
+
+        let error = generator(true).render().unwrap_err();
+        assert_eq!(
+            format!("{:#}", error),
+            "Invalid boot.json at \"/nix/var/nix/profiles/system-2-link/boot.json\": \
+             boot.json: expected an object"
+        );
+    }
+
+    /// A `nix` under `scratch/nix` answering diff-closures and path-info like the real one
+    fn fake_nix(scratch: &ScratchDir) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+        let nix_path = scratch.path().join("nix");
+        let bin = nix_path.join("bin/nix");
+        std::fs::create_dir_all(bin.parent().unwrap()).unwrap();
+        std::fs::write(
+            &bin,
+            "#!/bin/sh\n\
+             case \"$*\" in\n\
+             *diff-closures*) echo 'firefox: 120.0 -> 121.0, +1.2 MiB' ;;\n\
+             *path-info*) echo \"$5 1610612736\" ;;\n\
+             *) exit 1 ;;\n\
+             esac\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&bin, std::fs::Permissions::from_mode(0o755)).unwrap();
+        nix_path
+    }
+
+    #[test]
+    fn changelogs_and_sizes_use_the_configured_nix() {
+        let scratch = ScratchDir::new();
+        add_generation(&scratch, "system", 1, "aaa-linux", "aaa-initrd");
+        add_generation(&scratch, "system", 2, "bbb-linux", "bbb-initrd");
+
+        let config = Generator::new(GeneratorOptions {
+            efi_mount: PathBuf::from("/boot"),
+            profiles_root: PathBuf::from(PROFILES),
+            changelog_in_description: true,
+            with_sizes: true,
+            nix_path: Some(fake_nix(&scratch)),
+            ..Default::default()
+        })
+        .filesystem(scratch.rooted())
+        .render()
+        .unwrap();
+
+        let titles: Vec<_> = config
+            .lines()
+            .filter(|line| line.contains("submenuentry"))
+            .collect();
+        assert!(
+            titles[0].ends_with(", 1.5 GiB, firefox: 120.0 -> 121.0, +1.2 MiB\" {"),
+            "{}",
+            config
+        );
+        // The oldest generation has nothing to diff against
+        assert!(titles[1].ends_with(", 1.5 GiB\" {"), "{}", config);
+    }
+
+    #[test]
+    fn dangling_toplevels_have_no_size() {
+        let scratch = ScratchDir::new();
+        let toplevel = add_generation(&scratch, "system", 1, "aaa-linux", "aaa-initrd");
+        let filesystem = scratch.rooted();
+        let link = generation::get_system_path(Path::new(PROFILES), "system", Some(1), None);
+        let nix = generation::nix_binary(Some(&fake_nix(&scratch)));
+        let mut cache = HashMap::new();
+
+        let (size, warned) =
+            warnings(|| closure_size(filesystem.as_ref(), &nix, &link, &mut cache));
+        assert_eq!(size, Some(1610612736));
+        assert!(warned.is_empty(), "{:?}", warned);
+
+        std::fs::remove_dir_all(filesystem.host_path(Path::new(&toplevel))).unwrap();
+        let (size, warned) =
+            warnings(|| closure_size(filesystem.as_ref(), &nix, &link, &mut cache));
+        assert_eq!(size, None);
+        assert_eq!(warned.len(), 1);
+        assert!(
+            warned[0].starts_with("no size for /nix/var/nix/profiles/system-1-link: "),
+            "{:?}",
+            warned
+        );
+    }
+
+    /// Counts the generations it sees, cancelling at the `limit`th
+    struct CancelAfter {
+        limit: usize,
+        started: std::cell::Cell<usize>,
+        completed: std::cell::Cell<bool>,
+    }
+
+    impl ConfigGenObserver for CancelAfter {
+        fn on_generation_start(&self, _g: &Gen) -> ControlFlow<()> {
+            self.started.set(self.started.get() + 1);
+            if self.started.get() == self.limit {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        }
+
+        fn on_config_complete(&self, _config: &str) {
+            self.completed.set(true);
+        }
+    }
+
+    #[test]
+    fn observers_can_cancel_rendering() {
+        let scratch = ScratchDir::new();
+        for number in 1..=3 {
+            add_generation(&scratch, "system", number, "linux", "initrd");
+        }
+        let generator = Generator::new(GeneratorOptions {
+            efi_mount: PathBuf::from("/boot"),
+            profiles_root: PathBuf::from(PROFILES),
+            ..Default::default()
+        })
+        .filesystem(scratch.rooted());
+
+        let observer = CancelAfter {
+            limit: 2,
+            started: Default::default(),
+            completed: Default::default(),
+        };
+        let error = generator.render_with_observer(&observer).unwrap_err();
+        assert_eq!(error.to_string(), "Cancelled at system generation 2");
+        assert_eq!(observer.started.get(), 2);
+        assert!(!observer.completed.get());
+
+        let observer = CancelAfter {
+            limit: 4,
+            started: Default::default(),
+            completed: Default::default(),
+        };
+        generator.render_with_observer(&observer).unwrap();
+        assert_eq!(observer.started.get(), 3);
+        assert!(observer.completed.get());
+    }
+
+    #[test]
+    fn console_directives_follow_the_config() {
+        assert_eq!(console_directives(false, None, ""), "");
+        assert_eq!(console_directives(true, None, ""), "textonly\n");
+        assert_eq!(
+            console_directives(true, Some(1024), ""),
+            "textonly\ntextmode 1024\n"
+        );
+    }
+
+    #[test]
+    fn directives_repeated_in_extra_config_are_warned_about() {
+        let (out, warned) = warnings(|| {
+            console_directives(true, Some(2), "# textonly\nTextMode 0\nshowtools shell\n")
+        });
+        assert_eq!(out, "textonly\ntextmode 2\n");
+        assert_eq!(
+            warned,
+            ["extraConfig sets textmode as well as textMode, rEFInd uses whichever comes last"]
+        );
+
+        let (_, warned) = warnings(|| console_directives(false, None, "textonly\ntextmode 1\n"));
+        assert!(warned.is_empty(), "{:?}", warned);
+
+        let (_, warned) = warnings(|| console_directives(true, None, "  TEXTONLY\n"));
+        assert_eq!(
+            warned,
+            ["extraConfig sets textonly as well as textOnly, rEFInd uses whichever comes last"]
+        );
+    }
+}
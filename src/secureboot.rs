@@ -0,0 +1,90 @@
+//! Secure Boot signatures for the EFI binaries refindgen stages: made with sbsign or the
+//! user's own command, and checked with sbverify before anything can boot them.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::{config::SecureBoot, fs};
+
+/// Write a signed copy of `source` to `dest`, atomically: the copy is signed and verified
+/// beside `dest`, and only then replaces it
+pub fn sign(
+    secure_boot: &SecureBoot,
+    source: &Path,
+    dest: &Path,
+    copies: &fs::CopySettings,
+) -> Result<()> {
+    fs::replace_atomic_with(dest, copies, |temp| {
+        let mut command = match secure_boot.sign_command {
+            Some(ref sign_command) => {
+                let mut command = Command::new(sign_command);
+                command.arg(source).arg(temp);
+                command
+            }
+            None => {
+                let key = secure_boot
+                    .key_path
+                    .as_ref()
+                    .context("secureBoot needs keyPath or signCommand")?;
+                let mut command = Command::new(tool(secure_boot, "sbsign"));
+                command
+                    .arg("--key")
+                    .arg(key)
+                    .arg("--cert")
+                    .arg(&secure_boot.cert_path)
+                    .arg("--output")
+                    .arg(temp)
+                    .arg(source);
+                command
+            }
+        };
+        run(&mut command)?;
+        verify(secure_boot, temp)
+    })
+    .with_context(|| format!("Failed to sign {}", dest.display()))
+}
+
+/// Check that `path` carries a signature by `certPath`
+pub fn verify(secure_boot: &SecureBoot, path: &Path) -> Result<()> {
+    run(Command::new(tool(secure_boot, "sbverify"))
+        .arg("--cert")
+        .arg(&secure_boot.cert_path)
+        .arg(path))
+    .with_context(|| {
+        format!(
+            "Signature of {} does not verify against {}",
+            path.display(),
+            secure_boot.cert_path.display()
+        )
+    })
+}
+
+/// SHA-256 of `certPath`, recorded with each signed file so a new certificate re-signs them
+pub fn cert_fingerprint(secure_boot: &SecureBoot) -> Result<String> {
+    crate::hash::sha256_file(&secure_boot.cert_path)
+}
+
+/// `name` from `sbsigntoolPath`, or from `PATH` without one
+fn tool(secure_boot: &SecureBoot, name: &str) -> PathBuf {
+    match secure_boot.sbsigntool_path {
+        Some(ref package) => package.join("bin").join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    let program = command.get_program().to_string_lossy().to_string();
+    let output = command
+        .output()
+        .with_context(|| format!("Failed to run {}", program))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} failed with {}: {}",
+            program,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}